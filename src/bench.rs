@@ -0,0 +1,264 @@
+// A small benchmark harness in the style of criterion, which doesn't need any dependencies. Every
+// benchmark program is run through both the interpreter and the compiler and VM, and the time
+// spent parsing, compiling and executing it is measured separately.
+//
+// Each measurement is warmed up first, which also estimates how long one iteration takes. Then the
+// iterations are split into samples that take roughly the same time, and the mean and standard
+// deviation of the time per iteration in each sample are reported. Only the measured step is
+// timed: the steps before it, like parsing before compiling, run outside of the timer.
+//
+// The results can be saved to a file, and the next run compares itself against it, so it's easy
+// to tell whether a change made things faster. Like in criterion, small changes are reported as
+// noise, and so are changes smaller than the spread of the samples of both runs.
+
+use crate::compiler::{optimize::OptLevel, Compiler};
+use crate::error::MonkeyResult;
+use crate::interpreter;
+use crate::limits::Limits;
+use crate::parser::{self, ast::NodeStatement};
+use crate::vm::VM;
+
+use std::collections::HashMap;
+use std::fs;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The benchmark programs, with their names. Each one is a representative workload that takes a
+/// few milliseconds to run.
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "fib",
+        "let fib = fn(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }; fib(20)",
+    ),
+    // Loops made of tail calls, which the optimiser turns into superinstructions
+    (
+        "count_down",
+        "let count = fn(n, acc) { if n <= 0 { acc } else { count(n - 1, acc + 2) } };
+        count(20000, 0)",
+    ),
+    (
+        "sum_up",
+        "let sum = fn(i, n, acc) { if i < n { sum(i + 1, n, acc + i) } else { acc } };
+        sum(0, 20000, 0)",
+    ),
+    (
+        "closures",
+        "let compose = fn(f, g) { fn(x) { g(f(x)) } };
+        let make_adder = fn(n) { fn(x) { x + n } };
+        let add_all = fn(f, n) { if n == 0 { f } else { add_all(compose(f, make_adder(n)), n - 1) } };
+        let f = add_all(fn(x) { x }, 200);
+        for i in range(100) { f(i); }",
+    ),
+    (
+        "array_push",
+        "let build = fn(arr, n) { if n == 0 { arr } else { build(push(arr, n * 2), n - 1) } };
+        for i in range(20) { build([], 500); }",
+    ),
+    (
+        "string_concat",
+        r#"let repeat = fn(s, n) { if n == 0 { s } else { repeat(s + str(n) + ",", n - 1) } };
+        for i in range(20) { repeat("", 500); }"#,
+    ),
+    (
+        "hash",
+        "let build = fn(h, n) { if n == 0 { h } else { build(#{..h, n: n * n}, n - 1) } };
+        let h = build(#{}, 300);
+        let sum = fn(n, acc) { if n == 0 { acc } else { sum(n - 1, acc + h[n]) } };
+        for i in range(20) { sum(300, 0); }",
+    ),
+];
+
+const WARM_UP_TIME: Duration = Duration::from_millis(300);
+const MEASUREMENT_TIME: Duration = Duration::from_secs(1);
+const NUM_SAMPLES: usize = 20;
+
+// Changes in the mean time smaller than this fraction are always considered noise
+const NOISE_THRESHOLD: f64 = 0.05;
+
+/// Runs every benchmark and prints the results. If `baseline` is given, the results are compared
+/// with the ones saved in that file, if it exists, and then saved to it.
+pub fn run(baseline: Option<&Path>, opt_level: OptLevel) -> MonkeyResult<()> {
+    let previous = match baseline {
+        Some(path) if path.exists() => read_results(&fs::read_to_string(path)?),
+        _ => HashMap::new(),
+    };
+    let mut results = Vec::new();
+    for (name, source) in PROGRAMS {
+        let parse = || parser::parse(source.to_string()).expect("Benchmark doesn't parse");
+        let compile = |program: Vec<NodeStatement>| {
+            let mut compiler = Compiler::new();
+            compiler.set_opt_level(opt_level);
+            compiler.compile_block(program).unwrap();
+            compiler.bytecode()
+        };
+
+        let mut report = |step: &str, stats: Stats| {
+            let id = format!("{}/{}", name, step);
+            print_stats(&id, &stats, previous.get(&id));
+            results.push((id, stats));
+        };
+        report("parse", measure(|| source.to_string(), parser::parse));
+        report("compile", measure(parse, compile));
+        let vm = measure(
+            || (VM::new(), compile(parse())),
+            |(mut vm, bytecode)| vm.run(bytecode).map(|_| vm),
+        );
+        let interpreter = measure(parse, |program| {
+            interpreter::run_program(program, Limits::default())
+        });
+        let speedup = interpreter.mean / vm.mean;
+        report("vm", vm);
+        report("interpreter", interpreter);
+        println!("{:<28} the VM is {:.2}x as fast\n", "", speedup);
+    }
+
+    if let Some(path) = baseline {
+        let saved: String = results
+            .iter()
+            .map(|(id, stats)| format!("{} {} {}\n", id, stats.mean, stats.std_dev))
+            .collect();
+        fs::write(path, saved)?;
+    }
+    Ok(())
+}
+
+// The time per iteration, in seconds
+#[derive(Debug, PartialEq)]
+struct Stats {
+    mean: f64,
+    std_dev: f64,
+}
+
+/// Measures the time it takes to run `routine`, which is given a new value created by `setup` on
+/// every iteration. Creating the value isn't timed, and neither is dropping what `routine` returns.
+fn measure<I, O>(mut setup: impl FnMut() -> I, mut routine: impl FnMut(I) -> O) -> Stats {
+    let mut run = |iterations: u64| {
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..iterations {
+            let input = setup();
+            let start = Instant::now();
+            let output = black_box(routine(black_box(input)));
+            elapsed += start.elapsed();
+            drop(output);
+        }
+        elapsed
+    };
+
+    let warm_up_start = Instant::now();
+    let (mut warm_up_iterations, mut warm_up_elapsed) = (0, Duration::ZERO);
+    while warm_up_start.elapsed() < WARM_UP_TIME {
+        warm_up_elapsed += run(1);
+        warm_up_iterations += 1;
+    }
+    let iteration_time = warm_up_elapsed.as_secs_f64() / warm_up_iterations as f64;
+    let sample_time = MEASUREMENT_TIME.as_secs_f64() / NUM_SAMPLES as f64;
+    let iterations = ((sample_time / iteration_time) as u64).max(1);
+
+    let samples: Vec<_> = (0..NUM_SAMPLES)
+        .map(|_| run(iterations).as_secs_f64() / iterations as f64)
+        .collect();
+    let mean = samples.iter().sum::<f64>() / NUM_SAMPLES as f64;
+    let variance =
+        samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (NUM_SAMPLES - 1) as f64;
+    Stats {
+        mean,
+        std_dev: variance.sqrt(),
+    }
+}
+
+fn print_stats(id: &str, stats: &Stats, previous: Option<&Stats>) {
+    print!(
+        "{:<28} time: {:>10} ± {}",
+        id,
+        format_time(stats.mean),
+        format_time(stats.std_dev)
+    );
+    if let Some(previous) = previous {
+        let change = stats.mean / previous.mean - 1.0;
+        let spread = 2.0 * (stats.std_dev.powi(2) + previous.std_dev.powi(2)).sqrt();
+        let verdict =
+            if change.abs() < NOISE_THRESHOLD || (stats.mean - previous.mean).abs() < spread {
+                "no change"
+            } else if change < 0.0 {
+                "improved"
+            } else {
+                "regressed"
+            };
+        print!("   change: {:+.2}% ({})", change * 100.0, verdict);
+    }
+    println!();
+}
+
+fn format_time(seconds: f64) -> String {
+    if seconds < 1e-6 {
+        format!("{:.2} ns", seconds * 1e9)
+    } else if seconds < 1e-3 {
+        format!("{:.2} µs", seconds * 1e6)
+    } else if seconds < 1.0 {
+        format!("{:.2} ms", seconds * 1e3)
+    } else {
+        format!("{:.2} s", seconds)
+    }
+}
+
+/// Parses saved results, which have the id of a measurement, its mean time and its standard
+/// deviation on each line. Lines that can't be parsed are ignored.
+fn read_results(saved: &str) -> HashMap<String, Stats> {
+    saved
+        .lines()
+        .filter_map(|line| match *line.split(' ').collect::<Vec<_>>() {
+            [id, mean, std_dev] => {
+                let stats = Stats {
+                    mean: mean.parse().ok()?,
+                    std_dev: std_dev.parse().ok()?,
+                };
+                Some((id.to_string(), stats))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::DEFAULT_MAX_CALL_DEPTH;
+
+    #[test]
+    fn test_programs() {
+        // The programs must run without errors in both the interpreter and the VM
+        for (name, source) in PROGRAMS {
+            let program = parser::parse(source.to_string()).unwrap();
+            let mut compiler = Compiler::new();
+            compiler.compile_block(program.clone()).unwrap();
+            if let Err(e) = VM::new().run(compiler.bytecode()) {
+                panic!("{} failed in the VM: {}", name, e);
+            }
+            // The interpreter needs a bigger stack for the recursive programs, like `main` gives it
+            let result = std::thread::Builder::new()
+                .stack_size(interpreter::stack_size(DEFAULT_MAX_CALL_DEPTH))
+                .spawn(|| {
+                    interpreter::run_program(program, Limits::default()).map_err(|e| e.to_string())
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+            if let Err(e) = result {
+                panic!("{} failed in the interpreter: {}", name, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_results() {
+        let saved =
+            "fib/parse 0.000012 0.000001\nfib/vm 1.5e-3 0\n\nfib/compile 1 abc\nhash/vm 1\n";
+        let stats = |mean, std_dev| Stats { mean, std_dev };
+        let expected = HashMap::from([
+            ("fib/parse".to_string(), stats(0.000012, 0.000001)),
+            ("fib/vm".to_string(), stats(0.0015, 0.0)),
+        ]);
+        assert_eq!(expected, read_results(saved));
+    }
+}
@@ -1,10 +1,23 @@
 use crate::error::*;
+use crate::gc;
+use crate::limits;
+use crate::module;
 use crate::object::*;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Clone)]
-pub struct BuiltinFn(pub fn(Vec<Object>) -> Result<Object, RuntimeError>);
+pub enum BuiltinFn {
+    /// A function that only needs its arguments.
+    Native(fn(Vec<Object>) -> Result<Object, RuntimeError>),
+    /// `next`. Resuming a generator needs to run code, so calls to `next` are handled by the
+    /// interpreter and the VM instead.
+    Next,
+}
 
 impl fmt::Debug for BuiltinFn {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -19,16 +32,29 @@ impl PartialEq for BuiltinFn {
     }
 }
 
-pub const ALL_BUILTINS: [(&str, BuiltinFn); 9] = [
-    ("type", BuiltinFn(builtin_type)),
-    ("puts", BuiltinFn(builtin_puts)),
-    ("len", BuiltinFn(builtin_len)),
-    ("push", BuiltinFn(builtin_push)),
-    ("cons", BuiltinFn(builtin_cons)),
-    ("head", BuiltinFn(builtin_head)),
-    ("tail", BuiltinFn(builtin_tail)),
-    ("range", BuiltinFn(builtin_range)),
-    ("assert", BuiltinFn(builtin_assert)),
+pub const ALL_BUILTINS: [(&str, BuiltinFn); 22] = [
+    ("type", BuiltinFn::Native(builtin_type)),
+    ("puts", BuiltinFn::Native(builtin_puts)),
+    ("len", BuiltinFn::Native(builtin_len)),
+    ("push", BuiltinFn::Native(builtin_push)),
+    ("cons", BuiltinFn::Native(builtin_cons)),
+    ("head", BuiltinFn::Native(builtin_head)),
+    ("tail", BuiltinFn::Native(builtin_tail)),
+    ("range", BuiltinFn::Native(builtin_range)),
+    ("assert", BuiltinFn::Native(builtin_assert)),
+    ("str", BuiltinFn::Native(builtin_str)),
+    ("trim", BuiltinFn::Native(builtin_trim)),
+    ("split", BuiltinFn::Native(builtin_split)),
+    ("join", BuiltinFn::Native(builtin_join)),
+    ("keys", BuiltinFn::Native(builtin_keys)),
+    ("values", BuiltinFn::Native(builtin_values)),
+    ("import", BuiltinFn::Native(builtin_import)),
+    ("error", BuiltinFn::Native(builtin_error)),
+    ("is_error", BuiltinFn::Native(builtin_is_error)),
+    ("unwrap", BuiltinFn::Native(builtin_unwrap)),
+    ("unwrap_or", BuiltinFn::Native(builtin_unwrap_or)),
+    ("next", BuiltinFn::Next),
+    ("gc", BuiltinFn::Native(builtin_gc)),
 ];
 
 pub fn get_builtin(name: &str) -> Option<Object> {
@@ -38,6 +64,65 @@ pub fn get_builtin(name: &str) -> Option<Object> {
         .map(|(_, f)| Object::Builtin(f.clone()))
 }
 
+/// Returns the names of the built-in functions that can be called with method syntax
+/// (`value.method(args)`) on objects of the given type. Any value can be an error, so every type
+/// has the methods for handling errors.
+fn get_methods(type_str: &str) -> &'static [&'static str] {
+    match type_str {
+        "string" => &[
+            "len",
+            "str",
+            "trim",
+            "split",
+            "is_error",
+            "unwrap",
+            "unwrap_or",
+        ],
+        "array" => &[
+            "len",
+            "str",
+            "push",
+            "head",
+            "tail",
+            "join",
+            "is_error",
+            "unwrap",
+            "unwrap_or",
+        ],
+        "hash" => &["str", "keys", "values", "is_error", "unwrap", "unwrap_or"],
+        "generator" => &["str", "next", "is_error", "unwrap", "unwrap_or"],
+        _ => &["str", "is_error", "unwrap", "unwrap_or"],
+    }
+}
+
+/// The function called by a method call expression.
+pub enum Method {
+    /// A struct field, which is called with just the method call arguments.
+    Field(Object),
+    /// A built-in function, which is called with the receiver as its first argument.
+    Builtin(BuiltinFn),
+}
+
+/// Resolves a method call on `receiver`. Struct fields take precedence over built-in methods.
+/// Returns an error listing the available methods if there is no method with the given name.
+pub fn get_method(receiver: &Object, name: &str) -> Result<Method, RuntimeError> {
+    if let Ok(value) = receiver.get_field(name) {
+        return Ok(Method::Field(value));
+    }
+    let methods = get_methods(receiver.type_str());
+    match methods.iter().find(|&&m| m == name) {
+        Some(&method) => match get_builtin(method) {
+            Some(Object::Builtin(f)) => Ok(Method::Builtin(f)),
+            _ => unreachable!(), // Every method name is the name of a built-in function
+        },
+        None => Err(RuntimeError::AttributeError(
+            receiver.type_str(),
+            name.into(),
+            methods,
+        )),
+    }
+}
+
 fn assert_num_arguments(args: &[Object], expected: usize) -> Result<(), RuntimeError> {
     if args.len() != expected {
         Err(RuntimeError::WrongNumberOfArgs(expected, args.len()))
@@ -62,7 +147,29 @@ fn assert_object_type_array(obj: &Object) -> Result<&Vec<Object>, RuntimeError>
         Ok(a)
     } else {
         Err(RuntimeError::TypeError(
-            Object::Array(Box::new(vec![])).type_str(),
+            Object::Array(Box::default()).type_str(),
+            obj.type_str(),
+        ))
+    }
+}
+
+fn assert_object_type_string(obj: &Object) -> Result<&str, RuntimeError> {
+    if let Object::Str(s) = obj {
+        Ok(s)
+    } else {
+        Err(RuntimeError::TypeError(
+            Object::from("").type_str(),
+            obj.type_str(),
+        ))
+    }
+}
+
+fn assert_object_type_hash(obj: &Object) -> Result<&HashMap<HashableObject, Object>, RuntimeError> {
+    if let Object::Hash(h) = obj {
+        Ok(h)
+    } else {
+        Err(RuntimeError::TypeError(
+            Object::Hash(Box::default()).type_str(),
             obj.type_str(),
         ))
     }
@@ -70,7 +177,10 @@ fn assert_object_type_array(obj: &Object) -> Result<&Vec<Object>, RuntimeError>
 
 fn builtin_type(args: Vec<Object>) -> Result<Object, RuntimeError> {
     assert_num_arguments(&args, 1)?;
-    Ok(Object::from(args[0].type_str()))
+    match &args[0] {
+        Object::Struct(s) => Ok(Object::from(s.definition.name.as_str())),
+        other => Ok(Object::from(other.type_str())),
+    }
 }
 
 fn builtin_puts(args: Vec<Object>) -> Result<Object, RuntimeError> {
@@ -105,7 +215,9 @@ fn builtin_len(args: Vec<Object>) -> Result<Object, RuntimeError> {
 
 fn builtin_push(args: Vec<Object>) -> Result<Object, RuntimeError> {
     assert_num_arguments(&args, 2)?;
-    let mut array = assert_object_type_array(&args[0])?.clone();
+    let array = assert_object_type_array(&args[0])?;
+    limits::allocate(array.len() + 1)?;
+    let mut array = array.clone();
     array.push(args[1].clone());
     Ok(Object::Array(Box::new(array)))
 }
@@ -113,6 +225,7 @@ fn builtin_push(args: Vec<Object>) -> Result<Object, RuntimeError> {
 fn builtin_cons(args: Vec<Object>) -> Result<Object, RuntimeError> {
     assert_num_arguments(&args, 2)?;
     let tail = assert_object_type_array(&args[1])?;
+    limits::allocate(tail.len() + 1)?;
     let mut new = vec![args[0].clone()];
     new.extend_from_slice(tail);
     Ok(Object::Array(Box::new(new)))
@@ -121,7 +234,7 @@ fn builtin_cons(args: Vec<Object>) -> Result<Object, RuntimeError> {
 fn builtin_head(args: Vec<Object>) -> Result<Object, RuntimeError> {
     assert_num_arguments(&args, 1)?;
     let array = assert_object_type_array(&args[0])?;
-    if let Some(obj) = array.get(0) {
+    if let Some(obj) = array.first() {
         Ok(obj.clone())
     } else {
         Ok(Object::Nil)
@@ -132,7 +245,10 @@ fn builtin_tail(args: Vec<Object>) -> Result<Object, RuntimeError> {
     assert_num_arguments(&args, 1)?;
     let array = assert_object_type_array(&args[0])?;
     match array.get(1..) {
-        Some(tail) => Ok(Object::Array(Box::new(tail.to_vec()))),
+        Some(tail) => {
+            limits::allocate(tail.len())?;
+            Ok(Object::Array(Box::new(tail.to_vec())))
+        }
         None => Ok(Object::Nil),
     }
 }
@@ -163,6 +279,14 @@ fn builtin_range(args: Vec<Object>) -> Result<Object, RuntimeError> {
             "Third argument to `range` must be positive".into(),
         ));
     }
+    // The size is checked before the range is created, since it can be huge
+    let (start_wide, end_wide) = (i128::from(start), i128::from(end));
+    let size = if start < end {
+        (end_wide - start_wide - 1) / i128::from(step) + 1
+    } else {
+        0
+    };
+    limits::allocate(usize::try_from(size).unwrap_or(usize::MAX))?;
 
     Ok(Object::Array(Box::new(
         (start..end)
@@ -177,9 +301,140 @@ fn builtin_assert(args: Vec<Object>) -> Result<Object, RuntimeError> {
     if args[0].is_truthy() {
         Ok(Object::Nil)
     } else {
-        Err(RuntimeError::Custom(format!(
-            "Assertion failed on value {}",
-            args[0]
-        )))
+        Err(RuntimeError::AssertionFailed(Box::new(args[0].clone())))
+    }
+}
+
+fn builtin_str(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 1)?;
+    let s = args[0].stringify();
+    limits::allocate(s.len())?;
+    Ok(Object::Str(Box::new(s)))
+}
+
+fn builtin_trim(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 1)?;
+    let s = assert_object_type_string(&args[0])?.trim();
+    limits::allocate(s.len())?;
+    Ok(Object::from(s))
+}
+
+fn builtin_split(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 2)?;
+    let s = assert_object_type_string(&args[0])?;
+    let separator = assert_object_type_string(&args[1])?;
+    // Splitting by an empty separator splits the string into its characters
+    let parts: Vec<_> = if separator.is_empty() {
+        s.chars()
+            .map(|c| Object::from(c.to_string().as_str()))
+            .collect()
+    } else {
+        s.split(separator).map(Object::from).collect()
+    };
+    limits::allocate(parts.len())?;
+    Ok(Object::Array(Box::new(parts)))
+}
+
+fn builtin_join(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 2)?;
+    let array = assert_object_type_array(&args[0])?;
+    let separator = assert_object_type_string(&args[1])?;
+    let parts = array.iter().map(Object::stringify).collect::<Vec<_>>();
+    let joined = parts.join(separator);
+    limits::allocate(joined.len())?;
+    Ok(Object::Str(Box::new(joined)))
+}
+
+fn builtin_keys(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 1)?;
+    let hash = assert_object_type_hash(&args[0])?;
+    limits::allocate(hash.len())?;
+    // The keys are sorted, so the result is consistent with the way hashes are printed
+    let mut keys = hash.keys().collect::<Vec<_>>();
+    keys.sort();
+    let keys = keys.into_iter().cloned().map(Object::from).collect();
+    Ok(Object::Array(Box::new(keys)))
+}
+
+fn builtin_values(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 1)?;
+    let hash = assert_object_type_hash(&args[0])?;
+    limits::allocate(hash.len())?;
+    // The values are sorted by their keys, in the same order returned by `keys`
+    let mut entries = hash.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    let values = entries.into_iter().map(|(_, v)| v.clone()).collect();
+    Ok(Object::Array(Box::new(values)))
+}
+
+// When compiling, calls to `import` are resolved by the compiler, so this is only used by the
+// interpreter
+fn builtin_import(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 1)?;
+    let path = assert_object_type_string(&args[0])?;
+    module::import(path)
+}
+
+fn builtin_error(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    if args.is_empty() {
+        return Err(RuntimeError::WrongNumberOfArgs(1, 0));
+    } else if args.len() > 2 {
+        return Err(RuntimeError::WrongNumberOfArgs(2, args.len()));
+    }
+    let message = assert_object_type_string(&args[0])?.to_string();
+    let data = args.into_iter().nth(1).unwrap_or(Object::Nil);
+    limits::allocate(0)?;
+    Ok(Object::Error(Box::new(ErrorObject { message, data })))
+}
+
+fn builtin_is_error(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 1)?;
+    Ok(Object::Boolean(matches!(args[0], Object::Error(_))))
+}
+
+fn builtin_unwrap(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 1)?;
+    match args.into_iter().next().unwrap() {
+        Object::Error(e) => Err(RuntimeError::UnwrapError(Box::new(Object::Error(e)))),
+        value => Ok(value),
+    }
+}
+
+fn builtin_unwrap_or(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 2)?;
+    let mut args = args.into_iter();
+    match (args.next().unwrap(), args.next().unwrap()) {
+        (Object::Error(_), default) => Ok(default),
+        (value, _) => Ok(value),
+    }
+}
+
+// Runs the cycle collector, and returns the number of objects it freed along with the memory
+// statistics
+fn builtin_gc(args: Vec<Object>) -> Result<Object, RuntimeError> {
+    assert_num_arguments(&args, 0)?;
+    let collected = gc::collect();
+    let stats = gc::stats();
+    let entries = [
+        ("collected", collected),
+        ("total_collected", stats.collected),
+        ("collections", stats.collections),
+        ("allocated", stats.allocated),
+        ("environments", stats.environments),
+        ("generators", stats.generators),
+    ];
+    let hash = entries
+        .iter()
+        .map(|&(key, value)| (HashableObject::from(key), Object::Integer(value as i64)))
+        .collect();
+    Ok(Object::Hash(Box::new(hash)))
+}
+
+/// Checks the arguments of a call to `next`, and returns the generator.
+pub fn next_generator(args: &[Object]) -> Result<&Rc<RefCell<GeneratorObject>>, RuntimeError> {
+    assert_num_arguments(args, 1)?;
+    match &args[0] {
+        Object::Generator(generator) => Ok(generator),
+        other => Err(RuntimeError::TypeError("generator", other.type_str())),
     }
 }
@@ -1,6 +1,7 @@
-use crate::object::Object;
 use crate::lexer::token;
+use crate::object::Object;
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::mem;
@@ -34,10 +35,18 @@ impl fmt::Debug for Instructions {
 pub struct Bytecode {
     pub instructions: Instructions,
     pub constants: Vec<Object>,
+    // Debugging information, used to show the source of the code and where runtime errors happen
+    pub lines: LineTable,
 }
 
+/// Maps the code of the main program, with the key `None`, and of each function, by the index of
+/// its constant, to the source lines it was compiled from. Holds the offsets where the code of each
+/// line starts, and the line.
+pub type LineTable = HashMap<Option<usize>, Vec<(usize, usize)>>;
+
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     OpConstant,
     OpPop,
@@ -52,7 +61,7 @@ pub enum OpCode {
     OpEquals,
     OpNotEquals,
     OpGreaterThan,
-    OpGreaterEq, // @PERFORMANCE: Maybe this should be implemented in terms of "!" and "<"?
+    OpGreaterEq,
     OpPrefixMinus,
     OpPrefixNot,
     OpJumpNotTruthy,
@@ -70,6 +79,45 @@ pub enum OpCode {
     OpGetBuiltin,
     OpClosure,
     OpGetFree,
+    OpArrayPush,
+    OpArrayExtend,
+    OpHashInsert,
+    OpHashExtend,
+    OpCallSpread,
+    OpConcat,
+    OpBitAnd,
+    OpBitOr,
+    OpBitXor,
+    OpShiftLeft,
+    OpShiftRight,
+    OpPrefixBitNot,
+    OpJumpNotNil,
+    OpIndexOptional,
+    OpGetField,
+    OpSetField,
+    OpMethodCall,
+    OpMethodCallSpread,
+    OpTry,
+    OpEndTry,
+    OpThrow,
+    OpJumpNotError,
+    OpGenerator,
+    OpYield,
+    OpIter,
+    OpIterNext,
+    OpTailCall,
+    OpLessThan,
+    OpLessEq,
+    // Superinstructions, emitted by the peephole optimiser in place of common sequences of
+    // instructions. Fused comparisons pop two values and jump if the comparison is false, and
+    // `OpIncrementLocal` and `OpDecrementLocal` push the sum or difference of a local and a
+    // constant
+    OpJumpNotLess,
+    OpJumpNotLessEq,
+    OpJumpNotGreater,
+    OpJumpNotGreaterEq,
+    OpIncrementLocal,
+    OpDecrementLocal,
 }
 
 impl OpCode {
@@ -106,15 +154,108 @@ impl OpCode {
             OpCode::OpGetBuiltin => &[1],
             OpCode::OpClosure => &[2, 1],
             OpCode::OpGetFree => &[1],
+            OpCode::OpArrayPush => &[],
+            OpCode::OpArrayExtend => &[],
+            OpCode::OpHashInsert => &[],
+            OpCode::OpHashExtend => &[],
+            OpCode::OpCallSpread => &[],
+            OpCode::OpConcat => &[2],
+            OpCode::OpBitAnd => &[],
+            OpCode::OpBitOr => &[],
+            OpCode::OpBitXor => &[],
+            OpCode::OpShiftLeft => &[],
+            OpCode::OpShiftRight => &[],
+            OpCode::OpPrefixBitNot => &[],
+            OpCode::OpJumpNotNil => &[2],
+            OpCode::OpIndexOptional => &[],
+            OpCode::OpGetField => &[2],
+            OpCode::OpSetField => &[2],
+            OpCode::OpMethodCall => &[2, 1],
+            OpCode::OpMethodCallSpread => &[2],
+            OpCode::OpTry => &[2],
+            OpCode::OpEndTry => &[],
+            OpCode::OpThrow => &[],
+            OpCode::OpJumpNotError => &[2],
+            OpCode::OpGenerator => &[],
+            OpCode::OpYield => &[],
+            OpCode::OpIter => &[],
+            OpCode::OpIterNext => &[2],
+            OpCode::OpTailCall => &[1],
+            OpCode::OpLessThan => &[],
+            OpCode::OpLessEq => &[],
+            OpCode::OpJumpNotLess => &[2],
+            OpCode::OpJumpNotLessEq => &[2],
+            OpCode::OpJumpNotGreater => &[2],
+            OpCode::OpJumpNotGreaterEq => &[2],
+            OpCode::OpIncrementLocal => &[1, 2],
+            OpCode::OpDecrementLocal => &[1, 2],
+        }
+    }
+
+    /// Returns whether the first operand of the instruction is the position of another
+    /// instruction, that the program can continue from.
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            OpCode::OpJump
+                | OpCode::OpJumpNotTruthy
+                | OpCode::OpJumpNotNil
+                | OpCode::OpJumpNotError
+                | OpCode::OpTry
+                | OpCode::OpIterNext
+                | OpCode::OpJumpNotLess
+                | OpCode::OpJumpNotLessEq
+                | OpCode::OpJumpNotGreater
+                | OpCode::OpJumpNotGreaterEq
+        )
+    }
+
+    /// Returns the fused compare-and-jump instruction for a comparison, which replaces the
+    /// comparison followed by `OpJumpNotTruthy`.
+    pub fn fused_jump(self) -> Option<OpCode> {
+        match self {
+            OpCode::OpLessThan => Some(OpCode::OpJumpNotLess),
+            OpCode::OpLessEq => Some(OpCode::OpJumpNotLessEq),
+            OpCode::OpGreaterThan => Some(OpCode::OpJumpNotGreater),
+            OpCode::OpGreaterEq => Some(OpCode::OpJumpNotGreaterEq),
+            _ => None,
+        }
+    }
+
+    /// Returns the comparison done by a fused compare-and-jump instruction.
+    pub fn fused_comparison(self) -> Option<OpCode> {
+        match self {
+            OpCode::OpJumpNotLess => Some(OpCode::OpLessThan),
+            OpCode::OpJumpNotLessEq => Some(OpCode::OpLessEq),
+            OpCode::OpJumpNotGreater => Some(OpCode::OpGreaterThan),
+            OpCode::OpJumpNotGreaterEq => Some(OpCode::OpGreaterEq),
+            _ => None,
         }
     }
 
     pub fn from_byte(byte: u8) -> OpCode {
+        OpCode::try_from_byte(byte).expect("byte does not represent valid opcode")
+    }
+
+    /// Converts a byte to an opcode, returning `None` if the byte doesn't represent one.
+    pub fn try_from_byte(byte: u8) -> Option<OpCode> {
         // Safety: `OpCode` is #[repr(u8)], so as long as `byte` represents a valid enum
-        // variant, this transmute will be safe. We make sure of that by asserting that `byte`
+        // variant, this transmute will be safe. We make sure of that by checking that `byte`
         // is no greater than the last variant.
-        assert!(byte <= (OpCode::OpGetFree as u8), "byte does not represent valid opcode");
-        unsafe { mem::transmute(byte) }
+        if byte <= (OpCode::OpDecrementLocal as u8) {
+            Some(unsafe { mem::transmute::<u8, OpCode>(byte) })
+        } else {
+            None
+        }
+    }
+
+    /// Converts a byte to an opcode without checking that it represents one.
+    ///
+    /// # Safety
+    ///
+    /// The byte must be no greater than the last opcode, like the bytes the verifier accepts.
+    pub unsafe fn from_byte_unchecked(byte: u8) -> OpCode {
+        mem::transmute::<u8, OpCode>(byte)
     }
 
     pub fn equivalent_token(self) -> Option<token::Token> {
@@ -129,8 +270,16 @@ impl OpCode {
             OpCode::OpNotEquals => Some(token::Token::NotEquals),
             OpCode::OpGreaterThan => Some(token::Token::GreaterThan),
             OpCode::OpGreaterEq => Some(token::Token::GreaterEq),
+            OpCode::OpLessThan => Some(token::Token::LessThan),
+            OpCode::OpLessEq => Some(token::Token::LessEq),
             OpCode::OpPrefixMinus => Some(token::Token::Minus),
             OpCode::OpPrefixNot => Some(token::Token::Bang),
+            OpCode::OpBitAnd => Some(token::Token::BitAnd),
+            OpCode::OpBitOr => Some(token::Token::BitOr),
+            OpCode::OpBitXor => Some(token::Token::Tilde),
+            OpCode::OpShiftLeft => Some(token::Token::ShiftLeft),
+            OpCode::OpShiftRight => Some(token::Token::ShiftRight),
+            OpCode::OpPrefixBitNot => Some(token::Token::Tilde),
             _ => None,
         }
     }
@@ -139,7 +288,7 @@ impl OpCode {
 #[macro_export]
 macro_rules! make {
     ($op:expr $(,$rand:expr )*) => {
-        crate::compiler::code::make($op, &[ $( $rand ),*])
+        $crate::compiler::code::make($op, &[ $( $rand ),*])
     };
 }
 
@@ -0,0 +1,183 @@
+// Shows the whole bytecode of a program in a readable form: the constant pool, the main program
+// and every compiled function. Jump targets are replaced by labels, and instructions that refer to
+// constants or built-ins are annotated with them. If the program was compiled from source, each
+// source line is shown before the code compiled from it.
+
+use super::code::*;
+use crate::builtins;
+use crate::object::*;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+// The column where annotations start
+const ANNOTATION_COLUMN: usize = 32;
+
+/// Returns the disassembly of the bytecode. `source` is the source code of the program, which is
+/// used to show the source lines, if the bytecode has a line table.
+pub fn disassemble(bytecode: &Bytecode, source: Option<&str>) -> String {
+    let disassembler = Disassembler {
+        bytecode,
+        source_lines: source.map(|s| s.lines().collect()).unwrap_or_default(),
+    };
+    let mut out = String::new();
+    disassembler.write_constants(&mut out);
+    out.push_str("\nmain:\n");
+    disassembler.write_code(&mut out, None, &bytecode.instructions.0);
+    for index in disassembler.function_order() {
+        if let Object::CompiledFunc(func) = &bytecode.constants[index] {
+            let _ = writeln!(
+                out,
+                "\nfn#{} (params: {}, locals: {}):",
+                index, func.num_params, func.num_locals
+            );
+            disassembler.write_code(&mut out, Some(index), &func.instructions.0);
+        }
+    }
+    out
+}
+
+struct Disassembler<'a> {
+    bytecode: &'a Bytecode,
+    source_lines: Vec<&'a str>,
+}
+
+impl Disassembler<'_> {
+    fn write_constants(&self, out: &mut String) {
+        out.push_str("constants:\n");
+        for index in 0..self.bytecode.constants.len() {
+            let _ = writeln!(out, "{:6}  {}", index, self.constant(index));
+        }
+    }
+
+    /// Returns the indices of the function constants, in the order they are shown. Functions are
+    /// shown after the code that creates them, so the nested functions of a function come right
+    /// after it.
+    fn function_order(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        self.add_closures(&self.bytecode.instructions.0, &mut order);
+        for (index, constant) in self.bytecode.constants.iter().enumerate() {
+            if let Object::CompiledFunc(_) = constant {
+                if !order.contains(&index) {
+                    order.push(index);
+                }
+            }
+        }
+        order
+    }
+
+    fn add_closures(&self, bytes: &[u8], order: &mut Vec<usize>) {
+        for (_, op, operands) in decode(bytes).0 {
+            if let OpCode::OpClosure = op {
+                let index = operands[0];
+                if let Some(Object::CompiledFunc(func)) = self.bytecode.constants.get(index) {
+                    if !order.contains(&index) {
+                        order.push(index);
+                        self.add_closures(&func.instructions.0, order);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the instructions of the main program or of a function, with the key `unit` in the
+    /// line table.
+    fn write_code(&self, out: &mut String, unit: Option<usize>, bytes: &[u8]) {
+        let (instructions, invalid) = decode(bytes);
+
+        // Labels are numbered in the order they appear in the code
+        let targets: BTreeSet<usize> = instructions
+            .iter()
+            .filter(|(_, op, _)| op.is_jump())
+            .map(|(_, _, operands)| operands[0])
+            .collect();
+        let labels: HashMap<usize, usize> = targets
+            .into_iter()
+            .enumerate()
+            .map(|(label, target)| (target, label))
+            .collect();
+
+        let lines = self.bytecode.lines.get(&unit).map(Vec::as_slice);
+        let mut lines = lines.unwrap_or_default().iter().peekable();
+        for (offset, op, operands) in &instructions {
+            while let Some(&(_, line)) = lines.next_if(|&&(start, _)| start <= *offset) {
+                if let Some(source) = self.source_lines.get(line.wrapping_sub(1)) {
+                    let _ = writeln!(out, "  ; {}: {}", line, source.trim());
+                }
+            }
+            if let Some(label) = labels.get(offset) {
+                let _ = writeln!(out, "  L{}:", label);
+            }
+
+            let mut text = format!("    {:04} {:?}", offset, op);
+            for (i, operand) in operands.iter().enumerate() {
+                let _ = match labels.get(operand) {
+                    Some(label) if i == 0 && op.is_jump() => write!(text, " L{}", label),
+                    _ => write!(text, " {}", operand),
+                };
+            }
+            if let Some(annotation) = self.annotation(*op, operands) {
+                let padding = ANNOTATION_COLUMN.saturating_sub(text.len()).max(1);
+                let _ = write!(text, "{:padding$}; {}", "", annotation, padding = padding);
+            }
+            out.push_str(&text);
+            out.push('\n');
+        }
+        if let Some(offset) = invalid {
+            let _ = writeln!(out, "    {:04} <invalid instruction>", offset);
+        }
+        // Jumps to the end of the code
+        if let Some(label) = labels.get(&bytes.len()) {
+            let _ = writeln!(out, "  L{}:", label);
+        }
+    }
+
+    /// Returns a description of the constant or built-in the instruction refers to, if any.
+    fn annotation(&self, op: OpCode, operands: &[usize]) -> Option<String> {
+        use OpCode::*;
+        match op {
+            OpConstant | OpClosure | OpGetField | OpSetField | OpMethodCall
+            | OpMethodCallSpread => Some(self.constant(operands[0])),
+            OpIncrementLocal | OpDecrementLocal => Some(self.constant(operands[1])),
+            OpGetBuiltin => {
+                let name = builtins::ALL_BUILTINS
+                    .get(operands[0])
+                    .map(|&(name, _)| name);
+                Some(name.unwrap_or("<invalid built-in>").to_string())
+            }
+            _ => None,
+        }
+    }
+
+    fn constant(&self, index: usize) -> String {
+        match self.bytecode.constants.get(index) {
+            Some(Object::CompiledFunc(_)) => format!("fn#{}", index),
+            Some(constant) => constant.to_string(),
+            None => "<invalid constant>".into(),
+        }
+    }
+}
+
+// The offset, opcode and operands of each instruction
+type Decoded = Vec<(usize, OpCode, Vec<usize>)>;
+
+/// Splits the bytes into instructions. If there is an invalid opcode or a truncated instruction,
+/// returns the instructions before it and its offset.
+fn decode(bytes: &[u8]) -> (Decoded, Option<usize>) {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let op = match OpCode::try_from_byte(bytes[offset]) {
+            Some(op) => op,
+            None => return (instructions, Some(offset)),
+        };
+        let instruction_len = 1 + op.operand_widths().iter().sum::<usize>();
+        if offset + instruction_len > bytes.len() {
+            return (instructions, Some(offset));
+        }
+        let (operands, _) = read_operands(op, &bytes[offset + 1..]);
+        instructions.push((offset, op, operands));
+        offset += instruction_len;
+    }
+    (instructions, None)
+}
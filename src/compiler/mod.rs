@@ -1,60 +1,153 @@
 #[macro_use]
 pub mod code;
+pub mod disassemble;
+pub mod optimize;
+pub mod serialize;
 pub mod symbol_table;
 #[cfg(test)]
 mod tests;
 
 use crate::builtins;
-use crate::error::{CompilerError::*, MonkeyError, MonkeyResult};
+use crate::error::{CompilerError::*, ImportError, MonkeyError, MonkeyResult};
 use crate::lexer::token::Token;
+use crate::module;
 use crate::object::*;
 use crate::parser::ast::*;
 use code::*;
+use optimize::OptLevel;
 use symbol_table::*;
 
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
 pub struct CompilationScope {
     instructions: Instructions,
+    // The last instruction emitted, or `None` if the end of the instructions is a jump target
+    last_instruction: Option<OpCode>,
+    // Whether calls in tail position can reuse the frame of the function. This is not the case at
+    // the top level, in generators, or inside the body of a `try` expression, whose handler
+    // belongs to the frame.
+    tail_calls: bool,
+    // The offsets where the code of each source line starts, and the line
+    lines: Vec<(usize, usize)>,
 }
 
 pub struct Compiler {
     scopes: Vec<CompilationScope>,
     pub constants: Vec<Object>,
     pub symbol_table: Option<SymbolTable>,
+    // The files being compiled, with the innermost import last. Used to resolve relative import
+    // paths and to detect import cycles.
+    import_stack: Vec<PathBuf>,
+    // The modules already compiled, by canonical path. Holds the index of the constant with the
+    // module function, and the global that holds the module object after the module is run.
+    modules: HashMap<PathBuf, (usize, usize)>,
+    // The source lines of the functions compiled so far, by the index of their constant
+    function_lines: LineTable,
+    opt_level: OptLevel,
 }
 
 impl Compiler {
     pub fn new() -> Compiler {
         let root_scope = CompilationScope {
             instructions: Instructions(Vec::new()),
+            last_instruction: None,
+            tail_calls: false,
+            lines: Vec::new(),
         };
-        let mut builtins_table = SymbolTable::new();
-        for (index, &(name, _)) in builtins::ALL_BUILTINS.iter().enumerate() {
-            builtins_table.define_builtin(name.into(), index);
-        }
-
         Compiler {
             scopes: vec![root_scope],
             constants: Vec::new(),
-            symbol_table: Some(builtins_table),
+            symbol_table: Some(Compiler::builtins_table()),
+            import_stack: Vec::new(),
+            modules: HashMap::new(),
+            function_lines: LineTable::new(),
+            opt_level: OptLevel::None,
         }
     }
 
+    /// Creates a compiler for the program in the given file. Imports in the program are resolved
+    /// relative to the file's directory.
+    pub fn with_file(path: &Path) -> io::Result<Compiler> {
+        let mut compiler = Compiler::new();
+        compiler.import_stack.push(path.canonicalize()?);
+        Ok(compiler)
+    }
+
+    /// Sets how much the compiler optimises the code. Compilers don't optimise by default.
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        self.opt_level = level;
+    }
+
+    fn builtins_table() -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for (index, &(name, _)) in builtins::ALL_BUILTINS.iter().enumerate() {
+            table.define_builtin(name.into(), index);
+        }
+        table
+    }
+
     /// Resets the instructions of the compiler, without changing the constants, and returns a
     /// `Bytecode` containing the old instructions and a clone of the constants. Used in the REPL.
     pub fn reset_instructions(&mut self) -> Bytecode {
-        let instructions = std::mem::replace(self.current_instructions(), Instructions(Vec::new()));
+        let mut instructions =
+            std::mem::replace(self.current_instructions(), Instructions(Vec::new()));
+        let mut main_lines = std::mem::take(&mut self.current_scope().lines);
+        self.optimize_code(&mut instructions, &mut main_lines);
         let constants = self.constants.clone();
+        let mut lines = self.function_lines.clone();
+        lines.insert(None, main_lines);
         Bytecode {
             instructions,
             constants,
+            lines,
         }
     }
 
     pub fn bytecode(mut self) -> Bytecode {
-        let top_scope_instructions = self.scopes.pop().map(|scope| scope.instructions);
+        let (mut instructions, mut main_lines) = match self.scopes.pop() {
+            Some(scope) => (scope.instructions, scope.lines),
+            None => (Instructions(Vec::new()), Vec::new()),
+        };
+        self.optimize_code(&mut instructions, &mut main_lines);
+        let mut lines = self.function_lines;
+        lines.insert(None, main_lines);
         Bytecode {
-            instructions: top_scope_instructions.unwrap_or_else(|| Instructions(Vec::new())),
+            instructions,
             constants: self.constants,
+            lines,
+        }
+    }
+
+    /// Runs the peephole optimisations on the finished code of a function or of the main program,
+    /// if they are enabled.
+    fn optimize_code(&self, instructions: &mut Instructions, lines: &mut Vec<(usize, usize)>) {
+        if self.opt_level >= OptLevel::Full {
+            optimize::peephole(instructions, lines);
+        }
+    }
+
+    /// Emits the instruction that pushes a value computed at compile time.
+    fn emit_value(&mut self, value: Object) {
+        match value {
+            Object::Boolean(true) => self.emit(OpCode::OpTrue, &[]),
+            Object::Boolean(false) => self.emit(OpCode::OpFalse, &[]),
+            value => {
+                let constant_index = self.add_constant(value);
+                self.emit(OpCode::OpConstant, &[constant_index])
+            }
+        };
+    }
+
+    /// Evaluates the expression at compile time, if constant folding is enabled and the expression
+    /// is constant.
+    fn fold(&self, expression: &NodeExpression) -> Option<Object> {
+        if self.opt_level >= OptLevel::Fold {
+            optimize::constant_value(expression)
+        } else {
+            None
         }
     }
 
@@ -67,18 +160,27 @@ impl Compiler {
             .instructions
     }
 
-    fn enter_scope(&mut self) {
+    fn current_scope(&mut self) -> &mut CompilationScope {
+        self.scopes
+            .last_mut()
+            .expect("No compilation scope in stack")
+    }
+
+    fn enter_scope(&mut self, tail_calls: bool) {
         let empty_scope = CompilationScope {
             instructions: Instructions(Vec::new()),
+            last_instruction: None,
+            tail_calls,
+            lines: Vec::new(),
         };
-        let old_table = std::mem::replace(&mut self.symbol_table, None);
+        let old_table = self.symbol_table.take();
         let new_table = SymbolTable::from_outer(Box::new(old_table.expect("No symbol table")));
         self.symbol_table = Some(new_table);
         self.scopes.push(empty_scope);
     }
 
     fn pop_scope(&mut self) -> CompilationScope {
-        let old_table = std::mem::replace(&mut self.symbol_table, None);
+        let old_table = self.symbol_table.take();
         self.symbol_table = old_table
             .expect("No symbol table")
             .outer
@@ -93,15 +195,40 @@ impl Compiler {
 
     fn emit(&mut self, op: OpCode, operands: &[usize]) -> usize {
         let ins = make(op, operands);
-        self.add_instruction(&*ins)
+        self.add_instruction(&ins)
     }
 
     fn add_instruction(&mut self, instruction: &[u8]) -> usize {
         let new_instruction_pos = self.current_instructions().0.len();
         self.current_instructions().0.extend_from_slice(instruction);
+        self.current_scope().last_instruction = Some(OpCode::from_byte(instruction[0]));
         new_instruction_pos
     }
 
+    /// Records that the code emitted next was compiled from the statement at `position`. Only
+    /// lines of the main file are recorded, since they are only used to show the source of the
+    /// code.
+    fn mark_line(&mut self, (line, _): (usize, usize)) {
+        if self.import_stack.len() > 1 {
+            return;
+        }
+        let offset = self.current_instructions().0.len();
+        let lines = &mut self.current_scope().lines;
+        match lines.last_mut() {
+            Some(last) if last.1 == line => {}
+            // The previous statement didn't emit any code
+            Some(last) if last.0 == offset => *last = (offset, line),
+            _ => lines.push((offset, line)),
+        }
+    }
+
+    /// Returns the offset of the next instruction, which is the target of a jump. Code before it
+    /// doesn't necessarily run before the next instruction, so there is no last instruction.
+    fn jump_target(&mut self) -> usize {
+        self.current_scope().last_instruction = None;
+        self.current_instructions().0.len()
+    }
+
     fn change_operand(&mut self, op_pos: usize, new_operand: usize) {
         let op_code = OpCode::from_byte(self.current_instructions().0[op_pos]);
         let new_instruction = make!(op_code, new_operand);
@@ -131,12 +258,62 @@ impl Compiler {
         } else {
             let last_index = block.len() - 1;
             for (i, statement) in block.into_iter().enumerate() {
+                self.mark_line(statement.position);
                 self.compile_statement(statement, i == last_index)?;
             }
         }
         Ok(())
     }
 
+    /// Compiles a block whose value is returned from the function, so calls in tail position in it
+    /// can reuse the frame.
+    fn compile_tail_block(&mut self, block: Vec<NodeStatement>) -> MonkeyResult<()> {
+        if block.is_empty() {
+            self.emit(OpCode::OpNil, &[]);
+            return Ok(());
+        }
+        let last_index = block.len() - 1;
+        for (i, statement) in block.into_iter().enumerate() {
+            self.mark_line(statement.position);
+            match statement.statement {
+                Statement::ExpressionStatement(exp) if i == last_index => {
+                    self.compile_tail_expression(*exp)?
+                }
+                _ => self.compile_statement(statement, i == last_index)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles an expression whose value is returned from the function. Calls in tail position
+    /// are compiled to `OpTailCall`, which reuses the frame of the function if the called object
+    /// is a closure.
+    fn compile_tail_expression(&mut self, expression: NodeExpression) -> MonkeyResult<()> {
+        if !self.current_scope().tail_calls {
+            return self.compile_expression(expression);
+        }
+        match expression.expression {
+            Expression::CallExpression {
+                function,
+                arguments,
+            } if !self.is_import(&function) && !arguments.iter().any(is_spread) => {
+                let num_args = arguments.len();
+                self.compile_expression(*function)?;
+                for arg in arguments {
+                    self.compile_expression(arg)?;
+                }
+                self.emit(OpCode::OpTailCall, &[num_args]);
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => self.compile_if(*condition, consequence, alternative, true)?,
+            _ => self.compile_expression(expression)?,
+        }
+        Ok(())
+    }
+
     fn compile_statement(&mut self, statement: NodeStatement, last: bool) -> MonkeyResult<()> {
         match statement.statement {
             Statement::ExpressionStatement(exp) => {
@@ -146,7 +323,7 @@ impl Compiler {
                 }
             }
             Statement::Let(let_statement) => {
-                let (name, exp) = *let_statement;
+                let (Variable { name, .. }, exp) = *let_statement;
                 let symbol = self
                     .symbol_table
                     .as_mut()
@@ -165,12 +342,31 @@ impl Compiler {
                     self.emit(OpCode::OpNil, &[]);
                 }
             }
+            Statement::Throw(value) => {
+                self.compile_expression(*value)?;
+                self.emit(OpCode::OpThrow, &[]);
+            }
+            Statement::Yield(value) => {
+                // The parser only accepts `yield` statements inside generator functions
+                self.compile_expression(*value)?;
+                self.emit(OpCode::OpYield, &[]);
+                if last {
+                    self.emit(OpCode::OpNil, &[]);
+                }
+            }
             Statement::Return(value) => {
-                // If we are at the root compilation scope, we are not in a function context
-                if self.scopes.len() == 1 {
+                // If we are using the global symbol table, we are not in a function context. This
+                // also happens at the root of a module
+                if self
+                    .symbol_table
+                    .as_ref()
+                    .expect("No symbol table")
+                    .outer
+                    .is_none()
+                {
                     return Err(MonkeyError::Compiler(statement.position, InvalidReturn));
                 }
-                self.compile_expression(*value)?;
+                self.compile_tail_expression(*value)?;
                 self.emit(OpCode::OpReturn, &[]);
             }
         };
@@ -179,15 +375,18 @@ impl Compiler {
 
     fn compile_expression(&mut self, expression: NodeExpression) -> MonkeyResult<()> {
         use Token::*;
+        if let Expression::InfixExpression(..) | Expression::PrefixExpression(..) =
+            expression.expression
+        {
+            if let Some(value) = self.fold(&expression) {
+                self.emit_value(value);
+                return Ok(());
+            }
+        }
         match expression.expression {
             Expression::InfixExpression(left, tk, right) => {
-                if let Token::LessThan | Token::LessEq = tk {
-                    self.compile_expression(*right)?;
-                    self.compile_expression(*left)?;
-                } else {
-                    self.compile_expression(*left)?;
-                    self.compile_expression(*right)?;
-                }
+                self.compile_expression(*left)?;
+                self.compile_expression(*right)?;
                 match tk {
                     Plus => self.emit(OpCode::OpAdd, &[]),
                     Minus => self.emit(OpCode::OpSub, &[]),
@@ -197,8 +396,15 @@ impl Compiler {
                     Modulo => self.emit(OpCode::OpModulo, &[]),
                     Equals => self.emit(OpCode::OpEquals, &[]),
                     NotEquals => self.emit(OpCode::OpNotEquals, &[]),
-                    GreaterThan | LessThan => self.emit(OpCode::OpGreaterThan, &[]),
-                    GreaterEq | LessEq => self.emit(OpCode::OpGreaterEq, &[]),
+                    GreaterThan => self.emit(OpCode::OpGreaterThan, &[]),
+                    GreaterEq => self.emit(OpCode::OpGreaterEq, &[]),
+                    LessThan => self.emit(OpCode::OpLessThan, &[]),
+                    LessEq => self.emit(OpCode::OpLessEq, &[]),
+                    BitAnd => self.emit(OpCode::OpBitAnd, &[]),
+                    BitOr => self.emit(OpCode::OpBitOr, &[]),
+                    Tilde => self.emit(OpCode::OpBitXor, &[]),
+                    ShiftLeft => self.emit(OpCode::OpShiftLeft, &[]),
+                    ShiftRight => self.emit(OpCode::OpShiftRight, &[]),
                     _ => unreachable!(),
                 };
             }
@@ -207,6 +413,7 @@ impl Compiler {
                 match tk {
                     Minus => self.emit(OpCode::OpPrefixMinus, &[]),
                     Bang => self.emit(OpCode::OpPrefixNot, &[]),
+                    Tilde => self.emit(OpCode::OpPrefixBitNot, &[]),
                     _ => unreachable!(),
                 };
             }
@@ -226,6 +433,16 @@ impl Compiler {
                 let constant_index = self.add_constant(obj);
                 self.emit(OpCode::OpConstant, &[constant_index]);
             }
+            Expression::InterpolatedString(parts) => {
+                let length = parts.len();
+                for exp in parts {
+                    self.compile_expression(exp)?;
+                }
+                self.emit(OpCode::OpConcat, &[length]);
+            }
+            Expression::ArrayLiteral(v) if v.iter().any(is_spread) => {
+                self.compile_spread_list(v, expression.position)?;
+            }
             Expression::ArrayLiteral(v) => {
                 let length = v.len();
                 if length > 65536 {
@@ -237,45 +454,99 @@ impl Compiler {
                 self.emit(OpCode::OpArray, &[length]);
             }
             Expression::HashLiteral(v) => {
-                let length = v.len();
+                // Leading key-value pairs are built in one go by `OpHash`. If there is a spread
+                // entry, it and every entry after it are added one by one to the hash on top of
+                // the stack.
+                let length = v
+                    .iter()
+                    .take_while(|entry| matches!(entry, HashEntry::Pair(..)))
+                    .count();
                 if length > 65536 {
                     return Err(MonkeyError::Compiler(expression.position, LiteralTooBig));
                 }
-                for (key, value) in v {
-                    self.compile_expression(key)?;
-                    self.compile_expression(value)?;
+                let mut entries = v.into_iter();
+                for entry in entries.by_ref().take(length) {
+                    if let HashEntry::Pair(key, value) = entry {
+                        self.compile_expression(key)?;
+                        self.compile_expression(value)?;
+                    }
                 }
                 self.emit(OpCode::OpHash, &[length]);
+                for entry in entries {
+                    match entry {
+                        HashEntry::Pair(key, value) => {
+                            self.compile_expression(key)?;
+                            self.compile_expression(value)?;
+                            self.emit(OpCode::OpHashInsert, &[]);
+                        }
+                        HashEntry::Spread(exp) => {
+                            self.compile_expression(exp)?;
+                            self.emit(OpCode::OpHashExtend, &[]);
+                        }
+                    }
+                }
             }
             Expression::Nil => {
                 self.emit(OpCode::OpNil, &[]);
             }
-            Expression::IfExpression {
-                condition,
-                consequence,
-                alternative,
+            Expression::Propagate(value) => {
+                if self
+                    .symbol_table
+                    .as_ref()
+                    .expect("No symbol table")
+                    .outer
+                    .is_none()
+                {
+                    return Err(MonkeyError::Compiler(expression.position, InvalidPropagate));
+                }
+                // If the value is an error, return it. Otherwise, skip the return, keeping the
+                // value on the stack
+                self.compile_expression(*value)?;
+                let jump_pos = self.emit(OpCode::OpJumpNotError, &[9999]);
+                self.emit(OpCode::OpReturn, &[]);
+                let after_return = self.jump_target();
+                self.change_operand(jump_pos, after_return);
+            }
+            Expression::TryExpression {
+                body,
+                name,
+                handler,
             } => {
-                self.compile_expression(*condition)?;
-                // Emit an OpJumpNotTruthy instruction that will eventually point to after the
-                // consequence
-                let jump_not_truthy_pos = self.emit(OpCode::OpJumpNotTruthy, &[9999]);
-
-                self.compile_block(consequence)?;
-
-                // Emit an OpJump instruction that will eventually point to after the alternative
+                // `OpTry` registers the handler, which is unregistered by `OpEndTry` if the body
+                // finishes normally. If an exception happens in the body, the VM unwinds the stack
+                // and jumps to the handler, with the exception on top of the stack.
+                let try_pos = self.emit(OpCode::OpTry, &[9999]);
+                let tail_calls = std::mem::replace(&mut self.current_scope().tail_calls, false);
+                self.compile_block(body)?;
+                self.current_scope().tail_calls = tail_calls;
+                self.emit(OpCode::OpEndTry, &[]);
                 let jump_pos = self.emit(OpCode::OpJump, &[9999]);
 
-                // Modify the OpJumpNotTruthy instruction
-                let after_consequence = self.current_instructions().0.len();
-                self.change_operand(jump_not_truthy_pos, after_consequence);
-
-                self.compile_block(alternative)?;
+                let handler_pos = self.jump_target();
+                self.change_operand(try_pos, handler_pos);
+                let symbol = self
+                    .symbol_table
+                    .as_mut()
+                    .expect("No symbol table")
+                    .define(name);
+                let op = match symbol.scope {
+                    SymbolScope::Global => OpCode::OpSetGlobal,
+                    SymbolScope::Local => OpCode::OpSetLocal,
+                    _ => unreachable!(),
+                };
+                let index = symbol.index;
+                self.emit(op, &[index]);
+                self.compile_block(handler)?;
 
-                // Modify the OpJump instruction
-                let after_alternative = self.current_instructions().0.len();
-                self.change_operand(jump_pos, after_alternative);
+                let after_handler = self.jump_target();
+                self.change_operand(jump_pos, after_handler);
             }
-            Expression::Identifier(name) => {
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => self.compile_if(*condition, consequence, alternative, false)?,
+            Expression::Identifier(Variable { name, .. }) => {
                 let symbol = self
                     .symbol_table
                     .as_mut()
@@ -292,40 +563,109 @@ impl Compiler {
                 self.compile_expression(*index)?;
                 self.emit(OpCode::OpIndex, &[]);
             }
+            Expression::OptionalIndexExpression(obj, index) => {
+                // If the object is nil, it's popped and the result is `nil`, without evaluating
+                // the index. Otherwise, jump to the index, keeping the object on the stack.
+                self.compile_expression(*obj)?;
+                let jump_not_nil_pos = self.emit(OpCode::OpJumpNotNil, &[9999]);
+                self.emit(OpCode::OpNil, &[]);
+                let jump_pos = self.emit(OpCode::OpJump, &[9999]);
+                let index_pos = self.jump_target();
+                self.change_operand(jump_not_nil_pos, index_pos);
+                self.compile_expression(*index)?;
+                self.emit(OpCode::OpIndexOptional, &[]);
+                let after_index = self.jump_target();
+                self.change_operand(jump_pos, after_index);
+            }
+            Expression::StructDeclaration { name, fields } => {
+                let definition = StructDefinition { name, fields };
+                let constant_index =
+                    self.add_constant(Object::StructConstructor(Rc::new(definition)));
+                self.emit(OpCode::OpConstant, &[constant_index]);
+            }
+            Expression::FieldAccess(obj, field) => {
+                self.compile_expression(*obj)?;
+                let field_index = self.add_constant(Object::from(field.as_str()));
+                self.emit(OpCode::OpGetField, &[field_index]);
+            }
+            Expression::FieldUpdate {
+                object,
+                field,
+                value,
+            } => {
+                self.compile_expression(*object)?;
+                self.compile_expression(*value)?;
+                let field_index = self.add_constant(Object::from(field.as_str()));
+                self.emit(OpCode::OpSetField, &[field_index]);
+            }
+            Expression::NilCoalescing(left, right) => {
+                self.compile_expression(*left)?;
+                // If the left side is not nil, jump over the right side, keeping the left side
+                // value on the stack. Otherwise, the left side value is popped.
+                let jump_not_nil_pos = self.emit(OpCode::OpJumpNotNil, &[9999]);
+                self.compile_expression(*right)?;
+                let after_right = self.jump_target();
+                self.change_operand(jump_not_nil_pos, after_right);
+            }
             Expression::FunctionLiteral { body, parameters } => {
-                self.enter_scope();
-                let num_params = parameters.len() as u8;
-                for param in parameters {
-                    self.symbol_table
-                        .as_mut()
-                        .expect("No symbol table")
-                        .define(param);
-                }
-                self.compile_block(body)?;
-                // If the last instruction emitted was not a return instruction, emit one. It's safe
-                // to `.unwrap` here because every block is guaranteed to emit at least one
-                // instruction.
-                if *self.current_instructions().0.last().unwrap() != OpCode::OpReturn as u8 {
-                    self.emit(OpCode::OpReturn, &[]);
-                }
-
+                self.compile_function(parameters, body, false)?;
+            }
+            Expression::GeneratorLiteral { body, parameters } => {
+                self.compile_function(parameters, body, true)?;
+            }
+            Expression::ForExpression {
+                name,
+                iterable,
+                body,
+            } => {
+                // `OpIter` pushes the iteration index on top of the iterable. While there are
+                // values left, `OpIterNext` pushes the next one. Otherwise, it pops the iterable
+                // and the index, and jumps to the end of the loop.
+                self.compile_expression(*iterable)?;
+                self.emit(OpCode::OpIter, &[]);
+                let loop_start = self.current_instructions().0.len();
+                let iter_next_pos = self.emit(OpCode::OpIterNext, &[9999]);
+                // The loop variable gets its own slot, and is only visible in the body, so a
+                // variable with the same name outside of the loop isn't changed
                 let table = self.symbol_table.as_mut().expect("No symbol table");
-                let num_locals = table.num_definitions as u8;
-                let free_symbols = table.free_symbols.clone().into_iter();
-                let instructions = self.pop_scope().instructions;
-
-                let num_free_symbols = free_symbols.len();
-                for symbol in free_symbols {
-                    self.load_symbol(symbol)
-                }
-
-                let compiled_fn = CompiledFunction {
-                    instructions,
-                    num_locals,
-                    num_params,
+                let shadowed = table.get(&name);
+                let symbol = table.define(name.clone());
+                let op = match symbol.scope {
+                    SymbolScope::Global => OpCode::OpSetGlobal,
+                    SymbolScope::Local => OpCode::OpSetLocal,
+                    _ => unreachable!(),
                 };
-                let index = self.add_constant(Object::CompiledFunc(Box::new(compiled_fn)));
-                self.emit(OpCode::OpClosure, &[index, num_free_symbols]);
+                let index = symbol.index;
+                self.emit(op, &[index]);
+                let body = self.compile_block(body);
+                self.symbol_table
+                    .as_mut()
+                    .expect("No symbol table")
+                    .undefine(&name, shadowed);
+                body?;
+                self.emit(OpCode::OpPop, &[]);
+                self.emit(OpCode::OpJump, &[loop_start]);
+
+                let after_loop = self.jump_target();
+                self.change_operand(iter_next_pos, after_loop);
+                // `for` loops evaluate to `nil`
+                self.emit(OpCode::OpNil, &[]);
+            }
+            Expression::CallExpression {
+                function,
+                arguments,
+            } if self.is_import(&function) => {
+                self.compile_import(arguments, expression.position)?;
+            }
+            Expression::CallExpression {
+                function,
+                arguments,
+            } if arguments.iter().any(is_spread) => {
+                // When there are spread arguments, the number of arguments is only known at
+                // runtime, so we collect them in an array and use `OpCallSpread`
+                self.compile_expression(*function)?;
+                self.compile_spread_list(arguments, expression.position)?;
+                self.emit(OpCode::OpCallSpread, &[]);
             }
             Expression::CallExpression {
                 function,
@@ -338,8 +678,295 @@ impl Compiler {
                 }
                 self.emit(OpCode::OpCall, &[num_args]);
             }
+            Expression::MethodCall {
+                object,
+                method,
+                arguments,
+            } if arguments.iter().any(is_spread) => {
+                self.compile_expression(*object)?;
+                self.compile_spread_list(arguments, expression.position)?;
+                let method_index = self.add_constant(Object::from(method.as_str()));
+                self.emit(OpCode::OpMethodCallSpread, &[method_index]);
+            }
+            Expression::MethodCall {
+                object,
+                method,
+                arguments,
+            } => {
+                let num_args = arguments.len();
+                self.compile_expression(*object)?;
+                for arg in arguments {
+                    self.compile_expression(arg)?;
+                }
+                let method_index = self.add_constant(Object::from(method.as_str()));
+                self.emit(OpCode::OpMethodCall, &[method_index, num_args]);
+            }
             _ => todo!(),
         }
         Ok(())
     }
+
+    /// Compiles an `if` expression. If `tail` is true, the branches are compiled as tail blocks.
+    fn compile_if(
+        &mut self,
+        condition: NodeExpression,
+        consequence: Vec<NodeStatement>,
+        alternative: Vec<NodeStatement>,
+        tail: bool,
+    ) -> MonkeyResult<()> {
+        // If the condition is constant, the peephole optimisations remove the jump
+        match self.fold(&condition) {
+            Some(value) => self.emit_value(Object::Boolean(value.is_truthy())),
+            None => self.compile_expression(condition)?,
+        }
+        // Emit an OpJumpNotTruthy instruction that will eventually point to after the
+        // consequence
+        let jump_not_truthy_pos = self.emit(OpCode::OpJumpNotTruthy, &[9999]);
+
+        if tail {
+            self.compile_tail_block(consequence)?;
+        } else {
+            self.compile_block(consequence)?;
+        }
+
+        // Emit an OpJump instruction that will eventually point to after the alternative
+        let jump_pos = self.emit(OpCode::OpJump, &[9999]);
+
+        // Modify the OpJumpNotTruthy instruction
+        let after_consequence = self.jump_target();
+        self.change_operand(jump_not_truthy_pos, after_consequence);
+
+        if tail {
+            self.compile_tail_block(alternative)?;
+        } else {
+            self.compile_block(alternative)?;
+        }
+
+        // Modify the OpJump instruction
+        let after_alternative = self.jump_target();
+        self.change_operand(jump_pos, after_alternative);
+        Ok(())
+    }
+
+    /// Compiles a function literal into a closure. The body of generator functions starts with
+    /// `OpGenerator`, which suspends the new frame and returns the generator object.
+    fn compile_function(
+        &mut self,
+        parameters: Vec<String>,
+        body: Vec<NodeStatement>,
+        is_generator: bool,
+    ) -> MonkeyResult<()> {
+        // A generator's frame is suspended and resumed, so it can't be reused by a tail call
+        self.enter_scope(!is_generator);
+        let num_params = parameters.len() as u8;
+        for param in parameters {
+            self.symbol_table
+                .as_mut()
+                .expect("No symbol table")
+                .define(param);
+        }
+        if is_generator {
+            self.emit(OpCode::OpGenerator, &[]);
+        }
+        self.compile_tail_block(body)?;
+        // If the last instruction emitted was not a return instruction, emit one
+        if !matches!(
+            self.current_scope().last_instruction,
+            Some(OpCode::OpReturn)
+        ) {
+            self.emit(OpCode::OpReturn, &[]);
+        }
+
+        let table = self.symbol_table.as_mut().expect("No symbol table");
+        let num_locals = table.num_definitions as u8;
+        let free_symbols = table.free_symbols.clone().into_iter();
+        let mut scope = self.pop_scope();
+        self.optimize_code(&mut scope.instructions, &mut scope.lines);
+
+        let num_free_symbols = free_symbols.len();
+        for symbol in free_symbols {
+            self.load_symbol(symbol)
+        }
+
+        let compiled_fn = CompiledFunction {
+            instructions: scope.instructions,
+            num_locals,
+            num_params,
+        };
+        let index = self.add_constant(Object::CompiledFunc(Rc::new(compiled_fn)));
+        if !scope.lines.is_empty() {
+            self.function_lines.insert(Some(index), scope.lines);
+        }
+        self.emit(OpCode::OpClosure, &[index, num_free_symbols]);
+        Ok(())
+    }
+
+    /// Returns whether the called expression refers to the `import` built-in.
+    fn is_import(&mut self, function: &NodeExpression) -> bool {
+        match &function.expression {
+            Expression::Identifier(Variable { name, .. }) if name == "import" => {
+                let symbol = self
+                    .symbol_table
+                    .as_mut()
+                    .expect("No symbol table")
+                    .resolve(name);
+                matches!(
+                    symbol,
+                    Some(Symbol {
+                        scope: SymbolScope::Builtin,
+                        ..
+                    })
+                )
+            }
+            _ => false,
+        }
+    }
+
+    /// Compiles a call to `import`. Modules are resolved and compiled at compile time, so the path
+    /// must be a string literal. Each module is compiled into a function that runs the module and
+    /// returns a hash of its top-level bindings, and is only run the first time it's imported.
+    /// The resulting hash is stored in a global, which is returned by subsequent imports.
+    fn compile_import(
+        &mut self,
+        arguments: Vec<NodeExpression>,
+        position: (usize, usize),
+    ) -> MonkeyResult<()> {
+        let error = |e| MonkeyError::Compiler(position, Import(e));
+        let path = match arguments.as_slice() {
+            [NodeExpression {
+                expression: Expression::StringLiteral(path),
+                ..
+            }] => path,
+            _ => return Err(error(ImportError::DynamicPath)),
+        };
+        let importer = self.import_stack.last().map(PathBuf::as_path);
+        let path = module::resolve(path, importer).map_err(error)?;
+        let (function_index, global_index) = match self.modules.get(&path) {
+            Some(&module) => module,
+            None => {
+                module::check_cycle(&path, &self.import_stack).map_err(error)?;
+                let program = module::parse_file(&path).map_err(error)?;
+                let module = self.compile_module(program, &path).map_err(|e| {
+                    error(ImportError::InFile(path.display().to_string(), Box::new(e)))
+                })?;
+                self.modules.insert(path, module);
+                module
+            }
+        };
+
+        self.emit(OpCode::OpGetGlobal, &[global_index]);
+        // If the module was already run, skip to the end, keeping the module object on the stack
+        let jump_not_nil_pos = self.emit(OpCode::OpJumpNotNil, &[9999]);
+        self.emit(OpCode::OpClosure, &[function_index, 0]);
+        self.emit(OpCode::OpCall, &[0]);
+        self.emit(OpCode::OpSetGlobal, &[global_index]);
+        self.emit(OpCode::OpGetGlobal, &[global_index]);
+        let after_call = self.jump_target();
+        self.change_operand(jump_not_nil_pos, after_call);
+        Ok(())
+    }
+
+    /// Compiles a module into a function. The module has its own global symbol table, but its
+    /// globals are allocated after the ones already defined, so modules share the global store
+    /// with the importing program. Returns the index of the function constant and of the global
+    /// reserved for the module object.
+    fn compile_module(
+        &mut self,
+        program: Vec<NodeStatement>,
+        path: &Path,
+    ) -> MonkeyResult<(usize, usize)> {
+        let mut importer_table = self.symbol_table.take().expect("No symbol table");
+        let global_index = importer_table.global_mut().num_definitions;
+        let mut module_table = Compiler::builtins_table();
+        module_table.num_definitions = global_index + 1;
+
+        self.symbol_table = Some(module_table);
+        self.scopes.push(CompilationScope {
+            instructions: Instructions(Vec::new()),
+            last_instruction: None,
+            tail_calls: false,
+            lines: Vec::new(),
+        });
+        self.import_stack.push(path.to_path_buf());
+        let result = self.compile_module_body(program);
+        self.import_stack.pop();
+        let mut instructions = self
+            .scopes
+            .pop()
+            .expect("No compilation scope in stack")
+            .instructions;
+        self.optimize_code(&mut instructions, &mut Vec::new());
+        let module_table = self
+            .symbol_table
+            .replace(importer_table)
+            .expect("No symbol table");
+        result?;
+
+        // The globals defined by the module can't be reused by the importing program
+        let table = self.symbol_table.as_mut().expect("No symbol table");
+        table.global_mut().num_definitions = module_table.num_definitions;
+
+        let compiled_fn = CompiledFunction {
+            instructions,
+            num_locals: 0,
+            num_params: 0,
+        };
+        let function_index = self.add_constant(Object::CompiledFunc(Rc::new(compiled_fn)));
+        Ok((function_index, global_index))
+    }
+
+    fn compile_module_body(&mut self, program: Vec<NodeStatement>) -> MonkeyResult<()> {
+        self.compile_block(program)?;
+        self.emit(OpCode::OpPop, &[]);
+        let globals = self
+            .symbol_table
+            .as_ref()
+            .expect("No symbol table")
+            .defined_globals();
+        let num_globals = globals.len();
+        for (name, index) in globals {
+            let name_index = self.add_constant(Object::from(name.as_str()));
+            self.emit(OpCode::OpConstant, &[name_index]);
+            self.emit(OpCode::OpGetGlobal, &[index]);
+        }
+        self.emit(OpCode::OpHash, &[num_globals]);
+        self.emit(OpCode::OpReturn, &[]);
+        Ok(())
+    }
+
+    /// Compiles a list of expressions that may contain spread expressions into instructions that
+    /// build an array. The leading regular expressions are collected by a single `OpArray`
+    /// instruction, and the remaining elements are pushed or spread into the array one by one.
+    fn compile_spread_list(
+        &mut self,
+        list: Vec<NodeExpression>,
+        position: (usize, usize),
+    ) -> MonkeyResult<()> {
+        let length = list.iter().take_while(|exp| !is_spread(exp)).count();
+        if length > 65536 {
+            return Err(MonkeyError::Compiler(position, LiteralTooBig));
+        }
+        let mut list = list.into_iter();
+        for exp in list.by_ref().take(length) {
+            self.compile_expression(exp)?;
+        }
+        self.emit(OpCode::OpArray, &[length]);
+        for exp in list {
+            match exp.expression {
+                Expression::Spread(inner) => {
+                    self.compile_expression(*inner)?;
+                    self.emit(OpCode::OpArrayExtend, &[]);
+                }
+                _ => {
+                    self.compile_expression(exp)?;
+                    self.emit(OpCode::OpArrayPush, &[]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_spread(exp: &NodeExpression) -> bool {
+    matches!(exp.expression, Expression::Spread(_))
 }
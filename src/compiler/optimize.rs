@@ -0,0 +1,344 @@
+// Optimisations done by the compiler. Constant folding evaluates operators whose operands are
+// literals at compile time, following the same rules as the VM. Expressions that would fail at
+// runtime, like a division by zero, are not folded, so the error still happens when the program
+// runs.
+//
+// The peephole pass rewrites short sequences of instructions in the code of a function after it's
+// compiled, like a push followed by a pop, or a jump to the next instruction. Removing instructions
+// moves the ones after them, so jump targets and line tables are updated at the end.
+
+use super::code::*;
+use crate::lexer::token::Token;
+use crate::object::Object;
+use crate::parser::ast::*;
+
+use std::collections::{HashMap, HashSet};
+
+/// How much the compiler optimises the code. Each level includes the optimisations of the levels
+/// before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    None,
+    // Constant folding
+    Fold,
+    // Constant folding and peephole optimisations
+    Full,
+}
+
+impl OptLevel {
+    /// Parses an optimisation level from its number, from 0 to 2.
+    pub fn from_number(level: &str) -> Option<OptLevel> {
+        match level {
+            "0" => Some(OptLevel::None),
+            "1" => Some(OptLevel::Fold),
+            "2" => Some(OptLevel::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Evaluates the expression at compile time, if it only contains literals and operators. Returns
+/// `None` if the expression isn't constant, or if evaluating it would be a runtime error.
+pub fn constant_value(expression: &NodeExpression) -> Option<Object> {
+    match &expression.expression {
+        Expression::IntLiteral(i) => Some(Object::Integer(*i)),
+        Expression::Boolean(b) => Some(Object::Boolean(*b)),
+        Expression::StringLiteral(s) => Some(Object::from(s.as_str())),
+        Expression::PrefixExpression(tk, right) => fold_prefix(tk, constant_value(right)?),
+        Expression::InfixExpression(left, tk, right) => {
+            fold_infix(constant_value(left)?, tk, constant_value(right)?)
+        }
+        _ => None,
+    }
+}
+
+fn fold_prefix(tk: &Token, right: Object) -> Option<Object> {
+    match (tk, right) {
+        (Token::Minus, Object::Integer(i)) => i.checked_neg().map(Object::Integer),
+        (Token::Tilde, Object::Integer(i)) => Some(Object::Integer(!i)),
+        (Token::Bang, right) => Some(Object::Boolean(!right.is_truthy())),
+        _ => None,
+    }
+}
+
+fn fold_infix(left: Object, tk: &Token, right: Object) -> Option<Object> {
+    use Token::*;
+    let value = match (left, right) {
+        (Object::Integer(l), Object::Integer(r)) => match tk {
+            Plus => Object::Integer(l.checked_add(r)?),
+            Minus => Object::Integer(l.checked_sub(r)?),
+            Asterisk => Object::Integer(l.checked_mul(r)?),
+            Slash => Object::Integer(l.checked_div(r)?),
+            // The VM truncates the exponent, so this does too
+            Exponent if r >= 0 => Object::Integer(l.checked_pow(r as u32)?),
+            Modulo => Object::Integer(l.checked_rem(r)?),
+            BitAnd => Object::Integer(l & r),
+            BitOr => Object::Integer(l | r),
+            Tilde => Object::Integer(l ^ r),
+            ShiftLeft if (0..64).contains(&r) => Object::Integer(l << r),
+            ShiftRight if (0..64).contains(&r) => Object::Integer(l >> r),
+            Equals => Object::Boolean(l == r),
+            NotEquals => Object::Boolean(l != r),
+            GreaterThan => Object::Boolean(l > r),
+            GreaterEq => Object::Boolean(l >= r),
+            LessThan => Object::Boolean(l < r),
+            LessEq => Object::Boolean(l <= r),
+            _ => return None,
+        },
+        (Object::Boolean(l), Object::Boolean(r)) => match tk {
+            Equals => Object::Boolean(l == r),
+            NotEquals => Object::Boolean(l != r),
+            _ => return None,
+        },
+        (Object::Str(l), Object::Str(r)) if *tk == Plus => Object::Str(Box::new(*l + &r)),
+        _ => return None,
+    };
+    Some(value)
+}
+
+struct Instruction {
+    // The offset of the instruction before the peephole pass
+    offset: usize,
+    op: OpCode,
+    operands: Vec<usize>,
+    removed: bool,
+}
+
+/// Runs the peephole optimisations on the code of a function or of the main program, and updates
+/// its line table.
+pub fn peephole(instructions: &mut Instructions, lines: &mut Vec<(usize, usize)>) {
+    let mut code = Code::decode(&instructions.0);
+    while code.optimize_pass() {}
+    let (bytes, new_offsets) = code.assemble();
+    *instructions = Instructions(bytes);
+
+    let end = instructions.0.len();
+    let mut new_lines: Vec<(usize, usize)> = Vec::with_capacity(lines.len());
+    for &(offset, line) in lines.iter() {
+        let offset = new_offsets[&offset];
+        match new_lines.last_mut() {
+            // The code of the previous line was removed
+            Some(last) if last.0 == offset => *last = (offset, line),
+            _ if offset == end => {}
+            _ => new_lines.push((offset, line)),
+        }
+    }
+    *lines = new_lines;
+}
+
+struct Code {
+    instructions: Vec<Instruction>,
+    // The index of each instruction, by its original offset. The end of the code has the index
+    // `instructions.len()`
+    indices: HashMap<usize, usize>,
+    // The original offset of the end of the code
+    end: usize,
+}
+
+impl Code {
+    fn decode(bytes: &[u8]) -> Code {
+        let mut instructions = Vec::new();
+        let mut indices = HashMap::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let op = OpCode::from_byte(bytes[offset]);
+            let (operands, operands_len) = read_operands(op, &bytes[offset + 1..]);
+            indices.insert(offset, instructions.len());
+            instructions.push(Instruction {
+                offset,
+                op,
+                operands,
+                removed: false,
+            });
+            offset += 1 + operands_len;
+        }
+        indices.insert(bytes.len(), instructions.len());
+        Code {
+            instructions,
+            indices,
+            end: bytes.len(),
+        }
+    }
+
+    /// Returns the index of the instruction that runs when jumping to the original offset: the
+    /// instruction at that offset, or the first one after it that wasn't removed.
+    fn resolve(&self, offset: usize) -> usize {
+        let mut index = self.indices[&offset];
+        while index < self.instructions.len() && self.instructions[index].removed {
+            index += 1;
+        }
+        index
+    }
+
+    fn offset_of(&self, index: usize) -> usize {
+        self.instructions
+            .get(index)
+            .map_or(self.end, |instruction| instruction.offset)
+    }
+
+    /// Points every jump to the instruction that will actually run, skipping removed instructions
+    /// and following chains of unconditional jumps. Returns the indices of the jump targets.
+    fn update_targets(&mut self) -> HashSet<usize> {
+        let mut targets = HashSet::new();
+        for index in 0..self.instructions.len() {
+            let instruction = &self.instructions[index];
+            if instruction.removed || !instruction.op.is_jump() {
+                continue;
+            }
+            let mut target = self.resolve(instruction.operands[0]);
+            let mut visited = HashSet::new();
+            while let Some(next) = self.instructions.get(target) {
+                // A loop of jumps never ends, so it's left as is
+                if next.op != OpCode::OpJump || !visited.insert(target) {
+                    break;
+                }
+                target = self.resolve(next.operands[0]);
+            }
+            self.instructions[index].operands[0] = self.offset_of(target);
+            targets.insert(target);
+        }
+        targets
+    }
+
+    /// Applies the peephole optimisations once. Returns whether the code changed.
+    fn optimize_pass(&mut self) -> bool {
+        use OpCode::*;
+        let targets = self.update_targets();
+        let live: Vec<usize> = (0..self.instructions.len())
+            .filter(|&index| !self.instructions[index].removed)
+            .collect();
+        let op_at = |position: usize| live.get(position).map(|&index| self.instructions[index].op);
+        // Whether the instruction at the position in `live` can only be reached from the one
+        // before it
+        let entered_in_order = |position: usize| !targets.contains(&live[position]);
+
+        let mut to_remove = Vec::new();
+        // Instructions that are replaced by another one, and its operands
+        let mut to_replace = Vec::new();
+        let mut position = 0;
+        while position < live.len() {
+            let index = live[position];
+            let op = self.instructions[index].op;
+            let next_op = op_at(position + 1);
+            let has_next = position + 1 < live.len();
+            let next_in_order = has_next && entered_in_order(position + 1);
+            let next_index = live.get(position + 1).copied();
+            let jumps_to_next = op == OpJump
+                && self.resolve(self.instructions[index].operands[0])
+                    == next_index.unwrap_or(self.instructions.len());
+            let step = match (op, next_op) {
+                // Code after an unconditional jump, a return or a throw, that isn't the target of
+                // any jump, can never run
+                (OpJump | OpReturn | OpThrow, Some(_)) if next_in_order => {
+                    to_remove.push(live[position + 1]);
+                    2
+                }
+                // A jump to the next instruction does nothing
+                (OpJump, _) if jumps_to_next => {
+                    to_remove.push(index);
+                    1
+                }
+                // Pushing a value without side effects and popping it right away. The last value
+                // popped by the main program is its result, which the REPL shows, so it's kept
+                (
+                    OpConstant | OpTrue | OpFalse | OpNil | OpGetGlobal | OpGetLocal | OpGetFree
+                    | OpGetBuiltin,
+                    Some(OpPop),
+                ) if next_in_order && position + 2 < live.len() => {
+                    to_remove.extend([index, live[position + 1]]);
+                    2
+                }
+                // Conditions that are always true never jump, and conditions that are always
+                // false always do
+                (OpTrue, Some(OpJumpNotTruthy)) if next_in_order => {
+                    to_remove.extend([index, live[position + 1]]);
+                    2
+                }
+                (OpFalse, Some(OpJumpNotTruthy)) if next_in_order => {
+                    let jump = live[position + 1];
+                    to_remove.push(index);
+                    to_replace.push((jump, OpJump, self.instructions[jump].operands.clone()));
+                    2
+                }
+                // A comparison used as a condition becomes a single compare-and-jump instruction
+                (OpLessThan | OpLessEq | OpGreaterThan | OpGreaterEq, Some(OpJumpNotTruthy))
+                    if next_in_order =>
+                {
+                    let jump = live[position + 1];
+                    let fused = op.fused_jump().unwrap();
+                    to_remove.push(index);
+                    to_replace.push((jump, fused, self.instructions[jump].operands.clone()));
+                    2
+                }
+                // Adding a constant to a local, or subtracting it, like in `n - 1`
+                (OpGetLocal, Some(OpConstant))
+                    if next_in_order
+                        && entered_in_order(position + 2)
+                        && matches!(op_at(position + 2), Some(OpAdd | OpSub)) =>
+                {
+                    let (constant, operation) = (live[position + 1], live[position + 2]);
+                    let fused = match self.instructions[operation].op {
+                        OpAdd => OpIncrementLocal,
+                        _ => OpDecrementLocal,
+                    };
+                    let operands = vec![
+                        self.instructions[index].operands[0],
+                        self.instructions[constant].operands[0],
+                    ];
+                    to_remove.extend([constant, operation]);
+                    to_replace.push((index, fused, operands));
+                    3
+                }
+                // Negating a value twice doesn't change whether it's truthy
+                (OpPrefixNot, Some(OpPrefixNot))
+                    if next_in_order
+                        && matches!(op_at(position + 2), Some(OpJumpNotTruthy | OpPrefixNot)) =>
+                {
+                    to_remove.extend([index, live[position + 1]]);
+                    2
+                }
+                _ => 1,
+            };
+            position += step;
+        }
+
+        for &index in &to_remove {
+            self.instructions[index].removed = true;
+        }
+        let changed = !to_remove.is_empty() || !to_replace.is_empty();
+        for (index, op, operands) in to_replace {
+            self.instructions[index].op = op;
+            self.instructions[index].operands = operands;
+        }
+        changed
+    }
+
+    /// Returns the bytes of the instructions that weren't removed, and the new offset of every
+    /// original offset.
+    fn assemble(&mut self) -> (Vec<u8>, HashMap<usize, usize>) {
+        self.update_targets();
+        let mut bytes = Vec::new();
+        let mut new_offsets = HashMap::new();
+        for instruction in &self.instructions {
+            if !instruction.removed {
+                new_offsets.insert(instruction.offset, bytes.len());
+                bytes.extend_from_slice(&make(instruction.op, &instruction.operands));
+            }
+        }
+        new_offsets.insert(self.end, bytes.len());
+        // Removed instructions are replaced by the next instruction that wasn't removed
+        for instruction in &self.instructions {
+            let offset = self.offset_of(self.resolve(instruction.offset));
+            new_offsets.insert(instruction.offset, new_offsets[&offset]);
+        }
+        for index in 0..self.instructions.len() {
+            let instruction = &self.instructions[index];
+            if !instruction.removed && instruction.op.is_jump() {
+                let target = new_offsets[&instruction.operands[0]];
+                let start = new_offsets[&instruction.offset];
+                bytes[start + 1..start + 3].copy_from_slice(&(target as u16).to_be_bytes());
+            }
+        }
+        (bytes, new_offsets)
+    }
+}
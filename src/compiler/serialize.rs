@@ -0,0 +1,255 @@
+// The on-disk format for compiled programs. A bytecode file has a header with a signature, the
+// format version and a checksum of the rest of the file. It's followed by the instructions of the
+// program and its constant pool. All numbers are big-endian, like the operands in instructions.
+//
+// Functions are stored in the constant pool, so the functions nested inside them are stored as
+// separate constants, and the whole program is a flat list of sections. The constants are followed
+// by the line table, which is used to show where runtime errors happen.
+
+use super::code::*;
+use crate::error::BytecodeError;
+use crate::object::*;
+
+use std::convert::TryInto;
+use std::rc::Rc;
+
+const SIGNATURE: &[u8; 4] = b"MBC\0";
+
+/// The version of the file format. Changing the format, or the meaning of the instructions, must
+/// increment it, since old files can't be run anymore.
+pub const FORMAT_VERSION: u16 = 2;
+
+const HEADER_SIZE: usize = SIGNATURE.len() + 2 + 4;
+
+// The tags that identify the type of each constant
+const TAG_INTEGER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+const TAG_STRUCT: u8 = 3;
+
+impl Bytecode {
+    /// Serializes the bytecode into the bytecode file format.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        write_instructions(&mut payload, &self.instructions);
+        write_u32(&mut payload, self.constants.len());
+        for constant in &self.constants {
+            write_constant(&mut payload, constant);
+        }
+        write_lines(&mut payload, &self.lines);
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(SIGNATURE);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&checksum(&payload).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    /// Reads bytecode in the bytecode file format. Returns an error if the file has a different
+    /// version, is corrupted, or contains invalid instructions.
+    pub fn deserialize(bytes: &[u8]) -> Result<Bytecode, BytecodeError> {
+        if bytes.len() < SIGNATURE.len() || &bytes[..SIGNATURE.len()] != SIGNATURE {
+            return Err(BytecodeError::NotBytecode);
+        }
+        let mut reader = Reader { bytes, pos: 4 };
+        let version = reader.read_u16()?;
+        if version != FORMAT_VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+        let expected_checksum = reader.read_u32()?;
+        if checksum(&bytes[HEADER_SIZE..]) != expected_checksum as u32 {
+            return Err(BytecodeError::ChecksumMismatch);
+        }
+
+        let instructions = reader.read_instructions()?;
+        let num_constants = reader.read_u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..num_constants {
+            constants.push(reader.read_constant()?);
+        }
+        let lines = reader.read_lines()?;
+        if reader.pos != bytes.len() {
+            return Err(BytecodeError::TrailingBytes);
+        }
+        Ok(Bytecode {
+            instructions,
+            constants,
+            lines,
+        })
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value: u32 = value.try_into().expect("section too big for bytecode file");
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    write_u32(bytes, s.len());
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+fn write_instructions(bytes: &mut Vec<u8>, instructions: &Instructions) {
+    write_u32(bytes, instructions.0.len());
+    bytes.extend_from_slice(&instructions.0);
+}
+
+fn write_constant(bytes: &mut Vec<u8>, constant: &Object) {
+    match constant {
+        Object::Integer(i) => {
+            bytes.push(TAG_INTEGER);
+            bytes.extend_from_slice(&i.to_be_bytes());
+        }
+        Object::Str(s) => {
+            bytes.push(TAG_STRING);
+            write_str(bytes, s);
+        }
+        Object::CompiledFunc(func) => {
+            bytes.push(TAG_FUNCTION);
+            bytes.push(func.num_locals);
+            bytes.push(func.num_params);
+            write_instructions(bytes, &func.instructions);
+        }
+        Object::StructConstructor(definition) => {
+            bytes.push(TAG_STRUCT);
+            write_str(bytes, &definition.name);
+            write_u32(bytes, definition.fields.len());
+            for field in &definition.fields {
+                write_str(bytes, field);
+            }
+        }
+        // The compiler only adds these types of objects to the constant pool
+        other => unreachable!("unexpected {} constant", other.type_str()),
+    }
+}
+
+// Writes the lines of the main program, with the key 0, and of each function, with the index of
+// its constant plus one. They are sorted, so the same program always gives the same file.
+fn write_lines(bytes: &mut Vec<u8>, lines: &LineTable) {
+    let mut units: Vec<_> = lines.iter().collect();
+    units.sort_by_key(|&(unit, _)| *unit);
+    write_u32(bytes, units.len());
+    for (unit, unit_lines) in units {
+        write_u32(bytes, unit.map_or(0, |index| index + 1));
+        write_u32(bytes, unit_lines.len());
+        for &(offset, line) in unit_lines {
+            write_u32(bytes, offset);
+            write_u32(bytes, line);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self.pos.checked_add(len).ok_or(BytecodeError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(BytecodeError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<usize, BytecodeError> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String, BytecodeError> {
+        let len = self.read_u32()?;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::InvalidString)
+    }
+
+    fn read_instructions(&mut self) -> Result<Instructions, BytecodeError> {
+        let len = self.read_u32()?;
+        let bytes = self.read_bytes(len)?;
+        validate_instructions(bytes)?;
+        Ok(Instructions(bytes.to_vec()))
+    }
+
+    fn read_lines(&mut self) -> Result<LineTable, BytecodeError> {
+        let mut lines = LineTable::new();
+        for _ in 0..self.read_u32()? {
+            let unit = self.read_u32()?.checked_sub(1);
+            let mut unit_lines = Vec::new();
+            for _ in 0..self.read_u32()? {
+                unit_lines.push((self.read_u32()?, self.read_u32()?));
+            }
+            lines.insert(unit, unit_lines);
+        }
+        Ok(lines)
+    }
+
+    fn read_constant(&mut self) -> Result<Object, BytecodeError> {
+        let constant = match self.read_u8()? {
+            TAG_INTEGER => {
+                let bytes = self.read_bytes(8)?.try_into().unwrap();
+                Object::Integer(i64::from_be_bytes(bytes))
+            }
+            TAG_STRING => Object::Str(Box::new(self.read_string()?)),
+            TAG_FUNCTION => {
+                let num_locals = self.read_u8()?;
+                let num_params = self.read_u8()?;
+                let instructions = self.read_instructions()?;
+                Object::CompiledFunc(Rc::new(CompiledFunction {
+                    instructions,
+                    num_locals,
+                    num_params,
+                }))
+            }
+            TAG_STRUCT => {
+                let name = self.read_string()?;
+                let num_fields = self.read_u32()?;
+                let mut fields = Vec::new();
+                for _ in 0..num_fields {
+                    fields.push(self.read_string()?);
+                }
+                Object::StructConstructor(Rc::new(StructDefinition { name, fields }))
+            }
+            tag => return Err(BytecodeError::InvalidConstantTag(tag)),
+        };
+        Ok(constant)
+    }
+}
+
+/// Checks that the bytes are a sequence of valid opcodes, each followed by all of its operands.
+fn validate_instructions(bytes: &[u8]) -> Result<(), BytecodeError> {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let op =
+            OpCode::try_from_byte(bytes[pos]).ok_or(BytecodeError::InvalidOpCode(bytes[pos]))?;
+        pos += 1 + op.operand_widths().iter().sum::<usize>();
+    }
+    if pos > bytes.len() {
+        return Err(BytecodeError::TruncatedInstruction);
+    }
+    Ok(())
+}
+
+/// Computes the CRC-32 checksum of the bytes. Bytecode files are small, so this is computed one
+/// bit at a time instead of using a lookup table.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -56,6 +56,20 @@ impl SymbolTable {
         self.store.get(&name).unwrap()
     }
 
+    /// Returns the symbol defined with a name in this table, without looking in the outer tables.
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.store.get(name).cloned()
+    }
+
+    /// Removes the symbol defined with a name in this table, and restores the one it shadowed, if
+    /// any. Used at the end of the scope of `for` loop variables.
+    pub fn undefine(&mut self, name: &str, shadowed: Option<Symbol>) {
+        match shadowed {
+            Some(symbol) => self.store.insert(name.into(), symbol),
+            None => self.store.remove(name),
+        };
+    }
+
     pub fn define_builtin(&mut self, name: String, index: usize) -> &Symbol {
         let symbol = Symbol {
             scope: SymbolScope::Builtin,
@@ -75,6 +89,26 @@ impl SymbolTable {
         self.store.get(&name).unwrap()
     }
 
+    /// Returns the outermost symbol table, where the global symbols are defined.
+    pub fn global_mut(&mut self) -> &mut SymbolTable {
+        match self.outer {
+            Some(ref mut outer) => outer.global_mut(),
+            None => self,
+        }
+    }
+
+    /// Returns the names and indices of the global symbols defined in this table, ordered by index.
+    pub fn defined_globals(&self) -> Vec<(String, usize)> {
+        let mut globals: Vec<_> = self
+            .store
+            .iter()
+            .filter(|(_, symbol)| symbol.scope == SymbolScope::Global)
+            .map(|(name, symbol)| (name.clone(), symbol.index))
+            .collect();
+        globals.sort_by_key(|&(_, index)| index);
+        globals
+    }
+
     pub fn resolve(&mut self, name: &str) -> Option<Symbol> {
         self.store.get(name).cloned().or_else(|| {
             let outer_def = self.outer.as_mut().and_then(|outer| outer.resolve(name));
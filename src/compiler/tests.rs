@@ -14,8 +14,22 @@ fn assert_compile(
     expected_constants: Vec<Object>,
     expected_instructions: code::Instructions,
 ) {
-    let bytecode =
-        test_utils::parse_and_compile(input).expect("Parser or compiler error during test");
+    assert_compile_with(
+        OptLevel::None,
+        input,
+        expected_constants,
+        expected_instructions,
+    );
+}
+
+fn assert_compile_with(
+    opt_level: OptLevel,
+    input: &str,
+    expected_constants: Vec<Object>,
+    expected_instructions: code::Instructions,
+) {
+    let bytecode = test_utils::parse_and_compile_with(input, opt_level)
+        .expect("Parser or compiler error during test");
     for (exp, got) in expected_constants.iter().zip(bytecode.constants) {
         assert_eq!(exp, &got);
     }
@@ -95,6 +109,40 @@ fn test_integer_arithmetic() {
     );
 }
 
+#[test]
+fn test_bitwise_operators() {
+    assert_compile(
+        "1 & 2 | 3 ~ 4",
+        vec![
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+            Object::Integer(4),
+        ],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpBitAnd),
+            (OpCode::OpConstant, 2),
+            (OpCode::OpConstant, 3),
+            (OpCode::OpBitXor),
+            (OpCode::OpBitOr),
+        },
+    );
+    assert_compile(
+        "1 << 2 >> ~3",
+        vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpShiftLeft),
+            (OpCode::OpConstant, 2),
+            (OpCode::OpPrefixBitNot),
+            (OpCode::OpShiftRight),
+        },
+    );
+}
+
 #[test]
 fn test_boolean_expressions() {
     assert_compile("true", vec![], instructions! { (OpCode::OpTrue) });
@@ -110,11 +158,20 @@ fn test_boolean_expressions() {
     );
     assert_compile(
         "1 < 2",
-        vec![Object::Integer(2), Object::Integer(1)],
+        vec![Object::Integer(1), Object::Integer(2)],
         instructions! {
             (OpCode::OpConstant, 0),
             (OpCode::OpConstant, 1),
-            (OpCode::OpGreaterThan),
+            (OpCode::OpLessThan),
+        },
+    );
+    assert_compile(
+        "1 <= 2",
+        vec![Object::Integer(1), Object::Integer(2)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpLessEq),
         },
     );
     assert_compile(
@@ -176,6 +233,86 @@ fn test_conditionals() {
     );
 }
 
+#[test]
+fn test_exceptions() {
+    assert_compile(
+        "try { 1 } catch e { e }; throw 2;",
+        vec![Object::Integer(1), Object::Integer(2)],
+        instructions! {
+            (OpCode::OpTry, 10),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpEndTry),
+            (OpCode::OpJump, 16),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpPop),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpThrow),
+        },
+    );
+}
+
+#[test]
+fn test_propagate() {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpJumpNotError, 7),
+            (OpCode::OpReturn),
+            (OpCode::OpReturn),
+        },
+        num_locals: 0,
+        num_params: 0,
+    }));
+    assert_compile(
+        "fn() { 1? }",
+        vec![Object::Integer(1), expected_func],
+        instructions! { (OpCode::OpClosure, 1, 0) },
+    );
+
+    let error = test_utils::parse_and_compile("let a = 1; a?").err();
+    match error.expect("No compiler error encountered") {
+        MonkeyError::Compiler(_, InvalidPropagate) => {}
+        e => panic!("Wrong error: {}", e),
+    }
+}
+
+#[test]
+fn test_generators() {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpGenerator),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpYield),
+            (OpCode::OpNil),
+            (OpCode::OpReturn),
+        },
+        num_locals: 0,
+        num_params: 0,
+    }));
+    assert_compile(
+        "fn*() { yield 1; }",
+        vec![Object::Integer(1), expected_func],
+        instructions! { (OpCode::OpClosure, 1, 0) },
+    );
+
+    assert_compile(
+        "for x in [1] { x }",
+        vec![Object::Integer(1)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpArray, 1),
+            (OpCode::OpIter),
+            (OpCode::OpIterNext, 20),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpPop),
+            (OpCode::OpJump, 7),
+            (OpCode::OpNil),
+        },
+    );
+}
+
 #[test]
 fn test_global_assignment() {
     assert_compile(
@@ -229,6 +366,19 @@ fn test_strings() {
     );
 }
 
+#[test]
+fn test_interpolated_strings() {
+    assert_compile(
+        r#""a${1}""#,
+        vec![Object::from("a"), Object::Integer(1)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpConcat, 2),
+        },
+    );
+}
+
 #[test]
 fn test_arrays() {
     assert_compile(
@@ -314,6 +464,54 @@ fn test_hashes() {
     );
 }
 
+#[test]
+fn test_spread() {
+    assert_compile(
+        "[1, ..[2], 3]",
+        vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpArray, 1),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpArray, 1),
+            (OpCode::OpArrayExtend),
+            (OpCode::OpConstant, 2),
+            (OpCode::OpArrayPush),
+        },
+    );
+    assert_compile(
+        "#{ 1: 2, ..#{}, 3: 4 }",
+        vec![
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+            Object::Integer(4),
+        ],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpHash, 1),
+            (OpCode::OpHash, 0),
+            (OpCode::OpHashExtend),
+            (OpCode::OpConstant, 2),
+            (OpCode::OpConstant, 3),
+            (OpCode::OpHashInsert),
+        },
+    );
+    assert_compile(
+        "len(..[[]])",
+        vec![],
+        instructions! {
+            (OpCode::OpGetBuiltin, 2),
+            (OpCode::OpArray, 0),
+            (OpCode::OpArray, 0),
+            (OpCode::OpArray, 1),
+            (OpCode::OpArrayExtend),
+            (OpCode::OpCallSpread),
+        },
+    );
+}
+
 #[test]
 fn test_index_expressions() {
     assert_compile(
@@ -356,9 +554,97 @@ fn test_index_expressions() {
     );
 }
 
+#[test]
+fn test_nil_safe_operators() {
+    assert_compile(
+        "nil?[1]",
+        vec![Object::Integer(1)],
+        instructions! {
+            (OpCode::OpNil),
+            (OpCode::OpJumpNotNil, 8),
+            (OpCode::OpNil),
+            (OpCode::OpJump, 12),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpIndexOptional),
+        },
+    );
+    assert_compile(
+        "1 ?? 2; 3",
+        vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpJumpNotNil, 9),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpPop),
+            (OpCode::OpConstant, 2),
+        },
+    );
+}
+
+#[test]
+fn test_structs() {
+    let definition = Rc::new(StructDefinition {
+        name: "Point".into(),
+        fields: vec!["x".into(), "y".into()],
+    });
+    assert_compile(
+        "struct Point { x, y } Point(1, 2).x = 3",
+        vec![
+            Object::StructConstructor(definition),
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+            Object::from("x"),
+        ],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpConstant, 2),
+            (OpCode::OpCall, 2),
+            (OpCode::OpConstant, 3),
+            (OpCode::OpSetField, 4),
+        },
+    );
+    assert_compile(
+        "nil.y",
+        vec![Object::from("y")],
+        instructions! {
+            (OpCode::OpNil),
+            (OpCode::OpGetField, 0),
+        },
+    );
+}
+
+#[test]
+fn test_method_calls() {
+    assert_compile(
+        r#""a".split(",")"#,
+        vec![Object::from("a"), Object::from(","), Object::from("split")],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpMethodCall, 2, 1),
+        },
+    );
+    assert_compile(
+        "[].push(..[1])",
+        vec![Object::Integer(1), Object::from("push")],
+        instructions! {
+            (OpCode::OpArray, 0),
+            (OpCode::OpArray, 0),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpArray, 1),
+            (OpCode::OpArrayExtend),
+            (OpCode::OpMethodCallSpread, 1),
+        },
+    );
+}
+
 #[test]
 fn test_function_literals() {
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpConstant, 0),
             (OpCode::OpConstant, 1),
@@ -373,7 +659,7 @@ fn test_function_literals() {
         vec![Object::Integer(5), Object::Integer(10), expected_func],
         instructions! { (OpCode::OpClosure, 2, 0) },
     );
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpConstant, 0),
             (OpCode::OpReturn),
@@ -386,11 +672,47 @@ fn test_function_literals() {
         vec![Object::Integer(1), expected_func],
         instructions! { (OpCode::OpClosure, 1, 0) },
     );
+
+    // The end of the `if` is a jump target, so the `OpReturn` in the alternative doesn't return
+    // from the other branch
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpEquals),
+            (OpCode::OpJumpNotTruthy, 15),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpJump, 18),
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpReturn),
+            (OpCode::OpReturn),
+        },
+        num_locals: 1,
+        num_params: 1,
+    }));
+    assert_compile(
+        "let c = fn(n) { if n == 0 { 0 } else { return n; } }; puts(c(3));",
+        vec![
+            Object::Integer(0),
+            Object::Integer(0),
+            expected_func,
+            Object::Integer(3),
+        ],
+        instructions! {
+            (OpCode::OpClosure, 2, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpGetBuiltin, 1),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpConstant, 3),
+            (OpCode::OpCall, 1),
+            (OpCode::OpCall, 1),
+        },
+    );
 }
 
 #[test]
 fn test_function_calls() {
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpConstant, 0),
             (OpCode::OpReturn),
@@ -414,7 +736,7 @@ fn test_function_calls() {
         },
     );
 
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! { (OpCode::OpGetLocal, 0), (OpCode::OpReturn) },
         num_locals: 1,
         num_params: 1,
@@ -431,7 +753,7 @@ fn test_function_calls() {
         },
     );
 
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
            (OpCode::OpGetLocal, 0),
            (OpCode::OpPop),
@@ -465,7 +787,7 @@ fn test_function_calls() {
 
 #[test]
 fn test_binding_scopes() {
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpGetGlobal, 0),
             (OpCode::OpReturn),
@@ -482,7 +804,7 @@ fn test_binding_scopes() {
             (OpCode::OpClosure, 1, 0),
         },
     );
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpConstant, 0),
             (OpCode::OpSetLocal, 0),
@@ -499,6 +821,64 @@ fn test_binding_scopes() {
     );
 }
 
+#[test]
+fn test_tail_calls() {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpJumpNotTruthy, 14),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpFalse),
+            (OpCode::OpTailCall, 1),
+            (OpCode::OpJump, 15),
+            (OpCode::OpNil),
+            (OpCode::OpReturn),
+        },
+        num_locals: 1,
+        num_params: 1,
+    }));
+    assert_compile(
+        "let f = fn(x) { if x { f(false) } }",
+        vec![expected_func],
+        instructions! {
+            (OpCode::OpClosure, 0, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpNil),
+        },
+    );
+
+    // Calls that are not in tail position, or that are inside a `try` body, are regular calls
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpTry, 13),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpCall, 0),
+            (OpCode::OpReturn),
+            (OpCode::OpEndTry),
+            (OpCode::OpJump, 16),
+            (OpCode::OpSetLocal, 0),
+            (OpCode::OpNil),
+            (OpCode::OpPop),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpCall, 0),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpAdd),
+            (OpCode::OpReturn),
+        },
+        num_locals: 1,
+        num_params: 0,
+    }));
+    assert_compile(
+        "let f = fn() { try { return f(); } catch e {}; f() + 1 }",
+        vec![Object::Integer(1), expected_func],
+        instructions! {
+            (OpCode::OpClosure, 1, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpNil),
+        },
+    );
+}
+
 #[test]
 fn test_builtins() {
     assert_compile(
@@ -515,11 +895,11 @@ fn test_builtins() {
             (OpCode::OpCall, 2),
         },
     );
-    let expected_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let expected_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpGetBuiltin, 2),
             (OpCode::OpArray, 0),
-            (OpCode::OpCall, 1),
+            (OpCode::OpTailCall, 1),
             (OpCode::OpReturn),
         },
         num_locals: 0,
@@ -536,7 +916,7 @@ fn test_builtins() {
 
 #[test]
 fn test_closures() {
-    let outer_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let outer_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpGetLocal, 0),
             (OpCode::OpClosure, 0, 1),
@@ -545,7 +925,7 @@ fn test_closures() {
         num_locals: 1,
         num_params: 1,
     }));
-    let inner_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let inner_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpGetFree, 0),
             (OpCode::OpGetLocal, 0),
@@ -561,7 +941,7 @@ fn test_closures() {
         instructions! { (OpCode::OpClosure, 1, 0) },
     );
 
-    let outer_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let outer_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpGetLocal, 0),
             (OpCode::OpClosure, 1, 1),
@@ -570,7 +950,7 @@ fn test_closures() {
         num_locals: 1,
         num_params: 1,
     }));
-    let inner_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let inner_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpGetFree, 0),
             (OpCode::OpGetLocal, 0),
@@ -580,7 +960,7 @@ fn test_closures() {
         num_locals: 1,
         num_params: 1,
     }));
-    let inner_inner_func = Object::CompiledFunc(Box::new(CompiledFunction {
+    let inner_inner_func = Object::CompiledFunc(Rc::new(CompiledFunction {
         instructions: instructions! {
             (OpCode::OpGetFree, 0),
             (OpCode::OpGetFree, 1),
@@ -598,3 +978,383 @@ fn test_closures() {
         instructions! { (OpCode::OpClosure, 2, 0) },
     );
 }
+
+#[test]
+fn test_constant_folding() {
+    use OptLevel::Fold;
+    assert_compile_with(
+        Fold,
+        "1 + 2 * 3; -(2 ^ 10) % 7",
+        vec![Object::Integer(7), Object::Integer(-2)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpPop),
+            (OpCode::OpConstant, 1),
+        },
+    );
+    assert_compile_with(
+        Fold,
+        r#""mon" + "key"; 1 < 2; !(3 == 3)"#,
+        vec![Object::from("monkey")],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpPop),
+            (OpCode::OpTrue),
+            (OpCode::OpPop),
+            (OpCode::OpFalse),
+        },
+    );
+    // Expressions that fail at runtime, or that aren't constant, are compiled as they are
+    assert_compile_with(
+        Fold,
+        "1 / 0; 9223372036854775807 + 1; [1] + 2",
+        vec![
+            Object::Integer(1),
+            Object::Integer(0),
+            Object::Integer(9223372036854775807),
+            Object::Integer(1),
+            Object::Integer(1),
+            Object::Integer(2),
+        ],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpDiv),
+            (OpCode::OpPop),
+            (OpCode::OpConstant, 2),
+            (OpCode::OpConstant, 3),
+            (OpCode::OpAdd),
+            (OpCode::OpPop),
+            (OpCode::OpConstant, 4),
+            (OpCode::OpArray, 1),
+            (OpCode::OpConstant, 5),
+            (OpCode::OpAdd),
+        },
+    );
+    // Constant conditions are replaced by their truthiness
+    assert_compile_with(
+        Fold,
+        "if 1 + 1 { 10 } else { 20 }; 3333",
+        vec![
+            Object::Integer(10),
+            Object::Integer(20),
+            Object::Integer(3333),
+        ],
+        instructions! {
+            (OpCode::OpTrue),
+            (OpCode::OpJumpNotTruthy, 10),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpJump, 13),
+            (OpCode::OpConstant, 1),
+            (OpCode::OpPop),
+            (OpCode::OpConstant, 2),
+        },
+    );
+}
+
+#[test]
+fn test_peephole() {
+    use OptLevel::Full;
+    assert_compile_with(
+        Full,
+        "if 1 < 2 { 10 } else { 20 }; 3333",
+        vec![
+            Object::Integer(10),
+            Object::Integer(20),
+            Object::Integer(3333),
+        ],
+        instructions! { (OpCode::OpConstant, 2) },
+    );
+    assert_compile_with(
+        Full,
+        "if false { 10 }; 3333",
+        vec![Object::Integer(10), Object::Integer(3333)],
+        instructions! { (OpCode::OpConstant, 1) },
+    );
+    // The result of the program is kept
+    assert_compile_with(
+        Full,
+        "let x = 1; x; x",
+        vec![Object::Integer(1)],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpGetGlobal, 0),
+        },
+    );
+    // Jumps are moved along with the instructions they point to
+    assert_compile_with(
+        Full,
+        "let x = 1; if !!x { 2; 3 } else { 4 }",
+        vec![
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+            Object::Integer(4),
+        ],
+        instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpJumpNotTruthy, 18),
+            (OpCode::OpConstant, 2),
+            (OpCode::OpJump, 21),
+            (OpCode::OpConstant, 3),
+        },
+    );
+    let func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpConstant, 0),
+            (OpCode::OpReturn),
+        },
+        num_locals: 0,
+        num_params: 0,
+    }));
+    assert_compile_with(
+        Full,
+        "fn() { if true { 1 } else { 2 } }",
+        vec![Object::Integer(1), Object::Integer(2), func],
+        instructions! { (OpCode::OpClosure, 2, 0) },
+    );
+}
+
+#[test]
+fn test_superinstructions() {
+    let func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpGetLocal, 1),
+            (OpCode::OpJumpNotLess, 14),
+            (OpCode::OpIncrementLocal, 0, 0),
+            (OpCode::OpJump, 18),
+            (OpCode::OpDecrementLocal, 1, 1),
+            (OpCode::OpReturn),
+        },
+        num_locals: 2,
+        num_params: 2,
+    }));
+    assert_compile_with(
+        OptLevel::Full,
+        "fn(a, b) { if a < b { a + 1 } else { b - 2 } }",
+        vec![Object::Integer(1), Object::Integer(2), func],
+        instructions! { (OpCode::OpClosure, 2, 0) },
+    );
+    // Comparisons that aren't conditions stay as they are
+    let func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpGetLocal, 1),
+            (OpCode::OpGreaterEq),
+            (OpCode::OpReturn),
+        },
+        num_locals: 2,
+        num_params: 2,
+    }));
+    assert_compile_with(
+        OptLevel::Full,
+        "fn(a, b) { a >= b }",
+        vec![func],
+        instructions! { (OpCode::OpClosure, 0, 0) },
+    );
+    // Loops made of tail calls, where the condition and the loop variables are updated by
+    // superinstructions
+    let func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpConstant, 0),
+            (OpCode::OpJumpNotLessEq, 13),
+            (OpCode::OpGetLocal, 1),
+            (OpCode::OpJump, 26),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpDecrementLocal, 0, 1),
+            (OpCode::OpIncrementLocal, 1, 2),
+            (OpCode::OpTailCall, 2),
+            (OpCode::OpReturn),
+        },
+        num_locals: 2,
+        num_params: 2,
+    }));
+    assert_compile_with(
+        OptLevel::Full,
+        "let count = fn(n, acc) { if n <= 0 { acc } else { count(n - 1, acc + 2) } };",
+        vec![
+            Object::Integer(0),
+            Object::Integer(1),
+            Object::Integer(2),
+            func,
+        ],
+        instructions! {
+            (OpCode::OpClosure, 3, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpNil),
+        },
+    );
+    let func = Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: instructions! {
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpGetLocal, 1),
+            (OpCode::OpJumpNotLess, 26),
+            (OpCode::OpGetGlobal, 0),
+            (OpCode::OpIncrementLocal, 0, 0),
+            (OpCode::OpGetLocal, 1),
+            (OpCode::OpGetLocal, 2),
+            (OpCode::OpGetLocal, 0),
+            (OpCode::OpAdd),
+            (OpCode::OpTailCall, 3),
+            (OpCode::OpJump, 28),
+            (OpCode::OpGetLocal, 2),
+            (OpCode::OpReturn),
+        },
+        num_locals: 3,
+        num_params: 3,
+    }));
+    assert_compile_with(
+        OptLevel::Full,
+        "let sum = fn(i, n, acc) { if i < n { sum(i + 1, n, acc + i) } else { acc } };",
+        vec![Object::Integer(1), func],
+        instructions! {
+            (OpCode::OpClosure, 1, 0),
+            (OpCode::OpSetGlobal, 0),
+            (OpCode::OpNil),
+        },
+    );
+}
+
+fn serialize_program(input: &str) -> Vec<u8> {
+    test_utils::parse_and_compile(input)
+        .expect("Parser or compiler error during test")
+        .serialize()
+}
+
+#[test]
+fn test_serialize_roundtrip() {
+    let input = r#"
+        struct Point { x, y }
+        let add = fn(a) { fn(b) { a + b } };
+        let p = Point(add(1)(2), "three");
+        [p.x, p.y, -9223372036854775807]
+    "#;
+    let bytecode = test_utils::parse_and_compile(input).unwrap();
+    let deserialized = Bytecode::deserialize(&bytecode.serialize()).unwrap();
+    assert_eq!(bytecode.instructions, deserialized.instructions);
+    assert_eq!(bytecode.constants, deserialized.constants);
+
+    let mut vm = crate::vm::VM::new();
+    vm.run(deserialized).unwrap();
+    let expected = monkey_array![
+        Object::Integer(3),
+        Object::from("three"),
+        Object::Integer(-9223372036854775807),
+    ];
+    assert_eq!(expected, vm.pop().unwrap());
+
+    // The line table is kept, so runtime errors are reported at the right line
+    let input = "let f = fn(x) {\n    let y = x + 1;\n    y / 0\n};\nf(1);";
+    let bytecode = test_utils::parse_and_compile(input).unwrap();
+    let deserialized = Bytecode::deserialize(&bytecode.serialize()).unwrap();
+    assert_eq!(bytecode.lines, deserialized.lines);
+    match crate::vm::VM::new().run(deserialized) {
+        Err(MonkeyError::VmAt(3, crate::error::RuntimeError::DivOrModByZero)) => {}
+        other => panic!("Wrong result: {:?}", other.err()),
+    }
+}
+
+#[test]
+fn test_deserialize_errors() {
+    use crate::error::BytecodeError::*;
+
+    let bytes = serialize_program("let f = fn(x) { x * 2 }; f(\"a\")");
+    for len in 0..bytes.len() {
+        assert!(Bytecode::deserialize(&bytes[..len]).is_err());
+    }
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert_eq!(
+        Some(ChecksumMismatch),
+        Bytecode::deserialize(&corrupted).err()
+    );
+
+    let mut wrong_signature = bytes.clone();
+    wrong_signature[0] = b'X';
+    assert_eq!(
+        Some(NotBytecode),
+        Bytecode::deserialize(&wrong_signature).err()
+    );
+
+    let mut wrong_version = bytes;
+    wrong_version[4..6].copy_from_slice(&99u16.to_be_bytes());
+    assert_eq!(
+        Some(UnsupportedVersion(99)),
+        Bytecode::deserialize(&wrong_version).err()
+    );
+
+    let invalid_opcode = Bytecode {
+        instructions: Instructions(vec![255]),
+        constants: Vec::new(),
+        lines: LineTable::new(),
+    };
+    assert_eq!(
+        Some(InvalidOpCode(255)),
+        Bytecode::deserialize(&invalid_opcode.serialize()).err()
+    );
+
+    let missing_operand = Bytecode {
+        instructions: Instructions(vec![OpCode::OpConstant as u8, 0]),
+        constants: Vec::new(),
+        lines: LineTable::new(),
+    };
+    assert_eq!(
+        Some(TruncatedInstruction),
+        Bytecode::deserialize(&missing_operand.serialize()).err()
+    );
+}
+
+#[test]
+fn test_disassemble() {
+    let input = "let f = fn(x) {\n    fn() { x }\n};\nputs(if (f(1)()) { \"a\" });";
+    let bytecode = test_utils::parse_and_compile(input).unwrap();
+    assert_eq!(Some(&vec![(0, 1), (7, 4)]), bytecode.lines.get(&None));
+
+    let expected = r#"constants:
+     0  fn#0
+     1  fn#1
+     2  1
+     3  "a"
+
+main:
+  ; 1: let f = fn(x) {
+    0000 OpClosure 1 0          ; fn#1
+    0004 OpSetGlobal 0
+  ; 4: puts(if (f(1)()) { "a" });
+    0007 OpGetBuiltin 1         ; puts
+    0009 OpGetGlobal 0
+    0012 OpConstant 2           ; 1
+    0015 OpCall 1
+    0017 OpCall 0
+    0019 OpJumpNotTruthy L0
+    0022 OpConstant 3           ; "a"
+    0025 OpJump L1
+  L0:
+    0028 OpNil
+  L1:
+    0029 OpCall 1
+
+fn#1 (params: 1, locals: 1):
+  ; 2: fn() { x }
+    0000 OpGetLocal 0
+    0002 OpClosure 0 1          ; fn#0
+    0006 OpReturn
+
+fn#0 (params: 0, locals: 0):
+  ; 2: fn() { x }
+    0000 OpGetFree 0
+    0002 OpReturn
+"#;
+    assert_eq!(expected, disassemble::disassemble(&bytecode, Some(input)));
+
+    // Without the source, only the code is shown
+    let disassembly = disassemble::disassemble(&bytecode, None);
+    assert!(!disassembly.contains("; 1:"));
+    assert!(disassembly.contains("OpJumpNotTruthy L0"));
+}
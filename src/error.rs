@@ -1,12 +1,17 @@
-use crate::object::*;
+use crate::compiler::code::OpCode;
 use crate::lexer::token::Token;
+use crate::object::*;
 
 use colored::*;
 use std::fmt;
 use std::io;
+use std::time::Duration;
 
 pub type MonkeyResult<T> = Result<T, MonkeyError>;
 type Position = (usize, usize);
+// The location of an instruction: the index of its function in the constant pool, or `None` for
+// the main program, and its offset in the instructions
+pub type CodeLocation = (Option<usize>, usize);
 
 #[derive(Debug)]
 pub enum MonkeyError {
@@ -16,6 +21,10 @@ pub enum MonkeyError {
     Compiler(Position, CompilerError),
     Interpreter(Position, RuntimeError),
     Vm(RuntimeError),
+    // A runtime error in the VM, at a line of the main file
+    VmAt(usize, RuntimeError),
+    Bytecode(BytecodeError),
+    Verifier(CodeLocation, VerifierError),
 }
 
 impl std::error::Error for MonkeyError {}
@@ -43,6 +52,18 @@ impl fmt::Display for MonkeyError {
                 write!(f, "{} {}", "Runtime error:".red().bold(), e)
             }
             MonkeyError::Vm(e) => write!(f, "{} {}", "Runtime error:".red().bold(), e),
+            MonkeyError::VmAt(line, e) => {
+                writeln!(f, "At line {}:", line)?;
+                write!(f, "{} {}", "Runtime error:".red().bold(), e)
+            }
+            MonkeyError::Bytecode(e) => write!(f, "{} {}", "Bytecode error:".red().bold(), e),
+            MonkeyError::Verifier((function, offset), e) => {
+                match function {
+                    Some(index) => writeln!(f, "In function {}, at offset {}:", index, offset)?,
+                    None => writeln!(f, "In main program, at offset {}:", offset)?,
+                }
+                write!(f, "{} {}", "Invalid bytecode:".red().bold(), e)
+            }
         }
     }
 }
@@ -57,6 +78,9 @@ impl std::convert::From<io::Error> for MonkeyError {
 pub enum LexerError {
     UnexpectedEOF,
     UnknownEscapeSequence(char),
+    InvalidUnicodeEscape(String),
+    MalformedIntLiteral(String),
+    IntLiteralOutOfRange(String),
     IllegalChar(char),
 }
 
@@ -66,6 +90,9 @@ impl fmt::Display for LexerError {
         match self {
             UnexpectedEOF => write!(f, "Unexpected EOF"),
             UnknownEscapeSequence(ch) => write!(f, "Unknown escape sequence: \\{}", ch),
+            InvalidUnicodeEscape(s) => write!(f, "Invalid unicode escape sequence: \\{}", s),
+            MalformedIntLiteral(s) => write!(f, "Malformed integer literal: {}", s),
+            IntLiteralOutOfRange(s) => write!(f, "Integer literal out of range: {}", s),
             IllegalChar(ch) => write!(f, "Illegal character: \\{}", ch),
         }
     }
@@ -79,6 +106,9 @@ pub enum ParserError {
         got: Token,
     },
     NoPrefixParseFn(Token),
+    InvalidPipeTarget,
+    DuplicateField(String),
+    YieldOutsideGenerator,
 }
 
 impl fmt::Display for ParserError {
@@ -103,6 +133,12 @@ impl fmt::Display for ParserError {
                 )
             }
             NoPrefixParseFn(tk) => write!(f, "no prefix parse function found for token: {}", tk),
+            DuplicateField(field) => write!(f, "field '{}' is declared more than once", field),
+            YieldOutsideGenerator => write!(f, "`yield` outside of generator function"),
+            InvalidPipeTarget => write!(
+                f,
+                "right side of `|>` must be a function call or a callable expression"
+            ),
         }
     }
 }
@@ -112,6 +148,8 @@ pub enum CompilerError {
     InvalidReturn,
     IdenNotFound(String),
     LiteralTooBig,
+    InvalidPropagate,
+    Import(ImportError),
 }
 
 impl fmt::Display for CompilerError {
@@ -121,10 +159,79 @@ impl fmt::Display for CompilerError {
             InvalidReturn => write!(f, "`return` outside of function context"),
             IdenNotFound(s) => write!(f, "identifier not found: '{}'", s),
             LiteralTooBig => write!(f, "array or hash literal too big"),
+            InvalidPropagate => write!(f, "`?` outside of function context"),
+            Import(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    // Error while reading, parsing or running the imported file
+    InFile(String, Box<MonkeyError>),
+    // The imported file is already being imported. Holds the chain of imports that forms the cycle
+    Cycle(Vec<String>),
+    // The compiler resolves imports at compile time, so it only supports string literal paths
+    DynamicPath,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ImportError::*;
+        match self {
+            InFile(path, e) => write!(f, "error importing '{}':\n{}", path, e),
+            Cycle(chain) => write!(f, "import cycle: {}", chain.join(" -> ")),
+            DynamicPath => write!(f, "`import` path must be a string literal when compiling"),
         }
     }
 }
 
+// Errors when reading a bytecode file
+#[derive(Debug, PartialEq)]
+pub enum BytecodeError {
+    // The file doesn't start with the bytecode file signature
+    NotBytecode,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    // The file ended in the middle of a section
+    Truncated,
+    TrailingBytes,
+    InvalidOpCode(u8),
+    // The last instruction is missing some of its operands
+    TruncatedInstruction,
+    InvalidConstantTag(u8),
+    InvalidString,
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use BytecodeError::*;
+        match self {
+            NotBytecode => write!(f, "not a bytecode file"),
+            UnsupportedVersion(v) => write!(f, "unsupported bytecode format version: {}", v),
+            ChecksumMismatch => write!(f, "checksum mismatch, the file is corrupted"),
+            Truncated => write!(f, "unexpected end of file"),
+            TrailingBytes => write!(f, "unexpected data at the end of the file"),
+            InvalidOpCode(byte) => write!(f, "invalid opcode: {}", byte),
+            TruncatedInstruction => write!(f, "instruction is missing operands"),
+            InvalidConstantTag(tag) => write!(f, "invalid constant type: {}", tag),
+            InvalidString => write!(f, "string constant is not valid UTF-8"),
+        }
+    }
+}
+
+impl From<BytecodeError> for MonkeyError {
+    fn from(error: BytecodeError) -> MonkeyError {
+        MonkeyError::Bytecode(error)
+    }
+}
+
+impl From<ImportError> for RuntimeError {
+    fn from(error: ImportError) -> RuntimeError {
+        RuntimeError::Import(error)
+    }
+}
+
 #[derive(Debug)]
 pub enum RuntimeError {
     // Identifier not found in the current environment
@@ -145,20 +252,47 @@ pub enum RuntimeError {
     PrefixTypeError(Token, &'static str),
     // Invalid type in infix expression
     InfixTypeError(&'static str, Token, &'static str),
+    // Trying to access a field that doesn't exist in a struct
+    UnknownField(String, String),
+    // Trying to access a field of an object which is not a struct
+    NoFields(&'static str),
+    // Trying to call a method that doesn't exist for the type of the receiver. Also holds the
+    // names of the available methods.
+    AttributeError(&'static str, String, &'static [&'static str]),
     // Trying to call non-callable object
     NotCallable(&'static str),
     // Division or modulo by zero
     DivOrModByZero,
     // Exponentiation with negative exponent
     NegativeExponent,
+    // Bit shift by a negative amount, or by more than the number of bits in an integer
+    InvalidShiftAmount(i64),
     // General purpose TypeError, useful for type assertions
     TypeError(&'static str, &'static str),
     // Custom error
     Custom(String),
+    // Calling the `unwrap` built-in on an error value
+    UnwrapError(Box<Object>),
+    // The value passed to the `assert` built-in is not truthy
+    AssertionFailed(Box<Object>),
+    // Error while importing a module
+    Import(ImportError),
+    // Resuming a generator from inside its own body
+    GeneratorRunning,
+    // The interpreter only supports `yield` statements directly inside blocks
+    UnsupportedYield,
+    // A value thrown by a `throw` statement, that may be caught by a `try` expression
+    Thrown(Box<Object>),
 
     StackOverflow,
     StackUnderflow,
 
+    // The limits set in `limits::Limits` were reached. Each one holds the limit
+    StepLimitExceeded(u64),
+    Timeout(Duration),
+    CollectionTooLarge(usize),
+    AllocationLimitExceeded(u64),
+
     // This error is created whenever the interpreter encounters a return statement. We model
     // returning values this way to take advantage of the error forwarding already present in the
     // evaluator. When the interpreter encounters a runtime error, be it this one or any other,
@@ -197,22 +331,160 @@ impl fmt::Display for RuntimeError {
                 "unsuported operand types for infix operator {}: '{}' and '{}'",
                 tk, left, right,
             ),
+            UnknownField(name, field) => write!(f, "'{}' struct has no field '{}'", name, field),
+            NoFields(obj) => write!(f, "'{}' object has no fields", obj),
+            AttributeError(obj, method, available) => write!(
+                f,
+                "'{}' object has no method '{}' (available methods: {})",
+                obj,
+                method,
+                available.join(", ")
+            ),
             NotCallable(obj) => {
                 write!(f, "'{}' is not a function object or built-in function", obj)
             }
             DivOrModByZero => write!(f, "division or modulo by zero"),
             NegativeExponent => write!(f, "negative exponent"),
+            InvalidShiftAmount(i) => write!(f, "invalid shift amount: {}", i),
             TypeError(expected, got) => {
                 write!(f, "type error: expected '{}', got '{}'", expected, got)
             }
             Custom(msg) => write!(f, "{}", msg),
-            
+            UnwrapError(obj) => write!(f, "called `unwrap` on an error value: {}", obj),
+            AssertionFailed(obj) => write!(f, "Assertion failed on value {}", obj),
+            Import(e) => write!(f, "{}", e),
+            GeneratorRunning => write!(f, "generator is already running"),
+            UnsupportedYield => write!(
+                f,
+                "the interpreter only supports `yield` in statements directly inside the \
+                generator body or inside `if`, `for`, `try` and block expressions"
+            ),
+            Thrown(obj) => write!(f, "uncaught exception: {}", obj),
+
             StackOverflow => write!(f, "stack overflow"),
             StackUnderflow => write!(f, "stack underflow"),
 
+            StepLimitExceeded(max) => write!(f, "step limit exceeded: ran more than {} steps", max),
+            Timeout(timeout) => write!(f, "timeout: ran for more than {:?}", timeout),
+            CollectionTooLarge(max) => write!(
+                f,
+                "collection too large: collections can't have more than {} elements",
+                max
+            ),
+            AllocationLimitExceeded(max) => write!(
+                f,
+                "allocation limit exceeded: created more than {} objects",
+                max
+            ),
+
             // A `ReturnValue` that was not handled by `call_function_object` means that it was
             // located outside a function context.
             ReturnValue(_) => write!(f, "`return` outside of function context"),
         }
     }
 }
+
+impl RuntimeError {
+    /// Returns the name of the error type, used in the `type` field of exceptions.
+    pub fn error_type(&self) -> &'static str {
+        use RuntimeError::*;
+        match self {
+            IdenNotFound(_) => "NameError",
+            WrongNumberOfArgs(..) => "ArgumentError",
+            IndexOutOfBounds(_) => "IndexError",
+            KeyError(_) => "KeyError",
+            IndexTypeError(_) | HashKeyTypeError(_) | IndexingWrongType(_)
+            | PrefixTypeError(..) | InfixTypeError(..) | NoFields(_) | NotCallable(_)
+            | TypeError(..) => "TypeError",
+            UnknownField(..) | AttributeError(..) => "AttributeError",
+            DivOrModByZero => "ZeroDivisionError",
+            NegativeExponent | InvalidShiftAmount(_) => "ValueError",
+            UnwrapError(_) => "UnwrapError",
+            AssertionFailed(_) => "AssertionError",
+            Import(_) => "ImportError",
+            GeneratorRunning => "ValueError",
+            StackOverflow => "StackOverflowError",
+            StepLimitExceeded(_)
+            | Timeout(_)
+            | CollectionTooLarge(_)
+            | AllocationLimitExceeded(_) => "LimitError",
+            Custom(_) | UnsupportedYield | Thrown(_) | StackUnderflow | ReturnValue(_) => "Error",
+        }
+    }
+
+    /// Converts the error into the value caught by a `try` expression. Thrown values are caught
+    /// as they are, and other errors are converted to an `Exception` struct, with the error type
+    /// and message. Returns the error back if it can't be caught: stack underflows are bugs in the
+    /// VM, return values are not really errors, and programs must stop when they reach a limit.
+    pub fn into_exception(self) -> Result<Object, RuntimeError> {
+        use RuntimeError::*;
+        match self {
+            Thrown(obj) => Ok(*obj),
+            StackUnderflow
+            | ReturnValue(_)
+            | StepLimitExceeded(_)
+            | Timeout(_)
+            | CollectionTooLarge(_)
+            | AllocationLimitExceeded(_) => Err(self),
+            error => Ok(Object::exception(error.error_type(), error.to_string())),
+        }
+    }
+}
+
+// Errors found by the bytecode verifier before running a program
+#[derive(Debug, PartialEq)]
+pub enum VerifierError {
+    InvalidOpCode(u8),
+    // The last instruction is missing some of its operands
+    TruncatedInstruction,
+    // The target isn't the start of an instruction
+    InvalidJumpTarget(usize),
+    ConstantOutOfRange(usize),
+    // The constant at the index doesn't have the type the instruction expects
+    WrongConstantType(usize, &'static str),
+    InvalidLocal(usize),
+    InvalidFreeVariable(usize),
+    InvalidBuiltin(usize),
+    // The function declares more parameters than locals
+    InvalidParameterCount(u8, u8),
+    // The instruction can't be used in this function, like `OpReturn` in the main program
+    MisplacedInstruction(OpCode),
+    StackUnderflow,
+    // The instruction can be reached with different stack sizes
+    InconsistentStack,
+    // `OpEndTry` without a matching `OpTry`
+    UnbalancedTry,
+    // A tail call would reuse the frame while a `try` handler in it is registered
+    TailCallInTry,
+    // The function can reach the end of its instructions without returning
+    MissingReturn,
+}
+
+impl fmt::Display for VerifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use VerifierError::*;
+        match self {
+            InvalidOpCode(byte) => write!(f, "invalid opcode: {}", byte),
+            TruncatedInstruction => write!(f, "instruction is missing operands"),
+            InvalidJumpTarget(target) => write!(f, "invalid jump target: {}", target),
+            ConstantOutOfRange(index) => write!(f, "constant index out of range: {}", index),
+            WrongConstantType(index, expected) => {
+                write!(f, "constant {} is not a {}", index, expected)
+            }
+            InvalidLocal(index) => write!(f, "local index out of range: {}", index),
+            InvalidFreeVariable(index) => write!(f, "free variable index out of range: {}", index),
+            InvalidBuiltin(index) => write!(f, "built-in index out of range: {}", index),
+            InvalidParameterCount(params, locals) => write!(
+                f,
+                "function has {} parameters but only {} locals",
+                params, locals
+            ),
+            MisplacedInstruction(op) => write!(f, "{:?} is not allowed here", op),
+            StackUnderflow => write!(f, "instruction pops more values than the stack holds"),
+            InconsistentStack => write!(f, "instruction is reached with different stack sizes"),
+            UnbalancedTry => write!(f, "OpEndTry without a matching OpTry"),
+            TailCallInTry => write!(f, "tail call inside a `try` expression"),
+            MissingReturn => write!(f, "function reaches the end without returning"),
+        }
+    }
+}
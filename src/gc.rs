@@ -0,0 +1,279 @@
+// Objects are reference counted, which frees them as soon as they're unused, except when they're
+// part of a reference cycle. Cycles are common in the interpreter: a function defined with `let`
+// holds the environment it was defined in, which holds the function. Only environments and
+// generators can be mutated after they're created, so every cycle goes through one of them, and
+// they're the only containers the collector has to keep track of.
+//
+// The collector finds cycles the same way CPython does. Every tracked container starts with its
+// reference count, and the references from other tracked containers are subtracted from it. The
+// containers left with references are referenced from somewhere else, like a local variable in
+// the interpreter or the VM stack, so they're alive, and so is everything they reach. The rest
+// are only referenced by garbage, so they're cleared, which breaks their cycles and frees them.
+//
+// Containers that can't be inspected, because they're being mutated, are assumed to be alive, and
+// so is everything they refer to. Collections run automatically after enough containers are
+// created, or when the `gc` built-in is called.
+
+use crate::interpreter::environment::{EnvHandle, Environment};
+use crate::object::*;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+// The number of containers created between automatic collections, if there aren't many alive.
+// Otherwise, it's the number of containers that survived the last collection, so the time spent
+// collecting stays proportional to the number of containers created.
+const MIN_THRESHOLD: usize = 10_000;
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::default());
+}
+
+#[derive(Default)]
+struct Heap {
+    environments: Vec<Weak<RefCell<Environment>>>,
+    generators: Vec<Weak<RefCell<GeneratorObject>>>,
+    // The number of containers created since the last collection
+    created: usize,
+    stats: Stats,
+}
+
+impl Heap {
+    fn threshold(&self) -> usize {
+        MIN_THRESHOLD.max(self.environments.len() + self.generators.len() - self.created)
+    }
+}
+
+/// Memory statistics, counting the containers tracked by the collector.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub collections: usize,
+    // The number of containers freed by collections, since the program started
+    pub collected: usize,
+    // The number of containers created since the program started
+    pub allocated: usize,
+    pub environments: usize,
+    pub generators: usize,
+}
+
+/// Creates an environment that is tracked by the collector.
+pub fn new_environment(env: Environment) -> EnvHandle {
+    let env = Rc::new(RefCell::new(env));
+    let weak = Rc::downgrade(&env);
+    track(|heap| heap.environments.push(weak));
+    env
+}
+
+/// Creates a generator that is tracked by the collector.
+pub fn new_generator(generator: GeneratorObject) -> Rc<RefCell<GeneratorObject>> {
+    let generator = Rc::new(RefCell::new(generator));
+    let weak = Rc::downgrade(&generator);
+    track(|heap| heap.generators.push(weak));
+    generator
+}
+
+fn track(push: impl FnOnce(&mut Heap)) {
+    let should_collect = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        push(&mut heap);
+        heap.created += 1;
+        heap.stats.allocated += 1;
+        heap.created >= heap.threshold()
+    });
+    if should_collect {
+        collect();
+    }
+}
+
+/// Returns the current memory statistics.
+pub fn stats() -> Stats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let alive = |count: usize| count > 0;
+        Stats {
+            environments: heap
+                .environments
+                .iter()
+                .filter(|w| alive(w.strong_count()))
+                .count(),
+            generators: heap
+                .generators
+                .iter()
+                .filter(|w| alive(w.strong_count()))
+                .count(),
+            ..heap.stats
+        }
+    })
+}
+
+// A tracked container, kept alive during a collection
+enum Container {
+    Environment(EnvHandle),
+    Generator(Rc<RefCell<GeneratorObject>>),
+}
+
+impl Container {
+    fn address(&self) -> *const () {
+        match self {
+            Container::Environment(env) => Rc::as_ptr(env) as *const (),
+            Container::Generator(generator) => Rc::as_ptr(generator) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Container::Environment(env) => Rc::strong_count(env),
+            Container::Generator(generator) => Rc::strong_count(generator),
+        }
+    }
+
+    /// Returns the addresses of the containers this one refers to, or `None` if it can't be
+    /// inspected right now.
+    fn references(&self) -> Option<Vec<*const ()>> {
+        let mut tracer = Tracer::default();
+        match self {
+            Container::Environment(env) => env.try_borrow().ok()?.trace(&mut tracer),
+            Container::Generator(generator) => {
+                tracer.generator_state(&generator.try_borrow().ok()?.state)
+            }
+        }
+        Some(tracer.references)
+    }
+
+    /// Drops everything the container refers to, which breaks the cycles it's part of.
+    fn clear(&self) {
+        // The contents are dropped after the container is released, since dropping them can drop
+        // other containers
+        match self {
+            Container::Environment(env) => {
+                drop(mem::replace(&mut *env.borrow_mut(), Environment::empty()))
+            }
+            Container::Generator(generator) => drop(mem::replace(
+                &mut generator.borrow_mut().state,
+                GeneratorState::Done,
+            )),
+        }
+    }
+}
+
+/// Frees the containers that are only referenced by reference cycles. Returns the number of
+/// containers that were freed.
+pub fn collect() -> usize {
+    let containers = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.created = 0;
+        heap.stats.collections += 1;
+        let environments = upgrade_all(&heap.environments).map(Container::Environment);
+        let generators = upgrade_all(&heap.generators).map(Container::Generator);
+        environments.chain(generators).collect::<Vec<_>>()
+    });
+    let indices: HashMap<_, _> = containers
+        .iter()
+        .enumerate()
+        .map(|(index, container)| (container.address(), index))
+        .collect();
+
+    // The references that don't come from other containers, not counting the one in `containers`.
+    // Containers that can't be inspected are alive, since their references aren't known
+    let mut external: Vec<_> = containers.iter().map(|c| c.strong_count() - 1).collect();
+    let mut references = Vec::with_capacity(containers.len());
+    let mut inspected = Vec::with_capacity(containers.len());
+    for container in &containers {
+        let addresses = container.references();
+        inspected.push(addresses.is_some());
+        let targets: Vec<usize> = addresses
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|a| indices.get(a).copied())
+            .collect();
+        for &target in &targets {
+            external[target] -= 1;
+        }
+        references.push(targets);
+    }
+
+    let mut alive: Vec<bool> = (external.iter().zip(&inspected))
+        .map(|(&count, &inspected)| count > 0 || !inspected)
+        .collect();
+    let mut pending: Vec<usize> = (0..containers.len()).filter(|&i| alive[i]).collect();
+    while let Some(index) = pending.pop() {
+        for &target in &references[index] {
+            if !alive[target] {
+                alive[target] = true;
+                pending.push(target);
+            }
+        }
+    }
+
+    let garbage: Vec<_> = containers
+        .iter()
+        .zip(&alive)
+        .filter(|(_, &alive)| !alive)
+        .collect();
+    for (container, _) in &garbage {
+        container.clear();
+    }
+    let collected = garbage.len();
+    drop(garbage);
+    drop(containers);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.stats.collected += collected;
+        heap.environments.retain(|w| w.strong_count() > 0);
+        heap.generators.retain(|w| w.strong_count() > 0);
+    });
+    collected
+}
+
+// Upgrades the weak references to the containers that are still alive
+fn upgrade_all<T>(weak: &[Weak<T>]) -> impl Iterator<Item = Rc<T>> {
+    weak.iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+/// Finds the containers referenced by a container, going through the objects it holds.
+#[derive(Default)]
+pub struct Tracer {
+    references: Vec<*const ()>,
+}
+
+impl Tracer {
+    pub fn object(&mut self, object: &Object) {
+        match object {
+            Object::Array(elements) => elements.iter().for_each(|e| self.object(e)),
+            Object::Hash(map) => map.values().for_each(|v| self.object(v)),
+            Object::Struct(instance) => instance.values.iter().for_each(|v| self.object(v)),
+            Object::Error(error) => self.object(&error.data),
+            Object::InterpreterFunc(function) => self.environment(&function.environment),
+            Object::Generator(generator) => self.references.push(Rc::as_ptr(generator) as _),
+            // Closures are shared, and they aren't tracked, so the references they hold can only
+            // be counted if nothing else refers to them. Otherwise, whatever they refer to is
+            // assumed to be alive.
+            Object::Closure(closure) if Rc::strong_count(closure) == 1 => {
+                closure.free_vars.iter().for_each(|v| self.object(v))
+            }
+            _ => {}
+        }
+    }
+
+    pub fn environment(&mut self, env: &EnvHandle) {
+        self.references.push(Rc::as_ptr(env) as _);
+    }
+
+    fn generator_state(&mut self, state: &GeneratorState) {
+        match state {
+            GeneratorState::Suspended(frame) => {
+                if Rc::strong_count(&frame.closure) == 1 {
+                    frame.closure.free_vars.iter().for_each(|v| self.object(v));
+                }
+                frame.stack.iter().for_each(|v| self.object(v));
+            }
+            GeneratorState::Interpreted(generator) => generator.trace(self),
+            GeneratorState::Running | GeneratorState::Done => {}
+        }
+    }
+}
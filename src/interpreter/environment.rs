@@ -1,44 +1,65 @@
-use crate::builtins;
+use crate::gc::Tracer;
 use crate::object::Object;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::rc::Rc;
 
 pub type EnvHandle = Rc<RefCell<Environment>>;
 
+// The variables of a scope, stored in the slots the resolver assigned to them. Slots are empty
+// until their variable is defined.
 #[derive(Clone, Debug)]
 pub struct Environment {
-    map: HashMap<String, Object>,
+    slots: Vec<Option<Object>>,
     outer: Option<EnvHandle>,
 }
 
 impl Environment {
     pub fn empty() -> Environment {
         Environment {
-            map: HashMap::new(),
+            slots: Vec::new(),
             outer: None,
         }
     }
 
     pub fn extend(outer: &EnvHandle) -> Environment {
+        Environment::extend_with(outer, Vec::new())
+    }
+
+    /// Creates an environment where the first slots are initialized to `values`.
+    pub fn extend_with(outer: &EnvHandle, values: Vec<Object>) -> Environment {
         Environment {
-            map: HashMap::new(),
+            slots: values.into_iter().map(Some).collect(),
             outer: Some(Rc::clone(outer)),
         }
     }
 
-    pub fn insert(&mut self, key: String, value: Object) {
-        self.map.insert(key, value);
+    pub fn set(&mut self, slot: usize, value: Object) {
+        // Scopes don't keep track of how many variables they have, and the global scope can get
+        // new ones at any time, so the slots grow as needed
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, None);
+        }
+        self.slots[slot] = Some(value);
+    }
+
+    /// Returns the value in a slot of this environment, or of the one `depth` environments out
+    /// from it. Returns `None` if the slot hasn't been initialized.
+    pub fn get(&self, depth: usize, slot: usize) -> Option<Object> {
+        if depth == 0 {
+            return self.slots.get(slot).cloned().flatten();
+        }
+        match &self.outer {
+            Some(outer) => outer.borrow().get(depth - 1, slot),
+            None => None,
+        }
     }
 
-    pub fn get(&self, key: &str) -> Option<Object> {
-        self.map
-            .get(key) // Try to find the identifier in the environment
-            .cloned()
-            // if that fails, try the outer environment (if it exists)
-            .or_else(|| self.outer.as_ref().and_then(|e| e.borrow().get(key)))
-            // and finally, try the built-in functions
-            .or_else(|| builtins::get_builtin(key))
+    /// Reports the values in this environment, and the outer environment, to the collector.
+    pub fn trace(&self, tracer: &mut Tracer) {
+        self.slots.iter().flatten().for_each(|v| tracer.object(v));
+        if let Some(outer) = &self.outer {
+            tracer.environment(outer);
+        }
     }
 }
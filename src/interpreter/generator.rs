@@ -0,0 +1,301 @@
+// The interpreter evaluates expressions recursively, using the native stack, so it can't pause in
+// the middle of an expression like the VM does. Instead, a generator keeps an explicit stack of
+// the blocks it's running, and runs one statement at a time. `if`, `for`, `try` and block
+// expressions in statement position push a new block onto the stack, so `yield` statements are
+// supported directly inside them. Everything else is evaluated normally, so a `yield` anywhere else
+// is an error.
+
+use super::*;
+
+use std::mem;
+
+#[derive(Debug)]
+pub struct InterpreterGenerator {
+    frames: Vec<GeneratorFrame>,
+}
+
+#[derive(Debug)]
+enum GeneratorFrame {
+    Block {
+        statements: Rc<Vec<NodeStatement>>,
+        // The index of the next statement to run
+        next: usize,
+        env: EnvHandle,
+    },
+    For {
+        body: Rc<Vec<NodeStatement>>,
+        iter: Iter,
+        env: EnvHandle,
+    },
+    // The handler of a `try` expression. The body runs in the frames above it, and if an error
+    // happens there, they are unwound and the handler runs instead.
+    Try {
+        handler: Rc<Vec<NodeStatement>>,
+        env: EnvHandle,
+    },
+}
+
+impl InterpreterGenerator {
+    /// Creates a generator that runs `body` in the environment `env`, where the arguments are
+    /// already bound.
+    pub fn new(body: Vec<NodeStatement>, env: EnvHandle) -> InterpreterGenerator {
+        let frame = GeneratorFrame::Block {
+            statements: Rc::new(body),
+            next: 0,
+            env,
+        };
+        InterpreterGenerator {
+            frames: vec![frame],
+        }
+    }
+
+    /// Reports the environments of the frames, and the values being iterated over, to the
+    /// collector.
+    pub fn trace(&self, tracer: &mut gc::Tracer) {
+        for frame in &self.frames {
+            match frame {
+                GeneratorFrame::Block { env, .. } | GeneratorFrame::Try { env, .. } => {
+                    tracer.environment(env)
+                }
+                GeneratorFrame::For { iter, env, .. } => {
+                    tracer.object(&iter.iterable);
+                    tracer.environment(env);
+                }
+            }
+        }
+    }
+
+    /// Runs the generator until the next `yield` statement, and returns the yielded value. Returns
+    /// `None` if the generator finishes.
+    fn run(&mut self) -> MonkeyResult<Option<Object>> {
+        loop {
+            match self.step() {
+                Ok(Some(value)) => return Ok(Some(value)),
+                Ok(None) if self.frames.is_empty() => return Ok(None),
+                Ok(None) => {}
+                Err(error) => self.catch(error)?,
+            }
+        }
+    }
+
+    /// Unwinds the frames to the innermost `try` handler, and starts running it with the exception
+    /// bound to its name. If there is no handler or the error can't be caught, returns the error.
+    fn catch(&mut self, error: MonkeyError) -> MonkeyResult<()> {
+        let (position, error) = match error {
+            MonkeyError::Interpreter(position, error) => (position, error),
+            other => return Err(other),
+        };
+        let try_frame = self
+            .frames
+            .iter()
+            .rposition(|frame| matches!(frame, GeneratorFrame::Try { .. }));
+        match try_frame {
+            Some(index) => self.frames.truncate(index + 1),
+            None => return Err(MonkeyError::Interpreter(position, error)),
+        }
+        let (handler, env) = match self.frames.pop() {
+            Some(GeneratorFrame::Try { handler, env }) => (handler, env),
+            _ => unreachable!(),
+        };
+        let exception = error
+            .into_exception()
+            .map_err(|e| MonkeyError::Interpreter(position, e))?;
+        let handler_env = gc::new_environment(Environment::extend_with(&env, vec![exception]));
+        self.frames.push(GeneratorFrame::Block {
+            statements: handler,
+            next: 0,
+            env: handler_env,
+        });
+        Ok(())
+    }
+
+    /// Runs a single statement, or advances a `for` loop. Returns the yielded value if a `yield`
+    /// statement was run.
+    fn step(&mut self) -> MonkeyResult<Option<Object>> {
+        if let Some(frame) = self.frames.last_mut() {
+            match frame {
+                GeneratorFrame::Block {
+                    statements,
+                    next,
+                    env,
+                } => {
+                    let statements = Rc::clone(statements);
+                    let env = Rc::clone(env);
+                    match statements.get(*next) {
+                        Some(statement) => {
+                            *next += 1;
+                            return self.run_statement(statement, &env);
+                        }
+                        None => {
+                            self.frames.pop();
+                        }
+                    }
+                }
+                GeneratorFrame::For { body, iter, env } => match iter.next()? {
+                    Some(value) => {
+                        let loop_env =
+                            gc::new_environment(Environment::extend_with(env, vec![value]));
+                        let frame = GeneratorFrame::Block {
+                            statements: Rc::clone(body),
+                            next: 0,
+                            env: loop_env,
+                        };
+                        self.frames.push(frame);
+                    }
+                    None => {
+                        self.frames.pop();
+                    }
+                },
+                // The body of the `try` expression finished without errors
+                GeneratorFrame::Try { .. } => {
+                    self.frames.pop();
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs a statement from the generator body. Returns the yielded value if the statement is a
+    /// `yield` statement.
+    fn run_statement(
+        &mut self,
+        statement: &NodeStatement,
+        env: &EnvHandle,
+    ) -> MonkeyResult<Option<Object>> {
+        let expression = match &statement.statement {
+            Statement::Yield(exp) => return eval_expression(exp, env).map(Some),
+            Statement::Return(exp) => {
+                eval_expression(exp, env)?;
+                self.frames.clear();
+                return Ok(None);
+            }
+            Statement::ExpressionStatement(exp) => exp,
+            _ => {
+                eval_statement(statement, env)?;
+                return Ok(None);
+            }
+        };
+        let block = match &expression.expression {
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                if eval_expression(condition, env)?.is_truthy() {
+                    consequence
+                } else {
+                    alternative
+                }
+            }
+            Expression::BlockExpression(block) => block,
+            Expression::TryExpression { body, handler, .. } => {
+                self.frames.push(GeneratorFrame::Try {
+                    handler: Rc::new(handler.clone()),
+                    env: Rc::clone(env),
+                });
+                body
+            }
+            Expression::ForExpression { iterable, body, .. } => {
+                let iterable = eval_expression(iterable, env)?;
+                let frame = GeneratorFrame::For {
+                    body: Rc::new(body.clone()),
+                    iter: Iter::new(iterable, expression.position)?,
+                    env: Rc::clone(env),
+                };
+                self.frames.push(frame);
+                return Ok(None);
+            }
+            _ => {
+                eval_expression(expression, env)?;
+                return Ok(None);
+            }
+        };
+        let frame = GeneratorFrame::Block {
+            statements: Rc::new(block.clone()),
+            next: 0,
+            env: gc::new_environment(Environment::extend(env)),
+        };
+        self.frames.push(frame);
+        Ok(None)
+    }
+}
+
+/// Resumes a generator, and returns the next value it yields, or `None` if it's finished. If an
+/// error happens while running the generator, it finishes.
+pub fn resume(
+    generator: &Rc<RefCell<GeneratorObject>>,
+    position: (usize, usize),
+) -> MonkeyResult<Option<Object>> {
+    // Generators can resume other generators, so resuming counts as a nested call
+    let _guard = CallGuard::enter(position)?;
+    let state = mem::replace(&mut generator.borrow_mut().state, GeneratorState::Running);
+    let mut inner = match state {
+        GeneratorState::Interpreted(inner) => inner,
+        GeneratorState::Done => {
+            generator.borrow_mut().state = GeneratorState::Done;
+            return Ok(None);
+        }
+        GeneratorState::Running => {
+            return Err(MonkeyError::Interpreter(position, GeneratorRunning));
+        }
+        // The interpreter never creates VM generators
+        GeneratorState::Suspended(_) => unreachable!(),
+    };
+
+    let result = match inner.run() {
+        // A `return` nested in an expression also finishes the generator
+        Err(MonkeyError::Interpreter(_, ReturnValue(_))) => Ok(None),
+        other => other,
+    };
+    generator.borrow_mut().state = match result {
+        Ok(Some(_)) => GeneratorState::Interpreted(inner),
+        _ => GeneratorState::Done,
+    };
+    result
+}
+
+/// Evaluates a call to the `next` built-in. Returns the next value yielded by the generator, or
+/// `nil` if it's finished.
+pub fn call_next(args: Vec<Object>, call_position: (usize, usize)) -> MonkeyResult<Object> {
+    let generator =
+        builtins::next_generator(&args).map_err(|e| MonkeyError::Interpreter(call_position, e))?;
+    Ok(resume(generator, call_position)?.unwrap_or(Object::Nil))
+}
+
+/// Iterates over the values of the iterable objects: arrays, strings and generators.
+#[derive(Debug)]
+pub struct Iter {
+    iterable: Object,
+    index: usize,
+    position: (usize, usize),
+}
+
+impl Iter {
+    pub fn new(iterable: Object, position: (usize, usize)) -> MonkeyResult<Iter> {
+        match iterable {
+            Object::Array(_) | Object::Str(_) | Object::Generator(_) => Ok(Iter {
+                iterable,
+                index: 0,
+                position,
+            }),
+            other => Err(MonkeyError::Interpreter(
+                position,
+                TypeError("iterable", other.type_str()),
+            )),
+        }
+    }
+
+    pub fn next(&mut self) -> MonkeyResult<Option<Object>> {
+        let value = match &self.iterable {
+            Object::Array(a) => a.get(self.index).cloned(),
+            Object::Str(s) => s
+                .chars()
+                .nth(self.index)
+                .map(|c| Object::from(&*c.to_string())),
+            Object::Generator(g) => resume(g, self.position)?,
+            _ => unreachable!(), // This is checked in `Iter::new`
+        };
+        self.index += 1;
+        Ok(value)
+    }
+}
@@ -1,69 +1,141 @@
 // @TODO: Document this module
 pub mod environment;
+pub mod generator;
+pub mod resolver;
 #[cfg(test)]
 mod tests;
 
+use crate::builtins::{self, BuiltinFn};
 use crate::error::*;
+use crate::gc;
 use crate::lexer::token::Token;
+use crate::limits::{self, Limits, Meter};
 use crate::object::*;
 use crate::parser::ast::*;
 use environment::*;
+use resolver::Resolver;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 use RuntimeError::*;
 
-pub fn run_program(program: Vec<NodeStatement>) -> MonkeyResult<()> {
-    let env = Rc::new(RefCell::new(Environment::empty()));
+// The native stack used by each nested call, with some room for nested expressions, and by
+// everything else. Unoptimized builds use several times as much stack.
+const STACK_PER_CALL: usize = if cfg!(debug_assertions) {
+    128 * 1024
+} else {
+    16 * 1024
+};
+const STACK_BASE: usize = 1024 * 1024;
+
+/// The native stack size needed to evaluate programs that reach `max_call_depth` nested calls.
+/// Evaluation is recursive, so programs should run in a thread with this stack size.
+pub fn stack_size(max_call_depth: usize) -> usize {
+    STACK_BASE.saturating_add(max_call_depth.saturating_mul(STACK_PER_CALL))
+}
+
+// Counts a nested call in the active meter while it's alive. Calls to functions and generators
+// create one of these, so the call depth is restored even if the call fails.
+struct CallGuard(Rc<Meter>);
+
+impl CallGuard {
+    fn enter(position: (usize, usize)) -> MonkeyResult<CallGuard> {
+        let meter = limits::active();
+        meter
+            .enter_call()
+            .map_err(|e| MonkeyError::Interpreter(position, e))?;
+        Ok(CallGuard(meter))
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        self.0.exit_call();
+    }
+}
+
+/// Runs a program, which is stopped if it reaches `limits`.
+pub fn run_program(program: Vec<NodeStatement>, limits: Limits) -> MonkeyResult<()> {
+    let mut globals = Globals::with_limits(limits);
     for statement in program {
-        eval_statement(&statement, &env)?;
+        globals.eval_statement(statement)?;
     }
     Ok(())
 }
 
-pub fn eval_expression(expression: &NodeExpression, env: &EnvHandle) -> MonkeyResult<Object> {
-    match &expression.expression {
-        Expression::Identifier(s) => {
-            // Note: This clones the object
-            match env.borrow().get(&s) {
-                Some(value) => Ok(value),
-                None => Err(MonkeyError::Interpreter(
-                    expression.position,
-                    IdenNotFound(s.clone()),
-                )),
-            }
+/// The global scope of a program, where statements are resolved and then evaluated. The global
+/// variables are kept, so more statements can be evaluated later, like in the REPL.
+pub struct Globals {
+    resolver: Resolver,
+    env: EnvHandle,
+    // The resources used by the statements evaluated so far, and their limits
+    meter: Rc<Meter>,
+}
+
+impl Globals {
+    /// Creates a global scope where evaluating statements fails when they reach `limits`.
+    pub fn with_limits(limits: Limits) -> Globals {
+        Globals::with_meter(Rc::new(Meter::new(limits)))
+    }
+
+    /// Creates a global scope that counts the resources used in an existing meter, like modules,
+    /// which count them in the meter of the program that imports them.
+    pub fn with_meter(meter: Rc<Meter>) -> Globals {
+        Globals {
+            resolver: Resolver::new(),
+            env: gc::new_environment(Environment::empty()),
+            meter,
         }
+    }
+
+    pub fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
+    pub fn eval_statement(&mut self, mut statement: NodeStatement) -> MonkeyResult<Object> {
+        self.resolver.resolve(&mut statement)?;
+        let _active = self.meter.activate();
+        eval_statement(&statement, &self.env)
+    }
+
+    /// Returns the global variables that have been defined, with their values.
+    pub fn bindings(&self) -> Vec<(String, Object)> {
+        let env = self.env.borrow();
+        self.resolver
+            .globals()
+            .filter_map(|(name, slot)| Some((name.clone(), env.get(0, slot)?)))
+            .collect()
+    }
+}
+
+fn eval_expression(expression: &NodeExpression, env: &EnvHandle) -> MonkeyResult<Object> {
+    limits::step().map_err(|e| MonkeyError::Interpreter(expression.position, e))?;
+    match &expression.expression {
+        Expression::Identifier(variable) => match lookup(variable, env) {
+            Some(value) => Ok(value),
+            None => Err(MonkeyError::Interpreter(
+                expression.position,
+                IdenNotFound(variable.name.clone()),
+            )),
+        },
         Expression::IntLiteral(i) => Ok(Object::Integer(*i)),
         Expression::Boolean(b) => Ok(Object::Boolean(*b)),
         Expression::StringLiteral(s) => Ok(Object::Str(Box::new(s.clone()))),
-        Expression::ArrayLiteral(v) => {
-            let mut elements = Vec::with_capacity(v.len());
-            for exp in v {
-                elements.push(eval_expression(exp, env)?);
+        Expression::InterpolatedString(parts) => {
+            let mut result = String::new();
+            for exp in parts {
+                result.push_str(&eval_expression(exp, env)?.stringify());
             }
-            Ok(Object::Array(Box::new(elements)))
+            allocate(result.len(), expression.position)?;
+            Ok(Object::Str(Box::new(result)))
         }
-        Expression::HashLiteral(v) => {
-            let mut map = HashMap::new();
-            for (key, val) in v {
-                let obj = eval_expression(key, env)?;
-                let obj_type = obj.type_str();
-                let key = match HashableObject::from_object(obj) {
-                    Some(v) => v,
-                    None => {
-                        return Err(MonkeyError::Interpreter(
-                            expression.position,
-                            HashKeyTypeError(obj_type),
-                        ))
-                    }
-                };
-
-                let val = eval_expression(val, env)?;
-                map.insert(key, val);
-            }
-            Ok(Object::Hash(Box::new(map)))
+        Expression::ArrayLiteral(v) => {
+            let elements = eval_expression_list(v, env)?;
+            allocate(elements.len(), expression.position)?;
+            Ok(Object::Array(Box::new(elements)))
         }
+        Expression::HashLiteral(v) => eval_hash_literal(v, env, expression.position),
         Expression::PrefixExpression(tk, e) => {
             let right_side = eval_expression(e, env)?;
             eval_prefix_expression(tk, &right_side)
@@ -87,14 +159,18 @@ pub fn eval_expression(expression: &NodeExpression, env: &EnvHandle) -> MonkeyRe
                 eval_block(alternative, env)
             }
         }
+        Expression::TryExpression { body, handler, .. } => eval_try_expression(body, handler, env),
         Expression::Nil => Ok(Object::Nil),
         Expression::FunctionLiteral { parameters, body } => {
-            let fo = InterpreterFunctionObject {
-                environment: Rc::clone(env),
-                parameters: parameters.clone(),
-                body: body.clone(),
-            };
-            Ok(Object::InterpreterFunc(Box::new(fo)))
+            allocate(0, expression.position)?;
+            Ok(new_function_object(parameters, body, false, env))
+        }
+        Expression::GeneratorLiteral { parameters, body } => {
+            allocate(0, expression.position)?;
+            Ok(new_function_object(parameters, body, true, env))
+        }
+        Expression::ForExpression { iterable, body, .. } => {
+            eval_for_expression(iterable, body, env)
         }
         Expression::CallExpression {
             function,
@@ -103,10 +179,7 @@ pub fn eval_expression(expression: &NodeExpression, env: &EnvHandle) -> MonkeyRe
             // Evaluate the called object
             let obj = eval_expression(function, env)?;
             // Evaluate all arguments sequentially
-            let mut evaluated_args = Vec::with_capacity(arguments.len());
-            for exp in arguments {
-                evaluated_args.push(eval_expression(exp, env)?);
-            }
+            let evaluated_args = eval_expression_list(arguments, env)?;
 
             eval_call_expression(obj, evaluated_args, expression.position)
         }
@@ -116,11 +189,200 @@ pub fn eval_expression(expression: &NodeExpression, env: &EnvHandle) -> MonkeyRe
             eval_index_expression(&obj, &index)
                 .map_err(|e| MonkeyError::Interpreter(expression.position, e))
         }
+        Expression::OptionalIndexExpression(obj, index) => {
+            // The index isn't evaluated if the object is nil
+            let obj = eval_expression(obj, env)?;
+            if let Object::Nil = obj {
+                return Ok(Object::Nil);
+            }
+            let index = eval_expression(index, env)?;
+            match eval_index_expression(&obj, &index) {
+                Err(KeyError(_)) | Err(IndexOutOfBounds(_)) => Ok(Object::Nil),
+                other => other.map_err(|e| MonkeyError::Interpreter(expression.position, e)),
+            }
+        }
+        Expression::NilCoalescing(left, right) => match eval_expression(left, env)? {
+            Object::Nil => eval_expression(right, env),
+            value => Ok(value),
+        },
+        Expression::StructDeclaration { name, fields } => {
+            let definition = StructDefinition {
+                name: name.clone(),
+                fields: fields.clone(),
+            };
+            Ok(Object::StructConstructor(Rc::new(definition)))
+        }
+        Expression::FieldAccess(obj, field) => {
+            let obj = eval_expression(obj, env)?;
+            obj.get_field(field)
+                .map_err(|e| MonkeyError::Interpreter(expression.position, e))
+        }
+        Expression::MethodCall {
+            object,
+            method,
+            arguments,
+        } => {
+            let object = eval_expression(object, env)?;
+            let args = eval_expression_list(arguments, env)?;
+            eval_method_call(object, method, args, expression.position)
+        }
+        Expression::FieldUpdate {
+            object,
+            field,
+            value,
+        } => {
+            let object = eval_expression(object, env)?;
+            let value = eval_expression(value, env)?;
+            object
+                .with_field(field, value)
+                .map_err(|e| MonkeyError::Interpreter(expression.position, e))
+        }
         Expression::BlockExpression(block) => eval_block(block, env),
+        // Returning early is done just like in a return statement
+        Expression::Propagate(value) => match eval_expression(value, env)? {
+            Object::Error(e) => Err(MonkeyError::Interpreter(
+                expression.position,
+                ReturnValue(Box::new(Object::Error(e))),
+            )),
+            value => Ok(value),
+        },
+        // Spread expressions are handled by `eval_expression_list`, and the parser never produces
+        // them anywhere else
+        Expression::Spread(_) => unreachable!(),
+    }
+}
+
+// Finds the value of a variable in the environments the resolver found for it, or else in the
+// built-in functions. Note: This clones the object
+fn lookup(variable: &Variable, env: &EnvHandle) -> Option<Object> {
+    let env = env.borrow();
+    (variable.scopes.iter())
+        .find_map(|&(depth, slot)| env.get(depth, slot))
+        .or_else(|| {
+            let (_, builtin) = &builtins::ALL_BUILTINS[variable.builtin?];
+            Some(Object::Builtin(builtin.clone()))
+        })
+}
+
+// This is kept out of `eval_expression` to keep its stack frame small, since it is part of every
+// recursive call in the interpreter
+fn eval_hash_literal(
+    entries: &[HashEntry],
+    env: &EnvHandle,
+    position: (usize, usize),
+) -> MonkeyResult<Object> {
+    let mut map = HashMap::new();
+    for entry in entries {
+        let (key, val) = match entry {
+            HashEntry::Pair(key, val) => (key, val),
+            HashEntry::Spread(exp) => {
+                match eval_expression(exp, env)? {
+                    Object::Hash(h) => map.extend(*h),
+                    other => {
+                        return Err(MonkeyError::Interpreter(
+                            exp.position,
+                            TypeError("hash", other.type_str()),
+                        ))
+                    }
+                }
+                continue;
+            }
+        };
+        let obj = eval_expression(key, env)?;
+        let obj_type = obj.type_str();
+        let key = match HashableObject::from_object(obj) {
+            Some(v) => v,
+            None => {
+                return Err(MonkeyError::Interpreter(
+                    position,
+                    HashKeyTypeError(obj_type),
+                ))
+            }
+        };
+
+        let val = eval_expression(val, env)?;
+        map.insert(key, val);
     }
+    allocate(map.len(), position)?;
+    Ok(Object::Hash(Box::new(map)))
 }
 
-pub fn eval_statement(statement: &NodeStatement, env: &EnvHandle) -> MonkeyResult<Object> {
+// Counts a new object for the allocation limit
+fn allocate(size: usize, position: (usize, usize)) -> MonkeyResult<()> {
+    limits::allocate(size).map_err(|e| MonkeyError::Interpreter(position, e))
+}
+
+// Exceptions are propagated like any other runtime error, so catching one just means handling the
+// error returned by the body
+fn eval_try_expression(
+    body: &[NodeStatement],
+    handler: &[NodeStatement],
+    env: &EnvHandle,
+) -> MonkeyResult<Object> {
+    let (position, error) = match eval_block(body, env) {
+        Err(MonkeyError::Interpreter(position, error)) => (position, error),
+        other => return other,
+    };
+    let exception = error
+        .into_exception()
+        .map_err(|e| MonkeyError::Interpreter(position, e))?;
+    // The exception is bound in the same scope as the variables in the handler
+    let handler_env = gc::new_environment(Environment::extend_with(env, vec![exception]));
+    eval_statements(handler, &handler_env)
+}
+
+fn new_function_object(
+    parameters: &[String],
+    body: &[NodeStatement],
+    is_generator: bool,
+    env: &EnvHandle,
+) -> Object {
+    let fo = InterpreterFunctionObject {
+        environment: Rc::clone(env),
+        parameters: parameters.to_vec(),
+        body: body.to_vec(),
+        is_generator,
+    };
+    Object::InterpreterFunc(Box::new(fo))
+}
+
+fn eval_for_expression(
+    iterable: &NodeExpression,
+    body: &[NodeStatement],
+    env: &EnvHandle,
+) -> MonkeyResult<Object> {
+    let value = eval_expression(iterable, env)?;
+    let mut iter = generator::Iter::new(value, iterable.position)?;
+    while let Some(value) = iter.next()? {
+        let loop_env = gc::new_environment(Environment::extend_with(env, vec![value]));
+        eval_statements(body, &loop_env)?;
+    }
+    Ok(Object::Nil)
+}
+
+/// Evaluates a list of expressions sequentially, expanding any spread expressions into their
+/// elements.
+fn eval_expression_list(list: &[NodeExpression], env: &EnvHandle) -> MonkeyResult<Vec<Object>> {
+    let mut result = Vec::with_capacity(list.len());
+    for exp in list {
+        if let Expression::Spread(inner) = &exp.expression {
+            match eval_expression(inner, env)? {
+                Object::Array(a) => result.extend(*a),
+                other => {
+                    return Err(MonkeyError::Interpreter(
+                        exp.position,
+                        TypeError("array", other.type_str()),
+                    ))
+                }
+            }
+        } else {
+            result.push(eval_expression(exp, env)?);
+        }
+    }
+    Ok(result)
+}
+
+fn eval_statement(statement: &NodeStatement, env: &EnvHandle) -> MonkeyResult<Object> {
     match &statement.statement {
         Statement::ExpressionStatement(exp) => eval_expression(exp, env),
         Statement::Return(exp) => {
@@ -131,19 +393,38 @@ pub fn eval_statement(statement: &NodeStatement, env: &EnvHandle) -> MonkeyResul
             ))
         }
         Statement::Let(let_statement) => {
-            let (name, exp) = &**let_statement;
-            let value = eval_expression(&exp, env)?;
-            env.borrow_mut().insert(name.clone(), value);
+            let (variable, exp) = &**let_statement;
+            let value = eval_expression(exp, env)?;
+            // Variables are always bound in the current scope
+            let (_, slot) = variable.scopes[0];
+            env.borrow_mut().set(slot, value);
             Ok(Object::Nil)
         }
+        Statement::Throw(exp) => {
+            let value = eval_expression(exp, env)?;
+            Err(MonkeyError::Interpreter(
+                statement.position,
+                Thrown(Box::new(value)),
+            ))
+        }
+        // `yield` statements are only supported in the places handled in `generator::resume`
+        Statement::Yield(_) => Err(MonkeyError::Interpreter(
+            statement.position,
+            UnsupportedYield,
+        )),
     }
 }
 
 fn eval_block(block: &[NodeStatement], env: &EnvHandle) -> MonkeyResult<Object> {
+    let new_env = gc::new_environment(Environment::extend(env));
+    eval_statements(block, &new_env)
+}
+
+// Evaluates statements in the environment of their scope, which was already created
+fn eval_statements(block: &[NodeStatement], env: &EnvHandle) -> MonkeyResult<Object> {
     let mut last = Object::Nil;
-    let new_env = Rc::new(RefCell::new(Environment::extend(env)));
     for s in block {
-        last = eval_statement(s, &new_env)?;
+        last = eval_statement(s, env)?;
     }
     Ok(last)
 }
@@ -151,6 +432,7 @@ fn eval_block(block: &[NodeStatement], env: &EnvHandle) -> MonkeyResult<Object>
 fn eval_prefix_expression(operator: &Token, right: &Object) -> Result<Object, RuntimeError> {
     match (operator, right) {
         (Token::Minus, Object::Integer(i)) => Ok(Object::Integer(-i)),
+        (Token::Tilde, Object::Integer(i)) => Ok(Object::Integer(!i)),
         (Token::Bang, obj) => Ok(Object::Boolean(!obj.is_truthy())),
         _ => Err(PrefixTypeError(operator.clone(), right.type_str())),
     }
@@ -169,6 +451,7 @@ fn eval_infix_expression(
         (Object::Integer(l), op, Object::Integer(r)) => eval_int_infix_expression(op, *l, *r),
         // String concatenation
         (Object::Str(l), Token::Plus, Object::Str(r)) => {
+            limits::allocate(l.len() + r.len())?;
             let l = (**l).clone();
             let r = &**r;
             Ok(Object::Str(Box::new(l + r)))
@@ -199,6 +482,16 @@ fn eval_int_infix_expression(
         Token::Modulo if right == 0 => Err(DivOrModByZero),
         Token::Modulo => Ok(Object::Integer(left % right)),
 
+        // Bitwise operators
+        Token::BitAnd => Ok(Object::Integer(left & right)),
+        Token::BitOr => Ok(Object::Integer(left | right)),
+        Token::Tilde => Ok(Object::Integer(left ^ right)),
+        Token::ShiftLeft | Token::ShiftRight if !(0..64).contains(&right) => {
+            Err(InvalidShiftAmount(right))
+        }
+        Token::ShiftLeft => Ok(Object::Integer(left << right)),
+        Token::ShiftRight => Ok(Object::Integer(left >> right)),
+
         // Comparison operators
         Token::LessThan => Ok(Object::Boolean(left < right)),
         Token::LessEq => Ok(Object::Boolean(left <= right)),
@@ -216,9 +509,13 @@ pub fn eval_call_expression(
 ) -> MonkeyResult<Object> {
     match obj {
         Object::InterpreterFunc(fo) => call_function_object(*fo, args, call_position),
-        Object::Builtin(b) => {
-            b.0(args).map_err(|e| MonkeyError::Interpreter(call_position, e))
+        Object::Builtin(BuiltinFn::Next) => generator::call_next(args, call_position),
+        Object::Builtin(BuiltinFn::Native(f)) => {
+            f(args).map_err(|e| MonkeyError::Interpreter(call_position, e))
         }
+        Object::StructConstructor(def) => def
+            .construct(args)
+            .map_err(|e| MonkeyError::Interpreter(call_position, e)),
         other => Err(MonkeyError::Interpreter(
             call_position,
             NotCallable(other.type_str()),
@@ -226,29 +523,116 @@ pub fn eval_call_expression(
     }
 }
 
+fn eval_method_call(
+    object: Object,
+    method: &str,
+    mut args: Vec<Object>,
+    call_position: (usize, usize),
+) -> MonkeyResult<Object> {
+    let method = builtins::get_method(&object, method)
+        .map_err(|e| MonkeyError::Interpreter(call_position, e))?;
+    match method {
+        builtins::Method::Field(function) => eval_call_expression(function, args, call_position),
+        builtins::Method::Builtin(function) => {
+            args.insert(0, object);
+            eval_call_expression(Object::Builtin(function), args, call_position)
+        }
+    }
+}
+
 fn call_function_object(
-    fo: InterpreterFunctionObject,
-    args: Vec<Object>,
-    call_pos: (usize, usize),
+    mut fo: InterpreterFunctionObject,
+    mut args: Vec<Object>,
+    mut call_pos: (usize, usize),
 ) -> MonkeyResult<Object> {
-    if fo.parameters.len() != args.len() {
-        return Err(MonkeyError::Interpreter(
-            call_pos,
-            WrongNumberOfArgs(fo.parameters.len(), args.len()),
-        ));
+    let _guard = CallGuard::enter(call_pos)?;
+    // Calls in tail position are evaluated by this loop instead of recursively, so they don't
+    // grow the native stack
+    loop {
+        if fo.parameters.len() != args.len() {
+            return Err(MonkeyError::Interpreter(
+                call_pos,
+                WrongNumberOfArgs(fo.parameters.len(), args.len()),
+            ));
+        }
+        // The arguments are in the first slots, in the same order as the parameters
+        let call_env = gc::new_environment(Environment::extend_with(&fo.environment, args));
+        if fo.is_generator {
+            allocate(0, call_pos)?;
+            let generator = GeneratorObject {
+                state: GeneratorState::Interpreted(generator::InterpreterGenerator::new(
+                    fo.body, call_env,
+                )),
+            };
+            return Ok(Object::Generator(gc::new_generator(generator)));
+        }
+        match eval_tail_statements(&fo.body, &call_env) {
+            Ok(TailValue::Value(value)) => return Ok(value),
+            Ok(TailValue::Call(Object::InterpreterFunc(next), next_args, position)) => {
+                fo = *next;
+                args = next_args;
+                call_pos = position;
+            }
+            Ok(TailValue::Call(other, args, position)) => {
+                return eval_call_expression(other, args, position)
+            }
+            Err(MonkeyError::Interpreter(_, ReturnValue(obj))) => return Ok(*obj),
+            Err(e) => return Err(e),
+        }
     }
-    let mut call_env = fo.environment.borrow().clone();
-    for (name, value) in fo.parameters.into_iter().zip(args) {
-        call_env.insert(name, value);
+}
+
+// The result of evaluating the body of a function. If the value is the result of a call in tail
+// position, the call is returned to `call_function_object` instead of being made.
+enum TailValue {
+    Value(Object),
+    Call(Object, Vec<Object>, (usize, usize)),
+}
+
+fn eval_tail_block(block: &[NodeStatement], env: &EnvHandle) -> MonkeyResult<TailValue> {
+    let new_env = gc::new_environment(Environment::extend(env));
+    eval_tail_statements(block, &new_env)
+}
+
+fn eval_tail_statements(block: &[NodeStatement], env: &EnvHandle) -> MonkeyResult<TailValue> {
+    let (last, rest) = match block.split_last() {
+        Some(split) => split,
+        None => return Ok(TailValue::Value(Object::Nil)),
+    };
+    for s in rest {
+        eval_statement(s, env)?;
     }
-    let result = eval_block(&fo.body, &Rc::new(RefCell::new(call_env)));
-    result.or_else(|e| {
-        if let MonkeyError::Interpreter(_, ReturnValue(obj)) = e {
-            Ok(*obj)
-        } else {
-            Err(e)
+    match &last.statement {
+        Statement::ExpressionStatement(exp) | Statement::Return(exp) => {
+            eval_tail_expression(exp, env)
         }
-    })
+        _ => eval_statement(last, env).map(TailValue::Value),
+    }
+}
+
+fn eval_tail_expression(expression: &NodeExpression, env: &EnvHandle) -> MonkeyResult<TailValue> {
+    match &expression.expression {
+        Expression::CallExpression {
+            function,
+            arguments,
+        } => {
+            let obj = eval_expression(function, env)?;
+            let evaluated_args = eval_expression_list(arguments, env)?;
+            Ok(TailValue::Call(obj, evaluated_args, expression.position))
+        }
+        Expression::IfExpression {
+            condition,
+            consequence,
+            alternative,
+        } => {
+            if eval_expression(condition, env)?.is_truthy() {
+                eval_tail_block(consequence, env)
+            } else {
+                eval_tail_block(alternative, env)
+            }
+        }
+        _ => eval_expression(expression, env).map(TailValue::Value),
+    }
 }
 
 pub fn eval_index_expression(object: &Object, index: &Object) -> Result<Object, RuntimeError> {
@@ -264,9 +648,8 @@ pub fn eval_index_expression(object: &Object, index: &Object) -> Result<Object,
         (Object::Array(_), other) => Err(IndexTypeError(other.type_str())),
         (Object::Hash(map), key) => {
             let key_type = key.type_str();
-            let key = HashableObject::from_object(key.clone())
-                .ok_or_else(|| HashKeyTypeError(key_type))?;
-            let value = map.get(&key).ok_or_else(|| KeyError(key))?;
+            let key = HashableObject::from_object(key.clone()).ok_or(HashKeyTypeError(key_type))?;
+            let value = map.get(&key).ok_or(KeyError(key))?;
             Ok(value.clone())
         }
         (Object::Str(s), Object::Integer(i)) => {
@@ -0,0 +1,217 @@
+// The resolver runs over the code before the interpreter, and finds where each variable is stored,
+// so the interpreter can read it by index instead of looking its name up in every enclosing
+// environment.
+//
+// Every block is a scope, and gets its own environment when it runs. Function bodies, `for` loop
+// bodies and `catch` handlers share their environment with the parameters, the loop variable and
+// the exception. Each name bound in a scope gets a slot in its environment, and the slots are
+// assigned when the scope starts, so functions can refer to variables that are defined after
+// them, like in mutually recursive functions.
+//
+// A name can be bound in several enclosing scopes, and which one an identifier refers to depends
+// on which of them have been defined when it's evaluated. So identifiers are resolved to every
+// scope that binds their name, and the interpreter uses the innermost one that's initialized.
+// Names that aren't bound anywhere are global, since global variables can be defined later, like
+// in the REPL, and after that, they're looked up in the built-in functions.
+
+use crate::builtins;
+use crate::error::{CompilerError, MonkeyError, MonkeyResult};
+use crate::parser::ast::*;
+
+use std::collections::HashMap;
+use std::slice;
+use std::sync::Arc;
+
+#[derive(Default)]
+struct Scope {
+    slots: HashMap<String, usize>,
+    size: usize,
+}
+
+impl Scope {
+    fn declare(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.slots.get(name) {
+            return slot;
+        }
+        self.slots.insert(name.to_string(), self.size);
+        self.size += 1;
+        self.size - 1
+    }
+}
+
+/// Resolves code that runs in the global scope. The global variables are kept, so more code can
+/// be resolved later, like in the REPL.
+#[derive(Default)]
+pub struct Resolver {
+    globals: Scope,
+    // The scopes enclosing the code being resolved, innermost last
+    scopes: Vec<Scope>,
+    // The number of functions enclosing the code being resolved
+    functions: usize,
+    // The position of the first `?` found outside of a function
+    invalid_propagate: Option<(usize, usize)>,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver::default()
+    }
+
+    /// Resolves a statement in the global scope. Like the compiler, rejects `?` outside of
+    /// functions, where there is nothing to return from.
+    pub fn resolve(&mut self, statement: &mut NodeStatement) -> MonkeyResult<()> {
+        self.statement(statement);
+        match self.invalid_propagate.take() {
+            Some(position) => Err(MonkeyError::Compiler(
+                position,
+                CompilerError::InvalidPropagate,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the names of the global variables, with their slots.
+    pub fn globals(&self) -> impl Iterator<Item = (&String, usize)> {
+        self.globals.slots.iter().map(|(name, &slot)| (name, slot))
+    }
+
+    // Resolves a block that has its own scope, where `bound` are the names bound when the scope
+    // starts, in the first slots
+    fn scope(&mut self, bound: &[String], statements: &mut [NodeStatement]) {
+        let mut scope = Scope::default();
+        for (slot, name) in bound.iter().enumerate() {
+            scope.slots.insert(name.clone(), slot);
+        }
+        scope.size = bound.len();
+        for statement in statements.iter() {
+            if let Statement::Let(let_statement) = &statement.statement {
+                scope.declare(&let_statement.0.name);
+            }
+        }
+        self.scopes.push(scope);
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, statement: &mut NodeStatement) {
+        match &mut statement.statement {
+            Statement::Let(let_statement) => {
+                let (variable, value) = &mut **let_statement;
+                let scope = self.scopes.last_mut().unwrap_or(&mut self.globals);
+                variable.scopes = Arc::new([(0, scope.declare(&variable.name))]);
+                self.expression(value);
+            }
+            Statement::Return(exp)
+            | Statement::Throw(exp)
+            | Statement::Yield(exp)
+            | Statement::ExpressionStatement(exp) => self.expression(exp),
+        }
+    }
+
+    fn expressions(&mut self, expressions: &mut [NodeExpression]) {
+        expressions.iter_mut().for_each(|exp| self.expression(exp));
+    }
+
+    fn expression(&mut self, expression: &mut NodeExpression) {
+        match &mut expression.expression {
+            Expression::Identifier(variable) => self.variable(variable),
+            Expression::InterpolatedString(expressions) | Expression::ArrayLiteral(expressions) => {
+                self.expressions(expressions)
+            }
+            Expression::HashLiteral(entries) => {
+                for entry in entries {
+                    match entry {
+                        HashEntry::Pair(key, value) => {
+                            self.expression(key);
+                            self.expression(value);
+                        }
+                        HashEntry::Spread(exp) => self.expression(exp),
+                    }
+                }
+            }
+            Expression::IndexExpression(left, right)
+            | Expression::OptionalIndexExpression(left, right)
+            | Expression::InfixExpression(left, _, right)
+            | Expression::NilCoalescing(left, right)
+            | Expression::FieldUpdate {
+                object: left,
+                value: right,
+                ..
+            } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::Propagate(exp) => {
+                if self.functions == 0 && self.invalid_propagate.is_none() {
+                    self.invalid_propagate = Some(expression.position);
+                }
+                self.expression(exp);
+            }
+            Expression::PrefixExpression(_, exp)
+            | Expression::FieldAccess(exp, _)
+            | Expression::Spread(exp) => self.expression(exp),
+            Expression::BlockExpression(block) => self.scope(&[], block),
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.expression(condition);
+                self.scope(&[], consequence);
+                self.scope(&[], alternative);
+            }
+            Expression::TryExpression {
+                body,
+                name,
+                handler,
+            } => {
+                self.scope(&[], body);
+                self.scope(slice::from_ref(name), handler);
+            }
+            Expression::FunctionLiteral { parameters, body }
+            | Expression::GeneratorLiteral { parameters, body } => {
+                self.functions += 1;
+                self.scope(parameters, body);
+                self.functions -= 1;
+            }
+            Expression::ForExpression {
+                name,
+                iterable,
+                body,
+            } => {
+                self.expression(iterable);
+                self.scope(slice::from_ref(name), body);
+            }
+            Expression::CallExpression {
+                function,
+                arguments,
+            } => {
+                self.expression(function);
+                self.expressions(arguments);
+            }
+            Expression::MethodCall {
+                object, arguments, ..
+            } => {
+                self.expression(object);
+                self.expressions(arguments);
+            }
+            Expression::IntLiteral(_)
+            | Expression::StringLiteral(_)
+            | Expression::Boolean(_)
+            | Expression::StructDeclaration { .. }
+            | Expression::Nil => {}
+        }
+    }
+
+    fn variable(&mut self, variable: &mut Variable) {
+        let name = &variable.name;
+        let mut scopes: Vec<_> = (self.scopes.iter().rev().enumerate())
+            .filter_map(|(depth, scope)| scope.slots.get(name).map(|&slot| (depth, slot)))
+            .collect();
+        scopes.push((self.scopes.len(), self.globals.declare(name)));
+        variable.scopes = scopes.into();
+        variable.builtin = builtins::ALL_BUILTINS.iter().position(|(b, _)| b == name);
+    }
+}
@@ -1,25 +1,37 @@
 use super::*;
+use crate::limits::{Limits, DEFAULT_MAX_CALL_DEPTH};
 use crate::object;
 use crate::parser;
+use crate::test_utils;
 use Object::*;
 
+use std::time::Duration;
+
 fn assert_eval(input: &str, expected: &[object::Object]) {
+    assert_eval_with(Limits::default(), input, expected);
+}
+
+fn assert_eval_with(limits: Limits, input: &str, expected: &[object::Object]) {
     let parsed = parser::parse(input.into()).expect("Parser error during test");
     assert_eq!(parsed.len(), expected.len());
-    let env = Rc::new(RefCell::new(environment::Environment::empty()));
+    let mut globals = Globals::with_limits(limits);
 
     // Eval program statements and compare with expected
     for (statement, exp) in parsed.into_iter().zip(expected) {
-        let got = eval_statement(&statement, &env).expect("Runtime error during test");
+        let got = globals
+            .eval_statement(statement)
+            .expect("Runtime error during test");
         assert_eq!(exp, &got);
     }
 }
 
 fn assert_runtime_error(input: &str, expected_errors: &[&str]) {
     let parsed = parser::parse(input.into()).expect("Parser error during test");
-    let env = Rc::new(RefCell::new(environment::Environment::empty()));
-    for (statement, &error) in parsed.iter().zip(expected_errors) {
-        let got = eval_statement(statement, &env).expect_err("No runtime error encountered");
+    let mut globals = Globals::with_limits(Limits::default());
+    for (statement, &error) in parsed.into_iter().zip(expected_errors) {
+        let got = globals
+            .eval_statement(statement)
+            .expect_err("No runtime error encountered");
         match got {
             MonkeyError::Interpreter(_, e) => assert_eq!(format!("{}", e), error),
             _ => panic!("Wrong error type"),
@@ -64,6 +76,33 @@ fn test_int_expressions() {
     assert_eval(input, &expected);
 }
 
+#[test]
+fn test_bitwise_expressions() {
+    let input = "
+        12 & 10;
+        12 | 10;
+        12 ~ 10;
+        ~0;
+        ~-6;
+        1 << 62;
+        -16 >> 2;
+        1 | 2 & 3 << 1;
+        0xF0 >> 4 == 0x0F;
+    ";
+    let expected = [
+        Integer(8),
+        Integer(14),
+        Integer(6),
+        Integer(-1),
+        Integer(5),
+        Integer(1 << 62),
+        Integer(-4),
+        Integer(3),
+        Boolean(true),
+    ];
+    assert_eval(input, &expected);
+}
+
 #[test]
 fn test_bool_expressions() {
     let input = r#"
@@ -126,6 +165,25 @@ fn test_string_operations() {
     assert_eval(input, &expected);
 }
 
+#[test]
+fn test_interpolated_strings() {
+    let input = r#"
+        let x = 3;
+        "x = ${x}";
+        "${x + 1}${"!"}";
+        "${[1, "a"]} ${#{"k": nil}} ${fn() {}}";
+        str("a") + str(true);
+    "#;
+    let expected = [
+        Nil,
+        Object::from("x = 3"),
+        Object::from("4!"),
+        Object::from(r#"[1, "a"] #{"k": nil} <function>"#),
+        Object::from("atrue"),
+    ];
+    assert_eval(input, &expected);
+}
+
 #[test]
 fn test_blocks() {
     let input = "
@@ -313,6 +371,44 @@ fn test_let_statements() {
     assert_eval(input, &expected);
 }
 
+#[test]
+fn test_scopes() {
+    // A variable refers to the innermost scope where it has been defined when it's evaluated
+    let input = "
+        let x = 1;
+        fn() { let y = x; let x = 2; [y, x] }();
+        fn() { let f = fn() { x }; let a = f(); let x = 5; [a, f()] }();
+        fn(a) { let f = fn() { a }; let a = 10; f() }(3);
+        fn(a, a) { a }(1, 2);
+        fn() {
+            let even = fn(n) { if n == 0 { true } else { odd(n - 1) } };
+            let odd = fn(n) { if n == 0 { false } else { even(n - 1) } };
+            [even(4), odd(4)]
+        }();
+        let later = fn() { defined_later };
+        let defined_later = 3;
+        later();
+        fn() { let len = fn(x) { 0 }; len([1]) }() + len([1]);
+        for i in [1, 2] { let x = x + i; x }
+        try { throw 1; } catch e { let e = e + 1; e };
+    ";
+    let expected = [
+        Nil,
+        monkey_array![Integer(1), Integer(2)],
+        monkey_array![Integer(1), Integer(5)],
+        Integer(10),
+        Integer(2),
+        monkey_array![Boolean(true), Boolean(false)],
+        Nil,
+        Nil,
+        Integer(3),
+        Integer(1),
+        Nil,
+        Integer(2),
+    ];
+    assert_eval(input, &expected);
+}
+
 #[test]
 fn test_arrays() {
     let input = "
@@ -327,10 +423,7 @@ fn test_arrays() {
     let expected = [
         monkey_array![],
         monkey_array![Integer(0), Nil, Boolean(false)],
-        monkey_array![
-            Integer(0),
-            monkey_array![Integer(1)],
-        ],
+        monkey_array![Integer(0), monkey_array![Integer(1)],],
         Nil,
         Integer(5),
         Nil,
@@ -378,6 +471,172 @@ fn test_hashes() {
     assert_eval(input, &expected);
 }
 
+#[test]
+fn test_nil_safe_operators() {
+    let input = r#"
+        let h = #{"a": #{"b": [1, 2]}};
+        h?["a"]?["b"]?[1];
+        h?["x"]?["b"]?[1];
+        h["a"]?["b"]?[5];
+        nil?[0];
+        "str"?[10];
+        nil ?? 1;
+        false ?? 1;
+        h?["x"] ?? h?["a"]?["c"] ?? "default";
+        let calls = [];
+        let f = fn() { push(calls, 1) };
+        1 ?? f();
+        let gen = fn*() { yield 1; yield 2; };
+        let g = gen();
+        nil?[next(g)];
+        next(g);
+    "#;
+    let expected = [
+        Nil,
+        Integer(2),
+        Nil,
+        Nil,
+        Nil,
+        Nil,
+        Integer(1),
+        Boolean(false),
+        Object::from("default"),
+        Nil,
+        Nil,
+        Integer(1),
+        Nil,
+        Nil,
+        Nil,
+        Integer(1),
+    ];
+    assert_eval(input, &expected);
+
+    assert_runtime_error(
+        "1?[0]; [1]?[true]; #{}?[[]];",
+        &[
+            "'int' is not an array or hash object",
+            "index must be integer, not 'bool'",
+            "hash key must be hashable type, not 'array'",
+        ],
+    );
+}
+
+#[test]
+fn test_pipe_operator() {
+    let input = "
+        let add = fn(x, y) { x + y };
+        let double = fn(x) { x * 2 };
+        3 |> double;
+        3 |> add(4) |> double;
+        [1, 2] |> push(3) |> len;
+        2 |> fn(x) { x * x } |> add(..[1]);
+    ";
+    let expected = [Nil, Nil, Integer(6), Integer(14), Integer(3), Integer(5)];
+    assert_eval(input, &expected);
+}
+
+#[test]
+fn test_structs() {
+    let input = "
+        struct Point { x, y }
+        let p = Point(1, 2);
+        p.x + p.y;
+        let q = p.x = 10;
+        [p.x, q.x, q.y];
+        str(q);
+        type(p);
+        type(Point);
+        struct Line { from, to }
+        Line(p, q).to.x;
+        str(Line(p, q));
+    ";
+    let expected = [
+        Nil,
+        Nil,
+        Integer(3),
+        Nil,
+        monkey_array![Integer(1), Integer(10), Integer(2)],
+        Object::from("Point { x: 10, y: 2 }"),
+        Object::from("Point"),
+        Object::from("function"),
+        Nil,
+        Integer(10),
+        Object::from("Line { from: Point { x: 1, y: 2 }, to: Point { x: 10, y: 2 } }"),
+    ];
+    assert_eval(input, &expected);
+
+    let input = "
+        struct Point { x, y }
+        Point(1);
+        Point(1, 2).z;
+        Point(1, 2).z = 3;
+        [1].x;
+        nil.x = 1;
+    ";
+    let expected = [
+        "wrong number of arguments: expected 2 arguments but 1 were given",
+        "'Point' struct has no field 'z'",
+        "'Point' struct has no field 'z'",
+        "'array' object has no fields",
+        "'nil' object has no fields",
+    ];
+    let parsed = parser::parse(input.into()).unwrap();
+    let mut statements = parsed.into_iter();
+    let mut globals = Globals::with_limits(Limits::default());
+    globals.eval_statement(statements.next().unwrap()).unwrap();
+    for (statement, &error) in statements.zip(&expected) {
+        match globals.eval_statement(statement) {
+            Err(MonkeyError::Interpreter(_, e)) => assert_eq!(format!("{}", e), error),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+}
+
+#[test]
+fn test_method_calls() {
+    let input = r#"
+        "  a,b ".trim().split(",");
+        [1, 2].push(3).len();
+        #{"b": 2, "a": 1}.keys();
+        #{"b": 2, "a": 1}.values();
+        [1, "a", nil].join("-");
+        "abc".split("");
+        [0].push(..[1]);
+        struct Counter { count, step }
+        let c = Counter(1, fn(x) { x + 1 });
+        c.step(c.count);
+        c.str();
+    "#;
+    let expected = [
+        monkey_array![Object::from("a"), Object::from("b")],
+        Integer(3),
+        monkey_array![Object::from("a"), Object::from("b")],
+        monkey_array![Integer(1), Integer(2)],
+        Object::from("1-a-nil"),
+        monkey_array![Object::from("a"), Object::from("b"), Object::from("c")],
+        monkey_array![Integer(0), Integer(1)],
+        Nil,
+        Nil,
+        Integer(2),
+        Object::from("Counter { count: 1, step: <function> }"),
+    ];
+    assert_eval(input, &expected);
+
+    assert_runtime_error(
+        r#""a".push(1); 1.len(); [].keys(); #{}.foo();"#,
+        &[
+            "'string' object has no method 'push' (available methods: len, str, trim, split, \
+            is_error, unwrap, unwrap_or)",
+            "'int' object has no method 'len' (available methods: str, is_error, unwrap, \
+            unwrap_or)",
+            "'array' object has no method 'keys' (available methods: len, str, push, head, tail, \
+            join, is_error, unwrap, unwrap_or)",
+            "'hash' object has no method 'foo' (available methods: str, keys, values, is_error, \
+            unwrap, unwrap_or)",
+        ],
+    );
+}
+
 #[test]
 fn test_functions() {
     let input = "
@@ -515,6 +774,434 @@ fn test_recursion() {
     assert_eval(input, &expected);
 }
 
+#[test]
+fn test_call_depth_limit() {
+    let input = "
+        let f = fn(n) { if n == 0 { 0 } else { 1 + f(n - 1) } };
+        f(15);
+        try { f(25) } catch e { e.type };
+        f(15);
+        let count = fn(n) { if n == 0 { 0 } else { count(n - 1) } };
+        count(1000);
+        let gen = fn*(n) { if n > 0 { for x in gen(n - 1) { yield x; } } yield n; };
+        next(gen(10));
+    ";
+    let expected = [
+        Nil,
+        Integer(15),
+        Object::from("StackOverflowError"),
+        Integer(15),
+        Nil,
+        Integer(0),
+        Nil,
+        Integer(0),
+    ];
+    let limits = Limits {
+        max_call_depth: 20,
+        ..Limits::default()
+    };
+    assert_eval_with(limits, input, &expected);
+
+    let run = |input: &str| run_program(parser::parse(input.into()).unwrap(), limits);
+    let infinite_generator = "let gen = fn*(n) { for x in gen(n + 1) { yield x; } }; next(gen(0))";
+    for input in ["let f = fn(n) { 1 + f(n + 1) }; f(0)", infinite_generator] {
+        match run(input) {
+            Err(MonkeyError::Interpreter(_, StackOverflow)) => {}
+            other => panic!("Wrong result: {:?}", other),
+        }
+    }
+
+    // The error is reported at the call that exceeded the limit
+    match run("let f = fn(n) {\n    1 + f(n + 1)\n};\nf(0)") {
+        Err(MonkeyError::Interpreter((2, 10), StackOverflow)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+
+    // With the default limit, a thread with a stack of `stack_size` reaches the limit before
+    // overflowing its native stack
+    let overflowed = std::thread::Builder::new()
+        .stack_size(stack_size(DEFAULT_MAX_CALL_DEPTH))
+        .spawn(|| {
+            let program = parser::parse("let f = fn(n) { 1 + f(n + 1) }; f(0)".into()).unwrap();
+            matches!(
+                run_program(program, Limits::default()),
+                Err(MonkeyError::Interpreter(_, StackOverflow))
+            )
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert!(overflowed);
+}
+
+#[test]
+fn test_tail_calls() {
+    // Without tail calls, these would overflow the native stack
+    let input = "
+        let sum = fn(n, acc) { if n == 0 { acc } else { sum(n - 1, acc + n) } };
+        sum(10000, 0);
+        let is_even = fn(n, is_odd) { if n == 0 { return true; } return is_odd(n - 1); };
+        let is_odd = fn(n) { if n == 0 { false } else { is_even(n - 1, is_odd) } };
+        [is_even(10000, is_odd), is_odd(10001)];
+        let last = fn(n) { if n == 0 { len([1, 2]) } else { last(n - 1) } };
+        last(10000);
+    ";
+    let expected = [
+        Nil,
+        Integer(50_005_000),
+        Nil,
+        Nil,
+        monkey_array![Boolean(true), Boolean(true)],
+        Nil,
+        Integer(2),
+    ];
+    assert_eval(input, &expected);
+}
+
+#[test]
+fn test_spread() {
+    let input = r#"
+        let a = [1, 2];
+        [..a, 3, ..a, ..[]];
+        let sum = fn(x, y, z) { x + y + z };
+        sum(..a, 3);
+        sum(..[1], ..[2, 3]);
+        let base = #{"a": 1, "b": 2};
+        #{..base, "b": 3, ..#{"c": 4}};
+    "#;
+    let expected = [
+        Nil,
+        monkey_array![Integer(1), Integer(2), Integer(3), Integer(1), Integer(2)],
+        Nil,
+        Integer(6),
+        Integer(6),
+        Nil,
+        monkey_hash! {
+            HashableObject::from("a") => Integer(1),
+            HashableObject::from("b") => Integer(3),
+            HashableObject::from("c") => Integer(4)
+        },
+    ];
+    assert_eval(input, &expected);
+
+    let input = "
+        [..1];
+        #{..[]};
+        len(..nil);
+    ";
+    let expected = [
+        "type error: expected 'array', got 'int'",
+        "type error: expected 'hash', got 'array'",
+        "type error: expected 'array', got 'nil'",
+    ];
+    assert_runtime_error(input, &expected);
+}
+
+#[test]
+fn test_error_values() {
+    let input = r#"
+        let e = error("not found", 404);
+        e;
+        error("oops");
+        [is_error(e), is_error(1)];
+        [unwrap(5), unwrap_or(e, 0), unwrap_or(3, 0)];
+        e.unwrap_or(1);
+        let parse = fn(x) { if x < 0 { error("negative") } else { x } };
+        let double = fn(x) { parse(x)? * 2 };
+        [double(2), double(-1)];
+        str(e);
+        type(e);
+        [5.is_error(), "a".unwrap(), nil.unwrap_or(1)];
+        [e.type, e.message, e.data];
+    "#;
+    let error = |message: &str, data| {
+        let message = message.into();
+        Error(Box::new(ErrorObject { message, data }))
+    };
+    let expected = [
+        Nil,
+        error("not found", Integer(404)),
+        error("oops", Nil),
+        monkey_array![Boolean(true), Boolean(false)],
+        monkey_array![Integer(5), Integer(0), Integer(3)],
+        Integer(1),
+        Nil,
+        Nil,
+        monkey_array![Integer(4), error("negative", Nil)],
+        Object::from("error(\"not found\", 404)"),
+        Object::from("error"),
+        monkey_array![Boolean(false), Object::from("a"), Nil],
+        monkey_array![
+            Object::from("Error"),
+            Object::from("not found"),
+            Integer(404)
+        ],
+    ];
+    assert_eval(input, &expected);
+
+    let input = r#"
+        unwrap(error("bad"));
+        error(1);
+    "#;
+    let expected = [
+        "called `unwrap` on an error value: error(\"bad\")",
+        "type error: expected 'string', got 'int'",
+    ];
+    assert_runtime_error(input, &expected);
+
+    // `?` outside of a function is rejected before running, with the same error as in the VM
+    let input = r#"let x = error("bad")?;"#;
+    let interpreted = run_program(parser::parse(input.into()).unwrap(), Limits::default());
+    let compiled = test_utils::parse_and_compile(input).map(|_| ());
+    assert_eq!(
+        interpreted.unwrap_err().to_string(),
+        compiled.unwrap_err().to_string()
+    );
+}
+
+#[test]
+fn test_exceptions() {
+    let input = r#"
+        try { 1 } catch e { 2 };
+        try { throw 3; 4 } catch e { e + 1 };
+        try { #{}["a"] } catch e { [e.type, e.message] };
+        try { 1 / 0 } catch e { e.type };
+        try { assert(false) } catch e { e.message };
+        let f = fn(x) { if x == 0 { throw "zero"; } x };
+        try { f(1) + f(0) } catch e { e };
+        try { try { throw 1; } catch e { throw e + 1; } } catch e { e * 10 };
+        let g = fn() { try { return 5; } catch e { 0 } };
+        g();
+        1 + try { nil() } catch e { 10 };
+    "#;
+    let expected = [
+        Integer(1),
+        Integer(4),
+        monkey_array![
+            Object::from("KeyError"),
+            Object::from("hash key error: entry for \"a\" not found")
+        ],
+        Object::from("ZeroDivisionError"),
+        Object::from("Assertion failed on value false"),
+        Nil,
+        Object::from("zero"),
+        Integer(20),
+        Nil,
+        Integer(5),
+        Integer(11),
+    ];
+    assert_eval(input, &expected);
+
+    let input = r#"
+        throw "oops";
+        try { throw 1; } catch e { throw [e]; };
+        try { throw 1; } catch e { e.message };
+    "#;
+    let expected = [
+        "uncaught exception: \"oops\"",
+        "uncaught exception: [1]",
+        "'int' object has no fields",
+    ];
+    assert_runtime_error(input, &expected);
+}
+
+#[test]
+fn test_generators() {
+    let input = r#"
+        let collect = fn(g, acc) {
+            let x = next(g);
+            if is_error(x ?? error("")) { acc } else { collect(g, push(acc, x)) }
+        };
+        let count = fn*(n) { for i in range(n) { yield i; } };
+        collect(count(3), []);
+        let g = count(2);
+        [next(g), g.next(), next(g), next(g)];
+        let evens = fn*(xs) { for x in xs { if x % 2 == 0 { yield x; } } };
+        collect(evens(count(5)), []);
+        collect(evens([2, 3, 4]), []);
+        let chars = fn*(s) { for c in s { yield c + c; } };
+        collect(chars("héy"), []);
+        let stop = fn*() { yield 1; return; yield 2; };
+        collect(stop(), []);
+        let safe = fn*() { try { yield 1; throw "oops"; } catch e { yield e; } yield 3; };
+        collect(safe(), []);
+        let failing = fn*() { yield 1; nil(); };
+        let f = failing();
+        [next(f), try { next(f) } catch e { e.type }, next(f)];
+        for x in [1, 2] { x }
+        type(count(0));
+        let x = 10;
+        for x in [1, 2] { x }
+        x;
+    "#;
+    let expected = [
+        Nil,
+        Nil,
+        monkey_array![Integer(0), Integer(1), Integer(2)],
+        Nil,
+        monkey_array![Integer(0), Integer(1), Nil, Nil],
+        Nil,
+        monkey_array![Integer(0), Integer(2), Integer(4)],
+        monkey_array![Integer(2), Integer(4)],
+        Nil,
+        monkey_array![Object::from("hh"), Object::from("éé"), Object::from("yy")],
+        Nil,
+        monkey_array![Integer(1)],
+        Nil,
+        monkey_array![Integer(1), Object::from("oops"), Integer(3)],
+        Nil,
+        Nil,
+        monkey_array![Integer(1), Object::from("TypeError"), Nil],
+        Nil,
+        Object::from("generator"),
+        Nil,
+        Nil,
+        Integer(10),
+    ];
+    assert_eval(input, &expected);
+
+    let input = r#"
+        for x in 5 { x }
+        next([1]);
+        next(fn*() { let x = if true { yield 1; }; }());
+    "#;
+    let expected = [
+        "type error: expected 'iterable', got 'int'",
+        "type error: expected 'generator', got 'array'",
+        "the interpreter only supports `yield` in statements directly inside the generator body \
+        or inside `if`, `for`, `try` and block expressions",
+    ];
+    assert_runtime_error(input, &expected);
+}
+
+#[test]
+fn test_cycle_collection() {
+    // Every call creates a function that refers to itself through its environment, and a
+    // generator that holds an environment that refers to the generator
+    let input = "
+        let make = fn() { let f = fn() { f }; f };
+        let make_generator = fn() { let g = fn*() { yield 1; }(); next(g); g };
+        for i in range(2) { make(); make_generator(); }
+        let stats = gc();
+        [stats[\"collected\"] > 0, stats[\"total_collected\"] == stats[\"collected\"]];
+        for i in range(30000) { make(); make_generator(); }
+    ";
+    let expected = [
+        Nil,
+        Nil,
+        Nil,
+        Nil,
+        monkey_array![Boolean(true), Boolean(true)],
+        Nil,
+    ];
+    assert_eval(input, &expected);
+    // Without collections there would be more than 150000 environments and 30000 generators
+    let stats = crate::gc::stats();
+    assert!(stats.collections > 1);
+    assert!(stats.environments < 25000, "{:?}", stats);
+    assert!(stats.generators < 5000, "{:?}", stats);
+
+    // Once the program finishes, its environment is garbage too
+    let program = parser::parse(input.into()).expect("Parser error during test");
+    run_program(program, Limits::default()).expect("Runtime error during test");
+    crate::gc::collect();
+    let stats = crate::gc::stats();
+    assert_eq!((stats.environments, stats.generators), (0, 0));
+}
+
+#[test]
+fn test_limits() {
+    let no_limits = Limits::default();
+    let steps = Limits {
+        max_steps: Some(1000),
+        ..no_limits
+    };
+    let timeout = Limits {
+        timeout: Some(Duration::from_millis(50)),
+        ..no_limits
+    };
+    let size = Limits {
+        max_collection_size: Some(10),
+        ..no_limits
+    };
+    let allocations = Limits {
+        max_allocations: Some(10),
+        ..no_limits
+    };
+    let forever = "let f = fn(n) { f(n + 1) }; f(0);";
+    let too_large = "collection too large: collections can't have more than 10 elements";
+    let tests = [
+        (
+            steps,
+            forever,
+            "step limit exceeded: ran more than 1000 steps",
+        ),
+        // Reaching a limit can't be caught
+        (
+            steps,
+            "try { fn() { for x in range(1000) { x; } }(); } catch e { 0 };",
+            "step limit exceeded: ran more than 1000 steps",
+        ),
+        (timeout, forever, "timeout: ran for more than 50ms"),
+        (size, "range(100);", too_large),
+        (size, "let a = range(8); [..a, ..a];", too_large),
+        (size, r#"let s = "abcdef"; s + s;"#, too_large),
+        (
+            allocations,
+            "for i in range(100) { [i]; };",
+            "allocation limit exceeded: created more than 10 objects",
+        ),
+    ];
+    for (limits, program, expected) in tests {
+        let parsed = parser::parse(program.into()).expect("Parser error during test");
+        match run_program(parsed, limits) {
+            Err(MonkeyError::Interpreter(_, e)) => assert_eq!(format!("{}", e), expected),
+            Err(_) => panic!("Wrong error type"),
+            Ok(_) => panic!("No runtime error encountered"),
+        }
+    }
+
+    // The resources are counted from the start of each program
+    let program = "let f = fn(n) { if n > 0 { f(n - 1) } else { 0 } }; f(80);";
+    for _ in 0..2 {
+        let parsed = parser::parse(program.into()).expect("Parser error during test");
+        run_program(parsed, steps).expect("Runtime error during test");
+    }
+
+    // Each global scope has its own limits, even in the same thread
+    let mut limited = Globals::with_limits(size);
+    let mut unlimited = Globals::with_limits(no_limits);
+    let eval = |globals: &mut Globals, input: &str| {
+        let mut parsed = parser::parse(input.into()).expect("Parser error during test");
+        globals.eval_statement(parsed.remove(0))
+    };
+    assert!(eval(&mut unlimited, "len(range(100));").is_ok());
+    assert_eq!(eval(&mut limited, "len(range(10));").unwrap(), Integer(10));
+    assert!(eval(&mut limited, "len(range(100));").is_err());
+    assert!(eval(&mut unlimited, "len(range(100));").is_ok());
+}
+
+#[test]
+fn test_imports() {
+    let dir = test_utils::write_files("interpreter-imports", test_utils::IMPORT_TEST_FILES);
+    let run = |file: &str| -> MonkeyResult<Object> {
+        let path = dir.join(file);
+        crate::module::set_main_file(&path)?;
+        let program = crate::module::parse_file(&path).expect("Parser error during test");
+        let mut globals = Globals::with_limits(Limits::default());
+        let mut result = Nil;
+        for statement in program {
+            result = globals.eval_statement(statement)?;
+        }
+        Ok(result)
+    };
+
+    let expected = monkey_array![Integer(4), Integer(5), Integer(3)];
+    assert_eq!(run("main.mk").expect("Runtime error during test"), expected);
+    let error = run("cycle.mk").expect_err("No runtime error encountered");
+    assert!(format!("{}", error).contains("import cycle: "));
+}
+
 #[test]
 fn test_runtime_errors() {
     // Basic errors
@@ -571,11 +1258,19 @@ fn test_runtime_errors() {
         2 / 0;
         2 % 0;
         2 ^ (-1);
+        1 << 64;
+        1 >> -1;
+        true & false;
+        ~nil;
     ";
     let expected = [
         "division or modulo by zero",
         "division or modulo by zero",
         "negative exponent",
+        "invalid shift amount: 64",
+        "invalid shift amount: -1",
+        "unsuported operand types for infix operator `&`: 'bool' and 'bool'",
+        "unsuported operand type for prefix operator `~`: 'nil'",
     ];
     assert_runtime_error(input, &expected);
 }
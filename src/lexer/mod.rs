@@ -4,7 +4,7 @@ mod tests;
 pub mod token;
 
 use crate::error::*;
-use token::Token;
+use token::{StrPart, Token};
 
 use std::io::{self, BufRead, BufReader, Cursor};
 use std::iter::Peekable;
@@ -18,12 +18,23 @@ pub struct Lexer {
     // `Lexer::next_token`, and `current_position` is the position of `current_char`.
     pub token_position: (usize, usize),
     current_position: (usize, usize),
+    // The position of the first character of the input. This is only different from (1, 1) when
+    // lexing source code embedded in another file, like the expressions inside interpolated
+    // strings.
+    start_position: (usize, usize),
     current_char: Option<char>,
     current_line: Option<LexerLine>,
 }
 
 impl Lexer {
     pub fn new(input: Box<dyn BufRead>) -> Result<Lexer, io::Error> {
+        Lexer::with_start_position(input, (1, 1))
+    }
+
+    pub fn with_start_position(
+        input: Box<dyn BufRead>,
+        start_position: (usize, usize),
+    ) -> Result<Lexer, io::Error> {
         let lines = input
             .lines()
             .map(|line_result| {
@@ -39,10 +50,12 @@ impl Lexer {
             .enumerate();
         let lines = (Box::new(lines) as LexerLines).peekable();
 
+        // If the input is empty, the `EOF` token is at the start position
         let mut lex = Lexer {
             lines,
             token_position: (0, 0),
-            current_position: (0, 0),
+            current_position: start_position,
+            start_position,
             current_line: None,
             current_char: None,
         };
@@ -59,7 +72,7 @@ impl Lexer {
     fn read_line(&mut self) -> Result<(), io::Error> {
         match self.lines.next() {
             Some((number, line_result)) => {
-                self.current_position.0 = number + 1;
+                self.current_position.0 = number + self.start_position.0;
                 self.current_line = Some(line_result?);
             }
             None => self.current_line = None,
@@ -71,7 +84,11 @@ impl Lexer {
         match &mut self.current_line {
             Some(line) => match line.next() {
                 Some((number, character)) => {
-                    self.current_position.1 = number + 1;
+                    self.current_position.1 = if self.current_position.0 == self.start_position.0 {
+                        number + self.start_position.1
+                    } else {
+                        number + 1
+                    };
                     self.current_char = Some(character);
                 }
                 None => {
@@ -108,7 +125,7 @@ impl Lexer {
             }
 
             // Operators
-            // Two character operators (==, !=, <=, >=)
+            // Two character operators (==, !=, <=, >=, ..)
             Some('=') if peek_ch == Some('=') => {
                 self.read_char()?;
                 Token::Equals
@@ -125,6 +142,26 @@ impl Lexer {
                 self.read_char()?;
                 Token::GreaterEq
             }
+            Some('<') if peek_ch == Some('<') => {
+                self.read_char()?;
+                Token::ShiftLeft
+            }
+            Some('>') if peek_ch == Some('>') => {
+                self.read_char()?;
+                Token::ShiftRight
+            }
+            Some('|') if peek_ch == Some('>') => {
+                self.read_char()?;
+                Token::Pipe
+            }
+            Some('?') if peek_ch == Some('?') => {
+                self.read_char()?;
+                Token::NilCoalesce
+            }
+            Some('.') if peek_ch == Some('.') => {
+                self.read_char()?;
+                Token::Spread
+            }
             // Single character operators
             Some('=') => Token::Assign,
             Some('!') => Token::Bang,
@@ -134,8 +171,12 @@ impl Lexer {
             Some('/') => Token::Slash,
             Some('^') => Token::Exponent,
             Some('%') => Token::Modulo,
+            Some('.') => Token::Dot,
             Some('<') => Token::LessThan,
             Some('>') => Token::GreaterThan,
+            Some('&') => Token::BitAnd,
+            Some('|') => Token::BitOr,
+            Some('~') => Token::Tilde,
 
             // Delimiters
             Some(',') => Token::Comma,
@@ -151,8 +192,25 @@ impl Lexer {
                 self.read_char()?;
                 Token::OpenHash
             }
+            Some('?') if peek_ch == Some('[') => {
+                self.read_char()?;
+                Token::OpenOptionalIndex
+            }
+            Some('?') => Token::Question,
 
-            Some('\"') => self.read_string()?,
+            // Triple-quoted strings. If there are only two quotes, this is an empty string
+            Some('"') if peek_ch == Some('"') => {
+                self.read_char()?;
+                if self.peek_char() == Some('"') {
+                    self.read_char()?;
+                    self.read_string(true)?
+                } else {
+                    Token::Str(String::new())
+                }
+            }
+            Some('"') => self.read_string(false)?,
+            // Raw strings, like `r"..."` or `r#"..."#`
+            Some('r') if peek_ch == Some('"') || peek_ch == Some('#') => self.read_raw_string()?,
 
             // Early exit, because we don't need to `read_char()` after the match block
             Some(c) if c.is_ascii_digit() => return self.read_number(),
@@ -186,48 +244,212 @@ impl Lexer {
         Ok(Lexer::match_keyword(&literal).unwrap_or(Token::Identifier(literal)))
     }
 
+    /// Reads an integer literal. Besides decimal literals, this supports hexadecimal (`0x1F`),
+    /// octal (`0o17`) and binary (`0b1010`) literals. Any of them can have single underscores
+    /// between two digits, like `1_000_000`.
     fn read_number(&mut self) -> Result<Token, MonkeyError> {
+        let position = self.current_position;
+        let peek_ch = self.peek_char();
+        let radix = match (self.current_char, peek_ch) {
+            (Some('0'), Some('x')) => 16,
+            (Some('0'), Some('o')) => 8,
+            (Some('0'), Some('b')) => 2,
+            _ => 10,
+        };
+
         let mut literal = String::new();
+        if radix != 10 {
+            literal.push('0');
+            literal.extend(peek_ch);
+            self.read_char()?;
+            self.read_char()?;
+        }
+
+        let mut digits = String::new();
+        // Whether there is an underscore that doesn't come after a digit, or isn't followed by one
+        let mut misplaced_underscore = false;
+        let mut after_digit = false;
         while let Some(ch) = self.current_char {
-            if ch.is_ascii_digit() {
-                literal.push(ch);
-            } else if ch != '_' {
+            // Prefixed literals consume any alphanumeric character, so that things like `0b102`
+            // or `0xFG` are reported as malformed literals
+            let is_digit = match radix {
+                10 => ch.is_ascii_digit(),
+                _ => ch.is_alphanumeric(),
+            };
+            if is_digit {
+                digits.push(ch);
+            } else if ch == '_' {
+                misplaced_underscore |= !after_digit;
+            } else {
                 break;
             }
+            after_digit = is_digit;
+            literal.push(ch);
             self.read_char()?;
         }
+        misplaced_underscore |= literal.ends_with('_');
 
-        Ok(Token::Int(literal.parse().unwrap()))
+        if digits.is_empty() || misplaced_underscore || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(MonkeyError::Lexer(
+                position,
+                LexerError::MalformedIntLiteral(literal),
+            ));
+        }
+        i64::from_str_radix(&digits, radix)
+            .map(Token::Int)
+            .map_err(|_| MonkeyError::Lexer(position, LexerError::IntLiteralOutOfRange(literal)))
     }
 
-    fn read_string(&mut self) -> Result<Token, MonkeyError> {
+    /// Reads a string literal. If `triple_quoted` is true, the string only ends on three
+    /// consecutive quotes. If the string contains interpolated expressions (like `"${x}"`),
+    /// returns a `Token::InterpolatedStr` with the source code of each expression, which is later
+    /// lexed and parsed by the parser.
+    fn read_string(&mut self, triple_quoted: bool) -> Result<Token, MonkeyError> {
+        let mut parts = Vec::new();
         let mut result = String::new();
         loop {
             self.read_char()?;
+            let peek_ch = self.peek_char();
             match self.current_char {
-                Some('"') => break,
+                Some('"') if !triple_quoted => break,
+                Some('"') if peek_ch == Some('"') => {
+                    self.read_char()?;
+                    if self.peek_char() == Some('"') {
+                        self.read_char()?;
+                        break;
+                    }
+                    result.push_str("\"\"");
+                }
+                Some('$') if peek_ch == Some('{') => {
+                    self.read_char()?; // Consume "{"
+                    self.read_char()?; // Read first character of the expression
+                    let position = self.current_position;
+                    let mut code = String::new();
+                    self.read_interpolated_code(&mut code)?;
+                    parts.push(StrPart::Literal(std::mem::take(&mut result)));
+                    parts.push(StrPart::Code(code, position));
+                }
                 Some('\\') => {
                     self.read_char()?;
-                    match self.current_char {
-                        Some('\\') => result.push('\\'),
-                        Some('n') => result.push('\n'),
-                        Some('t') => result.push('\t'),
-                        Some('r') => result.push('\r'),
-                        Some('"') => result.push('"'),
-                        Some(c) => {
-                            return Err(MonkeyError::Lexer(
-                                self.current_position,
-                                LexerError::UnknownEscapeSequence(c),
-                            ))
-                        }
-                        None => {
-                            return Err(MonkeyError::Lexer(
-                                self.current_position,
-                                LexerError::UnexpectedEOF,
-                            ))
-                        }
+                    result.push(self.read_escape_sequence()?);
+                }
+                Some(c) => result.push(c),
+                None => {
+                    return Err(MonkeyError::Lexer(
+                        self.current_position,
+                        LexerError::UnexpectedEOF,
+                    ))
+                }
+            }
+        }
+        if parts.is_empty() {
+            Ok(Token::Str(result))
+        } else {
+            parts.push(StrPart::Literal(result));
+            parts.retain(|p| p != &StrPart::Literal(String::new()));
+            Ok(Token::InterpolatedStr(parts))
+        }
+    }
+
+    /// Reads the escape sequence that starts at `current_char`, that is, the character after the
+    /// backslash, and returns the character it represents. Leaves `current_char` at the last
+    /// character of the escape sequence.
+    fn read_escape_sequence(&mut self) -> Result<char, MonkeyError> {
+        let escaped = match self.current_char {
+            Some('\\') => '\\',
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('"') => '"',
+            Some('$') => '$',
+            Some('u') => return self.read_unicode_escape(),
+            Some(c) => {
+                return Err(MonkeyError::Lexer(
+                    self.current_position,
+                    LexerError::UnknownEscapeSequence(c),
+                ))
+            }
+            None => {
+                return Err(MonkeyError::Lexer(
+                    self.current_position,
+                    LexerError::UnexpectedEOF,
+                ))
+            }
+        };
+        Ok(escaped)
+    }
+
+    /// Reads a unicode escape sequence, like `\u{1F412}`. Expects `current_char` to be the "u"
+    /// character. The code point must have between one and six hexadecimal digits.
+    fn read_unicode_escape(&mut self) -> Result<char, MonkeyError> {
+        let position = self.current_position;
+        let mut literal = String::from("u");
+        let mut digits = String::new();
+        self.read_char()?;
+        if self.current_char == Some('{') {
+            literal.push('{');
+            loop {
+                self.read_char()?;
+                match self.current_char {
+                    Some('}') => break,
+                    Some(c) if c != '"' => digits.push(c),
+                    _ => {
+                        return Err(MonkeyError::Lexer(
+                            position,
+                            LexerError::InvalidUnicodeEscape(literal + &digits),
+                        ))
                     }
                 }
+            }
+            literal.push_str(&digits);
+            literal.push('}');
+        }
+        let is_valid =
+            (1..=6).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_hexdigit());
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .filter(|_| is_valid)
+            .and_then(std::char::from_u32)
+            .ok_or(MonkeyError::Lexer(
+                position,
+                LexerError::InvalidUnicodeEscape(literal),
+            ))
+    }
+
+    /// Reads a raw string, like `r"..."` or `r#"..."#`. Expects `current_char` to be the "r"
+    /// character. Raw strings have no escape sequences or interpolated expressions, and end on the
+    /// first quote followed by as many "#" characters as there were before the opening quote.
+    fn read_raw_string(&mut self) -> Result<Token, MonkeyError> {
+        let mut num_hashes = 0;
+        self.read_char()?;
+        while self.current_char == Some('#') {
+            num_hashes += 1;
+            self.read_char()?;
+        }
+        if self.current_char != Some('"') {
+            return Err(MonkeyError::Lexer(
+                self.current_position,
+                LexerError::IllegalChar(self.current_char.unwrap_or('#')),
+            ));
+        }
+
+        let mut result = String::new();
+        loop {
+            self.read_char()?;
+            match self.current_char {
+                Some('"') => {
+                    let mut hashes_found = 0;
+                    while hashes_found < num_hashes && self.peek_char() == Some('#') {
+                        self.read_char()?;
+                        hashes_found += 1;
+                    }
+                    if hashes_found == num_hashes {
+                        break;
+                    }
+                    result.push('"');
+                    result.extend(std::iter::repeat_n('#', hashes_found));
+                }
                 Some(c) => result.push(c),
                 None => {
                     return Err(MonkeyError::Lexer(
@@ -240,6 +462,57 @@ impl Lexer {
         Ok(Token::Str(result))
     }
 
+    /// Reads the source code of an expression inside an interpolated string, until the matching
+    /// "}" character, and pushes it into `code`. The closing "}" is not included. Expects
+    /// `current_char` to be the first character of the expression. Nested string literals, which
+    /// may themselves contain interpolated expressions, are copied verbatim.
+    fn read_interpolated_code(&mut self, code: &mut String) -> Result<(), MonkeyError> {
+        let mut depth = 0;
+        loop {
+            match self.current_char {
+                Some('}') if depth == 0 => return Ok(()),
+                Some('}') => depth -= 1,
+                Some('{') => depth += 1,
+                Some('"') => {
+                    code.push('"');
+                    loop {
+                        self.read_char()?;
+                        let peek_ch = self.peek_char();
+                        match self.current_char {
+                            Some('"') => break,
+                            Some('\\') => {
+                                code.push('\\');
+                                self.read_char()?;
+                            }
+                            Some('$') if peek_ch == Some('{') => {
+                                code.push_str("${");
+                                self.read_char()?; // Consume "{"
+                                self.read_char()?; // Read first character of the expression
+                                self.read_interpolated_code(code)?;
+                            }
+                            _ => (),
+                        }
+                        match self.current_char {
+                            Some(c) => code.push(c),
+                            None => break,
+                        }
+                    }
+                }
+                None => {
+                    return Err(MonkeyError::Lexer(
+                        self.current_position,
+                        LexerError::UnexpectedEOF,
+                    ))
+                }
+                _ => (),
+            }
+            if let Some(c) = self.current_char {
+                code.push(c);
+            }
+            self.read_char()?;
+        }
+    }
+
     fn consume_whitespace(&mut self) -> Result<(), io::Error> {
         while let Some(ch) = self.current_char {
             if !ch.is_whitespace() {
@@ -260,6 +533,13 @@ impl Lexer {
             "else" => Some(Token::Else),
             "return" => Some(Token::Return),
             "nil" => Some(Token::Nil),
+            "struct" => Some(Token::Struct),
+            "throw" => Some(Token::Throw),
+            "try" => Some(Token::Try),
+            "catch" => Some(Token::Catch),
+            "yield" => Some(Token::Yield),
+            "for" => Some(Token::For),
+            "in" => Some(Token::In),
             _ => None,
         }
     }
@@ -53,7 +53,7 @@ fn test_identifiers() {
     assert_lex(input, &expected);
 
     // Test keywords
-    let input = "fn let true false if else return nil";
+    let input = "fn let true false if else return nil struct throw try catch yield for in";
     let expected = [
         Token::Function,
         Token::Let,
@@ -63,6 +63,13 @@ fn test_identifiers() {
         Token::Else,
         Token::Return,
         Token::Nil,
+        Token::Struct,
+        Token::Throw,
+        Token::Try,
+        Token::Catch,
+        Token::Yield,
+        Token::For,
+        Token::In,
         Token::EOF,
     ];
     assert_lex(input, &expected);
@@ -70,7 +77,7 @@ fn test_identifiers() {
 
 #[test]
 fn test_int_literals() {
-    let input = "0 1729 808017424794 1_000_000 1___0____2 _1_000_000";
+    let input = "0 1729 808017424794 1_000_000 1_0_2 _1_000_000";
     let expected = [
         Token::Int(0),
         Token::Int(1729),
@@ -81,6 +88,19 @@ fn test_int_literals() {
         Token::EOF,
     ];
     assert_lex(input, &expected);
+
+    let input = "0x1F 0xdead_BEEF 0o17 0b1010 0b1111_0000 0x7fff_ffff_ffff_ffff 00";
+    let expected = [
+        Token::Int(0x1F),
+        Token::Int(0xdead_beef),
+        Token::Int(0o17),
+        Token::Int(0b1010),
+        Token::Int(0b1111_0000),
+        Token::Int(i64::MAX),
+        Token::Int(0),
+        Token::EOF,
+    ];
+    assert_lex(input, &expected);
 }
 
 #[test]
@@ -100,9 +120,56 @@ fn test_strings() {
     assert_lex(input, &expected);
 }
 
+#[test]
+fn test_raw_and_multiline_strings() {
+    let input = r###"
+        r"raw \n ${x}"
+        r#"with "quotes""#
+        r##"a "# inside"##
+        ""
+        """triple "quoted"
+string\t"""
+        "\0\u{41}\u{1F412}"
+    "###;
+    let expected = [
+        Token::Str(r"raw \n ${x}".into()),
+        Token::Str(r#"with "quotes""#.into()),
+        Token::Str(r##"a "# inside"##.into()),
+        Token::Str("".into()),
+        Token::Str("triple \"quoted\"\nstring\t".into()),
+        Token::Str("\0A\u{1F412}".into()),
+        Token::EOF,
+    ];
+    assert_lex(input, &expected);
+}
+
+#[test]
+fn test_interpolated_strings() {
+    use super::token::StrPart::*;
+    let input = r#"
+        "a ${x} b"
+        "${ f("}", "${y}") }"
+        "\${not} ${ {1} }"
+    "#;
+    let expected = [
+        Token::InterpolatedStr(vec![
+            Literal("a ".into()),
+            Code("x".into(), (2, 14)),
+            Literal(" b".into()),
+        ]),
+        Token::InterpolatedStr(vec![Code(r#" f("}", "${y}") "#.into(), (3, 12))]),
+        Token::InterpolatedStr(vec![
+            Literal("${not} ".into()),
+            Code(" {1} ".into(), (4, 20)),
+        ]),
+        Token::EOF,
+    ];
+    assert_lex(input, &expected);
+}
+
 #[test]
 fn test_operators() {
-    let input = "= ! + - * / ^ % < > == != <= >=";
+    let input = "= ! + - * / ^ % < > == != <= >= & | ~ << >> <<< ?? |> .. . ?";
     let expected = [
         Token::Assign,
         Token::Bang,
@@ -118,6 +185,18 @@ fn test_operators() {
         Token::NotEquals,
         Token::LessEq,
         Token::GreaterEq,
+        Token::BitAnd,
+        Token::BitOr,
+        Token::Tilde,
+        Token::ShiftLeft,
+        Token::ShiftRight,
+        Token::ShiftLeft,
+        Token::LessThan,
+        Token::NilCoalesce,
+        Token::Pipe,
+        Token::Spread,
+        Token::Dot,
+        Token::Question,
         Token::EOF,
     ];
     assert_lex(input, &expected);
@@ -125,7 +204,7 @@ fn test_operators() {
 
 #[test]
 fn test_delimiters() {
-    let input = ", ; : () {} [] #{}";
+    let input = ", ; : () {} [] #{} ?[";
     let expected = [
         Token::Comma,
         Token::Semicolon,
@@ -138,6 +217,7 @@ fn test_delimiters() {
         Token::CloseSquareBracket,
         Token::OpenHash,
         Token::CloseCurlyBrace,
+        Token::OpenOptionalIndex,
         Token::EOF,
     ];
     assert_lex(input, &expected);
@@ -301,6 +381,25 @@ fn test_lexer_position() {
     }
 }
 
+#[test]
+fn test_multiline_string_position() {
+    let program = "x \"\"\"a\nbc\"\"\" y\nr\"d\ne\" z";
+    let expected = [
+        (iden!("x"), (1, 1)),
+        (Token::Str("a\nbc".into()), (1, 3)),
+        (iden!("y"), (2, 7)),
+        (Token::Str("d\ne".into()), (3, 1)),
+        (iden!("z"), (4, 4)),
+    ];
+
+    let mut lex = Lexer::from_string(program.into()).unwrap();
+    for (tk, token_pos) in &expected {
+        let got = lex.next_token().unwrap();
+        assert_eq!(tk, &got);
+        assert_eq!(token_pos, &lex.token_position);
+    }
+}
+
 #[test]
 fn test_lexer_errors() {
     assert_lexer_error(
@@ -311,8 +410,38 @@ fn test_lexer_errors() {
         r#" "i don't know this guy: \w" "#,
         LexerError::UnknownEscapeSequence('w'),
     );
+    assert_lexer_error(r#" "unfinished ${ 1 + " "#, LexerError::UnexpectedEOF);
+    assert_lexer_error(r#" """only "" two"" "#, LexerError::UnexpectedEOF);
+    assert_lexer_error(r###" r#"unfinished raw"" "###, LexerError::UnexpectedEOF);
+    assert_lexer_error(r#" r#x "#, LexerError::IllegalChar('x'));
+    assert_lexer_error(
+        r#" "\u{110000}" "#,
+        LexerError::InvalidUnicodeEscape("u{110000}".into()),
+    );
+    assert_lexer_error(
+        r#" "\u{zz}" "#,
+        LexerError::InvalidUnicodeEscape("u{zz}".into()),
+    );
+    assert_lexer_error(r#" "\u41" "#, LexerError::InvalidUnicodeEscape("u".into()));
+    assert_lexer_error("0x", LexerError::MalformedIntLiteral("0x".into()));
+    assert_lexer_error("0b_", LexerError::MalformedIntLiteral("0b_".into()));
+    assert_lexer_error("0b102", LexerError::MalformedIntLiteral("0b102".into()));
+    assert_lexer_error("0o8", LexerError::MalformedIntLiteral("0o8".into()));
+    assert_lexer_error("0x1G", LexerError::MalformedIntLiteral("0x1G".into()));
+    // Underscores are only allowed between two digits
+    for literal in ["1_", "1__0", "1_000__000", "0x_1", "0b1_", "0o1__7"] {
+        assert_lexer_error(literal, LexerError::MalformedIntLiteral(literal.into()));
+    }
+    assert_lexer_error(
+        "9_223_372_036_854_775_808",
+        LexerError::IntLiteralOutOfRange("9_223_372_036_854_775_808".into()),
+    );
+    assert_lexer_error(
+        "0x1_0000_0000_0000_0000",
+        LexerError::IntLiteralOutOfRange("0x1_0000_0000_0000_0000".into()),
+    );
     assert_lexer_error(
-        r#" "whats up with this weird symbol:" & "#,
-        LexerError::IllegalChar('&'),
+        r#" "whats up with this weird symbol:" @ "#,
+        LexerError::IllegalChar('@'),
     );
 }
@@ -1,5 +1,6 @@
 use std::fmt;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug, Clone)]
 pub enum Token {
     EOF,
@@ -7,6 +8,7 @@ pub enum Token {
     Identifier(String),
     Int(i64),
     Str(String),
+    InterpolatedStr(Vec<StrPart>),
 
     // Operators
     Assign,
@@ -23,6 +25,16 @@ pub enum Token {
     NotEquals,
     LessEq,
     GreaterEq,
+    BitAnd,
+    BitOr,
+    Tilde,
+    ShiftLeft,
+    ShiftRight,
+    NilCoalesce,
+    Pipe,
+    Spread,
+    Dot,
+    Question,
 
     // Delimiters
     Comma,
@@ -35,6 +47,7 @@ pub enum Token {
     OpenSquareBracket,
     CloseSquareBracket,
     OpenHash,
+    OpenOptionalIndex,
 
     // Keywords
     Function,
@@ -45,6 +58,13 @@ pub enum Token {
     Else,
     Return,
     Nil,
+    Struct,
+    Throw,
+    Try,
+    Catch,
+    Yield,
+    For,
+    In,
 }
 
 impl fmt::Display for Token {
@@ -60,7 +80,7 @@ impl Token {
         match self {
             Identifier(_) => "identifier",
             Int(_) => "integer literal",
-            Str(_) => "string literal",
+            Str(_) | InterpolatedStr(_) => "string literal",
             Assign => "`=`",
             Bang => "`!`",
             Plus => "`+`",
@@ -75,6 +95,16 @@ impl Token {
             NotEquals => "`!=`",
             LessEq => "`<=`",
             GreaterEq => "`>=`",
+            BitAnd => "`&`",
+            BitOr => "`|`",
+            Tilde => "`~`",
+            ShiftLeft => "`<<`",
+            ShiftRight => "`>>`",
+            NilCoalesce => "`??`",
+            Pipe => "`|>`",
+            Spread => "`..`",
+            Dot => "`.`",
+            Question => "`?`",
             Comma => "`,`",
             Semicolon => "`;`",
             Colon => "`:`",
@@ -85,6 +115,7 @@ impl Token {
             OpenSquareBracket => "`[`",
             CloseSquareBracket => "`]`",
             OpenHash => "`#{`",
+            OpenOptionalIndex => "`?[`",
             Function => "`fn`",
             Let => "`let`",
             True | False => "boolean literal",
@@ -93,6 +124,21 @@ impl Token {
             Return => "`return`",
             EOF => "EOF",
             Nil => "`nil`",
+            Struct => "`struct`",
+            Throw => "`throw`",
+            Try => "`try`",
+            Catch => "`catch`",
+            Yield => "`yield`",
+            For => "`for`",
+            In => "`in`",
         }
     }
 }
+
+/// A part of an interpolated string literal. Embedded expressions are stored as source code,
+/// together with the position of their first character, and are parsed by the parser.
+#[derive(PartialEq, Debug, Clone)]
+pub enum StrPart {
+    Literal(String),
+    Code(String, (usize, usize)),
+}
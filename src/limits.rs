@@ -0,0 +1,209 @@
+// Limits on the resources a program can use, for running code that can't be trusted, which could
+// otherwise run forever or use all the memory. Both the VM and the interpreter count the steps
+// they run: instructions in the VM, and expressions in the interpreter. They also count the
+// objects they create, and check the size of every collection before it's created, as do the
+// built-in functions.
+//
+// The limits are part of the configuration of each VM and interpreter, which keep the resources
+// used by the program they run in a `Meter`. Built-in functions and the interpreter don't have
+// access to the VM or the interpreter that called them, so the meter is made active while its
+// program runs, and they count the resources they use in the active meter. When no meter is
+// active, there are no limits. Reaching a limit stops the program with an error that can't be
+// caught.
+
+use crate::error::RuntimeError;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Limits on the resources used by programs. `None` means there is no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// The most instructions the VM can execute, or expressions the interpreter can evaluate.
+    pub max_steps: Option<u64>,
+    /// How long a program can run for.
+    pub timeout: Option<Duration>,
+    /// The most elements an array or a hash can have, or bytes a string can have.
+    pub max_collection_size: Option<usize>,
+    /// The most objects a program can create. Arrays, hashes and strings created by operators and
+    /// built-in functions count, and so do functions, generators, structs and errors.
+    pub max_allocations: Option<u64>,
+    /// The most nested function calls in the interpreter, past which calls fail with a stack
+    /// overflow error instead of overflowing the native stack. The VM's stack has a fixed size.
+    pub max_call_depth: usize,
+}
+
+/// The default maximum number of nested function calls in the interpreter. This allows at least
+/// as much recursion as the VM, whose stack fits a few hundred frames of a typical function.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 2048;
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_steps: None,
+            timeout: None,
+            max_collection_size: None,
+            max_allocations: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
+}
+
+// The most steps that can run between checks of the step limit and the deadline, since reading the
+// clock is slow
+const CHECK_INTERVAL: u64 = 4096;
+
+/// Counts the resources used by a program, and checks them against its limits.
+#[derive(Debug)]
+pub struct Meter {
+    limits: Limits,
+    // The steps counted so far, including the ones that were handed out and haven't run yet
+    steps: Cell<u64>,
+    allocations: Cell<u64>,
+    deadline: Cell<Option<Instant>>,
+    // The steps the interpreter can run before it has to check the limits again
+    fuel: Cell<u64>,
+    // The number of nested function calls being evaluated by the interpreter
+    call_depth: Cell<usize>,
+}
+
+thread_local! {
+    // The meter of the program that is running in the current thread
+    static ACTIVE: RefCell<Option<Rc<Meter>>> = const { RefCell::new(None) };
+}
+
+impl Meter {
+    /// Creates a meter, which starts counting the resources used from now.
+    pub fn new(limits: Limits) -> Meter {
+        let meter = Meter {
+            limits,
+            steps: Cell::new(0),
+            allocations: Cell::new(0),
+            deadline: Cell::new(None),
+            fuel: Cell::new(0),
+            call_depth: Cell::new(0),
+        };
+        meter.start();
+        meter
+    }
+
+    /// Starts counting the resources used by a new program from zero.
+    pub fn start(&self) {
+        self.steps.set(0);
+        self.allocations.set(0);
+        let deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.deadline.set(deadline);
+        self.fuel.set(0);
+    }
+
+    /// Makes this the active meter until the returned guard is dropped, when the meter that was
+    /// active before is restored.
+    pub fn activate(self: &Rc<Self>) -> ActiveGuard {
+        let previous = ACTIVE.with(|active| active.replace(Some(Rc::clone(self))));
+        ActiveGuard { previous }
+    }
+
+    /// Checks the step limit and the deadline, and returns how many steps can run before they
+    /// have to be checked again. Those steps are counted as if they had already run.
+    pub fn refuel(&self) -> Result<u64, RuntimeError> {
+        if let (Some(deadline), Some(timeout)) = (self.deadline.get(), self.limits.timeout) {
+            if Instant::now() >= deadline {
+                return Err(RuntimeError::Timeout(timeout));
+            }
+        }
+        let steps = self.steps.get();
+        let fuel = match self.limits.max_steps {
+            Some(max) if steps >= max => return Err(RuntimeError::StepLimitExceeded(max)),
+            Some(max) => CHECK_INTERVAL.min(max - steps),
+            None => CHECK_INTERVAL,
+        };
+        self.steps.set(steps + fuel);
+        Ok(fuel)
+    }
+
+    /// Counts a step of the interpreter.
+    pub fn step(&self) -> Result<(), RuntimeError> {
+        let fuel = match self.fuel.get() {
+            0 => self.refuel()?,
+            fuel => fuel,
+        };
+        self.fuel.set(fuel - 1);
+        Ok(())
+    }
+
+    /// Counts a new object, which is a collection with `size` elements, or 0 if it isn't a
+    /// collection. Should be called before the object is created.
+    pub fn allocate(&self, size: usize) -> Result<(), RuntimeError> {
+        self.check_size(size)?;
+        match self.limits.max_allocations {
+            Some(max) if self.allocations.get() >= max => {
+                Err(RuntimeError::AllocationLimitExceeded(max))
+            }
+            _ => {
+                self.allocations.set(self.allocations.get() + 1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts a nested call of the interpreter, which fails if there are already
+    /// `max_call_depth` of them. Every successful call must be followed by `exit_call`.
+    pub fn enter_call(&self) -> Result<(), RuntimeError> {
+        let depth = self.call_depth.get();
+        if depth >= self.limits.max_call_depth {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.call_depth.set(depth + 1);
+        Ok(())
+    }
+
+    /// Counts the end of a call counted by `enter_call`.
+    pub fn exit_call(&self) {
+        self.call_depth.set(self.call_depth.get() - 1);
+    }
+
+    /// Checks the size of a collection that grew, or that is about to be created.
+    pub fn check_size(&self, size: usize) -> Result<(), RuntimeError> {
+        match self.limits.max_collection_size {
+            Some(max) if size > max => Err(RuntimeError::CollectionTooLarge(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Restores the meter that was active before, when dropped.
+pub struct ActiveGuard {
+    previous: Option<Rc<Meter>>,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        ACTIVE.with(|active| *active.borrow_mut() = previous);
+    }
+}
+
+/// Returns the active meter, or a meter without limits if there isn't one.
+pub fn active() -> Rc<Meter> {
+    let active = ACTIVE.with(|active| active.borrow().clone());
+    active.unwrap_or_else(|| Rc::new(Meter::new(Limits::default())))
+}
+
+// Runs `f` with the active meter, if there is one
+fn with_active(f: impl FnOnce(&Meter) -> Result<(), RuntimeError>) -> Result<(), RuntimeError> {
+    ACTIVE.with(|active| match &*active.borrow() {
+        Some(meter) => f(meter),
+        None => Ok(()),
+    })
+}
+
+/// Counts a step of the interpreter in the active meter.
+pub fn step() -> Result<(), RuntimeError> {
+    with_active(Meter::step)
+}
+
+/// Counts a new object in the active meter. See `Meter::allocate`.
+pub fn allocate(size: usize) -> Result<(), RuntimeError> {
+    with_active(|meter| meter.allocate(size))
+}
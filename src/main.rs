@@ -2,29 +2,74 @@
 #[macro_use]
 mod test_utils;
 
+mod bench;
 mod builtins;
 mod compiler;
 mod error;
+mod gc;
 mod interpreter;
 mod lexer;
+mod limits;
+mod module;
 mod object;
 mod parser;
 mod repl;
 mod vm;
 
+use compiler::code::Bytecode;
+use compiler::optimize::OptLevel;
 use error::MonkeyError;
-use std::fs::File;
-use std::io::BufReader;
+use limits::Limits;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
 fn main() {
-    let mut args = std::env::args();
-    let first = args.nth(1);
+    let mut args: Vec<_> = std::env::args().skip(1).collect();
+    let mut opt_level = OptLevel::Full;
+    if let Some(level) = take_option(&mut args, "--opt-level") {
+        opt_level = OptLevel::from_number(&level).expect("`--opt-level` expects 0, 1 or 2");
+    }
+    let timeout = take_option(&mut args, "--timeout").map(|seconds| {
+        let timeout = seconds.parse().ok();
+        let timeout = timeout.and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+        timeout.expect("`--timeout` expects a number of seconds")
+    });
+    let limits = Limits {
+        max_steps: take_number(&mut args, "--max-steps"),
+        timeout,
+        max_collection_size: take_number(&mut args, "--max-collection-size"),
+        max_allocations: take_number(&mut args, "--max-allocations"),
+        max_call_depth: take_number(&mut args, "--max-depth")
+            .unwrap_or(limits::DEFAULT_MAX_CALL_DEPTH),
+    };
+    let mut args = args.into_iter();
+    let first = args.next();
     let second = args.next();
     let r = match (first.as_deref(), second.as_deref()) {
-        (Some("-c"), None) | (None, _) => repl::start(true),
-        (Some("-i"), None) => repl::start(false),
-        (Some(path), None) | (Some("-c"), Some(path)) => run_program_file(true, path.into()),
-        (Some("-i"), Some(path)) => run_program_file(false, path.into()),
+        (Some("-c"), None) | (None, _) => repl::start(true, opt_level, limits),
+        (Some("-i"), None) => {
+            return run_in_interpreter_thread(limits, move || repl::start(false, opt_level, limits))
+        }
+        (Some("--compile"), Some(path)) => compile_file(path, opt_level),
+        (Some("--disassemble"), Some(path)) => disassemble_file(path, opt_level),
+        (Some("--bench"), baseline) => {
+            let baseline = baseline.map(String::from);
+            return run_in_interpreter_thread(limits, move || {
+                bench::run(baseline.as_deref().map(Path::new), opt_level)
+            });
+        }
+        (Some(path), None) | (Some("-c"), Some(path)) => {
+            run_program_file(true, path.into(), opt_level, limits)
+        }
+        (Some("-i"), Some(path)) => {
+            let path = path.to_string();
+            return run_in_interpreter_thread(limits, move || {
+                run_program_file(false, path, opt_level, limits)
+            });
+        }
         (Some(_), Some(_)) => panic!("Wrong arguments"),
     };
     if let Err(e) = r {
@@ -32,18 +77,94 @@ fn main() {
     }
 }
 
-fn run_program_file(compiled: bool, path: String) -> Result<(), MonkeyError> {
-    let reader = BufReader::new(File::open(path)?);
-    let lexer = lexer::Lexer::new(Box::new(reader))?;
-    let parsed_program = parser::Parser::new(lexer)?.parse_program()?;
+// The interpreter evaluates programs recursively, so it runs in a thread with a stack big enough
+// for the call depth limit. That way, deep recursion fails with a stack overflow error when the
+// limit is reached, instead of crashing the process.
+fn run_in_interpreter_thread(
+    limits: Limits,
+    run: impl FnOnce() -> Result<(), MonkeyError> + Send + 'static,
+) {
+    let child = std::thread::Builder::new()
+        .stack_size(interpreter::stack_size(limits.max_call_depth))
+        .spawn(move || {
+            if let Err(e) = run() {
+                eprintln!("{}", e)
+            }
+        })
+        .expect("Failed to spawn the interpreter thread");
+    if child.join().is_err() {
+        std::process::exit(101);
+    }
+}
+
+// Removes a flag and the value after it from the arguments, and returns the value
+fn take_option(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    let value = args.get(i + 1).cloned();
+    args.drain(i..(i + 2).min(args.len()));
+    Some(value.unwrap_or_default())
+}
+
+// Removes a flag that expects a positive integer from the arguments, and returns the integer
+fn take_number<T: FromStr>(args: &mut Vec<String>, flag: &str) -> Option<T> {
+    let number = take_option(args, flag)?.parse().ok();
+    Some(number.unwrap_or_else(|| panic!("`{}` expects a positive integer", flag)))
+}
+
+fn run_program_file(
+    compiled: bool,
+    path: String,
+    opt_level: OptLevel,
+    limits: Limits,
+) -> Result<(), MonkeyError> {
+    let is_bytecode = Path::new(&path).extension() == Some("mbc".as_ref());
     if compiled {
-        let mut comp = compiler::Compiler::new();
-        comp.compile_block(parsed_program)?;
-        let code = comp.bytecode();
-        let mut vm = vm::VM::new();
+        let code = if is_bytecode {
+            Bytecode::deserialize(&fs::read(&path)?)?
+        } else {
+            compile_source_file(&path, opt_level)?
+        };
+        let mut vm = vm::VM::with_limits(limits);
         vm.run(code)?;
     } else {
-        interpreter::run_program(parsed_program)?;
+        if is_bytecode {
+            let message = "the interpreter can't run bytecode files";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+        let reader = BufReader::new(File::open(&path)?);
+        let lexer = lexer::Lexer::new(Box::new(reader))?;
+        let parsed_program = parser::Parser::new(lexer)?.parse_program()?;
+        module::set_main_file(Path::new(&path))?;
+        interpreter::run_program(parsed_program, limits)?;
     }
     Ok(())
 }
+
+fn compile_source_file(path: &str, opt_level: OptLevel) -> Result<Bytecode, MonkeyError> {
+    let reader = BufReader::new(File::open(path)?);
+    let lexer = lexer::Lexer::new(Box::new(reader))?;
+    let parsed_program = parser::Parser::new(lexer)?.parse_program()?;
+    let mut comp = compiler::Compiler::with_file(Path::new(path))?;
+    comp.set_opt_level(opt_level);
+    comp.compile_block(parsed_program)?;
+    Ok(comp.bytecode())
+}
+
+/// Compiles a source file into a bytecode file next to it, with the `.mbc` extension.
+fn compile_file(path: &str, opt_level: OptLevel) -> Result<(), MonkeyError> {
+    let code = compile_source_file(path, opt_level)?;
+    fs::write(Path::new(path).with_extension("mbc"), code.serialize())?;
+    Ok(())
+}
+
+/// Prints the disassembly of a source file or a bytecode file.
+fn disassemble_file(path: &str, opt_level: OptLevel) -> Result<(), MonkeyError> {
+    let disassembly = if Path::new(path).extension() == Some("mbc".as_ref()) {
+        compiler::disassemble::disassemble(&Bytecode::deserialize(&fs::read(path)?)?, None)
+    } else {
+        let code = compile_source_file(path, opt_level)?;
+        compiler::disassemble::disassemble(&code, Some(&fs::read_to_string(path)?))
+    };
+    print!("{}", disassembly);
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use crate::error::*;
+use crate::interpreter;
+use crate::lexer::Lexer;
+use crate::limits;
+use crate::object::{HashableObject, Object};
+use crate::parser::{ast::NodeStatement, Parser};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+thread_local! {
+    // The files currently being run by the interpreter, with the innermost import last. Used to
+    // resolve relative import paths and to detect import cycles.
+    static IMPORT_STACK: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+
+    // The modules already imported by the interpreter, by canonical path.
+    static MODULE_CACHE: RefCell<HashMap<PathBuf, Object>> = RefCell::new(HashMap::new());
+}
+
+/// Resolves an import path relative to the directory of the importing file, or to the current
+/// directory if there is no importing file (like in the REPL). Returns the canonical path, which
+/// is used to identify the module.
+pub fn resolve(path: &str, importer: Option<&Path>) -> Result<PathBuf, ImportError> {
+    let base = importer
+        .and_then(Path::parent)
+        .unwrap_or_else(|| Path::new(""));
+    base.join(path)
+        .canonicalize()
+        .map_err(|e| ImportError::InFile(path.into(), Box::new(e.into())))
+}
+
+/// Returns an error if importing `path` from the innermost file in `stack` would create an import
+/// cycle.
+pub fn check_cycle(path: &Path, stack: &[PathBuf]) -> Result<(), ImportError> {
+    if let Some(start) = stack.iter().position(|p| p == path) {
+        let chain = stack[start..]
+            .iter()
+            .chain(std::iter::once(&path.to_path_buf()))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(ImportError::Cycle(chain));
+    }
+    Ok(())
+}
+
+/// Lexes and parses a file. Errors are wrapped in an `ImportError`, since their positions refer
+/// to the imported file.
+pub fn parse_file(path: &Path) -> Result<Vec<NodeStatement>, ImportError> {
+    let parse = || -> MonkeyResult<_> {
+        let reader = BufReader::new(File::open(path)?);
+        let lexer = Lexer::new(Box::new(reader))?;
+        Parser::new(lexer)?.parse_program()
+    };
+    parse().map_err(|e| ImportError::InFile(path.display().to_string(), Box::new(e)))
+}
+
+/// Sets the file the interpreter is running, so relative imports are resolved from its directory.
+pub fn set_main_file(path: &Path) -> io::Result<()> {
+    let path = path.canonicalize()?;
+    IMPORT_STACK.with(|stack| *stack.borrow_mut() = vec![path]);
+    Ok(())
+}
+
+/// Imports a module using the interpreter. The module is evaluated in a new environment, and the
+/// result is a hash of its top-level bindings. Each module is only evaluated once, and later
+/// imports return the cached result.
+pub fn import(path: &str) -> Result<Object, RuntimeError> {
+    let importer = IMPORT_STACK.with(|stack| stack.borrow().last().cloned());
+    let path = resolve(path, importer.as_deref())?;
+    if let Some(module) = MODULE_CACHE.with(|cache| cache.borrow().get(&path).cloned()) {
+        return Ok(module);
+    }
+    IMPORT_STACK.with(|stack| check_cycle(&path, &stack.borrow()))?;
+
+    let program = parse_file(&path)?;
+    IMPORT_STACK.with(|stack| stack.borrow_mut().push(path.clone()));
+    let result = eval_module(program);
+    IMPORT_STACK.with(|stack| stack.borrow_mut().pop());
+
+    let module =
+        result.map_err(|e| ImportError::InFile(path.display().to_string(), Box::new(e)))?;
+    MODULE_CACHE.with(|cache| cache.borrow_mut().insert(path, module.clone()));
+    Ok(module)
+}
+
+fn eval_module(program: Vec<NodeStatement>) -> MonkeyResult<Object> {
+    let mut globals = interpreter::Globals::with_meter(limits::active());
+    for statement in program {
+        globals.eval_statement(statement)?;
+    }
+    let bindings = globals
+        .bindings()
+        .into_iter()
+        .map(|(name, value)| (HashableObject::from(name.as_str()), value))
+        .collect();
+    Ok(Object::Hash(Box::new(bindings)))
+}
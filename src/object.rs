@@ -1,12 +1,19 @@
 // @WIP
 use crate::builtins::BuiltinFn;
 use crate::compiler::code;
-use crate::interpreter::environment;
+use crate::error::RuntimeError;
+use crate::interpreter::{environment, generator::InterpreterGenerator};
+use crate::limits;
 use crate::parser::ast;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::From;
 use std::fmt;
+use std::rc::Rc;
 
+// We box strings and collections because we want the object representation to be as small as
+// possible.
+#[allow(clippy::box_collection)]
 #[derive(Debug, Clone)]
 #[cfg_attr(test, derive(PartialEq))]
 pub enum Object {
@@ -18,13 +25,18 @@ pub enum Object {
     // @PERFORMANCE: We use `Box<Vec<_>>` instead of just `Vec<_>` because we want the object
     // representation to be as small as possible. Currently the size of `Object` is 16 bytes -- if
     // we used just `Vec<_>` it would be 32.
-    #[allow(clippy::box_vec)]
     Array(Box<Vec<Object>>),
     Hash(Box<HashMap<HashableObject, Object>>),
-    CompiledFunc(Box<CompiledFunction>),
-    Closure(Box<Closure>),
+    // Functions and closures are immutable, so they are shared instead of copied
+    CompiledFunc(Rc<CompiledFunction>),
+    Closure(Rc<Closure>),
     InterpreterFunc(Box<InterpreterFunctionObject>),
     Builtin(BuiltinFn),
+    StructConstructor(Rc<StructDefinition>),
+    Struct(Box<StructObject>),
+    Error(Box<ErrorObject>),
+    // Generators are shared, so resuming a generator through any reference to it advances it
+    Generator(Rc<RefCell<GeneratorObject>>),
 }
 
 impl fmt::Display for Object {
@@ -63,6 +75,31 @@ impl fmt::Display for Object {
                 write!(f, "<function>")
             }
             Object::Builtin(_) => write!(f, "<built-in function>"),
+            Object::Generator(_) => write!(f, "<generator>"),
+            Object::StructConstructor(def) => write!(f, "<struct {}>", def.name),
+            Object::Struct(s) => {
+                if s.values.is_empty() {
+                    return write!(f, "{} {{}}", s.definition.name);
+                }
+
+                write!(f, "{} {{ ", s.definition.name)?;
+                let fields = s.definition.fields.iter().zip(s.values.iter());
+                for (i, (field, value)) in fields.enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", field, value)?;
+                }
+                write!(f, " }}")
+            }
+            Object::Error(e) => {
+                write!(f, "error(\"{}\"", e.message.escape_debug())?;
+                if let Object::Nil = e.data {
+                    write!(f, ")")
+                } else {
+                    write!(f, ", {})", e.data)
+                }
+            }
         }
     }
 }
@@ -77,17 +114,75 @@ impl Object {
             Str(_) => "string",
             Array(_) => "array",
             Hash(_) => "hash",
-            CompiledFunc(_) | Closure(_) | InterpreterFunc(_) | Builtin(_) => "function",
+            CompiledFunc(_) | Closure(_) | InterpreterFunc(_) | Builtin(_)
+            | StructConstructor(_) => "function",
+            Struct(_) => "struct",
+            Error(_) => "error",
+            Generator(_) => "generator",
         }
     }
 
-    pub fn is_truthy(&self) -> bool {
+    /// Creates an instance of the `Exception` struct, which is the value caught by a `try`
+    /// expression when a runtime error happens.
+    pub fn exception(error_type: &str, message: String) -> Object {
+        let definition = StructDefinition {
+            name: "Exception".into(),
+            fields: vec!["type".into(), "message".into()],
+        };
+        let values = vec![Object::from(error_type), Object::Str(Box::new(message))];
+        Object::Struct(Box::new(StructObject {
+            definition: Rc::new(definition),
+            values,
+        }))
+    }
+
+    /// Returns the value of a field, if the object is a struct instance that has that field. Error
+    /// values have the fields of exceptions, `type` and `message`, and their `data`.
+    pub fn get_field(&self, field: &str) -> Result<Object, RuntimeError> {
         match self {
-            Object::Boolean(false) | Object::Nil | Object::Integer(0) => false,
-            _ => true,
+            Object::Struct(s) => {
+                let index = s.field_index(field)?;
+                Ok(s.values[index].clone())
+            }
+            Object::Error(e) => match field {
+                "type" => Ok(Object::from("Error")),
+                "message" => Ok(Object::from(e.message.as_str())),
+                "data" => Ok(e.data.clone()),
+                _ => Err(RuntimeError::UnknownField("error".into(), field.into())),
+            },
+            other => Err(RuntimeError::NoFields(other.type_str())),
+        }
+    }
+
+    /// Returns a copy of the struct instance with the value of one field replaced. Used by the
+    /// functional update syntax, `obj.field = value`.
+    pub fn with_field(self, field: &str, value: Object) -> Result<Object, RuntimeError> {
+        match self {
+            Object::Struct(mut s) => {
+                let index = s.field_index(field)?;
+                s.values[index] = value;
+                Ok(Object::Struct(s))
+            }
+            other => Err(RuntimeError::NoFields(other.type_str())),
         }
     }
 
+    /// Converts the object to a string, following the `Display` rules, except that strings are not
+    /// quoted or escaped. Used in string interpolation and by the `str` built-in.
+    pub fn stringify(&self) -> String {
+        match self {
+            Object::Str(s) => (**s).clone(),
+            other => other.to_string(),
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !matches!(
+            self,
+            Object::Boolean(false) | Object::Nil | Object::Integer(0)
+        )
+    }
+
     pub fn eq(left: &Object, right: &Object) -> Option<bool> {
         // Function, array, and hash comparisons are unsupported
         match (left, right) {
@@ -103,7 +198,7 @@ impl Object {
 impl From<&str> for Object {
     fn from(s: &str) -> Self {
         Object::Str(Box::new(s.into()))
-    }    
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -117,8 +212,101 @@ pub struct CompiledFunction {
 #[derive(Debug, Clone)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct Closure {
-    pub func: CompiledFunction,
+    pub func: Rc<CompiledFunction>,
     pub free_vars: Vec<Object>,
+    // The most values the function can have on the stack at once, found by the verifier
+    pub max_depth: usize,
+}
+
+/// The definition of a struct type, created by a `struct` declaration. The constructor and every
+/// instance of the struct share the same definition.
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct StructDefinition {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+impl StructDefinition {
+    /// Creates a new instance of the struct. The values must be in the same order as the fields
+    /// were declared.
+    pub fn construct(self: &Rc<Self>, values: Vec<Object>) -> Result<Object, RuntimeError> {
+        if values.len() != self.fields.len() {
+            return Err(RuntimeError::WrongNumberOfArgs(
+                self.fields.len(),
+                values.len(),
+            ));
+        }
+        limits::allocate(0)?;
+        Ok(Object::Struct(Box::new(StructObject {
+            definition: Rc::clone(self),
+            values,
+        })))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct StructObject {
+    pub definition: Rc<StructDefinition>,
+    pub values: Vec<Object>,
+}
+
+impl StructObject {
+    fn field_index(&self, field: &str) -> Result<usize, RuntimeError> {
+        self.definition
+            .fields
+            .iter()
+            .position(|f| f == field)
+            .ok_or_else(|| RuntimeError::UnknownField(self.definition.name.clone(), field.into()))
+    }
+}
+
+/// A recoverable error value, created by the `error` built-in. Functions can return errors like
+/// any other value, and the `?` operator returns them early from the enclosing function.
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ErrorObject {
+    pub message: String,
+    // Optional data attached to the error. `nil` if there is none
+    pub data: Object,
+}
+
+#[derive(Debug)]
+pub struct GeneratorObject {
+    pub state: GeneratorState,
+}
+
+#[derive(Debug)]
+pub enum GeneratorState {
+    // A generator created by the VM, paused at the start of its body or at a `yield`
+    Suspended(SuspendedFrame),
+    // A generator created by the interpreter, paused at the start of its body or at a `yield`
+    Interpreted(InterpreterGenerator),
+    // The generator is being resumed, and hasn't yielded yet
+    Running,
+    // The generator returned, or an error happened while running it
+    Done,
+}
+
+#[cfg(test)]
+impl PartialEq for GeneratorObject {
+    fn eq(&self, _: &Self) -> bool {
+        panic!("Trying to compare `GeneratorObject`s")
+    }
+}
+
+/// The state of a paused VM frame, which is restored when the generator is resumed.
+#[derive(Debug)]
+pub struct SuspendedFrame {
+    pub closure: Rc<Closure>,
+    // The position of the instruction to run when resuming
+    pub pc: usize,
+    // The part of the stack that belonged to the frame, including the arguments and locals
+    pub stack: Vec<Object>,
+    // The exception handlers registered in the frame. Holds the stack pointer, relative to the
+    // start of the frame, and the position of the handler
+    pub handlers: Vec<(usize, usize)>,
 }
 
 #[derive(Debug, Clone)]
@@ -126,6 +314,7 @@ pub struct InterpreterFunctionObject {
     pub environment: environment::EnvHandle,
     pub parameters: Vec<String>,
     pub body: Vec<ast::NodeStatement>,
+    pub is_generator: bool,
 }
 
 #[cfg(test)]
@@ -135,6 +324,7 @@ impl PartialEq for InterpreterFunctionObject {
     }
 }
 
+#[allow(clippy::box_collection)]
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum HashableObject {
     Nil,
@@ -166,8 +356,19 @@ impl HashableObject {
     }
 }
 
+impl From<HashableObject> for Object {
+    fn from(obj: HashableObject) -> Self {
+        match obj {
+            HashableObject::Nil => Object::Nil,
+            HashableObject::Integer(i) => Object::Integer(i),
+            HashableObject::Boolean(b) => Object::Boolean(b),
+            HashableObject::Str(s) => Object::Str(s),
+        }
+    }
+}
+
 impl From<&str> for HashableObject {
     fn from(s: &str) -> Self {
         HashableObject::Str(Box::new(s.into()))
-    }    
+    }
 }
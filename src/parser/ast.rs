@@ -1,6 +1,7 @@
 use crate::lexer::token::Token;
 
 use std::fmt;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct NodeExpression {
@@ -14,34 +15,137 @@ impl fmt::Debug for NodeExpression {
     }
 }
 
+// A variable that is read by an identifier or bound by a "let" statement. Before the interpreter
+// runs the code, its resolver finds the environments the variable can be stored in, so it isn't
+// looked up by name at runtime. The compiler doesn't use them, since it has its own symbol table.
+#[derive(Clone)]
+pub struct Variable {
+    pub name: String,
+    // The environments the variable can be in, as the number of environments to go out from the
+    // current one and the index of its slot, innermost first. Its value is in the first one that
+    // has been initialized. Empty until it's resolved.
+    pub scopes: Arc<[(usize, usize)]>,
+    // The index of the built-in function with the same name, if there is one. It's used if the
+    // variable isn't initialized in any of the environments.
+    pub builtin: Option<usize>,
+}
+
+impl Variable {
+    pub fn new(name: String) -> Variable {
+        Variable {
+            name,
+            scopes: Arc::new([]),
+            builtin: None,
+        }
+    }
+}
+
+impl fmt::Debug for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.name)
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
 pub enum Expression {
-    Identifier(String),
+    Identifier(Variable),
     IntLiteral(i64),
     StringLiteral(String),
+    // The parts of an interpolated string are string literals and arbitrary expressions, which are
+    // converted to strings and concatenated at runtime.
+    InterpolatedString(Vec<NodeExpression>),
     Boolean(bool),
     ArrayLiteral(Vec<NodeExpression>),
-    HashLiteral(Vec<(NodeExpression, NodeExpression)>),
+    HashLiteral(Vec<HashEntry>),
     IndexExpression(Box<NodeExpression>, Box<NodeExpression>),
+    // Optional indexing (`obj?[index]`) evaluates to nil if the object is nil or if the index or
+    // key is not present.
+    OptionalIndexExpression(Box<NodeExpression>, Box<NodeExpression>),
     PrefixExpression(Token, Box<NodeExpression>),
     InfixExpression(Box<NodeExpression>, Token, Box<NodeExpression>),
+    // `left ?? right` only evaluates the right side if the left side is nil.
+    NilCoalescing(Box<NodeExpression>, Box<NodeExpression>),
     BlockExpression(Vec<NodeStatement>),
     IfExpression {
         condition: Box<NodeExpression>,
         consequence: Vec<NodeStatement>,
         alternative: Vec<NodeStatement>,
     },
+    // `try { body } catch name { handler }` evaluates to the value of the body, or, if an error is
+    // raised or a value is thrown while evaluating the body, to the value of the handler, with the
+    // exception bound to `name`.
+    TryExpression {
+        body: Vec<NodeStatement>,
+        name: String,
+        handler: Vec<NodeStatement>,
+    },
     FunctionLiteral {
         parameters: Vec<String>,
         body: Vec<NodeStatement>,
     },
+    // Calling a generator function (`fn*(params) { body }`) creates a generator, which runs the
+    // body lazily, pausing at every `yield` statement.
+    GeneratorLiteral {
+        parameters: Vec<String>,
+        body: Vec<NodeStatement>,
+    },
+    // `for name in iterable { body }` runs the body for every element of an array, character of a
+    // string, or value yielded by a generator. Evaluates to nil.
+    ForExpression {
+        name: String,
+        iterable: Box<NodeExpression>,
+        body: Vec<NodeStatement>,
+    },
     CallExpression {
         function: Box<NodeExpression>,
         arguments: Vec<NodeExpression>,
     },
+    // Struct declarations (`struct Name { fields }`) are parsed as a let statement that binds the
+    // struct name to this expression, which evaluates to the struct constructor.
+    StructDeclaration {
+        name: String,
+        fields: Vec<String>,
+    },
+    FieldAccess(Box<NodeExpression>, String),
+    // Method calls (`obj.method(args)`) call a struct field, or a built-in function with the object
+    // as the first argument.
+    MethodCall {
+        object: Box<NodeExpression>,
+        method: String,
+        arguments: Vec<NodeExpression>,
+    },
+    // Functional update of a struct field (`obj.field = value`). Evaluates to a copy of the struct
+    // with the field replaced, and leaves the original struct untouched.
+    FieldUpdate {
+        object: Box<NodeExpression>,
+        field: String,
+        value: Box<NodeExpression>,
+    },
+    // `value?` evaluates to `value`, unless it's an error, in which case the enclosing function
+    // returns the error.
+    Propagate(Box<NodeExpression>),
+    // Spread expressions (`..expr`) are only produced by the parser inside array literals and call
+    // argument lists.
+    Spread(Box<NodeExpression>),
     Nil,
 }
 
+#[derive(Clone)]
+pub enum HashEntry {
+    Pair(NodeExpression, NodeExpression),
+    Spread(NodeExpression),
+}
+
+impl fmt::Debug for HashEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashEntry::Pair(key, value) => write!(f, "({:?}, {:?})", key, value),
+            HashEntry::Spread(exp) => write!(f, "Spread({:?})", exp),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct NodeStatement {
     pub position: (usize, usize),
@@ -53,11 +157,14 @@ impl fmt::Debug for NodeStatement {
         write!(f, "{:?}", self.statement)
     }
 }
-pub type LetStatement = (String, NodeExpression);
+pub type LetStatement = (Variable, NodeExpression);
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
 pub enum Statement {
     Let(Box<LetStatement>),
     Return(Box<NodeExpression>),
+    Throw(Box<NodeExpression>),
+    Yield(Box<NodeExpression>),
     ExpressionStatement(Box<NodeExpression>),
 }
@@ -3,9 +3,13 @@ pub mod ast;
 mod tests;
 
 use crate::error::*;
-use crate::lexer::{token::Token, Lexer};
+use crate::lexer::{
+    token::{StrPart, Token},
+    Lexer,
+};
 use ast::*;
 
+use std::io::Cursor;
 use std::mem;
 
 pub fn parse(input: String) -> MonkeyResult<Vec<NodeStatement>> {
@@ -20,8 +24,14 @@ type InfixParseFn = fn(&mut Parser, Box<NodeExpression>) -> MonkeyResult<NodeExp
 #[derive(PartialEq, PartialOrd)]
 enum Precedence {
     Lowest,
+    NilCoalesce,
+    Pipe,
     Equals,
     LessGreater,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Sum,
     Product,
     Exponent,
@@ -35,6 +45,8 @@ pub struct Parser {
     current_token: Token,
     peek_token: Token,
     position: (usize, usize),
+    // Whether the parser is in the body of a generator function, where `yield` is allowed
+    in_generator: bool,
 }
 
 impl Parser {
@@ -47,6 +59,7 @@ impl Parser {
             current_token,
             peek_token,
             position,
+            in_generator: false,
         })
     }
 
@@ -152,6 +165,24 @@ impl Parser {
                 let exp = Box::new(self.parse_return_statement()?);
                 Statement::Return(exp)
             }
+            Token::Throw => {
+                let exp = Box::new(self.parse_throw_statement()?);
+                Statement::Throw(exp)
+            }
+            Token::Yield if self.in_generator => {
+                let exp = Box::new(self.parse_return_statement()?);
+                Statement::Yield(exp)
+            }
+            Token::Yield => {
+                return Err(MonkeyError::Parser(
+                    position,
+                    ParserError::YieldOutsideGenerator,
+                ))
+            }
+            Token::Struct => {
+                let let_st = Box::new(self.parse_struct_declaration()?);
+                Statement::Let(let_st)
+            }
             _ => {
                 let exp = Box::new(self.parse_expression_statement()?);
                 Statement::ExpressionStatement(exp)
@@ -178,7 +209,7 @@ impl Parser {
             // At this point, self.current_token is the first token in the expression
             let value = self.parse_expression(Precedence::Lowest)?;
             self.expect_token_or_eof(Token::Semicolon)?;
-            Ok((identifier, value))
+            Ok((Variable::new(identifier), value))
         } else {
             Err(MonkeyError::Parser(
                 self.position,
@@ -190,9 +221,58 @@ impl Parser {
         }
     }
 
+    /// Parses a struct declaration, like `struct Point { x, y }`. The declaration is parsed as a
+    /// "let" statement that binds the struct name to the struct constructor. Field names are
+    /// separated by commas, and a trailing comma is allowed. The semicolon after the closing "}"
+    /// is optional. Doesn't check if `self.current_token` is a "struct" token.
+    fn parse_struct_declaration(&mut self) -> MonkeyResult<LetStatement> {
+        let position = self.position;
+        let name = self.expect_identifier()?;
+        self.expect_token(Token::OpenCurlyBrace)?;
+
+        let mut fields: Vec<String> = Vec::new();
+        while self.peek_token != Token::CloseCurlyBrace {
+            let field = self.expect_identifier()?;
+            if fields.contains(&field) {
+                return Err(MonkeyError::Parser(
+                    self.position,
+                    ParserError::DuplicateField(field),
+                ));
+            }
+            fields.push(field);
+            if self.peek_token != Token::CloseCurlyBrace {
+                self.expect_token(Token::Comma)?;
+            }
+        }
+        self.expect_token(Token::CloseCurlyBrace)?;
+        self.consume_optional_token(Token::Semicolon)?;
+
+        let value = NodeExpression {
+            position,
+            expression: Expression::StructDeclaration {
+                name: name.clone(),
+                fields,
+            },
+        };
+        Ok((Variable::new(name), value))
+    }
+
+    /// Reads the next token, and returns its name if it's an identifier. Otherwise, returns an
+    /// error.
+    fn expect_identifier(&mut self) -> MonkeyResult<String> {
+        self.read_token()?;
+        match &self.current_token {
+            Token::Identifier(iden) => Ok(iden.clone()),
+            other => Err(MonkeyError::Parser(
+                self.position,
+                ParserError::UnexpectedToken(Token::Identifier("".into()), other.clone()),
+            )),
+        }
+    }
+
     /// Parses a "return" statement. Expects a valid expression, and returns an error if its
     /// parsing fails. Doesn't check if `self.current_token` is a "return" token.  Must end in a
-    /// semicolon.
+    /// semicolon. Also used for "yield" statements, which have the same syntax.
     fn parse_return_statement(&mut self) -> MonkeyResult<NodeExpression> {
         let return_value = if self.peek_token == Token::Semicolon {
             // In case of no return value, we return nil
@@ -208,18 +288,31 @@ impl Parser {
         Ok(return_value)
     }
 
+    /// Parses a "throw" statement. Expects a valid expression, and returns an error if its parsing
+    /// fails. Doesn't check if `self.current_token` is a "throw" token. Must end in a semicolon.
+    fn parse_throw_statement(&mut self) -> MonkeyResult<NodeExpression> {
+        self.read_token()?; // Read first token from the expression
+        let value = self.parse_expression(Precedence::Lowest)?;
+        self.expect_token_or_eof(Token::Semicolon)?;
+        Ok(value)
+    }
+
     /// Parses an expression statement, returns an error if parsing fails.  Must end in a
     /// semicolon, unless either:
-    /// * The expression is an "if" expression, a function literal or a block expression.
+    /// * The expression is an "if", "try" or "for" expression, a function or generator literal, or
+    ///   a block expression.
     /// * The first token after the expression is a "}" token, meaning the expression is the last
-    /// expression in the current block.
+    ///   expression in the current block.
     fn parse_expression_statement(&mut self) -> MonkeyResult<NodeExpression> {
         let exp = self.parse_expression(Precedence::Lowest)?;
         match exp.expression {
             Expression::IfExpression { .. }
+            | Expression::TryExpression { .. }
+            | Expression::ForExpression { .. }
             | Expression::FunctionLiteral { .. }
+            | Expression::GeneratorLiteral { .. }
             | Expression::BlockExpression { .. } => {
-                // In these three cases, the semicolon is optional
+                // In these cases, the semicolon is optional
                 self.consume_optional_token(Token::Semicolon)?
             }
             _ => {
@@ -291,7 +384,7 @@ impl Parser {
     fn parse_prefix_expression(&mut self) -> MonkeyResult<NodeExpression> {
         let position = self.position;
         match &self.current_token {
-            Token::Bang | Token::Minus => {
+            Token::Bang | Token::Minus | Token::Tilde => {
                 let operator = self.current_token.clone();
                 self.read_token()?;
                 let right_side = Box::new(self.parse_expression(Precedence::Prefix)?);
@@ -358,19 +451,77 @@ impl Parser {
         })
     }
 
+    /// Parses a "try" expression. These are composed of the "try" keyword and a block of
+    /// statements, followed by the "catch" keyword, an identifier to bind the exception to, and
+    /// another block of statements. May return an error if parsing fails at any point. Doesn't
+    /// check if `self.current_token` is a "try" token.
+    fn parse_try_expression(&mut self) -> MonkeyResult<NodeExpression> {
+        let position = self.position;
+        self.expect_token(Token::OpenCurlyBrace)?;
+        let body = self.parse_block_statement()?;
+        self.expect_token(Token::Catch)?;
+        let name = self.expect_identifier()?;
+        self.expect_token(Token::OpenCurlyBrace)?;
+        let handler = self.parse_block_statement()?;
+        Ok(NodeExpression {
+            position,
+            expression: Expression::TryExpression {
+                body,
+                name,
+                handler,
+            },
+        })
+    }
+
     /// Parses a function literal. Expects a valid function parameter list enclosed by parentheses,
-    /// followed by a block of statements. May return an error if parsing fails. Doesn't check if
+    /// followed by a block of statements. If the "fn" token is followed by a "*", the function is
+    /// a generator function. May return an error if parsing fails. Doesn't check if
     /// `self.current_token` is an "fn" token.
     fn parse_function_literal(&mut self) -> MonkeyResult<NodeExpression> {
         let position = self.position;
+        let is_generator = self.peek_token == Token::Asterisk;
+        if is_generator {
+            self.read_token()?;
+        }
         self.expect_token(Token::OpenParen)?;
         let parameters = self.parse_function_parameters()?;
         self.expect_token(Token::OpenCurlyBrace)?;
-        let body = self.parse_block_statement()?;
 
+        // `yield` statements are only allowed directly in the body of a generator function, not in
+        // nested functions
+        let outer_in_generator = mem::replace(&mut self.in_generator, is_generator);
+        let body = self.parse_block_statement();
+        self.in_generator = outer_in_generator;
+        let body = body?;
+
+        let expression = if is_generator {
+            Expression::GeneratorLiteral { parameters, body }
+        } else {
+            Expression::FunctionLiteral { parameters, body }
+        };
         Ok(NodeExpression {
             position,
-            expression: Expression::FunctionLiteral { parameters, body },
+            expression,
+        })
+    }
+
+    /// Parses a "for" expression, like `for x in iterable { body }`. May return an error if parsing
+    /// fails at any point. Doesn't check if `self.current_token` is a "for" token.
+    fn parse_for_expression(&mut self) -> MonkeyResult<NodeExpression> {
+        let position = self.position;
+        let name = self.expect_identifier()?;
+        self.expect_token(Token::In)?;
+        self.read_token()?; // Read first token from the iterable expression
+        let iterable = self.parse_expression(Precedence::Lowest)?;
+        self.expect_token(Token::OpenCurlyBrace)?;
+        let body = self.parse_block_statement()?;
+        Ok(NodeExpression {
+            position,
+            expression: Expression::ForExpression {
+                name,
+                iterable: Box::new(iterable),
+                body,
+            },
         })
     }
 
@@ -392,6 +543,62 @@ impl Parser {
         })
     }
 
+    /// Parses a pipe expression, like `x |> f(a)`, which is lowered to a call expression with the
+    /// left side inserted as the first argument, i.e. `f(x, a)`. If the right side is not a call,
+    /// it must be an expression that may evaluate to a function, and it's called with the left side
+    /// as its only argument. Expects `self.current_token` to be a "|>" token.
+    #[allow(clippy::boxed_local)] // The signature must match `InfixParseFn`
+    fn parse_pipe_expression(&mut self, left: Box<NodeExpression>) -> MonkeyResult<NodeExpression> {
+        let position = self.position;
+        self.read_token()?;
+        let right = self.parse_expression(Precedence::Pipe)?;
+        let (function, arguments) = match right.expression {
+            Expression::CallExpression {
+                function,
+                mut arguments,
+            } => {
+                arguments.insert(0, *left);
+                (function, arguments)
+            }
+            // Method calls are not lowered to call expressions, so they are handled separately
+            Expression::MethodCall {
+                object,
+                method,
+                mut arguments,
+            } => {
+                arguments.insert(0, *left);
+                return Ok(NodeExpression {
+                    position,
+                    expression: Expression::MethodCall {
+                        object,
+                        method,
+                        arguments,
+                    },
+                });
+            }
+            Expression::Identifier(_)
+            | Expression::FieldAccess(..)
+            | Expression::FunctionLiteral { .. }
+            | Expression::IndexExpression(..)
+            | Expression::OptionalIndexExpression(..)
+            | Expression::BlockExpression(_)
+            | Expression::IfExpression { .. } => (Box::new(right), vec![*left]),
+            _ => {
+                return Err(MonkeyError::Parser(
+                    right.position,
+                    ParserError::InvalidPipeTarget,
+                ))
+            }
+        };
+        Ok(NodeExpression {
+            position,
+            expression: Expression::CallExpression {
+                function,
+                arguments,
+            },
+        })
+    }
+
     /// Parses a function parameter list. These are a list of identifiers, enclosed by parentheses
     /// and separated by commas. There should be no trailing comma. Returns an error if the parser
     /// encounters an unexpected token while parsing. Doesn't check if `self.current_token` is an
@@ -441,7 +648,7 @@ impl Parser {
         match &self.current_token {
             Token::Identifier(s) => Ok(NodeExpression {
                 position: self.position,
-                expression: Expression::Identifier(s.clone()),
+                expression: Expression::Identifier(Variable::new(s.clone())),
             }),
             _ => panic!(),
         }
@@ -469,6 +676,43 @@ impl Parser {
         }
     }
 
+    /// Parses an interpolated string token into an interpolated string expression. The source code
+    /// of each embedded expression is lexed and parsed separately, and must consist of exactly one
+    /// expression.
+    fn parse_interpolated_string(&mut self) -> MonkeyResult<NodeExpression> {
+        let parts = match &self.current_token {
+            Token::InterpolatedStr(parts) => parts.clone(),
+            _ => panic!(),
+        };
+        let mut expressions = Vec::with_capacity(parts.len());
+        for part in parts {
+            let exp = match part {
+                StrPart::Literal(s) => NodeExpression {
+                    position: self.position,
+                    expression: Expression::StringLiteral(s),
+                },
+                StrPart::Code(code, position) => {
+                    let reader = Box::new(Cursor::new(code.into_bytes()));
+                    let lexer = Lexer::with_start_position(reader, position)?;
+                    let mut parser = Parser::new(lexer)?;
+                    let exp = parser.parse_expression(Precedence::Lowest)?;
+                    if parser.peek_token != Token::EOF {
+                        return Err(MonkeyError::Parser(
+                            parser.position,
+                            ParserError::UnexpectedToken(Token::EOF, parser.peek_token),
+                        ));
+                    }
+                    exp
+                }
+            };
+            expressions.push(exp);
+        }
+        Ok(NodeExpression {
+            position: self.position,
+            expression: Expression::InterpolatedString(expressions),
+        })
+    }
+
     /// Parses a boolean token into a boolean literal expression.
     fn parse_boolean(&mut self) -> MonkeyResult<NodeExpression> {
         let value = match &self.current_token {
@@ -519,6 +763,79 @@ impl Parser {
         })
     }
 
+    /// Parses an optional indexing expression, like `hash?["key"]`. Works just like
+    /// `parse_index_expression`, but expects `self.current_token` to be a "?[" token.
+    fn parse_optional_index_expression(
+        &mut self,
+        left: Box<NodeExpression>,
+    ) -> MonkeyResult<NodeExpression> {
+        self.read_token()?; // Read first token of index expression
+        let index = self.parse_expression(Precedence::Lowest)?;
+        self.expect_token(Token::CloseSquareBracket)?;
+        Ok(NodeExpression {
+            position: self.position,
+            expression: Expression::OptionalIndexExpression(left, Box::new(index)),
+        })
+    }
+
+    /// Parses a field access expression, like `obj.field`, a functional field update, like
+    /// `obj.field = value`, or a method call, like `obj.method(args)`. The object must be already
+    /// parsed, and passed as an expression. Doesn't check if `self.current_token` is a "." token.
+    fn parse_field_access(&mut self, object: Box<NodeExpression>) -> MonkeyResult<NodeExpression> {
+        let position = self.position;
+        let field = self.expect_identifier()?;
+        let expression = match self.peek_token {
+            Token::Assign => {
+                self.read_token()?; // Read "=" token
+                self.read_token()?; // Read first token of the value expression
+                let value = self.parse_expression(Precedence::Lowest)?;
+                Expression::FieldUpdate {
+                    object,
+                    field,
+                    value: Box::new(value),
+                }
+            }
+            Token::OpenParen => {
+                self.read_token()?; // Read "(" token
+                let arguments = self.parse_expression_list(Token::CloseParen)?;
+                Expression::MethodCall {
+                    object,
+                    method: field,
+                    arguments,
+                }
+            }
+            _ => Expression::FieldAccess(object, field),
+        };
+        Ok(NodeExpression {
+            position,
+            expression,
+        })
+    }
+
+    /// Parses a nil-coalescing expression, like `a ?? b`. Takes an already parsed left side.
+    /// Expects `self.current_token` to be a "??" token.
+    fn parse_nil_coalescing(&mut self, left: Box<NodeExpression>) -> MonkeyResult<NodeExpression> {
+        let position = self.position;
+        self.read_token()?;
+        let right = self.parse_expression(Precedence::NilCoalesce)?;
+        Ok(NodeExpression {
+            position,
+            expression: Expression::NilCoalescing(left, Box::new(right)),
+        })
+    }
+
+    /// Parses a postfix error propagation expression, like `a?`. Takes an already parsed operand.
+    /// Expects `self.current_token` to be a "?" token.
+    fn parse_propagate_expression(
+        &mut self,
+        value: Box<NodeExpression>,
+    ) -> MonkeyResult<NodeExpression> {
+        Ok(NodeExpression {
+            position: self.position,
+            expression: Expression::Propagate(value),
+        })
+    }
+
     /// Parses a hash literal. Doesn't check if `self.current_token` is an "#{" token.
     fn parse_hash_literal(&mut self) -> MonkeyResult<NodeExpression> {
         let position = self.position;
@@ -545,13 +862,35 @@ impl Parser {
         })
     }
 
-    /// Parses a hash entry, that is, two expressions separated by a ":" token.
-    fn parse_hash_entry(&mut self) -> MonkeyResult<(NodeExpression, NodeExpression)> {
+    /// Parses a hash entry, that is, either two expressions separated by a ":" token, or a spread
+    /// expression.
+    fn parse_hash_entry(&mut self) -> MonkeyResult<HashEntry> {
+        if self.current_token == Token::Spread {
+            self.read_token()?; // Read first token of spread expression
+            let exp = self.parse_expression(Precedence::Lowest)?;
+            return Ok(HashEntry::Spread(exp));
+        }
         let key = self.parse_expression(Precedence::Lowest)?;
         self.expect_token(Token::Colon)?;
         self.read_token()?;
         let value = self.parse_expression(Precedence::Lowest)?;
-        Ok((key, value))
+        Ok(HashEntry::Pair(key, value))
+    }
+
+    /// Parses an element of an expression list, which can be either a regular expression or a
+    /// spread expression, that is, an expression preceded by a ".." token.
+    fn parse_list_element(&mut self) -> MonkeyResult<NodeExpression> {
+        if self.current_token == Token::Spread {
+            let position = self.position;
+            self.read_token()?; // Read first token of spread expression
+            let exp = self.parse_expression(Precedence::Lowest)?;
+            Ok(NodeExpression {
+                position,
+                expression: Expression::Spread(Box::new(exp)),
+            })
+        } else {
+            self.parse_expression(Precedence::Lowest)
+        }
     }
 
     fn parse_block_expression(&mut self) -> MonkeyResult<NodeExpression> {
@@ -563,9 +902,9 @@ impl Parser {
         })
     }
 
-    /// Parses a list of expressions, separated by commas and ending on `closing_token`. There
-    /// should be no trailing comma. May return an error if parsing of a list element fails, or if
-    /// the parser encounters an unexpected token.
+    /// Parses a list of expressions, separated by commas and ending on `closing_token`. Elements
+    /// may be spread expressions. There should be no trailing comma. May return an error if
+    /// parsing of a list element fails, or if the parser encounters an unexpected token.
     fn parse_expression_list(&mut self, closing_token: Token) -> MonkeyResult<Vec<NodeExpression>> {
         let mut list = Vec::new();
         // In case of empty expression list
@@ -575,11 +914,11 @@ impl Parser {
         }
 
         self.read_token()?; // Read first token of expression
-        list.push(self.parse_expression(Precedence::Lowest)?);
+        list.push(self.parse_list_element()?);
         while self.peek_token == Token::Comma {
             self.read_token()?; // Consume comma token
             self.read_token()?; // Read first token of expression
-            list.push(self.parse_expression(Precedence::Lowest)?);
+            list.push(self.parse_list_element()?);
         }
         self.expect_token(closing_token)?;
         Ok(list)
@@ -591,13 +930,16 @@ impl Parser {
             Token::Identifier(_) => Some(Parser::parse_identifier),
             Token::Int(_) => Some(Parser::parse_int_literal),
             Token::Str(_) => Some(Parser::parse_string_literal),
-            Token::Bang | Token::Minus => Some(Parser::parse_prefix_expression),
+            Token::InterpolatedStr(_) => Some(Parser::parse_interpolated_string),
+            Token::Bang | Token::Minus | Token::Tilde => Some(Parser::parse_prefix_expression),
             Token::OpenParen => Some(Parser::parse_grouped_expression),
             Token::OpenCurlyBrace => Some(Parser::parse_block_expression),
             Token::OpenSquareBracket => Some(Parser::parse_array_literal),
             Token::OpenHash => Some(Parser::parse_hash_literal),
             Token::True | Token::False => Some(Parser::parse_boolean),
             Token::If => Some(Parser::parse_if_expression),
+            Token::Try => Some(Parser::parse_try_expression),
+            Token::For => Some(Parser::parse_for_expression),
             Token::Function => Some(Parser::parse_function_literal),
             Token::Nil => Some(Parser::parse_nil),
             _ => None,
@@ -618,9 +960,19 @@ impl Parser {
             | Token::Slash
             | Token::Asterisk
             | Token::Exponent
-            | Token::Modulo => Some(Parser::parse_infix_expression),
+            | Token::Modulo
+            | Token::BitAnd
+            | Token::BitOr
+            | Token::Tilde
+            | Token::ShiftLeft
+            | Token::ShiftRight => Some(Parser::parse_infix_expression),
             Token::OpenParen => Some(Parser::parse_call_expression),
             Token::OpenSquareBracket => Some(Parser::parse_index_expression),
+            Token::OpenOptionalIndex => Some(Parser::parse_optional_index_expression),
+            Token::NilCoalesce => Some(Parser::parse_nil_coalescing),
+            Token::Pipe => Some(Parser::parse_pipe_expression),
+            Token::Dot => Some(Parser::parse_field_access),
+            Token::Question => Some(Parser::parse_propagate_expression),
             _ => None,
         }
     }
@@ -629,13 +981,19 @@ impl Parser {
     fn get_precedence(token: &Token) -> Precedence {
         use Token::*;
         match token {
+            NilCoalesce => Precedence::NilCoalesce,
+            Pipe => Precedence::Pipe,
             Equals | NotEquals => Precedence::Equals,
             LessThan | LessEq | GreaterThan | GreaterEq => Precedence::LessGreater,
+            BitOr => Precedence::BitOr,
+            Tilde => Precedence::BitXor,
+            BitAnd => Precedence::BitAnd,
+            ShiftLeft | ShiftRight => Precedence::Shift,
             Plus | Minus => Precedence::Sum,
             Slash | Asterisk | Modulo => Precedence::Product,
             Exponent => Precedence::Exponent,
             OpenParen => Precedence::Call,
-            OpenSquareBracket => Precedence::Index,
+            OpenSquareBracket | OpenOptionalIndex | Dot | Question => Precedence::Index,
             _ => Precedence::Lowest,
         }
     }
@@ -91,6 +91,40 @@ fn test_call_expressions() {
     assert_parse_fails("foo(x y)");
 }
 
+#[test]
+fn test_pipe_expressions() {
+    let input = "x |> f; x |> f(a) |> g(); 1 + 2 |> h[0]; x |> fn(y) { y };";
+    let expected = [
+        "ExpressionStatement(CallExpression { function: Identifier(\"f\"), arguments: \
+        [Identifier(\"x\")] })",
+        "ExpressionStatement(CallExpression { function: Identifier(\"g\"), arguments: \
+        [CallExpression { function: Identifier(\"f\"), arguments: [Identifier(\"x\"), \
+        Identifier(\"a\")] }] })",
+        "ExpressionStatement(CallExpression { function: IndexExpression(Identifier(\"h\"), \
+        IntLiteral(0)), arguments: [InfixExpression(IntLiteral(1), Plus, IntLiteral(2))] })",
+        "ExpressionStatement(CallExpression { function: FunctionLiteral { parameters: \
+        [\"y\"], body: [ExpressionStatement(Identifier(\"y\"))] }, arguments: \
+        [Identifier(\"x\")] })",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("x |>");
+    assert_parse_fails("|> f");
+    for input in [
+        "x |> 1",
+        "x |> \"f\"",
+        "x |> [f]",
+        "x |> -f",
+        "x |> f + g",
+        "x |> f == y",
+    ] {
+        match parse(input.into()) {
+            Err(MonkeyError::Parser(_, ParserError::InvalidPipeTarget)) => {}
+            other => panic!("expected invalid pipe target error, got {:?}", other),
+        }
+    }
+}
+
 #[test]
 fn test_index_expression() {
     let input = "
@@ -108,6 +142,34 @@ fn test_index_expression() {
     assert_parse_fails("array[only, one, index, man]");
 }
 
+#[test]
+fn test_nil_safe_operators() {
+    let input = "
+        a?[0];
+        h?[\"a\"][\"b\"]?[1];
+        a ?? b ?? c;
+        a ?? 1 + 2 == 3;
+        [nil][0] ?? a?[0];
+    ";
+    let expected = [
+        "ExpressionStatement(OptionalIndexExpression(Identifier(\"a\"), IntLiteral(0)))",
+        "ExpressionStatement(OptionalIndexExpression(IndexExpression(OptionalIndexExpression(\
+        Identifier(\"h\"), StringLiteral(\"a\")), StringLiteral(\"b\")), IntLiteral(1)))",
+        "ExpressionStatement(NilCoalescing(NilCoalescing(Identifier(\"a\"), Identifier(\"b\")), \
+        Identifier(\"c\")))",
+        "ExpressionStatement(NilCoalescing(Identifier(\"a\"), InfixExpression(InfixExpression(\
+        IntLiteral(1), Plus, IntLiteral(2)), Equals, IntLiteral(3))))",
+        "ExpressionStatement(NilCoalescing(IndexExpression(ArrayLiteral([Nil]), IntLiteral(0)), \
+        OptionalIndexExpression(Identifier(\"a\"), IntLiteral(0))))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("a?[]");
+    assert_parse_fails("a?[0");
+    assert_parse_fails("a ??");
+    assert_parse_fails("?? b");
+}
+
 #[test]
 fn test_let_statements() {
     assert_parse("let a = 1;", &["Let((\"a\", IntLiteral(1)))"]);
@@ -117,6 +179,63 @@ fn test_let_statements() {
     assert_parse_fails("let baz;");
 }
 
+#[test]
+fn test_structs() {
+    let input = "
+        struct Point { x, y }
+        struct Empty {};
+        struct Trailing { a, };
+        p.x;
+        a.b.c[0];
+        p.x = 1 + 2;
+        p.x == 1;
+    ";
+    let expected = [
+        "Let((\"Point\", StructDeclaration { name: \"Point\", fields: [\"x\", \"y\"] }))",
+        "Let((\"Empty\", StructDeclaration { name: \"Empty\", fields: [] }))",
+        "Let((\"Trailing\", StructDeclaration { name: \"Trailing\", fields: [\"a\"] }))",
+        "ExpressionStatement(FieldAccess(Identifier(\"p\"), \"x\"))",
+        "ExpressionStatement(IndexExpression(FieldAccess(FieldAccess(Identifier(\"a\"), \
+        \"b\"), \"c\"), IntLiteral(0)))",
+        "ExpressionStatement(FieldUpdate { object: Identifier(\"p\"), field: \"x\", value: \
+        InfixExpression(IntLiteral(1), Plus, IntLiteral(2)) })",
+        "ExpressionStatement(InfixExpression(FieldAccess(Identifier(\"p\"), \"x\"), Equals, \
+        IntLiteral(1)))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("struct { x }");
+    assert_parse_fails("struct Point x, y");
+    assert_parse_fails("struct Point { x y }");
+    assert_parse_fails("struct Point { 1 }");
+    assert_parse_fails("p.");
+    assert_parse_fails("p.0");
+    assert_parse_fails("p.x =");
+    match parse("struct Point { x, y, x }".into()) {
+        Err(MonkeyError::Parser(_, ParserError::DuplicateField(f))) => assert_eq!(f, "x"),
+        other => panic!("expected duplicate field error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_method_calls() {
+    let input = "s.trim(); a.push(1, ..b).len(); x |> s.split(\",\");";
+    let expected = [
+        "ExpressionStatement(MethodCall { object: Identifier(\"s\"), method: \"trim\", \
+        arguments: [] })",
+        "ExpressionStatement(MethodCall { object: MethodCall { object: Identifier(\"a\"), \
+        method: \"push\", arguments: [IntLiteral(1), Spread(Identifier(\"b\"))] }, method: \
+        \"len\", arguments: [] })",
+        "ExpressionStatement(MethodCall { object: Identifier(\"s\"), method: \"split\", \
+        arguments: [Identifier(\"x\"), StringLiteral(\",\")] })",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("s.trim(");
+    assert_parse_fails("s.(1)");
+    assert_parse_fails("s.split(1,)");
+}
+
 #[test]
 fn test_return_statements() {
     // Not much to test here, to be honest
@@ -126,12 +245,13 @@ fn test_return_statements() {
 
 #[test]
 fn test_prefix_expressions() {
-    let input = "-5; !true; --!!-foo;";
+    let input = "-5; !true; --!!-foo; ~1;";
     let expected = [
         "ExpressionStatement(PrefixExpression(Minus, IntLiteral(5)))",
         "ExpressionStatement(PrefixExpression(Bang, Boolean(true)))",
         "ExpressionStatement(PrefixExpression(Minus, PrefixExpression(Minus, PrefixExpression(\
         Bang, PrefixExpression(Bang, PrefixExpression(Minus, Identifier(\"foo\")))))))",
+        "ExpressionStatement(PrefixExpression(Tilde, IntLiteral(1)))",
     ];
     assert_parse(input, &expected);
 
@@ -157,6 +277,25 @@ fn test_infix_expressions() {
     assert_parse_fails("> 1 + 2");
 }
 
+#[test]
+fn test_bitwise_expressions() {
+    let input = "1 | 2 ~ 3 & 4 << 5 + 6; a & b == c; 1 >> ~2;";
+    let expected = [
+        "ExpressionStatement(InfixExpression(IntLiteral(1), BitOr, InfixExpression(\
+        IntLiteral(2), Tilde, InfixExpression(IntLiteral(3), BitAnd, InfixExpression(\
+        IntLiteral(4), ShiftLeft, InfixExpression(IntLiteral(5), Plus, IntLiteral(6)))))))",
+        "ExpressionStatement(InfixExpression(InfixExpression(Identifier(\"a\"), BitAnd, \
+        Identifier(\"b\")), Equals, Identifier(\"c\")))",
+        "ExpressionStatement(InfixExpression(IntLiteral(1), ShiftRight, PrefixExpression(\
+        Tilde, IntLiteral(2))))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("1 & ");
+    assert_parse_fails("| 1");
+    assert_parse_fails("1 << >> 2");
+}
+
 #[test]
 fn test_if_expressions() {
     let input = "
@@ -184,6 +323,66 @@ fn test_if_expressions() {
     assert_parse_fails("if true {} else");
 }
 
+#[test]
+fn test_propagate_expressions() {
+    let input = "foo(x)?; -a?; a? + b; a?.b?;";
+    let expected = [
+        "ExpressionStatement(Propagate(CallExpression { function: Identifier(\"foo\"), \
+        arguments: [Identifier(\"x\")] }))",
+        "ExpressionStatement(PrefixExpression(Minus, Propagate(Identifier(\"a\"))))",
+        "ExpressionStatement(InfixExpression(Propagate(Identifier(\"a\")), Plus, \
+        Identifier(\"b\")))",
+        "ExpressionStatement(Propagate(FieldAccess(Propagate(Identifier(\"a\")), \"b\")))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("?a");
+}
+
+#[test]
+fn test_exceptions() {
+    let input = r#"
+        throw "error";
+        try { 1 } catch e { e }
+        let a = try {} catch err {};
+    "#;
+    let expected = [
+        "Throw(StringLiteral(\"error\"))",
+        "ExpressionStatement(TryExpression { body: [ExpressionStatement(IntLiteral(1))], \
+        name: \"e\", handler: [ExpressionStatement(Identifier(\"e\"))] })",
+        "Let((\"a\", TryExpression { body: [], name: \"err\", handler: [] }))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("throw;");
+    assert_parse_fails("try { 1 }");
+    assert_parse_fails("try { 1 } catch { 2 }");
+    assert_parse_fails("try 1 catch e { 2 }");
+}
+
+#[test]
+fn test_generators() {
+    let input = "
+        fn*(x) { yield x; yield; }
+        for x in xs { x }
+        let a = for c in \"ab\" {};
+    ";
+    let expected = [
+        "ExpressionStatement(GeneratorLiteral { parameters: [\"x\"], body: \
+        [Yield(Identifier(\"x\")), Yield(Nil)] })",
+        "ExpressionStatement(ForExpression { name: \"x\", iterable: Identifier(\"xs\"), body: \
+        [ExpressionStatement(Identifier(\"x\"))] })",
+        "Let((\"a\", ForExpression { name: \"c\", iterable: StringLiteral(\"ab\"), body: [] }))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("yield 1;");
+    assert_parse_fails("fn() { yield 1; }");
+    assert_parse_fails("fn*() { fn() { yield 1; } }");
+    assert_parse_fails("for in xs {}");
+    assert_parse_fails("for x xs {}");
+}
+
 #[test]
 fn test_grouped_expression() {
     let input = "(2 + 3) * (5 + 7); (1 + (1 + (1 + 1)));";
@@ -217,3 +416,44 @@ fn test_block_expressions() {
 
     assert_parse_fails("{ return 0");
 }
+
+#[test]
+fn test_spread_expressions() {
+    let input = "[..a, 1, ..[b]]; f(..args, x); #{ ..base, \"k\": v };";
+    let expected = [
+        "ExpressionStatement(ArrayLiteral([Spread(Identifier(\"a\")), IntLiteral(1), \
+        Spread(ArrayLiteral([Identifier(\"b\")]))]))",
+        "ExpressionStatement(CallExpression { function: Identifier(\"f\"), arguments: \
+        [Spread(Identifier(\"args\")), Identifier(\"x\")] })",
+        "ExpressionStatement(HashLiteral([Spread(Identifier(\"base\")), \
+        (StringLiteral(\"k\"), Identifier(\"v\"))]))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails("[..]");
+    assert_parse_fails("f(..)");
+    assert_parse_fails("#{ ..base: 1 }");
+    assert_parse_fails("..a;");
+}
+
+#[test]
+fn test_interpolated_strings() {
+    let input = r#""a ${x + 1} b"; "${"nested ${y}"}";"#;
+    let expected = [
+        "ExpressionStatement(InterpolatedString([StringLiteral(\"a \"), InfixExpression(\
+        Identifier(\"x\"), Plus, IntLiteral(1)), StringLiteral(\" b\")]))",
+        "ExpressionStatement(InterpolatedString([InterpolatedString([StringLiteral(\"nested \"), \
+        Identifier(\"y\")])]))",
+    ];
+    assert_parse(input, &expected);
+
+    assert_parse_fails(r#""${}""#);
+    assert_parse_fails(r#""${1 2}""#);
+    assert_parse_fails(r#""${let a = 1;}""#);
+
+    // Errors in empty placeholders are reported where the placeholder ends
+    match parse("let s = 1;\nputs(\"a ${} b\");".into()) {
+        Err(MonkeyError::Parser((2, 11), _)) => {}
+        other => panic!("Wrong result: {:?}", other),
+    }
+}
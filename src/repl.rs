@@ -1,6 +1,7 @@
-use crate::compiler;
+use crate::compiler::{self, optimize::OptLevel};
 use crate::error::MonkeyResult;
-use crate::interpreter::{self, environment};
+use crate::interpreter;
+use crate::limits::Limits;
 use crate::object;
 use crate::parser;
 use crate::vm;
@@ -14,8 +15,6 @@ use rustyline::{
 use rustyline_derive::{Completer, Helper, Hinter};
 
 use std::borrow::Cow;
-use std::cell::RefCell;
-use std::rc::Rc;
 
 const PROMPT: &str = "monkey » ";
 
@@ -49,20 +48,14 @@ impl Validator for ReplHelper {
             } else {
                 match c {
                     '(' | '[' | '{' | '"' => stack.push(c),
-                    ')' => {
-                        if !try_pop(&mut stack, '(') {
-                            return Ok(ValidationResult::Valid(None));
-                        }
+                    ')' if !try_pop(&mut stack, '(') => {
+                        return Ok(ValidationResult::Valid(None));
                     }
-                    ']' => {
-                        if !try_pop(&mut stack, '[') {
-                            return Ok(ValidationResult::Valid(None));
-                        }
+                    ']' if !try_pop(&mut stack, '[') => {
+                        return Ok(ValidationResult::Valid(None));
                     }
-                    '}' => {
-                        if !try_pop(&mut stack, '{') {
-                            return Ok(ValidationResult::Valid(None));
-                        }
+                    '}' if !try_pop(&mut stack, '{') => {
+                        return Ok(ValidationResult::Valid(None));
                     }
                     _ => (),
                 }
@@ -91,7 +84,7 @@ impl Highlighter for ReplHelper {
     }
 }
 
-pub fn start(compiled: bool) -> MonkeyResult<()> {
+pub fn start(compiled: bool, opt_level: OptLevel, limits: Limits) -> MonkeyResult<()> {
     eprintln!("Now with an even fancier REPL!");
     eprintln!(
         "(running using {})",
@@ -112,17 +105,22 @@ pub fn start(compiled: bool) -> MonkeyResult<()> {
         rustyline::Cmd::Insert(1, "    ".into()),
     );
     let res = if compiled {
-        start_compiled(rl)
+        start_compiled(rl, opt_level, limits)
     } else {
-        start_interpreted(rl)
+        start_interpreted(rl, limits)
     };
     eprintln!("Goodbye!");
     res.map_err(|e| e.into())
 }
 
-fn start_compiled(mut rl: rustyline::Editor<ReplHelper>) -> Result<(), std::io::Error> {
+fn start_compiled(
+    mut rl: rustyline::Editor<ReplHelper>,
+    opt_level: OptLevel,
+    limits: Limits,
+) -> Result<(), std::io::Error> {
     let mut comp = compiler::Compiler::new();
-    let mut vm = vm::VM::new();
+    comp.set_opt_level(opt_level);
+    let mut vm = vm::VM::with_limits(limits);
 
     let mut run_line = |line: String| -> MonkeyResult<Vec<object::Object>> {
         let parsed = parser::parse(line)?;
@@ -140,12 +138,16 @@ fn start_compiled(mut rl: rustyline::Editor<ReplHelper>) -> Result<(), std::io::
     }
 }
 
-fn start_interpreted(mut rl: rustyline::Editor<ReplHelper>) -> Result<(), std::io::Error> {
-    let env = Rc::new(RefCell::new(environment::Environment::empty()));
-    let run_line = |line: String| -> MonkeyResult<Vec<object::Object>> {
+fn start_interpreted(
+    mut rl: rustyline::Editor<ReplHelper>,
+    limits: Limits,
+) -> Result<(), std::io::Error> {
+    let mut globals = interpreter::Globals::with_limits(limits);
+    let mut run_line = |line: String| -> MonkeyResult<Vec<object::Object>> {
+        globals.meter().start();
         parser::parse(line)?
             .into_iter()
-            .map(|s| interpreter::eval_statement(&s, &env))
+            .map(|s| globals.eval_statement(s))
             .collect()
     };
 
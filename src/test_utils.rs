@@ -1,7 +1,10 @@
-use crate::compiler::{self, code};
+use crate::compiler::{self, code, optimize::OptLevel};
 use crate::error::*;
 use crate::parser;
 
+use std::fs;
+use std::path::PathBuf;
+
 macro_rules! monkey_hash {
     ($($key:expr => $value:expr),* $(,)?) => {
         {
@@ -15,16 +18,56 @@ macro_rules! monkey_hash {
 macro_rules! monkey_array {
     ($($element:expr),* $(,)?) => {
         {
-            crate::object::Object::Array(Box::new(
-                vec![ $($element),* ]
-            ))
+            let _vec = vec![ $($element),* ];
+            crate::object::Object::Array(Box::new(_vec))
         }
     };
 }
 
 pub fn parse_and_compile(program: &str) -> Result<code::Bytecode, MonkeyError> {
+    parse_and_compile_with(program, OptLevel::None)
+}
+
+pub fn parse_and_compile_with(
+    program: &str,
+    opt_level: OptLevel,
+) -> Result<code::Bytecode, MonkeyError> {
     let parsed = parser::parse(program.into())?;
     let mut comp = compiler::Compiler::new();
+    comp.set_opt_level(opt_level);
     comp.compile_block(parsed)?;
     Ok(comp.bytecode())
 }
+
+/// Writes the given files to a new temporary directory, to test imports. Returns the directory.
+pub fn write_files(test_name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("monkey-{}-{}", test_name, std::process::id()));
+    for (name, contents) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    dir
+}
+
+/// Files used to test imports. `main.mk` evaluates to `[4, 5, 3]`, and `cycle.mk` fails because
+/// of an import cycle.
+pub const IMPORT_TEST_FILES: &[(&str, &str)] = &[
+    (
+        "main.mk",
+        r#"let two = 5;
+        let math = import("lib/math.mk");
+        let again = import("./lib/../lib/math.mk");
+        [again["square"](math["two"]), two, len(keys(math))]"#,
+    ),
+    (
+        "lib/math.mk",
+        r#"let consts = import("consts.mk");
+        let two = consts["two"];
+        let square = fn(x) { x * x };"#,
+    ),
+    ("lib/consts.mk", "let two = 2;"),
+    ("cycle.mk", r#"import("cycle/a.mk");"#),
+    ("cycle/a.mk", r#"import("b.mk");"#),
+    ("cycle/b.mk", r#"import("a.mk");"#),
+];
@@ -1,65 +1,169 @@
 #[cfg(test)]
 mod tests;
+pub mod verifier;
 
 use crate::builtins::{self, BuiltinFn};
 use crate::compiler::code::*;
-use crate::error::{MonkeyError, MonkeyResult, RuntimeError::*};
+use crate::error::{MonkeyError, MonkeyResult, RuntimeError, RuntimeError::*};
+use crate::gc;
 use crate::lexer::token::Token;
+use crate::limits::{Limits, Meter};
 use crate::object::*;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::iter;
+use std::mem;
+use std::rc::Rc;
 
 const STACK_SIZE: usize = 2048;
 pub const GLOBALS_SIZE: usize = 65536;
 
 struct Frame {
-    instructions: Instructions,
-    free_vars: Vec<Object>,
+    closure: Rc<Closure>,
+    // The position of the next byte to read from the instructions of the closure. While the frame
+    // is running, the position is kept by the dispatch loop instead, and only saved here when the
+    // frame stops running.
     pc: usize,
+    // The position in the stack of the first argument, followed by the other locals. If the frame
+    // was created by a call, the slot of the closure that was called is right below it, where the
+    // returned value is stored.
     base_pointer: usize,
+    // The generator that is running in this frame, if any
+    generator: Option<ResumedGenerator>,
 }
 
-struct FrameStack(Vec<Frame>);
+impl Frame {
+    fn new(
+        closure: Rc<Closure>,
+        pc: usize,
+        base_pointer: usize,
+        generator: Option<ResumedGenerator>,
+    ) -> Frame {
+        Frame {
+            closure,
+            pc,
+            base_pointer,
+            generator,
+        }
+    }
+}
 
-impl FrameStack {
-    fn top(&self) -> &Frame {
-        self.0.last().expect("No frames in frame stack")
+// Reads the instructions of the running frame. The verifier checks that every instruction is
+// complete, that every opcode is valid, and that jumps land on instructions, so the instructions
+// are read without bounds checks. Functions always return, and the main program ends with an
+// `OpReturn` added after it's verified, so the code is never read past its end.
+struct Cursor {
+    // The instructions of the frame, which live as long as the frame holds on to its closure
+    code: *const u8,
+    pc: usize,
+}
+
+impl Cursor {
+    fn new(frame: &Frame) -> Cursor {
+        Cursor {
+            code: frame.closure.func.instructions.0.as_ptr(),
+            pc: frame.pc,
+        }
+    }
+
+    #[inline]
+    fn read_op(&mut self) -> OpCode {
+        let op = unsafe { OpCode::from_byte_unchecked(*self.code.add(self.pc)) };
+        self.pc += 1;
+        op
+    }
+
+    #[inline]
+    fn read_u8(&mut self) -> usize {
+        let value = unsafe { *self.code.add(self.pc) };
+        self.pc += 1;
+        value as usize
+    }
+
+    #[inline]
+    fn read_u16(&mut self) -> usize {
+        let value = unsafe { [*self.code.add(self.pc), *self.code.add(self.pc + 1)] };
+        self.pc += 2;
+        u16::from_be_bytes(value) as usize
     }
+}
+
+struct ResumedGenerator {
+    object: Rc<RefCell<GeneratorObject>>,
+    // If the generator was resumed by a `for` loop, the position of the end of the loop, where the
+    // caller jumps to if the generator finishes. Otherwise, the generator was resumed by `next`,
+    // which evaluates to `nil` if the generator finishes.
+    on_done: Option<usize>,
+}
 
-    fn top_mut(&mut self) -> &mut Frame {
-        self.0.last_mut().expect("No frames in frame stack")
+// The frame that is running is kept apart from the frames of its callers, so it can be accessed
+// without going through the vector.
+struct FrameStack {
+    current: Frame,
+    callers: Vec<Frame>,
+}
+
+impl FrameStack {
+    fn len(&self) -> usize {
+        self.callers.len() + 1
     }
 
     fn push(&mut self, frame: Frame) {
-        self.0.push(frame);
+        let caller = mem::replace(&mut self.current, frame);
+        self.callers.push(caller);
     }
 
-    fn pop(&mut self) {
-        self.0.pop();
+    fn pop(&mut self) -> Frame {
+        let caller = self.callers.pop().expect("Popping the root frame");
+        mem::replace(&mut self.current, caller)
     }
 
-    // Reads a u16 from the top frame, and incremets its program counter
-    fn read_u16_from_top(&mut self) -> u16 {
-        let value = read_u16(&self.top().instructions.0[self.top().pc + 1..]);
-        self.top_mut().pc += 2;
-        value
+    fn truncate(&mut self, len: usize) {
+        while self.len() > len {
+            self.pop();
+        }
     }
 
-    fn read_u8_from_top(&mut self) -> u8 {
-        let value = self.top().instructions.0[self.top().pc + 1];
-        self.top_mut().pc += 1;
-        value
+    // The frames from the root frame to the current one
+    fn iter(&self) -> impl Iterator<Item = &Frame> {
+        self.callers.iter().chain(iter::once(&self.current))
     }
 }
 
+// An exception handler registered by a `try` expression
+struct Handler {
+    // The number of frames in the frame stack when the handler was registered. The handler belongs
+    // to the top frame at that time.
+    num_frames: usize,
+    // The stack size when the handler was registered, which is restored when unwinding
+    sp: usize,
+    // The position of the handler code in the instructions of its frame
+    target: usize,
+}
+
 pub struct VM {
+    // The size of the stack is never more than `STACK_SIZE`, so it's allocated only once. Before a
+    // function starts running, there must be room for as many values as it can push, so pushing
+    // doesn't have to check for overflows.
     stack: Vec<Object>,
-    sp: usize,
     pub globals: Box<[Object]>,
+    // The handlers of the `try` expressions currently being executed, with the innermost last
+    handlers: Vec<Handler>,
+    // The resources used by the program that is running, and its limits
+    meter: Rc<Meter>,
+    // The instructions that can run before the limits have to be checked again
+    fuel: u64,
 }
 
 impl VM {
+    /// Creates a VM without limits on the resources programs can use.
     pub fn new() -> VM {
+        VM::with_limits(Limits::default())
+    }
+
+    /// Creates a VM that stops programs when they reach `limits`.
+    pub fn with_limits(limits: Limits) -> VM {
         // @PERFORMANCE: Maybe we shouldn't allocate all the memory for the globals upfront.
         let mut globals = Vec::with_capacity(GLOBALS_SIZE);
         globals.resize(GLOBALS_SIZE, Object::Nil);
@@ -67,92 +171,235 @@ impl VM {
 
         VM {
             stack: Vec::with_capacity(STACK_SIZE),
-            sp: 0,
             globals,
+            handlers: Vec::new(),
+            meter: Rc::new(Meter::new(limits)),
+            fuel: 0,
         }
     }
 
     pub fn run(&mut self, bytecode: Bytecode) -> MonkeyResult<()> {
-        // We can't store the frames in the VM struct because we need to borrow both `self` and the
-        // current frame mutably at the same time. If the frames were part of `self`, that would
-        // mean two mutable references to `self`.
-        let mut frame_stack = FrameStack({
-            let root_frame = Frame {
-                instructions: bytecode.instructions,
-                free_vars: Vec::new(),
-                pc: 0,
-                base_pointer: 0,
-            };
-            vec![root_frame]
-        });
+        let max_depths = verifier::verify(&bytecode)?;
+        if self.stack.len() + max_depths.main > STACK_SIZE {
+            return Err(MonkeyError::Vm(StackOverflow));
+        }
+
+        // The main program runs in the root frame, as a closure without arguments or locals. We
+        // can't store the frames in the VM struct because we need to borrow both `self` and the
+        // current frame mutably at the same time.
+        let mut instructions = bytecode.instructions;
+        instructions.0.push(OpCode::OpReturn as u8);
+        let main = CompiledFunction {
+            instructions,
+            num_locals: 0,
+            num_params: 0,
+        };
+        let main = Closure {
+            func: Rc::new(main),
+            free_vars: Vec::new(),
+            max_depth: max_depths.main,
+        };
+        let mut frames = FrameStack {
+            current: Frame::new(Rc::new(main), 0, self.stack.len(), None),
+            callers: Vec::new(),
+        };
         let constants = bytecode.constants;
+        let lines = bytecode.lines;
+        let max_depths = max_depths.functions;
+        self.handlers.clear();
+        // Built-in functions count the objects they create in the active meter
+        self.meter.start();
+        let _active = self.meter.activate();
+        self.fuel = 0;
 
+        // Runtime errors stop the dispatch loop. If the error is caught, the loop starts again
+        // from the handler. Otherwise, it's reported at the line where it happened, if it's known.
         loop {
-            // If we reach the end of the instructions and we are at the root frame, this is the
-            // end of the program and we break the loop. Otherwise, if we are not in the root frame,
-            // we reached the end of a function and there was no `OpReturn` instruction at the end,
-            // so we panic.
-            if frame_stack.top().pc >= frame_stack.top().instructions.0.len() {
-                if frame_stack.0.len() == 1 {
-                    break; // End of program
-                } else {
-                    panic!("Reached end of instructions in non-root frame")
-                }
+            match self.execute(&mut frames, &constants, &max_depths) {
+                Ok(()) => return Ok(()),
+                Err(MonkeyError::Vm(error)) => match self.catch(error, &mut frames) {
+                    Err(MonkeyError::Vm(error)) => {
+                        return Err(match error_line(&frames, &constants, &lines) {
+                            Some(line) => MonkeyError::VmAt(line, error),
+                            None => MonkeyError::Vm(error),
+                        })
+                    }
+                    other => other?,
+                },
+                Err(e) => return Err(e),
             }
+        }
+    }
 
-            use OpCode::*;
-            let op = OpCode::from_byte(frame_stack.top().instructions.0[frame_stack.top().pc]);
+    /// Runs instructions until the end of the main program, or until a runtime error happens. When
+    /// an error happens, the position of the instruction that failed is saved in the current frame.
+    fn execute(
+        &mut self,
+        frames: &mut FrameStack,
+        constants: &[Object],
+        max_depths: &[usize],
+    ) -> MonkeyResult<()> {
+        let mut ip = Cursor::new(&frames.current);
+        let result = self.dispatch(frames, constants, max_depths, &mut ip);
+        if result.is_err() {
+            frames.current.pc = ip.pc;
+        }
+        result
+    }
+
+    // The dispatch loop of `execute`. It's inlined, so the cursor is kept in registers.
+    #[inline(always)]
+    fn dispatch(
+        &mut self,
+        frames: &mut FrameStack,
+        constants: &[Object],
+        max_depths: &[usize],
+        ip: &mut Cursor,
+    ) -> MonkeyResult<()> {
+        use OpCode::*;
+        loop {
+            if self.fuel == 0 {
+                self.fuel = self.meter.refuel().map_err(MonkeyError::Vm)?;
+            }
+            self.fuel -= 1;
+            let op = ip.read_op();
             match op {
                 OpConstant => {
-                    let constant_index = frame_stack.read_u16_from_top() as usize;
-                    self.push(constants[constant_index].clone())?;
+                    let constant_index = ip.read_u16();
+                    self.push(clone_object(&constants[constant_index]));
                 }
                 OpPop => {
-                    self.pop()?;
+                    self.pop_value();
+                }
+                // The most common operations on integers have their own fast paths, which update
+                // the left operand in place
+                OpAdd | OpSub | OpMul => match self.integer_operands() {
+                    Some((l, r)) => {
+                        *l = match op {
+                            OpAdd => *l + r,
+                            OpSub => *l - r,
+                            _ => *l * r,
+                        };
+                        self.discard_integers(1);
+                    }
+                    None => self.execute_binary_operation(op)?,
+                },
+                OpDiv | OpExponent | OpModulo | OpEquals | OpNotEquals | OpGreaterThan
+                | OpGreaterEq | OpLessThan | OpLessEq | OpBitAnd | OpBitOr | OpBitXor
+                | OpShiftLeft | OpShiftRight => match self.integer_operands() {
+                    Some((&mut l, r)) => {
+                        let result = integer_operation(op, l, r).map_err(MonkeyError::Vm)?;
+                        self.discard_integers(2);
+                        self.push(result);
+                    }
+                    None => self.execute_binary_operation(op)?,
+                },
+                OpTrue => self.push(Object::Boolean(true)),
+                OpFalse => self.push(Object::Boolean(false)),
+                OpPrefixMinus | OpPrefixNot | OpPrefixBitNot => {
+                    self.execute_prefix_operation(op)?
                 }
-                OpAdd | OpSub | OpMul | OpDiv | OpExponent | OpModulo | OpEquals | OpNotEquals
-                | OpGreaterThan | OpGreaterEq => self.execute_binary_operation(op)?,
-                OpTrue => self.push(Object::Boolean(true))?,
-                OpFalse => self.push(Object::Boolean(false))?,
-                OpPrefixMinus | OpPrefixNot => self.execute_prefix_operation(op)?,
                 OpJumpNotTruthy => {
-                    let pos = frame_stack.read_u16_from_top() as usize;
+                    let pos = ip.read_u16();
 
                     // @PERFORMANCE: Using `is_truthy` might be slow
-                    if !Object::is_truthy(&self.pop()?) {
-                        frame_stack.top_mut().pc = pos - 1;
+                    if !Object::is_truthy(&self.pop_value()) {
+                        ip.pc = pos;
                     }
                 }
-                OpJump => {
-                    let pos = frame_stack.read_u16_from_top() as usize;
-                    frame_stack.top_mut().pc = pos - 1;
+                OpJumpNotNil => {
+                    let pos = ip.read_u16();
+                    if let Some(Object::Nil) = self.stack.last() {
+                        self.pop_value();
+                    } else {
+                        ip.pc = pos;
+                    }
                 }
-                OpNil => self.push(Object::Nil)?,
+                OpJumpNotError => {
+                    let pos = ip.read_u16();
+                    if !matches!(self.stack.last(), Some(Object::Error(_))) {
+                        ip.pc = pos;
+                    }
+                }
+                OpJump => ip.pc = ip.read_u16(),
+                OpJumpNotLess | OpJumpNotLessEq | OpJumpNotGreater | OpJumpNotGreaterEq => {
+                    let pos = ip.read_u16();
+                    let comparison = op.fused_comparison().unwrap();
+                    let holds = match self.integer_operands() {
+                        Some((&mut l, r)) => {
+                            self.discard_integers(2);
+                            match comparison {
+                                OpLessThan => l < r,
+                                OpLessEq => l <= r,
+                                OpGreaterThan => l > r,
+                                _ => l >= r,
+                            }
+                        }
+                        // Other values are compared like the comparison instruction would, so
+                        // errors are the same
+                        None => {
+                            self.execute_binary_operation(comparison)?;
+                            self.pop_value().is_truthy()
+                        }
+                    };
+                    if !holds {
+                        ip.pc = pos;
+                    }
+                }
+                OpNil => self.push(Object::Nil),
                 OpSetGlobal => {
-                    let index = frame_stack.read_u16_from_top() as usize;
-                    self.globals[index] = self.pop()?.clone();
+                    let index = ip.read_u16();
+                    self.globals[index] = self.pop_value();
                 }
                 OpGetGlobal => {
-                    let index = frame_stack.read_u16_from_top() as usize;
-                    // @PERFORMANCE: This clone may be slow
-                    self.push(self.globals[index].clone())?;
+                    let index = ip.read_u16();
+                    self.push(clone_object(&self.globals[index]));
                 }
                 OpSetLocal => {
-                    let index = frame_stack.read_u8_from_top() as usize;
-                    self.stack[frame_stack.top().base_pointer + index] = self.pop()?;
+                    let index = ip.read_u8();
+                    self.stack[frames.current.base_pointer + index] = self.pop_value();
                 }
                 OpGetLocal => {
-                    let index = frame_stack.read_u8_from_top() as usize;
-                    // @PERFORMANCE: This clone may be slow
-                    self.push(self.stack[frame_stack.top().base_pointer + index].clone())?
+                    let index = ip.read_u8();
+                    self.push(clone_object(
+                        &self.stack[frames.current.base_pointer + index],
+                    ))
+                }
+                OpIncrementLocal | OpDecrementLocal => {
+                    let index = ip.read_u8();
+                    let constant_index = ip.read_u16();
+                    let local = &self.stack[frames.current.base_pointer + index];
+                    match (local, &constants[constant_index]) {
+                        (Object::Integer(l), Object::Integer(r)) => {
+                            let result = if let OpIncrementLocal = op {
+                                l + r
+                            } else {
+                                l - r
+                            };
+                            self.push(Object::Integer(result));
+                        }
+                        (local, constant) => {
+                            let operation = if let OpIncrementLocal = op {
+                                OpAdd
+                            } else {
+                                OpSub
+                            };
+                            let (local, constant) = (local.clone(), constant.clone());
+                            self.push(local);
+                            self.push(constant);
+                            self.execute_binary_operation(operation)?;
+                        }
+                    }
                 }
                 OpArray => {
-                    let num_elements = frame_stack.read_u16_from_top() as usize;
+                    let num_elements = ip.read_u16();
+                    self.meter.allocate(num_elements).map_err(MonkeyError::Vm)?;
                     let arr = self.take(num_elements);
-                    self.push(Object::Array(Box::new(arr)))?;
+                    self.push(Object::Array(Box::new(arr)));
                 }
                 OpHash => {
-                    let num_elements = frame_stack.read_u16_from_top() as usize;
+                    let num_elements = ip.read_u16();
+                    self.meter.allocate(num_elements).map_err(MonkeyError::Vm)?;
                     let entries = self.take(2 * num_elements);
                     let mut map = HashMap::new();
                     for i in 0..num_elements {
@@ -162,100 +409,475 @@ impl VM {
                             .ok_or_else(|| MonkeyError::Vm(HashKeyTypeError(key.type_str())))?;
                         map.insert(hashable, value.clone());
                     }
-                    self.push(Object::Hash(Box::new(map)))?;
+                    self.push(Object::Hash(Box::new(map)));
                 }
                 OpIndex => {
-                    let index = self.pop()?;
-                    let obj = self.pop()?;
+                    let index = self.pop_value();
+                    let obj = self.pop_value();
                     self.execute_index_operation(obj, index)?;
                 }
-                OpCall => {
-                    let num_args = frame_stack.read_u8_from_top() as usize;
-                    // @PERFORMANCE: This `remove` might be slow. Specifically, it's O(num_args).
-                    // Using `swap_remove` would be faster, but it would leave an object in the
-                    // stack that would have to be popped off later.
-                    let func = self.stack.remove(self.sp - 1 - num_args);
-                    self.sp -= 1;
-                    match func {
-                        Object::Closure(c) => {
-                            self.execute_closure_call(&mut frame_stack, *c, num_args)?;
-                            continue; // Skip the pc increment
+                OpIndexOptional => {
+                    let index = self.pop_value();
+                    let obj = self.pop_value();
+                    match obj {
+                        Object::Nil => self.push(Object::Nil),
+                        obj => match self.execute_index_operation(obj, index) {
+                            Err(MonkeyError::Vm(KeyError(_)))
+                            | Err(MonkeyError::Vm(IndexOutOfBounds(_))) => self.push(Object::Nil),
+                            other => other?,
+                        },
+                    }
+                }
+                OpGetField => {
+                    let field_index = ip.read_u16();
+                    let obj = self.pop_value();
+                    let value = match &constants[field_index] {
+                        Object::Str(field) => obj.get_field(field).map_err(MonkeyError::Vm)?,
+                        _ => unreachable!(), // The compiler always stores field names as strings
+                    };
+                    self.push(value);
+                }
+                OpSetField => {
+                    let field_index = ip.read_u16();
+                    let value = self.pop_value();
+                    let obj = self.pop_value();
+                    let updated = match &constants[field_index] {
+                        Object::Str(field) => {
+                            obj.with_field(field, value).map_err(MonkeyError::Vm)?
                         }
-                        Object::Builtin(f) => self.execute_builtin_call(f, num_args)?,
-                        _ => return Err(MonkeyError::Vm(NotCallable(func.type_str()))),
+                        _ => unreachable!(), // The compiler always stores field names as strings
+                    };
+                    self.push(updated);
+                }
+                // Calls to closures are the most common, so they're handled here instead of in
+                // `execute_call`
+                // Instructions that can change the running frame save the position in the code
+                // before doing it, and continue from the position in the frame that runs next
+                OpCall => {
+                    let num_args = ip.read_u8();
+                    frames.current.pc = ip.pc;
+                    let callee_pos = self.stack.len() - 1 - num_args;
+                    match self.take_closure(callee_pos) {
+                        Some(closure) => self.execute_closure_call(frames, closure, num_args)?,
+                        None => self.execute_native_call(frames, callee_pos, num_args)?,
                     }
+                    *ip = Cursor::new(&frames.current);
+                }
+                OpTailCall => {
+                    let num_args = ip.read_u8();
+                    frames.current.pc = ip.pc;
+                    let callee_pos = self.stack.len() - 1 - num_args;
+                    if let Some(closure) = self.take_closure(callee_pos) {
+                        self.execute_tail_call(frames, closure, num_args)?;
+                    } else {
+                        // Other callable objects don't need a frame, so this is just a regular call
+                        self.execute_call(frames, num_args)?;
+                    }
+                    *ip = Cursor::new(&frames.current);
+                }
+                OpCallSpread => {
+                    frames.current.pc = ip.pc;
+                    let args = self.pop_spread_args()?;
+                    let num_args = self.push_spread_args(*args)?;
+                    self.execute_call(frames, num_args)?;
+                    *ip = Cursor::new(&frames.current);
+                }
+                OpMethodCall | OpMethodCallSpread => {
+                    let method_index = ip.read_u16();
+                    let num_args = if let OpMethodCall = op {
+                        ip.read_u8()
+                    } else {
+                        let args = self.pop_spread_args()?;
+                        self.push_spread_args(*args)?
+                    };
+                    let method = match &constants[method_index] {
+                        Object::Str(method) => method,
+                        _ => unreachable!(), // The compiler always stores method names as strings
+                    };
+                    frames.current.pc = ip.pc;
+                    self.execute_method_call(frames, method, num_args)?;
+                    *ip = Cursor::new(&frames.current);
                 }
                 OpReturn => {
-                    let returned_value = self.pop()?;
-                    self.sp = frame_stack.top().base_pointer;
-                    self.stack.truncate(self.sp);
-                    frame_stack.pop();
-                    self.push(returned_value)?;
-                    continue;
+                    // The main program ends with the value of its last expression on the stack
+                    if frames.callers.is_empty() {
+                        return Ok(());
+                    }
+                    // Returning from a function unregisters the handlers in its frame
+                    let num_frames = frames.len();
+                    while let Some(true) = self.handlers.last().map(|h| h.num_frames >= num_frames)
+                    {
+                        self.handlers.pop();
+                    }
+                    let returned_value = self.pop_value();
+                    let frame = frames.pop();
+                    match frame.generator {
+                        // Returning from a generator finishes it, and the returned value is
+                        // discarded
+                        Some(generator) => {
+                            self.stack.truncate(frame.base_pointer);
+                            generator.object.borrow_mut().state = GeneratorState::Done;
+                            if let Some(end) = generator.on_done {
+                                self.take(2); // Pop the iterable and the index of the `for` loop
+                                frames.current.pc = end;
+                            } else {
+                                self.push(Object::Nil);
+                            }
+                        }
+                        // The slot of the closure that was called is replaced by the returned value
+                        None => {
+                            self.stack.truncate(frame.base_pointer);
+                            // The call left `nil` in the slot, so it doesn't need to be dropped
+                            let slot = &mut self.stack[frame.base_pointer - 1];
+                            mem::forget(mem::replace(slot, returned_value));
+                        }
+                    }
+                    *ip = Cursor::new(&frames.current);
                 }
                 OpGetBuiltin => {
-                    let index = frame_stack.read_u8_from_top() as usize;
+                    let index = ip.read_u8();
                     let builtin = builtins::ALL_BUILTINS[index].1.clone();
-                    self.push(Object::Builtin(builtin))?;
+                    self.push(Object::Builtin(builtin));
                 }
                 OpClosure => {
-                    let constant_index = frame_stack.read_u16_from_top() as usize;
-                    let num_free_vars = frame_stack.read_u8_from_top() as usize;
-                    let func = constants[constant_index].clone();
+                    let constant_index = ip.read_u16();
+                    let num_free_vars = ip.read_u8();
+                    let func = match &constants[constant_index] {
+                        Object::CompiledFunc(func) => Rc::clone(func),
+                        _ => panic!("Trying to build closure with non-function object"),
+                    };
+                    self.meter.allocate(0).map_err(MonkeyError::Vm)?;
                     let free_vars = self.take(num_free_vars);
-                    if let Object::CompiledFunc(func) = func {
-                        let closure = Closure {
-                            func: *func,
-                            free_vars,
-                        };
-                        self.push(Object::Closure(Box::new(closure)))?;
-                    } else {
-                        panic!("Trying to build closure with non-function object");
-                    }
-                },
+                    let closure = Closure {
+                        func,
+                        free_vars,
+                        max_depth: max_depths[constant_index],
+                    };
+                    self.push(Object::Closure(Rc::new(closure)));
+                }
                 OpGetFree => {
-                    let index = frame_stack.read_u8_from_top() as usize;
-                    self.push(frame_stack.top().free_vars[index].clone())?;
+                    let index = ip.read_u8();
+                    self.push(frames.current.closure.free_vars[index].clone());
+                }
+                OpConcat => {
+                    let num_parts = ip.read_u16();
+                    let result: String =
+                        self.take(num_parts).iter().map(Object::stringify).collect();
+                    self.meter.allocate(result.len()).map_err(MonkeyError::Vm)?;
+                    self.push(Object::Str(Box::new(result)));
+                }
+                OpArrayPush => {
+                    let value = self.pop_value();
+                    if let Some(Object::Array(a)) = self.stack.last_mut() {
+                        self.meter
+                            .check_size(a.len() + 1)
+                            .map_err(MonkeyError::Vm)?;
+                        a.push(value);
+                    }
+                }
+                OpArrayExtend => {
+                    let value = self.pop_value();
+                    match (self.stack.last_mut(), value) {
+                        (Some(Object::Array(a)), Object::Array(other)) => {
+                            self.meter
+                                .check_size(a.len() + other.len())
+                                .map_err(MonkeyError::Vm)?;
+                            a.extend(*other)
+                        }
+                        (_, other) => {
+                            return Err(MonkeyError::Vm(TypeError("array", other.type_str())))
+                        }
+                    }
+                }
+                OpHashInsert => {
+                    let value = self.pop_value();
+                    let key = self.pop_value();
+                    let key_type = key.type_str();
+                    let hashable = HashableObject::from_object(key)
+                        .ok_or(MonkeyError::Vm(HashKeyTypeError(key_type)))?;
+                    if let Some(Object::Hash(h)) = self.stack.last_mut() {
+                        h.insert(hashable, value);
+                        self.meter.check_size(h.len()).map_err(MonkeyError::Vm)?;
+                    }
+                }
+                OpHashExtend => {
+                    let value = self.pop_value();
+                    match (self.stack.last_mut(), value) {
+                        (Some(Object::Hash(h)), Object::Hash(other)) => {
+                            h.extend(*other);
+                            self.meter.check_size(h.len()).map_err(MonkeyError::Vm)?;
+                        }
+                        (_, other) => {
+                            return Err(MonkeyError::Vm(TypeError("hash", other.type_str())))
+                        }
+                    }
+                }
+                OpTry => {
+                    let target = ip.read_u16();
+                    self.handlers.push(Handler {
+                        num_frames: frames.len(),
+                        sp: self.stack.len(),
+                        target,
+                    });
+                }
+                OpEndTry => {
+                    self.handlers.pop();
+                }
+                OpThrow => {
+                    let value = self.pop_value();
+                    return Err(MonkeyError::Vm(Thrown(Box::new(value))));
+                }
+                OpGenerator => {
+                    // Calling a generator function suspends the new frame right away, and
+                    // evaluates to the generator, which replaces the function that was called
+                    let frame = frames.pop();
+                    let stack = self.stack.split_off(frame.base_pointer);
+                    self.stack.pop();
+                    let suspended = SuspendedFrame {
+                        closure: frame.closure,
+                        pc: ip.pc,
+                        stack,
+                        handlers: Vec::new(),
+                    };
+                    self.meter.allocate(0).map_err(MonkeyError::Vm)?;
+                    let generator = GeneratorObject {
+                        state: GeneratorState::Suspended(suspended),
+                    };
+                    self.push(Object::Generator(gc::new_generator(generator)));
+                    *ip = Cursor::new(&frames.current);
+                }
+                OpYield => {
+                    frames.current.pc = ip.pc;
+                    let value = self.pop_value();
+                    self.suspend_generator(frames);
+                    self.push(value);
+                    *ip = Cursor::new(&frames.current);
+                }
+                OpIter => match self.stack.last() {
+                    Some(Object::Array(_)) | Some(Object::Str(_)) | Some(Object::Generator(_)) => {
+                        self.push(Object::Integer(0))
+                    }
+                    Some(other) => {
+                        return Err(MonkeyError::Vm(TypeError("iterable", other.type_str())))
+                    }
+                    None => return Err(MonkeyError::Vm(StackUnderflow)),
                 },
+                OpIterNext => {
+                    let end = ip.read_u16();
+                    let sp = self.stack.len();
+                    // The verifier checks the size of the stack, but not the types of the values
+                    // in it, so these are only checked here
+                    let index = match &self.stack[sp - 1] {
+                        Object::Integer(i) => *i as usize,
+                        other => return Err(MonkeyError::Vm(TypeError("int", other.type_str()))),
+                    };
+                    let value = match &self.stack[sp - 2] {
+                        Object::Array(a) => a.get(index).cloned(),
+                        Object::Str(s) => {
+                            s.chars().nth(index).map(|c| Object::from(&*c.to_string()))
+                        }
+                        Object::Generator(generator) => {
+                            let generator = Rc::clone(generator);
+                            frames.current.pc = ip.pc;
+                            if self.resume_generator(frames, generator, Some(end))? {
+                                *ip = Cursor::new(&frames.current);
+                                continue;
+                            }
+                            None
+                        }
+                        other => {
+                            return Err(MonkeyError::Vm(TypeError("iterable", other.type_str())))
+                        }
+                    };
+                    match value {
+                        Some(value) => {
+                            self.stack[sp - 1] = Object::Integer(index as i64 + 1);
+                            self.push(value);
+                        }
+                        None => {
+                            self.take(2);
+                            ip.pc = end;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resumes a generator in a new frame, on top of the stack. Returns `false` if the generator
+    /// is already finished, in which case nothing is done.
+    fn resume_generator(
+        &mut self,
+        frames: &mut FrameStack,
+        generator: Rc<RefCell<GeneratorObject>>,
+        on_done: Option<usize>,
+    ) -> MonkeyResult<bool> {
+        let state = mem::replace(&mut generator.borrow_mut().state, GeneratorState::Running);
+        let suspended = match state {
+            GeneratorState::Suspended(suspended) => suspended,
+            GeneratorState::Done => {
+                generator.borrow_mut().state = GeneratorState::Done;
+                return Ok(false);
             }
+            GeneratorState::Running => return Err(MonkeyError::Vm(GeneratorRunning)),
+            // The VM never creates interpreter generators
+            GeneratorState::Interpreted(_) => unreachable!(),
+        };
+        if self.stack.len() + suspended.closure.max_depth > STACK_SIZE {
+            generator.borrow_mut().state = GeneratorState::Suspended(suspended);
+            return Err(MonkeyError::Vm(StackOverflow));
+        }
+
+        let base_pointer = self.stack.len();
+        self.stack.extend(suspended.stack);
+        for (sp, target) in suspended.handlers {
+            self.handlers.push(Handler {
+                num_frames: frames.len() + 1,
+                sp: base_pointer + sp,
+                target,
+            });
+        }
+        let generator = ResumedGenerator {
+            object: generator,
+            on_done,
+        };
+        frames.push(Frame::new(
+            suspended.closure,
+            suspended.pc,
+            base_pointer,
+            Some(generator),
+        ));
+        Ok(true)
+    }
+
+    /// Pops the frame of the generator that is running, and saves its state in the generator
+    /// object, so it can be resumed later.
+    fn suspend_generator(&mut self, frames: &mut FrameStack) {
+        let num_frames = frames.len();
+        let frame = frames.pop();
+        let mut handlers = Vec::new();
+        while let Some(true) = self.handlers.last().map(|h| h.num_frames >= num_frames) {
+            let handler = self.handlers.pop().unwrap();
+            handlers.push((handler.sp - frame.base_pointer, handler.target));
+        }
+        handlers.reverse();
+        let stack = self.stack.split_off(frame.base_pointer);
+        let suspended = SuspendedFrame {
+            closure: frame.closure,
+            pc: frame.pc,
+            stack,
+            handlers,
+        };
+        // The parser only accepts `yield` statements inside generator functions
+        let generator = frame.generator.expect("`yield` outside of generator");
+        generator.object.borrow_mut().state = GeneratorState::Suspended(suspended);
+    }
 
-            frame_stack.top_mut().pc += 1;
+    /// Marks the generators running in the frames above the first `num_frames` frames as finished.
+    /// This is used when unwinding the frame stack.
+    fn finish_generators(frames: &FrameStack, num_frames: usize) {
+        for frame in frames.iter().skip(num_frames) {
+            if let Some(generator) = &frame.generator {
+                generator.object.borrow_mut().state = GeneratorState::Done;
+            }
         }
+    }
+
+    /// Handles a runtime error using the innermost exception handler. Unwinds the frame stack and
+    /// the stack to the state they were in when the handler was registered, and jumps to the
+    /// handler with the exception on top of the stack. If there is no handler or the error can't
+    /// be caught, returns the error.
+    fn catch(&mut self, error: RuntimeError, frames: &mut FrameStack) -> MonkeyResult<()> {
+        let caught = match self.handlers.pop() {
+            Some(handler) => error.into_exception().map(|exception| (handler, exception)),
+            None => Err(error),
+        };
+        let (handler, exception) = match caught {
+            Ok(caught) => caught,
+            Err(error) => {
+                VM::finish_generators(frames, 1);
+                return Err(MonkeyError::Vm(error));
+            }
+        };
+        VM::finish_generators(frames, handler.num_frames);
+        frames.truncate(handler.num_frames);
+        self.stack.truncate(handler.sp);
+        frames.current.pc = handler.target;
+        self.push(exception);
         Ok(())
     }
 
-    fn push(&mut self, obj: Object) -> MonkeyResult<()> {
-        if self.sp >= STACK_SIZE {
-            Err(MonkeyError::Vm(StackOverflow))
-        } else {
-            self.stack.push(obj);
-            self.sp += 1;
-            Ok(())
+    /// Pops the array of arguments of a call with spread arguments. The compiler always emits an
+    /// array before these calls, but the verifier doesn't check the types of values.
+    #[allow(clippy::box_collection)]
+    fn pop_spread_args(&mut self) -> MonkeyResult<Box<Vec<Object>>> {
+        match self.pop_value() {
+            Object::Array(a) => Ok(a),
+            other => Err(MonkeyError::Vm(TypeError("array", other.type_str()))),
         }
     }
 
+    #[inline]
+    fn push(&mut self, obj: Object) {
+        self.stack.push(obj);
+    }
+
+    // The verifier checks that instructions never pop values that aren't there
+    #[inline]
+    fn pop_value(&mut self) -> Object {
+        self.stack.pop().expect("Popping from an empty stack")
+    }
+
+    /// Pops the value on top of the stack, like the value of the last expression of the program.
     pub fn pop(&mut self) -> MonkeyResult<Object> {
-        if self.sp == 0 {
-            Err(MonkeyError::Vm(StackUnderflow))
-        } else {
-            self.sp -= 1;
-            Ok(self.stack.pop().unwrap())
+        self.stack.pop().ok_or(MonkeyError::Vm(StackUnderflow))
+    }
+
+    /// Pushes the arguments of a call with spread arguments. Unlike the values pushed by other
+    /// instructions, their number isn't known before the function runs, so it's checked here.
+    fn push_spread_args(&mut self, args: Vec<Object>) -> MonkeyResult<usize> {
+        if self.stack.len() + args.len() > STACK_SIZE {
+            return Err(MonkeyError::Vm(StackOverflow));
+        }
+        let num_args = args.len();
+        self.stack.extend(args);
+        Ok(num_args)
+    }
+
+    /// Returns the two values on top of the stack if they're both integers, with the left one
+    /// borrowed so it can be replaced in place.
+    #[inline]
+    fn integer_operands(&mut self) -> Option<(&mut i64, i64)> {
+        match self.stack.as_mut_slice() {
+            [.., Object::Integer(l), Object::Integer(r)] => Some((l, *r)),
+            _ => None,
         }
     }
 
+    /// Pops values that are known to be integers. Integers don't own anything, so they can be
+    /// forgotten instead of dropped, which is faster.
+    #[inline]
+    fn discard_integers(&mut self, num_items: usize) {
+        let len = self.stack.len() - num_items;
+        // Safety: shrinking a vector only leaks the values that are removed
+        unsafe { self.stack.set_len(len) }
+    }
+
     fn take(&mut self, num_items: usize) -> Vec<Object> {
-        let v = self.stack.split_off(self.sp - num_items);
-        self.sp -= num_items;
-        v
+        self.stack.split_off(self.stack.len() - num_items)
     }
 
     fn execute_binary_operation(&mut self, operation: OpCode) -> MonkeyResult<()> {
         use Object::*;
 
-        let right = self.pop()?;
-        let left = self.pop()?;
+        let right = self.pop_value();
+        let left = self.pop_value();
         match (left, operation, right) {
-            (Integer(l), op, Integer(r)) => self.execute_integer_operation(op, l, r),
+            (Integer(l), op, Integer(r)) => {
+                let result = integer_operation(op, l, r).map_err(MonkeyError::Vm)?;
+                self.push(result);
+                Ok(())
+            }
             (Boolean(l), op, Boolean(r)) => self.execute_bool_operation(op, l, r),
             (Str(l), OpCode::OpAdd, Str(r)) => self.execute_str_concat(&l, &r),
             (l, op, r) => Err(MonkeyError::Vm(InfixTypeError(
@@ -266,30 +888,6 @@ impl VM {
         }
     }
 
-    fn execute_integer_operation(&mut self, op: OpCode, left: i64, right: i64) -> MonkeyResult<()> {
-        let result = match op {
-            // Arithmetic operators
-            OpCode::OpAdd => Object::Integer(left + right),
-            OpCode::OpSub => Object::Integer(left - right),
-            OpCode::OpMul => Object::Integer(left * right),
-            OpCode::OpDiv if right == 0 => return Err(MonkeyError::Vm(DivOrModByZero)),
-            OpCode::OpDiv => Object::Integer(left / right),
-            OpCode::OpExponent if right < 0 => return Err(MonkeyError::Vm(NegativeExponent)),
-            OpCode::OpExponent => Object::Integer(left.pow(right as u32)),
-            OpCode::OpModulo if right == 0 => return Err(MonkeyError::Vm(DivOrModByZero)),
-            OpCode::OpModulo => Object::Integer(left % right),
-
-            // Comparison operators
-            OpCode::OpEquals => Object::Boolean(left == right),
-            OpCode::OpNotEquals => Object::Boolean(left != right),
-            OpCode::OpGreaterThan => Object::Boolean(left > right),
-            OpCode::OpGreaterEq => Object::Boolean(left >= right),
-            _ => unreachable!(),
-        };
-        self.push(result)?;
-        Ok(())
-    }
-
     fn execute_bool_operation(&mut self, op: OpCode, left: bool, right: bool) -> MonkeyResult<()> {
         let result = match op {
             OpCode::OpEquals => Object::Boolean(left == right),
@@ -302,20 +900,24 @@ impl VM {
                 )))
             }
         };
-        self.push(result)?;
+        self.push(result);
         Ok(())
     }
 
     fn execute_str_concat(&mut self, left: &str, right: &str) -> MonkeyResult<()> {
-        self.push(Object::Str(Box::new(left.to_string() + right)))
+        self.meter
+            .allocate(left.len() + right.len())
+            .map_err(MonkeyError::Vm)?;
+        self.push(Object::Str(Box::new(left.to_string() + right)));
+        Ok(())
     }
 
     fn execute_prefix_operation(&mut self, op: OpCode) -> MonkeyResult<()> {
-        let right = self.pop()?;
+        let right = self.pop_value();
         match op {
             OpCode::OpPrefixMinus => {
                 if let Object::Integer(i) = right {
-                    self.push(Object::Integer(-i))?;
+                    self.push(Object::Integer(-i));
                 } else {
                     return Err(MonkeyError::Vm(PrefixTypeError(
                         Token::Minus,
@@ -323,10 +925,20 @@ impl VM {
                     )));
                 }
             }
+            OpCode::OpPrefixBitNot => {
+                if let Object::Integer(i) = right {
+                    self.push(Object::Integer(!i));
+                } else {
+                    return Err(MonkeyError::Vm(PrefixTypeError(
+                        Token::Tilde,
+                        right.type_str(),
+                    )));
+                }
+            }
             OpCode::OpPrefixNot => {
                 // @PERFORMANCE: Using `is_truthy` might be slow
                 let value = !right.is_truthy();
-                self.push(Object::Boolean(value))?;
+                self.push(Object::Boolean(value));
             }
             _ => unreachable!(),
         }
@@ -362,13 +974,97 @@ impl VM {
             (other, _) => Err(IndexingWrongType(other.type_str())),
         };
         let result = result.map_err(MonkeyError::Vm)?;
-        self.push(result)
+        self.push(result);
+        Ok(())
+    }
+
+    /// Moves the closure at `pos` out of the stack, leaving `nil` in its place, so it can be owned
+    /// by the frame of a call to it. Returns `None` if the object isn't a closure.
+    #[inline]
+    fn take_closure(&mut self, pos: usize) -> Option<Rc<Closure>> {
+        match &mut self.stack[pos] {
+            slot @ Object::Closure(_) => match mem::replace(slot, Object::Nil) {
+                Object::Closure(closure) => Some(closure),
+                _ => unreachable!(),
+            },
+            _ => None,
+        }
+    }
+
+    /// Calls the function object that is below the `num_args` arguments on top of the stack.
+    /// Closures run in a new frame, and the other callable objects replace the function object
+    /// and the arguments with their result right away.
+    fn execute_call(&mut self, frames: &mut FrameStack, num_args: usize) -> MonkeyResult<()> {
+        let callee_pos = self.stack.len() - 1 - num_args;
+        match self.take_closure(callee_pos) {
+            Some(closure) => self.execute_closure_call(frames, closure, num_args),
+            None => self.execute_native_call(frames, callee_pos, num_args),
+        }
     }
 
+    /// Calls an object that isn't a closure, which doesn't need a frame.
+    fn execute_native_call(
+        &mut self,
+        frames: &mut FrameStack,
+        callee_pos: usize,
+        num_args: usize,
+    ) -> MonkeyResult<()> {
+        match &self.stack[callee_pos] {
+            Object::Builtin(BuiltinFn::Next) => {
+                let generator = builtins::next_generator(&self.stack[callee_pos + 1..])
+                    .map_err(MonkeyError::Vm)?;
+                let generator = Rc::clone(generator);
+                self.stack.truncate(callee_pos);
+                if !self.resume_generator(frames, generator, None)? {
+                    // `next` evaluates to `nil` if the generator is finished
+                    self.push(Object::Nil);
+                }
+                Ok(())
+            }
+            &Object::Builtin(BuiltinFn::Native(f)) => self.execute_builtin_call(f, num_args),
+            Object::StructConstructor(def) => {
+                let def = Rc::clone(def);
+                let values = self.take(num_args);
+                self.stack.pop();
+                let instance = def.construct(values).map_err(MonkeyError::Vm)?;
+                self.push(instance);
+                Ok(())
+            }
+            other => Err(MonkeyError::Vm(NotCallable(other.type_str()))),
+        }
+    }
+
+    /// Calls the method named `method` on the receiver that is below the `num_args` arguments on
+    /// top of the stack. The receiver is replaced by the resolved function, and, if it's a
+    /// built-in method, the receiver is passed as the first argument.
+    fn execute_method_call(
+        &mut self,
+        frames: &mut FrameStack,
+        method: &str,
+        num_args: usize,
+    ) -> MonkeyResult<()> {
+        let receiver_pos = self.stack.len() - 1 - num_args;
+        let method =
+            builtins::get_method(&self.stack[receiver_pos], method).map_err(MonkeyError::Vm)?;
+        match method {
+            builtins::Method::Field(function) => {
+                self.stack[receiver_pos] = function;
+                self.execute_call(frames, num_args)
+            }
+            builtins::Method::Builtin(function) => {
+                self.stack.insert(receiver_pos, Object::Builtin(function));
+                self.execute_call(frames, num_args + 1)
+            }
+        }
+    }
+
+    /// Pushes the frame of a call to the closure. The arguments become its first locals, and the
+    /// slot of the closure stays on the stack below them until the call returns.
+    #[inline(always)]
     fn execute_closure_call(
         &mut self,
-        frame_stack: &mut FrameStack,
-        closure: Closure,
+        frames: &mut FrameStack,
+        closure: Rc<Closure>,
         num_args: usize,
     ) -> MonkeyResult<()> {
         if closure.func.num_params as usize != num_args {
@@ -377,28 +1073,121 @@ impl VM {
                 num_args,
             )));
         }
-        frame_stack.top_mut().pc += 1;
-        let new_frame = Frame {
-            instructions: closure.func.instructions,
-            free_vars: closure.free_vars,
-            pc: 0,
-            base_pointer: self.sp - num_args,
-        };
-        frame_stack.push(new_frame);
-        self.sp += closure.func.num_locals as usize;
-        // @PERFORMANCE: This resize is slow, because it has to copy over `Object::Nil`. It
-        // would be faster to use `Vec::set_len`, but that method is unsafe. I'm fairly certain
-        // that it would be fine (safety wise) in these circumstances, but just to be sure I'm
-        // using `resize` for now.
-        self.stack.resize(self.sp, Object::Nil);
+        let base_pointer = self.stack.len() - num_args;
+        if base_pointer + closure.max_depth > STACK_SIZE {
+            return Err(MonkeyError::Vm(StackOverflow));
+        }
+        self.stack
+            .resize(base_pointer + closure.func.num_locals as usize, Object::Nil);
+        frames.push(Frame::new(closure, 0, base_pointer, None));
+        Ok(())
+    }
+
+    /// Calls the closure that is below the `num_args` arguments on top of the stack, reusing the
+    /// current frame. The locals of the current function are replaced by the arguments, so tail
+    /// calls don't grow the stack.
+    fn execute_tail_call(
+        &mut self,
+        frames: &mut FrameStack,
+        closure: Rc<Closure>,
+        num_args: usize,
+    ) -> MonkeyResult<()> {
+        if closure.func.num_params as usize != num_args {
+            return Err(MonkeyError::Vm(WrongNumberOfArgs(
+                closure.func.num_params as usize,
+                num_args,
+            )));
+        }
+        let base_pointer = frames.current.base_pointer;
+        if base_pointer + closure.max_depth > STACK_SIZE {
+            return Err(MonkeyError::Vm(StackOverflow));
+        }
+        let args = self.take(num_args);
+        self.stack.truncate(base_pointer);
+        self.stack.extend(args);
+        self.stack
+            .resize(base_pointer + closure.func.num_locals as usize, Object::Nil);
+        frames.current = Frame::new(closure, 0, base_pointer, None);
         Ok(())
     }
 
-    fn execute_builtin_call(&mut self, func: BuiltinFn, num_args: usize) -> MonkeyResult<()> {
+    fn execute_builtin_call(
+        &mut self,
+        func: fn(Vec<Object>) -> Result<Object, RuntimeError>,
+        num_args: usize,
+    ) -> MonkeyResult<()> {
         // @PERFORMANCE: This has to allocate a vector and move over the arguments. It might be
         // better for the built-in functions to just take a slice of objects instead of a `Vec`.
         let args = self.take(num_args);
-        let result = func.0(args).map_err(MonkeyError::Vm)?;
-        self.push(result)
+        self.stack.pop();
+        let result = func(args).map_err(MonkeyError::Vm)?;
+        self.push(result);
+        Ok(())
     }
 }
+
+// Finds the line of the main file that the instruction where the current frame stopped was
+// compiled from. Only known if the bytecode has a line table, and for code of the main file.
+fn error_line(frames: &FrameStack, constants: &[Object], lines: &LineTable) -> Option<usize> {
+    let current = &frames.current;
+    // The main program always runs in the root frame
+    let unit = if frames.callers.is_empty() {
+        None
+    } else {
+        let is_current = |constant: &Object| matches!(constant, Object::CompiledFunc(f) if Rc::ptr_eq(f, &current.closure.func));
+        Some(constants.iter().position(is_current)?)
+    };
+    // The position is past the start of the instruction that failed
+    let offset = current.pc.checked_sub(1)?;
+    let unit_lines = lines.get(&unit)?.iter();
+    let (_, line) = unit_lines
+        .take_while(|&&(start, _)| start <= offset)
+        .last()?;
+    Some(*line)
+}
+
+/// Clones an object. Integers and closures are cloned so often that they're copied without going
+/// through `Clone::clone`, which isn't inlined.
+#[inline]
+fn clone_object(object: &Object) -> Object {
+    match object {
+        Object::Integer(i) => Object::Integer(*i),
+        Object::Closure(closure) => Object::Closure(Rc::clone(closure)),
+        other => other.clone(),
+    }
+}
+
+fn integer_operation(op: OpCode, left: i64, right: i64) -> Result<Object, RuntimeError> {
+    let result = match op {
+        // Arithmetic operators
+        OpCode::OpAdd => Object::Integer(left + right),
+        OpCode::OpSub => Object::Integer(left - right),
+        OpCode::OpMul => Object::Integer(left * right),
+        OpCode::OpDiv if right == 0 => return Err(DivOrModByZero),
+        OpCode::OpDiv => Object::Integer(left / right),
+        OpCode::OpExponent if right < 0 => return Err(NegativeExponent),
+        OpCode::OpExponent => Object::Integer(left.pow(right as u32)),
+        OpCode::OpModulo if right == 0 => return Err(DivOrModByZero),
+        OpCode::OpModulo => Object::Integer(left % right),
+
+        // Bitwise operators
+        OpCode::OpBitAnd => Object::Integer(left & right),
+        OpCode::OpBitOr => Object::Integer(left | right),
+        OpCode::OpBitXor => Object::Integer(left ^ right),
+        OpCode::OpShiftLeft | OpCode::OpShiftRight if !(0..64).contains(&right) => {
+            return Err(InvalidShiftAmount(right))
+        }
+        OpCode::OpShiftLeft => Object::Integer(left << right),
+        OpCode::OpShiftRight => Object::Integer(left >> right),
+
+        // Comparison operators
+        OpCode::OpEquals => Object::Boolean(left == right),
+        OpCode::OpNotEquals => Object::Boolean(left != right),
+        OpCode::OpGreaterThan => Object::Boolean(left > right),
+        OpCode::OpGreaterEq => Object::Boolean(left >= right),
+        OpCode::OpLessThan => Object::Boolean(left < right),
+        OpCode::OpLessEq => Object::Boolean(left <= right),
+        _ => unreachable!(),
+    };
+    Ok(result)
+}
@@ -1,6 +1,12 @@
 use super::*;
+use crate::compiler::{optimize::OptLevel, Compiler};
+use crate::error::{CodeLocation, CompilerError, VerifierError};
+use crate::limits::Limits;
+use crate::make;
 use crate::test_utils;
 
+use std::time::Duration;
+
 fn assert_vm_runs(input: &[&str], expected: &[Object]) {
     for (program, exp) in input.iter().zip(expected) {
         let bytecode =
@@ -11,6 +17,19 @@ fn assert_vm_runs(input: &[&str], expected: &[Object]) {
     }
 }
 
+fn assert_vm_runtime_error(input: &[&str], expected: &[&str]) {
+    for (program, exp) in input.iter().zip(expected) {
+        let bytecode =
+            test_utils::parse_and_compile(program).expect("Parser or compiler error during test");
+        let mut vm = VM::new();
+        match vm.run(bytecode) {
+            Err(MonkeyError::Vm(e) | MonkeyError::VmAt(_, e)) => assert_eq!(exp, &format!("{}", e)),
+            Err(_) => panic!("Wrong error type"),
+            Ok(_) => panic!("No runtime error encountered"),
+        }
+    }
+}
+
 #[test]
 fn test_integer_arithmetic() {
     let input = ["2 + 3", "-3"];
@@ -18,6 +37,36 @@ fn test_integer_arithmetic() {
     assert_vm_runs(&input, &expected);
 }
 
+#[test]
+fn test_bitwise_operators() {
+    let input = [
+        "12 & 10",
+        "12 | 10",
+        "12 ~ 10",
+        "~0",
+        "-16 >> 2",
+        "1 | 2 & 3 << 1",
+    ];
+    let expected = [
+        Object::Integer(8),
+        Object::Integer(14),
+        Object::Integer(6),
+        Object::Integer(-1),
+        Object::Integer(-4),
+        Object::Integer(3),
+    ];
+    assert_vm_runs(&input, &expected);
+
+    let input = ["1 << 64", "1 >> -1", "true | false", "~true"];
+    let expected = [
+        "invalid shift amount: 64",
+        "invalid shift amount: -1",
+        "unsuported operand types for infix operator `|`: 'bool' and 'bool'",
+        "unsuported operand type for prefix operator `~`: 'bool'",
+    ];
+    assert_vm_runtime_error(&input, &expected);
+}
+
 #[test]
 fn test_boolean_expressions() {
     let input = [
@@ -27,6 +76,9 @@ fn test_boolean_expressions() {
         "false != 1 < 2",
         "!false",
         "!(if false { 3 })",
+        "1 < 2",
+        "2 <= 1",
+        "3 <= 3",
     ];
     let expected = [
         Object::Boolean(true),
@@ -35,8 +87,19 @@ fn test_boolean_expressions() {
         Object::Boolean(true),
         Object::Boolean(true),
         Object::Boolean(true),
+        Object::Boolean(true),
+        Object::Boolean(false),
+        Object::Boolean(true),
     ];
     assert_vm_runs(&input, &expected);
+
+    // The operands of every comparison are evaluated from left to right
+    let input = [r#"(1 / 0) < ("a" - 1)"#, r#""a" <= 1"#];
+    let expected = [
+        "division or modulo by zero",
+        "unsuported operand types for infix operator `<=`: 'string' and 'int'",
+    ];
+    assert_vm_runtime_error(&input, &expected);
 }
 
 #[test]
@@ -84,6 +147,23 @@ fn test_strings() {
     assert_vm_runs(&input, &expected);
 }
 
+#[test]
+fn test_interpolated_strings() {
+    let input = [
+        r#"let x = 3; "x = ${x}""#,
+        r#""${1 + 1}${"!"}""#,
+        r#""${[1, "a"]} ${#{"k": nil}} ${fn() {}}""#,
+        r#"str("a") + str(true)"#,
+    ];
+    let expected = [
+        Object::from("x = 3"),
+        Object::from("2!"),
+        Object::from(r#"[1, "a"] #{"k": nil} <function>"#),
+        Object::from("atrue"),
+    ];
+    assert_vm_runs(&input, &expected);
+}
+
 #[test]
 fn test_arrays() {
     let input = ["[]", "[1, 2, 3]", "[1 + 2, 3 - 4, 5 * 6]"];
@@ -131,6 +211,131 @@ fn test_index_expressions() {
     assert_vm_runs(&input, &expected);
 }
 
+#[test]
+fn test_nil_safe_operators() {
+    let input = [
+        r#"let h = #{"a": #{"b": [1, 2]}}; h?["a"]?["b"]?[1]"#,
+        r#"let h = #{"a": #{"b": [1, 2]}}; h?["x"]?["b"]?[1]"#,
+        "[1]?[1]",
+        "nil?[0]",
+        "nil ?? 1",
+        "false ?? 1",
+        r#"let h = #{}; h?["x"] ?? h?["y"] ?? "default""#,
+        "let calls = []; let f = fn() { push(calls, 1) }; 1 ?? f(); calls",
+        "let f = fn(x) { let y = x ?? 2; y * 2 }; f(nil) + f(1)",
+        "let gen = fn*() { yield 1; yield 2; }; let g = gen(); nil?[next(g)]; next(g)",
+    ];
+    let expected = [
+        Object::Integer(2),
+        Object::Nil,
+        Object::Nil,
+        Object::Nil,
+        Object::Integer(1),
+        Object::Boolean(false),
+        Object::from("default"),
+        monkey_array![],
+        Object::Integer(6),
+        Object::Integer(1),
+    ];
+    assert_vm_runs(&input, &expected);
+
+    let input = ["1?[0]", "[1]?[true]"];
+    let expected = [
+        "'int' is not an array or hash object",
+        "index must be integer, not 'bool'",
+    ];
+    assert_vm_runtime_error(&input, &expected);
+}
+
+#[test]
+fn test_pipe_operator() {
+    let input = [
+        "let double = fn(x) { x * 2 }; 3 |> double",
+        "let add = fn(x, y) { x + y }; let double = fn(x) { x * 2 }; 3 |> add(4) |> double",
+        "[1, 2] |> push(3) |> len",
+        "let add = fn(x, y) { x + y }; 2 |> fn(x) { x * x } |> add(..[1])",
+    ];
+    let expected = [
+        Object::Integer(6),
+        Object::Integer(14),
+        Object::Integer(3),
+        Object::Integer(5),
+    ];
+    assert_vm_runs(&input, &expected);
+}
+
+#[test]
+fn test_structs() {
+    let input = [
+        "struct Point { x, y } let p = Point(1, 2); p.x + p.y",
+        "struct Point { x, y } let p = Point(1, 2); let q = p.x = 10; [p.x, q.x, q.y]",
+        "struct Point { x, y } str(Point(1, 2).y = [3])",
+        "struct Point { x, y } type(Point(1, 2))",
+        "struct Wrapper { value } let f = fn(w) { w.value * 2 }; f(Wrapper(21))",
+        "struct Empty {} str(Empty())",
+    ];
+    let expected = [
+        Object::Integer(3),
+        monkey_array![Object::Integer(1), Object::Integer(10), Object::Integer(2)],
+        Object::from("Point { x: 1, y: [3] }"),
+        Object::from("Point"),
+        Object::Integer(42),
+        Object::from("Empty {}"),
+    ];
+    assert_vm_runs(&input, &expected);
+
+    let input = [
+        "struct Point { x, y } Point(1)",
+        "struct Point { x, y } Point(1, 2).z",
+        "struct Point { x, y } Point(1, 2).z = 3",
+        "[1].x",
+    ];
+    let expected = [
+        "wrong number of arguments: expected 2 arguments but 1 were given",
+        "'Point' struct has no field 'z'",
+        "'Point' struct has no field 'z'",
+        "'array' object has no fields",
+    ];
+    assert_vm_runtime_error(&input, &expected);
+}
+
+#[test]
+fn test_method_calls() {
+    let input = [
+        r#""  a,b ".trim().split(",")"#,
+        "[1, 2].push(3).len()",
+        r#"#{"b": 2, "a": 1}.keys()"#,
+        r#"#{"b": 2, "a": 1}.values()"#,
+        r#"[1, "a", nil].join("-")"#,
+        "[0].push(..[1])",
+        "struct Counter { count, step } let c = Counter(1, fn(x) { x + 1 }); c.step(c.count)",
+        "let f = fn(s) { s.trim().len() }; f(\" abc \")",
+        r#"let sep = ","; "1,2" |> sep.split()"#,
+    ];
+    let expected = [
+        monkey_array![Object::from("a"), Object::from("b")],
+        Object::Integer(3),
+        monkey_array![Object::from("a"), Object::from("b")],
+        monkey_array![Object::Integer(1), Object::Integer(2)],
+        Object::from("1-a-nil"),
+        monkey_array![Object::Integer(0), Object::Integer(1)],
+        Object::Integer(2),
+        Object::Integer(3),
+        monkey_array![Object::from(",")],
+    ];
+    assert_vm_runs(&input, &expected);
+
+    let input = [r#""a".push(1)"#, "#{}.foo()", "[].join(1)"];
+    let expected = [
+        "'string' object has no method 'push' (available methods: len, str, trim, split, \
+        is_error, unwrap, unwrap_or)",
+        "'hash' object has no method 'foo' (available methods: str, keys, values, is_error, \
+        unwrap, unwrap_or)",
+        "type error: expected 'string', got 'int'",
+    ];
+    assert_vm_runtime_error(&input, &expected);
+}
+
 #[test]
 fn test_function_calls() {
     let input = [
@@ -258,13 +463,11 @@ fn test_builtin_functions() {
 fn test_closures() {
     let input = [
         "fn(a) { fn(b) { fn(c) { a + b + c } } }(1)(3)(5)",
-
         "let make_closure = fn(a) {
             fn() { a }
         };
         let closure = make_closure(4);
         closure()",
-
         "let new_adder = fn(a, b) {
             fn(c) { a + b + c }
         };
@@ -274,7 +477,6 @@ fn test_closures() {
             fn(d) { c + d }
         };
         new_adder(1, 2)(8)",
-
         "let new_adder_outer = fn(a, b) {
             let c = a + b;
             fn(d) {
@@ -285,7 +487,6 @@ fn test_closures() {
         let new_adder_inner = new_adder_outer(1, 2);
         let adder = new_adder_inner(3);
         adder(8);",
-
         "let a = 1;
         let new_adder_outer = fn(b) {
             fn(c) { fn(d) { a + b + c + d } }
@@ -293,7 +494,6 @@ fn test_closures() {
         let new_adder_inner = new_adder_outer(2);
         let adder = new_adder_inner(3);
         adder(8);",
-
         "let new_closure = fn(a, b) {
             let first = fn() { a };
             let second = fn() { b };
@@ -326,6 +526,677 @@ fn test_recursive_fibonacci() {
         };
         fibonacci(30)
     "];
-    let expected = [Object::Integer(610)];
+    let expected = [Object::Integer(832_040)];
     assert_vm_runs(&input, &expected);
 }
+
+#[test]
+fn test_tail_calls() {
+    // Without tail calls, these would overflow the stack
+    let input = [
+        "let sum = fn(n, acc) { if n == 0 { acc } else { sum(n - 1, acc + n) } }; sum(100000, 0)",
+        "let is_even = fn(n, is_odd) { if n == 0 { return true; } return is_odd(n - 1); }; \
+        let is_odd = fn(n) { if n == 0 { false } else { is_even(n - 1, is_odd) } }; \
+        [is_even(100000, is_odd), is_odd(100001)]",
+        "let last = fn(n) { if n == 0 { len([1, 2]) } else { last(n - 1) } }; last(100000)",
+        // Tail calls to closures with a different number of locals
+        "let g = fn(m, f) { f(m) }; \
+        let f = fn(n) { let a = n; let b = a; if n == 0 { b } else { g(n - 1, f) } }; f(10000)",
+        // Calls inside `try` bodies are not tail calls, so the handler can still catch errors
+        "let fail = fn() { throw 1; }; let f = fn() { try { return fail(); } catch e { e + 1 } }; \
+        f()",
+        "let f = fn(x) { x }; let g = fn() { f() }; try { g() } catch e { e.type }",
+    ];
+    let expected = [
+        Object::Integer(5_000_050_000),
+        monkey_array![Object::Boolean(true), Object::Boolean(true)],
+        Object::Integer(2),
+        Object::Integer(0),
+        Object::Integer(2),
+        Object::from("ArgumentError"),
+    ];
+    assert_vm_runs(&input, &expected);
+
+    // Pushing the arguments of a call with spread arguments, or the elements of an array literal,
+    // can also overflow the stack
+    let long_array = format!("[{}]", vec!["0"; 3000].join(", "));
+    let input = [
+        "let f = fn(n) { if n == 0 { 0 } else { 1 + f(n - 1) } }; f(100000)",
+        "len(..range(3000))",
+        &long_array,
+    ];
+    let expected = ["stack overflow"; 3];
+    assert_vm_runtime_error(&input, &expected);
+}
+
+#[test]
+fn test_spread() {
+    let input = [
+        "let a = [1, 2]; [..a, 3, ..a, ..[]]",
+        "[0, ..[1], 2]",
+        "let sum = fn(x, y, z) { x + y + z }; sum(..[1, 2], 3)",
+        "let sum = fn(x, y, z) { x + y + z }; sum(..[1], ..[2, 3])",
+        "len(..[[1, 2, 3]])",
+        r#"let base = #{"a": 1, "b": 2}; #{..base, "b": 3, ..#{"c": 4}}"#,
+        r#"#{"a": 1, ..#{}}"#,
+    ];
+    let expected = [
+        monkey_array![
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+            Object::Integer(1),
+            Object::Integer(2)
+        ],
+        monkey_array![Object::Integer(0), Object::Integer(1), Object::Integer(2)],
+        Object::Integer(6),
+        Object::Integer(6),
+        Object::Integer(3),
+        monkey_hash! {
+            HashableObject::from("a") => Object::Integer(1),
+            HashableObject::from("b") => Object::Integer(3),
+            HashableObject::from("c") => Object::Integer(4)
+        },
+        monkey_hash! { HashableObject::from("a") => Object::Integer(1) },
+    ];
+    assert_vm_runs(&input, &expected);
+}
+
+#[test]
+fn test_error_values() {
+    let input = [
+        r#"error("not found", 404)"#,
+        r#"error("oops")"#,
+        r#"[is_error(error("oops")), is_error(1)]"#,
+        r#"[unwrap(5), unwrap_or(error("oops"), 0), unwrap_or(3, 0)]"#,
+        r#"error("oops").unwrap_or(1)"#,
+        r#"let parse = fn(x) { if x < 0 { error("negative") } else { x } };
+        let double = fn(x) { parse(x)? * 2 };
+        [double(2), double(-1)]"#,
+        r#"str(error("not found", 404))"#,
+        r#"type(error("oops"))"#,
+        r#"[5.is_error(), "a".unwrap(), nil.unwrap_or(1)]"#,
+        r#"let e = error("not found", 404); [e.type, e.message, e.data]"#,
+    ];
+    let error = |message: &str, data| {
+        let message = message.into();
+        Object::Error(Box::new(ErrorObject { message, data }))
+    };
+    let expected = [
+        error("not found", Object::Integer(404)),
+        error("oops", Object::Nil),
+        monkey_array![Object::Boolean(true), Object::Boolean(false)],
+        monkey_array![Object::Integer(5), Object::Integer(0), Object::Integer(3)],
+        Object::Integer(1),
+        monkey_array![Object::Integer(4), error("negative", Object::Nil)],
+        Object::from("error(\"not found\", 404)"),
+        Object::from("error"),
+        monkey_array![Object::Boolean(false), Object::from("a"), Object::Nil],
+        monkey_array![
+            Object::from("Error"),
+            Object::from("not found"),
+            Object::Integer(404)
+        ],
+    ];
+    assert_vm_runs(&input, &expected);
+
+    let input = [r#"unwrap(error("bad"))"#, "error(1)"];
+    let expected = [
+        "called `unwrap` on an error value: error(\"bad\")",
+        "type error: expected 'string', got 'int'",
+    ];
+    assert_vm_runtime_error(&input, &expected);
+}
+
+#[test]
+fn test_exceptions() {
+    let input = [
+        "try { 1 } catch e { 2 }",
+        "try { throw 3; 4 } catch e { e + 1 }",
+        r#"try { #{}["a"] } catch e { [e.type, e.message] }"#,
+        "try { 1 / 0 } catch e { e.type }",
+        "try { assert(false) } catch e { e.message }",
+        r#"let f = fn(x) { if x == 0 { throw "zero"; } x }; try { f(1) + f(0) } catch e { e }"#,
+        "try { try { throw 1; } catch e { throw e + 1; } } catch e { e * 10 }",
+        "let g = fn() { try { return 5; } catch e { 0 } }; g()",
+        "1 + try { nil() } catch e { 10 }",
+        // Unwinding through several frames
+        "let f = fn(a) { let b = [a]; b[5] }; let g = fn() { try { f(1) } catch e { e.type } }; \
+        [g(), g()]",
+        "let f = fn() { try { throw 1; } catch e { e } }; let g = fn(x) { x + f() }; g(1)",
+    ];
+    let expected = [
+        Object::Integer(1),
+        Object::Integer(4),
+        monkey_array![
+            Object::from("KeyError"),
+            Object::from("hash key error: entry for \"a\" not found")
+        ],
+        Object::from("ZeroDivisionError"),
+        Object::from("Assertion failed on value false"),
+        Object::from("zero"),
+        Object::Integer(20),
+        Object::Integer(5),
+        Object::Integer(11),
+        monkey_array![Object::from("IndexError"), Object::from("IndexError")],
+        Object::Integer(2),
+    ];
+    assert_vm_runs(&input, &expected);
+
+    let input = [
+        r#"throw "oops";"#,
+        "try { throw 1; } catch e { throw [e]; }",
+        "try { throw 1; } catch e { e.message }",
+        // Handlers are unregistered when their function returns
+        "let h = fn() { try { return 1; } catch e { 2 } }; h(); 1 + nil",
+    ];
+    let expected = [
+        "uncaught exception: \"oops\"",
+        "uncaught exception: [1]",
+        "'int' object has no fields",
+        "unsuported operand types for infix operator `+`: 'int' and 'nil'",
+    ];
+    assert_vm_runtime_error(&input, &expected);
+}
+
+#[test]
+fn test_generators() {
+    let collect = "let collect = fn(g, acc) { \
+        let x = next(g); if is_error(x ?? error(\"\")) { acc } else { collect(g, push(acc, x)) } }; \
+        let count = fn*(n) { for i in range(n) { yield i; } };";
+    let programs = [
+        "collect(count(3), [])",
+        "let g = count(2); [next(g), g.next(), next(g), next(g)]",
+        "let evens = fn*(xs) { for x in xs { if x % 2 == 0 { yield x; } } }; \
+        [collect(evens(count(5)), []), collect(evens([2, 3, 4]), [])]",
+        "let chars = fn*(s) { for c in s { yield c + c; } }; collect(chars(\"héy\"), [])",
+        "let stop = fn*() { yield 1; return; yield 2; }; collect(stop(), [])",
+        "let safe = fn*() { try { yield 1; throw \"oops\"; } catch e { yield e; } yield 3; }; \
+        collect(safe(), [])",
+        "let failing = fn*() { yield 1; nil(); }; let f = failing(); \
+        [next(f), try { next(f) } catch e { e.type }, next(f)]",
+        // Generators keep their locals and free variables between resumes
+        "let gen = fn*(a) { let b = a * 2; let f = fn() { a + b }; yield f(); yield b; yield a; }; \
+        collect(gen(1), [])",
+        // `yield` can happen in the middle of an expression
+        "let gen = fn*() { let x = 1 + if true { yield 10; 2 }; yield x; }; collect(gen(), [])",
+        // Generators can be resumed from inside of other generators and functions
+        "let inner = fn*() { yield 1; yield 2; }; let outer = fn*() { let g = inner(); \
+        yield next(g) * 10; yield fn() { next(g) }() * 10; }; collect(outer(), [])",
+        // Returning from inside a `for` loop
+        "let find = fn(xs, y) { for x in xs { if x == y { return true; } } false }; \
+        [find([1, 2], 2), find([1, 2], 3)]",
+        "for x in [1, 2] { x }",
+        "type(count(0))",
+        // The loop variable doesn't change a variable with the same name outside of the loop
+        "let x = 10; for x in [1, 2] { x }; x",
+        "let f = fn() { let x = 10; for x in [1, 2] { x }; x }; f()",
+    ];
+    let input: Vec<_> = programs
+        .iter()
+        .map(|p| format!("{} {}", collect, p))
+        .collect();
+    let input: Vec<_> = input.iter().map(String::as_str).collect();
+    let expected = [
+        monkey_array![Object::Integer(0), Object::Integer(1), Object::Integer(2)],
+        monkey_array![
+            Object::Integer(0),
+            Object::Integer(1),
+            Object::Nil,
+            Object::Nil
+        ],
+        monkey_array![
+            monkey_array![Object::Integer(0), Object::Integer(2), Object::Integer(4)],
+            monkey_array![Object::Integer(2), Object::Integer(4)]
+        ],
+        monkey_array![Object::from("hh"), Object::from("éé"), Object::from("yy")],
+        monkey_array![Object::Integer(1)],
+        monkey_array![Object::Integer(1), Object::from("oops"), Object::Integer(3)],
+        monkey_array![Object::Integer(1), Object::from("TypeError"), Object::Nil],
+        monkey_array![Object::Integer(3), Object::Integer(2), Object::Integer(1)],
+        monkey_array![Object::Integer(10), Object::Integer(3)],
+        monkey_array![Object::Integer(10), Object::Integer(20)],
+        monkey_array![Object::Boolean(true), Object::Boolean(false)],
+        Object::Nil,
+        Object::from("generator"),
+        Object::Integer(10),
+        Object::Integer(10),
+    ];
+    assert_vm_runs(&input, &expected);
+
+    // The loop variable is only visible in the body
+    match test_utils::parse_and_compile("for y in [1] { y }; y") {
+        Err(MonkeyError::Compiler(_, CompilerError::IdenNotFound(name))) => assert_eq!(name, "y"),
+        _ => panic!("The loop variable is visible after the loop"),
+    }
+
+    let input = [
+        "for x in 5 { x }",
+        "next([1])",
+        "let g = fn*() { yield 1; throw \"oops\"; }; for x in g() { x }",
+    ];
+    let expected = [
+        "type error: expected 'iterable', got 'int'",
+        "type error: expected 'generator', got 'array'",
+        "uncaught exception: \"oops\"",
+    ];
+    assert_vm_runtime_error(&input, &expected);
+}
+
+#[test]
+fn test_gc() {
+    // Closures in the VM don't form cycles, but generators are still tracked by the collector
+    let input = [
+        "let g = fn*() { yield 1; }(); next(g); let stats = gc(); \
+        [stats[\"collected\"], stats[\"generators\"], stats[\"environments\"]]",
+        "let make = fn() { let f = fn() { f }; f }; for i in range(100) { make(); } \
+        gc()[\"collected\"]",
+    ];
+    let expected = [
+        monkey_array![Object::Integer(0), Object::Integer(1), Object::Integer(0)],
+        Object::Integer(0),
+    ];
+    assert_vm_runs(&input, &expected);
+}
+
+#[test]
+fn test_limits() {
+    let no_limits = Limits::default();
+    let steps = Limits {
+        max_steps: Some(1000),
+        ..no_limits
+    };
+    let timeout = Limits {
+        timeout: Some(Duration::from_millis(50)),
+        ..no_limits
+    };
+    let size = Limits {
+        max_collection_size: Some(10),
+        ..no_limits
+    };
+    let allocations = Limits {
+        max_allocations: Some(10),
+        ..no_limits
+    };
+    let forever = "let f = fn(n) { f(n + 1) }; f(0)";
+    let tests = [
+        (
+            steps,
+            forever,
+            "step limit exceeded: ran more than 1000 steps",
+        ),
+        // Reaching a limit can't be caught
+        (
+            steps,
+            "try { fn() { for x in range(1000) { x; } }() } catch e { 0 }",
+            "step limit exceeded: ran more than 1000 steps",
+        ),
+        (timeout, forever, "timeout: ran for more than 50ms"),
+        (
+            size,
+            "range(100)",
+            "collection too large: collections can't have more than 10 elements",
+        ),
+        (
+            size,
+            "let a = range(8); [..a, ..a]",
+            "collection too large: collections can't have more than 10 elements",
+        ),
+        (
+            size,
+            r#"let s = "abcdef"; s + s"#,
+            "collection too large: collections can't have more than 10 elements",
+        ),
+        (
+            allocations,
+            "for i in range(100) { [i]; }",
+            "allocation limit exceeded: created more than 10 objects",
+        ),
+    ];
+    for (limits, program, expected) in tests {
+        let bytecode =
+            test_utils::parse_and_compile(program).expect("Parser or compiler error during test");
+        match VM::with_limits(limits).run(bytecode) {
+            Err(MonkeyError::Vm(e) | MonkeyError::VmAt(_, e)) => {
+                assert_eq!(format!("{}", e), expected)
+            }
+            Err(_) => panic!("Wrong error type"),
+            Ok(_) => panic!("No runtime error encountered"),
+        }
+    }
+
+    // The resources are counted from the start of each program
+    let mut vm = VM::with_limits(steps);
+    let program = "let f = fn(n) { if n > 0 { f(n - 1) } else { 0 } }; f(100)";
+    for _ in 0..2 {
+        let bytecode = test_utils::parse_and_compile(program).unwrap();
+        vm.run(bytecode).expect("Runtime error during test");
+    }
+
+    // Each VM has its own limits, even in the same thread
+    let mut limited = VM::with_limits(size);
+    let mut unlimited = VM::new();
+    let run = |vm: &mut VM, input: &str| vm.run(test_utils::parse_and_compile(input).unwrap());
+    assert!(run(&mut unlimited, "len(range(100))").is_ok());
+    run(&mut limited, "len(range(10))").expect("Runtime error during test");
+    assert_eq!(limited.pop().unwrap(), Object::Integer(10));
+    assert!(run(&mut limited, "len(range(100))").is_err());
+    assert!(run(&mut unlimited, "len(range(100))").is_ok());
+}
+
+#[test]
+fn test_imports() {
+    let dir = test_utils::write_files("vm-imports", test_utils::IMPORT_TEST_FILES);
+    let compile = |file: &str| -> MonkeyResult<Bytecode> {
+        let path = dir.join(file);
+        let mut comp = Compiler::with_file(&path)?;
+        comp.compile_block(crate::module::parse_file(&path).expect("Parser error during test"))?;
+        Ok(comp.bytecode())
+    };
+
+    let mut vm = VM::new();
+    vm.run(compile("main.mk").expect("Compiler error during test"))
+        .unwrap();
+    let expected = monkey_array![Object::Integer(4), Object::Integer(5), Object::Integer(3)];
+    assert_eq!(vm.pop().unwrap(), expected);
+
+    let error = compile("cycle.mk")
+        .err()
+        .expect("No compiler error encountered");
+    assert!(format!("{}", error).contains("import cycle: "));
+
+    // The path must be known at compile time
+    let error = test_utils::parse_and_compile(r#"let p = "a.mk"; import(p)"#).err();
+    let error = error.expect("No compiler error encountered");
+    assert!(format!("{}", error).contains("must be a string literal"));
+}
+
+fn assert_verifier_error(
+    instructions: &[Box<[u8]>],
+    constants: Vec<Object>,
+    expected_location: CodeLocation,
+    expected: VerifierError,
+) {
+    let bytecode = Bytecode {
+        instructions: Instructions(instructions.concat()),
+        constants,
+        lines: LineTable::new(),
+    };
+    match VM::new().run(bytecode) {
+        Err(MonkeyError::Verifier(location, e)) => {
+            assert_eq!((expected_location, expected), (location, e))
+        }
+        Err(e) => panic!("Wrong error type: {}", e),
+        Ok(_) => panic!("No verifier error encountered"),
+    }
+}
+
+fn function(instructions: &[Box<[u8]>], num_locals: u8, num_params: u8) -> Object {
+    Object::CompiledFunc(Rc::new(CompiledFunction {
+        instructions: Instructions(instructions.concat()),
+        num_locals,
+        num_params,
+    }))
+}
+
+#[test]
+fn test_verifier() {
+    use OpCode::*;
+    use VerifierError::*;
+
+    assert_verifier_error(&[Box::new([255])], vec![], (None, 0), InvalidOpCode(255));
+    assert_verifier_error(
+        &[make!(OpNil), Box::new([OpConstant as u8, 0])],
+        vec![],
+        (None, 1),
+        TruncatedInstruction,
+    );
+    assert_verifier_error(
+        &[make!(OpConstant, 3)],
+        vec![Object::Integer(1)],
+        (None, 0),
+        ConstantOutOfRange(3),
+    );
+    assert_verifier_error(
+        &[make!(OpClosure, 0, 0)],
+        vec![Object::Integer(1)],
+        (None, 0),
+        WrongConstantType(0, "function"),
+    );
+    assert_verifier_error(
+        &[make!(OpGetField, 0)],
+        vec![Object::Integer(1)],
+        (None, 0),
+        WrongConstantType(0, "string"),
+    );
+    assert_verifier_error(&[make!(OpGetLocal, 0)], vec![], (None, 0), InvalidLocal(0));
+    assert_verifier_error(
+        &[make!(OpGetBuiltin, 255)],
+        vec![],
+        (None, 0),
+        InvalidBuiltin(255),
+    );
+    assert_verifier_error(
+        &[make!(OpNil), make!(OpJump, 2)],
+        vec![],
+        (None, 1),
+        InvalidJumpTarget(2),
+    );
+    assert_verifier_error(&[make!(OpPop)], vec![], (None, 0), StackUnderflow);
+    assert_verifier_error(&[make!(OpAdd)], vec![], (None, 0), StackUnderflow);
+    // The jump reaches the end with an empty stack, and the other path with two values
+    assert_verifier_error(
+        &[
+            make!(OpTrue),
+            make!(OpJumpNotTruthy, 6),
+            make!(OpNil),
+            make!(OpNil),
+        ],
+        vec![],
+        (None, 6),
+        InconsistentStack,
+    );
+    assert_verifier_error(
+        &[make!(OpNil), make!(OpReturn)],
+        vec![],
+        (None, 1),
+        MisplacedInstruction(OpReturn),
+    );
+    assert_verifier_error(&[make!(OpEndTry)], vec![], (None, 0), UnbalancedTry);
+    // Values on the stack when the handler is registered can't be popped by the `try` body
+    assert_verifier_error(
+        &[make!(OpNil), make!(OpTry, 5), make!(OpPop)],
+        vec![],
+        (None, 4),
+        StackUnderflow,
+    );
+}
+
+#[test]
+fn test_verifier_functions() {
+    use OpCode::*;
+    use VerifierError::*;
+
+    let closure = [make!(OpClosure, 0, 0)];
+    assert_verifier_error(
+        &closure,
+        vec![function(&[make!(OpNil)], 0, 0)],
+        (Some(0), 1),
+        MissingReturn,
+    );
+    assert_verifier_error(
+        &closure,
+        vec![function(&[make!(OpGetFree, 0), make!(OpReturn)], 0, 0)],
+        (Some(0), 0),
+        InvalidFreeVariable(0),
+    );
+    // Arguments and locals can't be popped
+    assert_verifier_error(
+        &closure,
+        vec![function(&[make!(OpReturn)], 1, 1)],
+        (Some(0), 0),
+        StackUnderflow,
+    );
+    assert_verifier_error(
+        &closure,
+        vec![function(
+            &[make!(OpIncrementLocal, 1, 0), make!(OpReturn)],
+            1,
+            1,
+        )],
+        (Some(0), 0),
+        InvalidLocal(1),
+    );
+    assert_verifier_error(
+        &closure,
+        vec![function(
+            &[
+                make!(OpNil),
+                make!(OpJumpNotLess, 5),
+                make!(OpNil),
+                make!(OpReturn),
+            ],
+            0,
+            0,
+        )],
+        (Some(0), 1),
+        StackUnderflow,
+    );
+    assert_verifier_error(
+        &closure,
+        vec![function(&[make!(OpGetLocal, 0), make!(OpReturn)], 0, 1)],
+        (Some(0), 0),
+        InvalidParameterCount(1, 0),
+    );
+    assert_verifier_error(
+        &closure,
+        vec![function(
+            &[make!(OpNil), make!(OpYield), make!(OpNil), make!(OpReturn)],
+            0,
+            0,
+        )],
+        (Some(0), 1),
+        MisplacedInstruction(OpYield),
+    );
+    assert_verifier_error(
+        &closure,
+        vec![function(
+            &[
+                make!(OpTry, 7),
+                make!(OpGetBuiltin, 0),
+                make!(OpTailCall, 0),
+                make!(OpReturn),
+            ],
+            0,
+            0,
+        )],
+        (Some(0), 5),
+        TailCallInTry,
+    );
+
+    // The verifier doesn't check the types of values, so the VM still checks them
+    let bytecode = Bytecode {
+        instructions: Instructions(
+            [make!(OpGetBuiltin, 0), make!(OpNil), make!(OpCallSpread)].concat(),
+        ),
+        constants: vec![],
+        lines: LineTable::new(),
+    };
+    match VM::new().run(bytecode) {
+        Err(MonkeyError::Vm(e)) => {
+            assert_eq!("type error: expected 'array', got 'nil'", e.to_string())
+        }
+        _ => panic!("No runtime error encountered"),
+    }
+}
+
+#[test]
+fn test_verifier_max_depths() {
+    use OpCode::*;
+
+    let bytecode = Bytecode {
+        instructions: Instructions(
+            [
+                make!(OpConstant, 1),
+                make!(OpConstant, 1),
+                make!(OpAdd),
+                make!(OpClosure, 0, 1),
+                make!(OpPop),
+            ]
+            .concat(),
+        ),
+        constants: vec![
+            function(
+                &[
+                    make!(OpGetLocal, 0),
+                    make!(OpGetFree, 0),
+                    make!(OpGetLocal, 1),
+                    make!(OpArray, 3),
+                    make!(OpReturn),
+                ],
+                2,
+                1,
+            ),
+            Object::Integer(1),
+        ],
+        lines: LineTable::new(),
+    };
+    let max_depths = verifier::verify(&bytecode).unwrap();
+    assert_eq!(2, max_depths.main);
+    assert_eq!(vec![5, 0], max_depths.functions);
+
+    // The frames of calls have their own depth: at most, the function has `n`, `1`, `f`, `n` and `1`
+    // on its part of the stack
+    let bytecode =
+        test_utils::parse_and_compile("let f = fn(n) { if n == 0 { 0 } else { 1 + f(n - 1) } }")
+            .unwrap();
+    let max_depths = verifier::verify(&bytecode).unwrap();
+    assert_eq!(vec![0, 0, 0, 0, 5], max_depths.functions);
+}
+
+#[test]
+fn test_optimized_programs() {
+    let programs = [
+        "1 + 2 * 3 - 4 / 2",
+        "-(2 ^ 62) * 2",
+        "10 % 0",
+        "1 << 64",
+        "2 ^ -1",
+        "~(1 | 6) & (12 ~ 5) >> 1",
+        r#""mon" + "key" + "!""#,
+        r#""a" + 1"#,
+        "!!(1 < 2) == (3 >= 4)",
+        "-true",
+        "let x = 5; if x > 2 + 1 { x; 1; x * 2 } else { 0 }",
+        "if !!0 { 1 } else { 2 }",
+        "if nil { 1 }",
+        "if false { 1 } else { if true { 2 } }",
+        "let f = fn(n) { if n < 2 { n } else { f(n - 1) + f(n - 2) } }; f(15)",
+        "let f = fn() { if true { return 1; }; 3 }; f()",
+        "let s = fn(xs) { for x in xs { 4; if x > 1 { return x * 2; } } }; s([1, 2, 3])",
+        "let g = fn*() { yield 1 + 1; 3; yield !true; }; let it = g(); [next(it), next(it), next(it)]",
+        r#"try { 1 / 0 } catch e { "caught" + "!" }"#,
+        "let h = #{1 + 1: 2 * 2}; h[2]",
+        "let a = nil; a ?? 10 * 10",
+        "fn(x) { throw 1 + 1; }(1)",
+        "let f = fn(a, b) { if a < b { a - 1 } else { b + 2 } }; [f(1, 2), f(5, 3), f(4, 4)]",
+        "let f = fn(a, b) { if a <= b { 1 } else { if a > b { 2 } } }; [f(1, 2), f(4, 4), f(5, 3)]",
+        r#"let f = fn(a) { if a >= 3 { 1 } else { 2 } }; f("a")"#,
+        r#"let f = fn(a) { a + 1 }; f("a")"#,
+        r#"let f = fn(a) { a - "b" }; f(1)"#,
+        r#"let f = fn(a) { a + "b" }; f("a")"#,
+        "let f = fn(a) { if a > 1 { a }; a < 1 }; f(1)",
+    ];
+    for program in programs {
+        let mut results = Vec::new();
+        for opt_level in [OptLevel::None, OptLevel::Fold, OptLevel::Full] {
+            let bytecode = test_utils::parse_and_compile_with(program, opt_level)
+                .expect("Parser or compiler error during test");
+            let mut vm = VM::new();
+            let result = vm.run(bytecode).map(|_| vm.pop().unwrap());
+            results.push(format!("{:?}", result));
+        }
+        assert_eq!(results[0], results[1], "{}", program);
+        assert_eq!(results[0], results[2], "{}", program);
+    }
+}
@@ -0,0 +1,360 @@
+// The VM trusts the bytecode it runs: it doesn't check that opcodes and operands are valid, or
+// that there are enough values on the stack, since doing that for every instruction would be slow.
+// The verifier checks the bytecode once before it's run, so bytecode that wasn't produced by the
+// compiler, like a corrupted bytecode file, is rejected with an error instead of crashing the VM.
+//
+// The main program and each function are checked separately. Besides checking the operands of
+// each instruction, the verifier follows every path through the code, tracking the size of the
+// stack and the `try` handlers that are registered. Instructions must never pop values that don't
+// belong to them, and every path that reaches an instruction must reach it in the same state.
+// This also gives the most values each function can have on the stack at once, so the VM only
+// has to check for stack overflows when a function is called, instead of on every push.
+
+use crate::builtins;
+use crate::compiler::code::*;
+use crate::error::{MonkeyError, MonkeyResult, VerifierError, VerifierError::*};
+use crate::object::*;
+
+use std::collections::HashMap;
+
+// The code of the main program or of a function
+struct Unit<'a> {
+    // The index of the function in the constant pool, or `None` for the main program
+    function: Option<usize>,
+    bytes: &'a [u8],
+    num_locals: usize,
+    num_params: usize,
+}
+
+impl Unit<'_> {
+    fn is_generator(&self) -> bool {
+        self.function.is_some() && self.bytes.first() == Some(&(OpCode::OpGenerator as u8))
+    }
+
+    // The size of the stack of the frame when it starts running: the locals, starting with the
+    // arguments
+    fn frame_size(&self) -> usize {
+        self.num_locals
+    }
+
+    fn error(&self, (offset, error): (usize, VerifierError)) -> MonkeyError {
+        MonkeyError::Verifier((self.function, offset), error)
+    }
+}
+
+struct Instruction {
+    offset: usize,
+    op: OpCode,
+    operands: Vec<usize>,
+}
+
+impl Instruction {
+    fn next_offset(&self) -> usize {
+        self.offset + 1 + self.op.operand_widths().iter().sum::<usize>()
+    }
+}
+
+// The state of the frame before running an instruction
+#[derive(Clone, PartialEq)]
+struct State {
+    // The number of values on the stack of the frame, including the arguments and locals
+    depth: usize,
+    // The stack size when each of the registered `try` handlers was registered, innermost last
+    handlers: Vec<usize>,
+}
+
+// An error, and the offset of the instruction where it was found
+type UnitResult<T> = Result<T, (usize, VerifierError)>;
+
+/// The most values the main program and each function can have on their part of the stack at
+/// once, including their locals.
+pub struct MaxDepths {
+    pub main: usize,
+    // Indexed by the constant of each function. Constants that aren't functions have a depth of 0
+    pub functions: Vec<usize>,
+}
+
+/// Checks that the bytecode is well-formed, so it can be run by the VM without crashing it.
+pub fn verify(bytecode: &Bytecode) -> MonkeyResult<MaxDepths> {
+    let mut units = vec![Unit {
+        function: None,
+        bytes: &bytecode.instructions.0,
+        num_locals: 0,
+        num_params: 0,
+    }];
+    for (index, constant) in bytecode.constants.iter().enumerate() {
+        if let Object::CompiledFunc(func) = constant {
+            let unit = Unit {
+                function: Some(index),
+                bytes: &func.instructions.0,
+                num_locals: func.num_locals as usize,
+                num_params: func.num_params as usize,
+            };
+            if unit.num_params > unit.num_locals {
+                let error = InvalidParameterCount(func.num_params, func.num_locals);
+                return Err(unit.error((0, error)));
+            }
+            units.push(unit);
+        }
+    }
+
+    let mut decoded = Vec::with_capacity(units.len());
+    for unit in &units {
+        decoded.push(decode(unit.bytes).map_err(|e| unit.error(e))?);
+    }
+
+    // A closure gets its free variables from the `OpClosure` instruction that creates it, so a
+    // function can only use as many free variables as every `OpClosure` that refers to it provides
+    let mut num_free_vars = HashMap::new();
+    for (unit, instructions) in units.iter().zip(&decoded) {
+        for instruction in instructions {
+            check_operands(unit, instruction, &bytecode.constants)
+                .map_err(|e| unit.error((instruction.offset, e)))?;
+            if let (OpCode::OpClosure, &[function, num_free]) =
+                (instruction.op, &*instruction.operands)
+            {
+                let min = num_free_vars.entry(function).or_insert(num_free);
+                *min = num_free.min(*min);
+            }
+        }
+    }
+
+    let mut max_depths = MaxDepths {
+        main: 0,
+        functions: vec![0; bytecode.constants.len()],
+    };
+    for (unit, instructions) in units.iter().zip(&decoded) {
+        let num_free = unit
+            .function
+            .and_then(|index| num_free_vars.get(&index))
+            .copied()
+            .unwrap_or(0);
+        for instruction in instructions {
+            if let (OpCode::OpGetFree, &[index]) = (instruction.op, &*instruction.operands) {
+                if index >= num_free {
+                    return Err(unit.error((instruction.offset, InvalidFreeVariable(index))));
+                }
+            }
+        }
+        let max_depth = check_flow(unit, instructions).map_err(|e| unit.error(e))?;
+        match unit.function {
+            Some(index) => max_depths.functions[index] = max_depth,
+            None => max_depths.main = max_depth,
+        }
+    }
+    Ok(max_depths)
+}
+
+/// Splits the bytes into instructions, checking that every opcode is valid and has all of its
+/// operands.
+fn decode(bytes: &[u8]) -> UnitResult<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let op =
+            OpCode::try_from_byte(bytes[offset]).ok_or((offset, InvalidOpCode(bytes[offset])))?;
+        let instruction_len = 1 + op.operand_widths().iter().sum::<usize>();
+        if offset + instruction_len > bytes.len() {
+            return Err((offset, TruncatedInstruction));
+        }
+        let (operands, _) = read_operands(op, &bytes[offset + 1..]);
+        instructions.push(Instruction {
+            offset,
+            op,
+            operands,
+        });
+        offset += instruction_len;
+    }
+    Ok(instructions)
+}
+
+/// Checks the operands that refer to constants, locals and built-ins, and that the instruction can
+/// be used in the code it appears in.
+fn check_operands(
+    unit: &Unit,
+    instruction: &Instruction,
+    constants: &[Object],
+) -> Result<(), VerifierError> {
+    use OpCode::*;
+    let operands = &instruction.operands;
+    let constant = |index: usize| constants.get(index).ok_or(ConstantOutOfRange(index));
+    match instruction.op {
+        OpConstant => {
+            constant(operands[0])?;
+        }
+        OpGetField | OpSetField | OpMethodCall | OpMethodCallSpread => {
+            match constant(operands[0])? {
+                Object::Str(_) => {}
+                _ => return Err(WrongConstantType(operands[0], "string")),
+            }
+        }
+        OpClosure => match constant(operands[0])? {
+            Object::CompiledFunc(_) => {}
+            _ => return Err(WrongConstantType(operands[0], "function")),
+        },
+        OpGetLocal | OpSetLocal if operands[0] >= unit.num_locals => {
+            return Err(InvalidLocal(operands[0]));
+        }
+        OpIncrementLocal | OpDecrementLocal => {
+            if operands[0] >= unit.num_locals {
+                return Err(InvalidLocal(operands[0]));
+            }
+            constant(operands[1])?;
+        }
+        OpGetBuiltin if operands[0] >= builtins::ALL_BUILTINS.len() => {
+            return Err(InvalidBuiltin(operands[0]));
+        }
+        // Returning from the main program would pop the root frame
+        OpReturn if unit.function.is_none() => return Err(MisplacedInstruction(OpReturn)),
+        // Generator functions start by suspending their frame, and only their frames can be
+        // suspended. They can't be reused by tail calls either
+        OpGenerator if unit.function.is_none() || instruction.offset != 0 => {
+            return Err(MisplacedInstruction(OpGenerator));
+        }
+        OpYield if !unit.is_generator() => return Err(MisplacedInstruction(OpYield)),
+        OpTailCall if unit.function.is_none() || unit.is_generator() => {
+            return Err(MisplacedInstruction(OpTailCall));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Follows every path through the code, checking the state of the frame before each instruction.
+/// Returns the largest number of values the frame can have on the stack.
+fn check_flow(unit: &Unit, instructions: &[Instruction]) -> UnitResult<usize> {
+    // Maps offsets to indices in `instructions`. The end of the code is also a valid target, and
+    // its index is `instructions.len()`
+    let mut indices: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| (instruction.offset, index))
+        .collect();
+    indices.insert(unit.bytes.len(), instructions.len());
+
+    let mut states: Vec<Option<State>> = vec![None; instructions.len() + 1];
+    states[0] = Some(State {
+        depth: unit.frame_size(),
+        handlers: Vec::new(),
+    });
+    let mut pending = vec![0];
+    let mut max_depth = unit.frame_size();
+    while let Some(index) = pending.pop() {
+        // Reaching the end of the main program ends it, but functions must return
+        if index == instructions.len() {
+            if unit.function.is_some() {
+                return Err((unit.bytes.len(), MissingReturn));
+            }
+            continue;
+        }
+
+        let instruction = &instructions[index];
+        let state = states[index].clone().unwrap();
+        for (target, next_state) in successors(unit, instruction, state)? {
+            let target_index = match indices.get(&target) {
+                // Generator functions must only run `OpGenerator` when they are called, and the
+                // compiler never jumps back to the start of the code, so it's never a valid target
+                Some(&target_index) if target != 0 => target_index,
+                _ => return Err((instruction.offset, InvalidJumpTarget(target))),
+            };
+            match &states[target_index] {
+                Some(existing) if *existing != next_state => {
+                    return Err((target, InconsistentStack));
+                }
+                Some(_) => {}
+                None => {
+                    max_depth = max_depth.max(next_state.depth);
+                    states[target_index] = Some(next_state);
+                    pending.push(target_index);
+                }
+            }
+        }
+    }
+    Ok(max_depth)
+}
+
+/// Returns the instructions that can run after `instruction`, and the state of the frame when they
+/// do.
+fn successors(
+    unit: &Unit,
+    instruction: &Instruction,
+    state: State,
+) -> UnitResult<Vec<(usize, State)>> {
+    use OpCode::*;
+    let error = |e| Err((instruction.offset, e));
+    let operands = &instruction.operands;
+    let next = instruction.next_offset();
+
+    // The values below the stack size when the innermost handler was registered are restored if an
+    // exception happens, so they can't be popped. Neither can the arguments and locals
+    let min_depth = state
+        .handlers
+        .last()
+        .copied()
+        .unwrap_or_else(|| unit.frame_size());
+    let (pops, pushes) = stack_effect(instruction.op, operands);
+    if state.depth < min_depth + pops {
+        return error(StackUnderflow);
+    }
+    let mut after = state.clone();
+    after.depth = state.depth - pops + pushes;
+
+    let successors = match instruction.op {
+        OpReturn | OpThrow => vec![],
+        OpJump => vec![(operands[0], after)],
+        OpJumpNotTruthy | OpJumpNotError | OpJumpNotLess | OpJumpNotLessEq | OpJumpNotGreater
+        | OpJumpNotGreaterEq => vec![(next, after.clone()), (operands[0], after)],
+        // The value is only popped if it's `nil` and the jump isn't taken
+        OpJumpNotNil => vec![(next, after), (operands[0], state)],
+        // If the iterator is exhausted, the iterable and the index are popped
+        OpIterNext => {
+            let mut done = state;
+            done.depth -= 2;
+            vec![(next, after), (operands[0], done)]
+        }
+        // If an exception happens, the handler runs with the exception on top of the stack
+        OpTry => {
+            after.handlers.push(state.depth);
+            let mut caught = state;
+            caught.depth += 1;
+            vec![(next, after), (operands[0], caught)]
+        }
+        OpEndTry => {
+            if after.handlers.pop().is_none() {
+                return error(UnbalancedTry);
+            }
+            vec![(next, after)]
+        }
+        OpTailCall if !state.handlers.is_empty() => return error(TailCallInTry),
+        _ => vec![(next, after)],
+    };
+    Ok(successors)
+}
+
+/// Returns the number of values the instruction pops from the stack, and the number of values it
+/// pushes. For conditional jumps, this is the effect when the jump isn't taken.
+fn stack_effect(op: OpCode, operands: &[usize]) -> (usize, usize) {
+    use OpCode::*;
+    match op {
+        OpConstant | OpTrue | OpFalse | OpNil | OpGetGlobal | OpGetLocal | OpGetBuiltin
+        | OpGetFree | OpIncrementLocal | OpDecrementLocal => (0, 1),
+        OpPop | OpSetGlobal | OpSetLocal | OpJumpNotTruthy | OpJumpNotNil | OpReturn | OpThrow
+        | OpYield => (1, 0),
+        OpAdd | OpSub | OpMul | OpDiv | OpExponent | OpModulo | OpEquals | OpNotEquals
+        | OpGreaterThan | OpGreaterEq | OpLessThan | OpLessEq | OpBitAnd | OpBitOr | OpBitXor
+        | OpShiftLeft | OpShiftRight | OpIndex | OpIndexOptional | OpSetField | OpArrayPush
+        | OpArrayExtend | OpHashExtend | OpCallSpread | OpMethodCallSpread => (2, 1),
+        OpPrefixMinus | OpPrefixNot | OpPrefixBitNot | OpGetField | OpJumpNotError => (1, 1),
+        OpJump | OpTry | OpEndTry | OpGenerator => (0, 0),
+        OpJumpNotLess | OpJumpNotLessEq | OpJumpNotGreater | OpJumpNotGreaterEq => (2, 0),
+        OpArray | OpConcat => (operands[0], 1),
+        OpHash => (2 * operands[0], 1),
+        OpHashInsert => (3, 1),
+        OpClosure => (operands[1], 1),
+        OpCall | OpTailCall => (operands[0] + 1, 1),
+        OpMethodCall => (operands[1] + 1, 1),
+        // The iterable stays on the stack, below the index of the next element
+        OpIter => (1, 2),
+        OpIterNext => (2, 3),
+    }
+}